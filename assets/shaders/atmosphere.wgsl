@@ -0,0 +1,93 @@
+// Single scattering Rayleigh and Mie atmosphere, following "Display of the Earth Taking into Account Atmospheric Scattering" by Nishita et al.
+// Distances are in meters, the viewer stands on the ground
+#import "shaders/const.wgsl"::PI
+
+const PLANET_RADIUS: f32 = 6360e3;
+const ATMOSPHERE_RADIUS: f32 = 6420e3;
+const VIEWER_HEIGHT: f32 = 1.0;
+const RAYLEIGH_SCATTERING: vec3<f32> = vec3<f32>(5.8e-6, 13.5e-6, 33.1e-6);
+const RAYLEIGH_SCALE_HEIGHT: f32 = 7994.0;
+const MIE_SCATTERING: f32 = 21e-6;
+const MIE_SCALE_HEIGHT: f32 = 1200.0;
+// Mie scattering strongly prefers the forward direction
+const MIE_ANISOTROPY: f32 = 0.76;
+const VIEW_STEPS: u32 = 16u;
+const LIGHT_STEPS: u32 = 8u;
+
+// The light scattered towards the viewer along a direction, for a sun with an intensity of 1.0
+fn atmosphere(direction: vec3<f32>, sun_direction: vec3<f32>) -> vec3<f32> {
+    let view = normalize(direction);
+    let origin = vec3<f32>(0.0, PLANET_RADIUS + VIEWER_HEIGHT, 0.0);
+
+    // Rays going below the horizon see the atmosphere up to the ground
+    var length = sphere_exit(origin, view, ATMOSPHERE_RADIUS);
+    let ground = sphere_entry(origin, view, PLANET_RADIUS);
+    if ground > 0.0 {
+        length = ground;
+    }
+
+    let step_length = length / f32(VIEW_STEPS);
+    var rayleigh = vec3<f32>(0.0);
+    var mie = vec3<f32>(0.0);
+    var rayleigh_depth = 0.0;
+    var mie_depth = 0.0;
+
+    for (var step: u32 = 0; step < VIEW_STEPS; step++) {
+        let position = origin + view * (f32(step) + 0.5) * step_length;
+        let height = length(position) - PLANET_RADIUS;
+        let rayleigh_density = exp(-height / RAYLEIGH_SCALE_HEIGHT) * step_length;
+        let mie_density = exp(-height / MIE_SCALE_HEIGHT) * step_length;
+        rayleigh_depth += rayleigh_density;
+        mie_depth += mie_density;
+
+        // The sun is blocked by the planet
+        if sphere_entry(position, sun_direction, PLANET_RADIUS) > 0.0 {
+            continue;
+        }
+
+        let light_length = sphere_exit(position, sun_direction, ATMOSPHERE_RADIUS);
+        let light_step_length = light_length / f32(LIGHT_STEPS);
+        var light_rayleigh_depth = 0.0;
+        var light_mie_depth = 0.0;
+        for (var light_step: u32 = 0; light_step < LIGHT_STEPS; light_step++) {
+            let light_position = position + sun_direction * (f32(light_step) + 0.5) * light_step_length;
+            let light_height = length(light_position) - PLANET_RADIUS;
+            light_rayleigh_depth += exp(-light_height / RAYLEIGH_SCALE_HEIGHT) * light_step_length;
+            light_mie_depth += exp(-light_height / MIE_SCALE_HEIGHT) * light_step_length;
+        }
+
+        let transmittance = exp(-(RAYLEIGH_SCATTERING * (rayleigh_depth + light_rayleigh_depth) + MIE_SCATTERING * 1.1 * (mie_depth + light_mie_depth)));
+        rayleigh += transmittance * rayleigh_density;
+        mie += transmittance * mie_density;
+    }
+
+    let cos_angle = dot(view, sun_direction);
+    let rayleigh_phase = 3.0 / (16.0 * PI) * (1.0 + cos_angle * cos_angle);
+    let g = MIE_ANISOTROPY;
+    let mie_phase = 3.0 / (8.0 * PI) * ((1.0 - g * g) * (1.0 + cos_angle * cos_angle)) / ((2.0 + g * g) * pow(1.0 + g * g - 2.0 * g * cos_angle, 1.5));
+
+    return rayleigh * RAYLEIGH_SCATTERING * rayleigh_phase + mie * MIE_SCATTERING * mie_phase;
+}
+
+// How much light makes it through the air near the ground over a distance in meters
+fn aerial_transmittance(distance: f32) -> vec3<f32> {
+    return exp(-(RAYLEIGH_SCATTERING + vec3<f32>(MIE_SCATTERING * 1.1)) * distance);
+}
+
+// Distance along the ray to where it leaves a sphere around the planet center, the origin has to be inside
+fn sphere_exit(origin: vec3<f32>, direction: vec3<f32>, radius: f32) -> f32 {
+    let b = dot(origin, direction);
+    let c = dot(origin, origin) - radius * radius;
+    return -b + sqrt(max(b * b - c, 0.0));
+}
+
+// Distance along the ray to where it enters a sphere around the planet center, negative if it doesn't
+fn sphere_entry(origin: vec3<f32>, direction: vec3<f32>, radius: f32) -> f32 {
+    let b = dot(origin, direction);
+    let c = dot(origin, origin) - radius * radius;
+    let discriminant = b * b - c;
+    if discriminant < 0.0 {
+        return -1.0;
+    }
+    return -b - sqrt(discriminant);
+}
@@ -0,0 +1,30 @@
+// The interface between the path tracer and custom BSDFs
+// A custom BSDF is a module with a function `fn scatter(input: BsdfInput, state: ptr<private, u32>) -> BsdfOutput`,
+// registered for a tag with `register_raytrace_bsdf`. It can use the random functions from random.wgsl.
+
+struct BsdfInput {
+    // Direction of the incoming ray, not normalized
+    direction: vec3<f32>,
+    position: vec3<f32>,
+    // The outward facing surface normal
+    normal: vec3<f32>,
+    // Wether the ray hit the outside of the surface
+    front_face: bool,
+    uv: vec2<f32>,
+    // The material values of the hit object, with the procedural texture and decals already applied to the base color
+    base_color: vec3<f32>,
+    metallic: f32,
+    roughness: f32,
+    ior: f32,
+}
+
+struct BsdfOutput {
+    // Direction of the scattered ray
+    direction: vec3<f32>,
+    // How much of the light along the scattered ray reaches the incoming one
+    attenuation: vec3<f32>,
+    // Ends the path, for example when a ray scatters below the surface
+    absorbed: bool,
+    // Diffuse interactions gather caustics from the photon map, specular ones don't
+    diffuse: bool,
+}
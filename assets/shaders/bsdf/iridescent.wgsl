@@ -0,0 +1,23 @@
+// An example for a custom BSDF, a glossy coating that shifts its color with the viewing angle like a soap film
+#import "shaders/bsdf.wgsl"::{BsdfInput, BsdfOutput}
+#import "shaders/random.wgsl"::{rngNextFloat, randomUnitVec3}
+
+fn scatter(input: BsdfInput, state: ptr<private, u32>) -> BsdfOutput {
+    let direction = normalize(input.direction);
+    let cos_theta = abs(dot(direction, input.normal));
+
+    // Light interfering in a thin film, the phase of every channel changes differently with the angle
+    let phase = vec3<f32>(1.0, 1.3, 1.6) * 6.0 * cos_theta;
+    let film = 0.5 + 0.5 * cos(phase);
+
+    if rngNextFloat(state) < 0.5 {
+        // The coating reflects specularly
+        let reflected = direction - 2.0 * dot(direction, input.normal) * input.normal
+            + input.roughness * randomUnitVec3(state);
+        return BsdfOutput(reflected, film, dot(reflected, input.normal) <= 0.0, false);
+    }
+
+    // The base layer is diffuse
+    let scattered = input.normal + randomUnitVec3(state);
+    return BsdfOutput(scattered, input.base_color, dot(scattered, input.normal) <= 0.0, true);
+}
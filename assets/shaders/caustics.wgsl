@@ -0,0 +1,25 @@
+// Shared definitions of the caustic photon map
+// Photons are stored in a hashed grid with a fixed amount of slots per cell, photons that don't fit are dropped
+
+const PHOTON_CELLS: u32 = 65536u;
+const PHOTONS_PER_CELL: u32 = 8u;
+
+struct Caustics {
+    enabled: u32,
+    photon_count: u32,
+    gather_radius: f32,
+    random_seed: f32,
+    // photons are only shot at this sphere
+    center: vec3<f32>,
+    radius: f32,
+}
+
+struct Photon {
+    position: vec3<f32>,
+    power: vec3<f32>,
+}
+
+fn photon_cell(cell: vec3<i32>) -> u32 {
+    let hash = (u32(cell.x) * 73856093u) ^ (u32(cell.y) * 19349663u) ^ (u32(cell.z) * 83492791u);
+    return hash % PHOTON_CELLS;
+}
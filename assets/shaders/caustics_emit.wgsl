@@ -0,0 +1,81 @@
+// Shoots photons from the sky at the caustics region and stores them once they reach a diffuse surface through specular bounces
+#import "shaders/random.wgsl"::rngNextFloat
+#import "shaders/const.wgsl"::{PI, INF}
+#import "shaders/caustics.wgsl"::{Caustics, Photon, PHOTONS_PER_CELL, photon_cell}
+#import "shaders/scene.wgsl"::{Ray, raycast, scatter, sky_radiance}
+
+@group(0) @binding(0) var<uniform> caustics: Caustics;
+@group(0) @binding(1) var<storage, read_write> photon_counts: array<atomic<u32>>;
+@group(0) @binding(2) var<storage, read_write> photons: array<Photon>;
+
+const MAX_PHOTON_BOUNCES: u32 = 8u;
+
+var<private> rng_state: u32;
+
+@compute @workgroup_size(64)
+fn emit(@builtin(global_invocation_id) id: vec3<u32>) {
+    if id.x >= caustics.photon_count {
+        return;
+    }
+
+    rng_state = u32(caustics.random_seed * 4294967295.0) ^ (id.x * 9781u + 6271u);
+
+    // Uniform direction from the upper hemisphere, travelling downwards
+    let cos_theta = rngNextFloat(&rng_state);
+    let sin_theta = sqrt(1.0 - cos_theta * cos_theta);
+    let phi = 2.0 * PI * rngNextFloat(&rng_state);
+    let direction = -vec3<f32>(sin_theta * cos(phi), cos_theta, sin_theta * sin(phi));
+
+    // Uniform point on a disk facing the direction, covering the caustics region
+    var tangent = normalize(cross(direction, vec3<f32>(1.0, 0.0, 0.0)));
+    if abs(direction.x) > 0.9 {
+        tangent = normalize(cross(direction, vec3<f32>(0.0, 0.0, 1.0)));
+    }
+    let bitangent = cross(direction, tangent);
+    let disk_radius = caustics.radius * sqrt(rngNextFloat(&rng_state));
+    let disk_angle = 2.0 * PI * rngNextFloat(&rng_state);
+    let origin = caustics.center - direction * caustics.radius * 2.0
+        + (tangent * cos(disk_angle) + bitangent * sin(disk_angle)) * disk_radius;
+
+    var ray = Ray(origin, direction);
+
+    // Radiance of the sky times the disk area and the hemisphere, split between all photons
+    let disk_area = PI * caustics.radius * caustics.radius;
+    var power = sky_radiance(Ray(origin, -direction)) * disk_area * 2.0 * PI / f32(caustics.photon_count);
+
+    var specular_bounces: u32 = 0;
+    for (var bounce: u32 = 0; bounce < MAX_PHOTON_BOUNCES; bounce++) {
+        let hit = raycast(ray);
+        if hit.distance == INF {
+            return;
+        }
+
+        var attenuation: vec3<f32>;
+        var diffuse: bool;
+        let absorbed = scatter(&ray, &attenuation, &diffuse, hit, &rng_state);
+
+        if diffuse {
+            // Direct light is handled by the path tracer, only caustics go into the map
+            if specular_bounces > 0 {
+                store_photon(hit.position, power);
+            }
+            return;
+        }
+
+        if absorbed {
+            return;
+        }
+
+        power *= attenuation;
+        specular_bounces++;
+    }
+}
+
+fn store_photon(position: vec3<f32>, power: vec3<f32>) {
+    let cell_size = caustics.gather_radius * 2.0;
+    let cell = photon_cell(vec3<i32>(floor(position / cell_size)));
+    let slot = atomicAdd(&photon_counts[cell], 1u);
+    if slot < PHOTONS_PER_CELL {
+        photons[cell * PHOTONS_PER_CELL + slot] = Photon(position, power);
+    }
+}
@@ -0,0 +1,27 @@
+// Fills in the pixels checkerboard cameras neither traced nor reprojected this frame
+#import bevy_core_pipeline::fullscreen_vertex_shader::FullscreenVertexOutput
+
+// The raytraced image, the pixels left out are transparent
+@group(0) @binding(0) var screen_texture: texture_2d<f32>;
+
+// The left out pixels are surrounded by traced ones, the average of those stands in for them
+@fragment
+fn fragment(in: FullscreenVertexOutput) -> @location(0) vec4<f32> {
+    let pixel = vec2<i32>(in.position.xy);
+    let color = textureLoad(screen_texture, pixel, 0);
+    if color.a > 0.0 {
+        return color;
+    }
+
+    let size = vec2<i32>(textureDimensions(screen_texture));
+    var offsets = array<vec2<i32>, 4>(vec2<i32>(1, 0), vec2<i32>(-1, 0), vec2<i32>(0, 1), vec2<i32>(0, -1));
+    var sum = vec3<f32>(0.0, 0.0, 0.0);
+    var count = 0.0;
+    for (var index = 0; index < 4; index++) {
+        let neighbour = textureLoad(screen_texture, clamp(pixel + offsets[index], vec2<i32>(0), size - 1), 0);
+        sum += neighbour.rgb * neighbour.a;
+        count += neighbour.a;
+    }
+
+    return vec4<f32>(sum / max(count, 1.0), 1.0);
+}
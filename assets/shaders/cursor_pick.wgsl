@@ -0,0 +1,43 @@
+// Traces the single ray under the cursor, so picking finds what the traced image actually shows there
+#import "shaders/const.wgsl"::INF
+#import "shaders/scene.wgsl"::{Ray, raycast_scene, raycast_portals, pass_through_portal, NO_PORTAL}
+
+@group(0) @binding(0) var<uniform> cursor: CursorRay;
+struct CursorRay {
+    origin: vec3<f32>,
+    direction: vec3<f32>,
+    // The camera ray mask of the camera, so the ray hits what it shows
+    mask: u32,
+}
+@group(0) @binding(1) var<storage, read_write> result: PickResult;
+struct PickResult {
+    // NO_HIT if the ray leaves the scene
+    material: u32,
+    distance: f32,
+}
+
+const NO_HIT: u32 = 0xffffffffu;
+// Portals facing each other would go on forever
+const MAX_PORTALS: u32 = 8u;
+
+@compute @workgroup_size(1)
+fn pick() {
+    var ray = Ray(cursor.origin, cursor.direction);
+    var distance = 0.0;
+    for (var portal_count: u32 = 0; portal_count <= MAX_PORTALS; portal_count++) {
+        let hit = raycast_scene(ray, cursor.mask, false);
+        let portal = raycast_portals(ray, hit.distance);
+        if portal.index == NO_PORTAL {
+            if hit.distance < INF {
+                result = PickResult(hit.material, distance + hit.distance);
+                return;
+            }
+            break;
+        }
+
+        distance += portal.distance;
+        ray = pass_through_portal(ray, portal);
+    }
+
+    result = PickResult(NO_HIT, INF);
+}
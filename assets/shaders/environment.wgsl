@@ -0,0 +1,72 @@
+// Mapping between directions and the texels of a cubemap, used for importance sampling the environment map.
+// Faces are ordered +X, -X, +Y, -Y, +Z, -Z and s, t go from -1.0 to 1.0 across a face, like the texture coordinates do
+
+// Has to match the resolution the cdf gets built with on the CPU
+const ENVIRONMENT_CDF_RESOLUTION: u32 = 32u;
+
+// The direction through the point s, t on a face, not normalized
+fn cube_face_direction(face: u32, s: f32, t: f32) -> vec3<f32> {
+    switch face {
+        case 0u: {
+            return vec3<f32>(1.0, -t, -s);
+        }
+        case 1u: {
+            return vec3<f32>(-1.0, -t, s);
+        }
+        case 2u: {
+            return vec3<f32>(s, 1.0, t);
+        }
+        case 3u: {
+            return vec3<f32>(s, -1.0, -t);
+        }
+        case 4u: {
+            return vec3<f32>(s, -t, 1.0);
+        }
+        default: {
+            return vec3<f32>(-s, -t, -1.0);
+        }
+    }
+}
+
+// The face a direction points at, with s, t written to the point on it
+fn cube_face_coordinates(direction: vec3<f32>, st: ptr<function, vec2<f32>>) -> u32 {
+    let a = abs(direction);
+    if a.x >= a.y && a.x >= a.z {
+        if direction.x > 0.0 {
+            *st = vec2<f32>(-direction.z, -direction.y) / a.x;
+            return 0u;
+        }
+        *st = vec2<f32>(direction.z, -direction.y) / a.x;
+        return 1u;
+    }
+    if a.y >= a.z {
+        if direction.y > 0.0 {
+            *st = vec2<f32>(direction.x, direction.z) / a.y;
+            return 2u;
+        }
+        *st = vec2<f32>(direction.x, -direction.z) / a.y;
+        return 3u;
+    }
+    if direction.z > 0.0 {
+        *st = vec2<f32>(direction.x, -direction.y) / a.z;
+        return 4u;
+    }
+    *st = vec2<f32>(-direction.x, -direction.y) / a.z;
+    return 5u;
+}
+
+// Converts a density over the area of a face at s, t into one over solid angle
+fn cube_face_jacobian(st: vec2<f32>) -> f32 {
+    let d = 1.0 + dot(st, st);
+    return d * sqrt(d);
+}
+
+// Weight for combining two sampling strategies, the power heuristic from Veach's thesis
+fn power_heuristic(pdf: f32, other_pdf: f32) -> f32 {
+    let a = pdf * pdf;
+    let b = other_pdf * other_pdf;
+    if a + b == 0.0 {
+        return 0.0;
+    }
+    return a / (a + b);
+}
@@ -0,0 +1,7 @@
+// Drawn instead of the traced image while the raytracing pipeline is broken, so it can't be mistaken for the rasterized scene
+#import bevy_core_pipeline::fullscreen_vertex_shader::FullscreenVertexOutput
+
+@fragment
+fn fragment(in: FullscreenVertexOutput) -> @location(0) vec4<f32> {
+    return vec4<f32>(1.0, 0.0, 1.0, 1.0);
+}
@@ -0,0 +1,47 @@
+// Counts the log luminance of every pixel of a view into a histogram for `RaytraceAutoExposure`.
+// The bins follow bevy's auto_exposure.wgsl: the first one holds everything below the range, the others split it evenly
+struct Histogram {
+    min_log_luminance: f32,
+    inv_log_luminance_range: f32,
+}
+
+@group(0) @binding(0) var<uniform> settings: Histogram;
+@group(0) @binding(1) var view_texture: texture_2d<f32>;
+@group(0) @binding(2) var<storage, read_write> histogram: array<atomic<u32>, 64>;
+
+// Has to match the constants in exposure.rs
+const BINS: u32 = 64u;
+// Rec. 709 luminance
+const LUMINANCE: vec3<f32> = vec3<f32>(0.2126, 0.7152, 0.0722);
+
+// Every workgroup counts its pixels on its own first, so they don't all fight over the same few bins
+var<workgroup> workgroup_histogram: array<atomic<u32>, 64>;
+
+fn luminance_bin(color: vec3<f32>) -> u32 {
+    let luminance = dot(color, LUMINANCE);
+    if !(luminance >= exp2(settings.min_log_luminance)) {
+        return 0u;
+    }
+
+    let position = saturate((log2(luminance) - settings.min_log_luminance) * settings.inv_log_luminance_range);
+    return u32(position * f32(BINS - 2u) + 1.0);
+}
+
+@compute @workgroup_size(16, 16)
+fn count_luminance(@builtin(global_invocation_id) id: vec3<u32>, @builtin(local_invocation_index) index: u32) {
+    if index < BINS {
+        atomicStore(&workgroup_histogram[index], 0u);
+    }
+    workgroupBarrier();
+
+    let size = textureDimensions(view_texture);
+    if all(id.xy < size) {
+        let color = textureLoad(view_texture, vec2<i32>(id.xy), 0).rgb;
+        atomicAdd(&workgroup_histogram[luminance_bin(color)], 1u);
+    }
+    workgroupBarrier();
+
+    if index < BINS {
+        atomicAdd(&histogram[index], atomicLoad(&workgroup_histogram[index]));
+    }
+}
@@ -0,0 +1,62 @@
+// Bakes the light arriving at a mesh surface into its lightmap
+// The mesh gets rasterized in lightmap UV space, so every fragment is one texel of the lightmap
+#import "shaders/random.wgsl"::{rngNextFloat, randomUnitVec3}
+#import "shaders/scene.wgsl"::{Ray, trace_path, ALL_RAY_MASKS}
+
+@group(0) @binding(0) var<uniform> bake: LightmapBake;
+struct LightmapBake {
+    world_from_local: mat4x4<f32>,
+    // inverse transpose of the upper 3x3 of world_from_local
+    normal_from_local: mat4x4<f32>,
+    random_seed: f32,
+    sample_count: u32,
+    bounce_count: u32,
+}
+
+struct Vertex {
+    @location(0) position: vec3<f32>,
+    @location(1) normal: vec3<f32>,
+    @location(2) lightmap_uv: vec2<f32>,
+}
+
+struct VertexOutput {
+    @builtin(position) position: vec4<f32>,
+    @location(0) world_position: vec3<f32>,
+    @location(1) world_normal: vec3<f32>,
+}
+
+var<private> rng_state: u32;
+
+@vertex
+fn vertex(vertex: Vertex) -> VertexOutput {
+    var out: VertexOutput;
+    // (0, 0) is the top left corner of the lightmap
+    out.position = vec4<f32>(vertex.lightmap_uv.x * 2.0 - 1.0, 1.0 - vertex.lightmap_uv.y * 2.0, 0.0, 1.0);
+    out.world_position = (bake.world_from_local * vec4<f32>(vertex.position, 1.0)).xyz;
+    let normal_from_local = mat3x3<f32>(bake.normal_from_local[0].xyz, bake.normal_from_local[1].xyz, bake.normal_from_local[2].xyz);
+    out.world_normal = normalize(normal_from_local * vertex.normal);
+    return out;
+}
+
+@fragment
+fn fragment(in: VertexOutput) -> @location(0) vec4<f32> {
+    rng_state = u32(bake.random_seed * 4294967295.0) ^ (u32(in.position.x) * 1973u + u32(in.position.y) * 9277u);
+
+    let normal = normalize(in.world_normal);
+    // Offset the origin to not hit the surface itself
+    let origin = in.world_position + normal * 0.001;
+
+    var radiance = vec3<f32>(0.0, 0.0, 0.0);
+    for (var sample_index: u32 = 0; sample_index < bake.sample_count; sample_index++) {
+        // Cosine weighted hemisphere sampling, the average radiance is the irradiance divided by PI, which is what bevy expects
+        var direction = normal + normalize(randomUnitVec3(&rng_state));
+        if dot(direction, direction) < 1e-8 {
+            direction = normal;
+        }
+
+        radiance += trace_path(Ray(origin, normalize(direction)), bake.bounce_count, false, 0.0, 0.0, bake.bounce_count, false, ALL_RAY_MASKS, &rng_state).radiance;
+    }
+
+    // The alpha marks the texel as baked for the dilation, see lightmap_dilate.wgsl
+    return vec4<f32>(radiance / f32(max(bake.sample_count, 1u)), 1.0);
+}
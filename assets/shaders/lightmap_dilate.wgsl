@@ -0,0 +1,47 @@
+// Grows the baked texels of a lightmap into the ones around its UV charts, so filtering at the edge of a chart
+// doesn't pull in the black of the texels no triangle covers
+#import bevy_core_pipeline::fullscreen_vertex_shader::FullscreenVertexOutput
+
+// How many texels the charts grow by
+const DILATION_TEXELS: i32 = 2;
+
+// A copy of the lightmap, baked texels have an alpha of 1
+@group(0) @binding(0) var lightmap: texture_2d<f32>;
+
+// Texels outside of the charts take the average of the closest ring of baked texels around them.
+// They keep an alpha of 0, so they are filled in again from the next frame of the bake
+@fragment
+fn fragment(in: FullscreenVertexOutput) -> @location(0) vec4<f32> {
+    let texel = vec2<i32>(in.position.xy);
+    let color = textureLoad(lightmap, texel, 0);
+    if color.a > 0.5 {
+        return color;
+    }
+
+    let size = vec2<i32>(textureDimensions(lightmap));
+    for (var ring = 1; ring <= DILATION_TEXELS; ring++) {
+        var sum = vec3<f32>(0.0, 0.0, 0.0);
+        var count = 0.0;
+        for (var y = -ring; y <= ring; y++) {
+            for (var x = -ring; x <= ring; x++) {
+                if max(abs(x), abs(y)) != ring {
+                    continue;
+                }
+                let neighbour_texel = texel + vec2<i32>(x, y);
+                if any(neighbour_texel < vec2<i32>(0)) || any(neighbour_texel >= size) {
+                    continue;
+                }
+                let neighbour = textureLoad(lightmap, neighbour_texel, 0);
+                if neighbour.a > 0.5 {
+                    sum += neighbour.rgb;
+                    count += 1.0;
+                }
+            }
+        }
+        if count > 0.0 {
+            return vec4<f32>(sum / count, 0.0);
+        }
+    }
+
+    return vec4<f32>(0.0, 0.0, 0.0, 0.0);
+}
@@ -0,0 +1,62 @@
+// Updates a grid of irradiance probes stored in the layout of bevy irradiance volumes
+// A volume with resolution (x, y, z) is a (x, 2y, 3z) texture, every probe is an ambient cube with one color per side:
+// the second dimension picks the positive or negative side, the third one the axis
+#import "shaders/random.wgsl"::randomUnitVec3
+#import "shaders/scene.wgsl"::{Ray, trace_path, ALL_RAY_MASKS}
+
+@group(0) @binding(0) var<uniform> grid: ProbeGrid;
+struct ProbeGrid {
+    world_from_local: mat4x4<f32>,
+    rays_per_probe: u32,
+    bounce_count: u32,
+    // how much of the previous frame is kept
+    hysteresis: f32,
+    random_seed: f32,
+}
+@group(0) @binding(1) var history: texture_3d<f32>;
+@group(0) @binding(2) var output: texture_storage_3d<rgba16float, write>;
+
+// Keeps the distance representable in half floats
+const MAX_PROBE_DISTANCE: f32 = 1000.0;
+
+var<private> rng_state: u32;
+
+@compute @workgroup_size(4, 4, 4)
+fn update(@builtin(global_invocation_id) id: vec3<u32>) {
+    let size = textureDimensions(output);
+    if any(id >= size) {
+        return;
+    }
+
+    let resolution = size / vec3<u32>(1u, 2u, 3u);
+    let probe = vec3<u32>(id.x, id.y % resolution.y, id.z % resolution.z);
+    let axis = id.z / resolution.z;
+
+    var side = vec3<f32>(0.0, 0.0, 0.0);
+    side[axis] = select(1.0, -1.0, id.y >= resolution.y);
+
+    // Probes sit in the center of their voxel inside the unit cube
+    let local_position = (vec3<f32>(probe) + 0.5) / vec3<f32>(resolution) - 0.5;
+    let origin = (grid.world_from_local * vec4<f32>(local_position, 1.0)).xyz;
+
+    rng_state = u32(grid.random_seed * 4294967295.0) ^ (id.x * 1973u + id.y * 9277u + id.z * 26699u);
+
+    var radiance = vec3<f32>(0.0, 0.0, 0.0);
+    var distance = 0.0;
+    for (var ray_index: u32 = 0; ray_index < grid.rays_per_probe; ray_index++) {
+        // Cosine weighted around the side, just like the lightmap bake
+        var direction = side + normalize(randomUnitVec3(&rng_state));
+        if dot(direction, direction) < 1e-8 {
+            direction = side;
+        }
+
+        let path = trace_path(Ray(origin, normalize(direction)), grid.bounce_count, false, 0.0, 0.0, grid.bounce_count, false, ALL_RAY_MASKS, &rng_state);
+        radiance += path.radiance;
+        distance += min(path.first_distance, MAX_PROBE_DISTANCE);
+    }
+
+    let ray_count = f32(max(grid.rays_per_probe, 1u));
+    let estimate = vec4<f32>(radiance / ray_count, distance / ray_count);
+    let previous = textureLoad(history, id, 0);
+    textureStore(output, id, mix(estimate, previous, grid.hysteresis));
+}
@@ -18,9 +18,20 @@
 //
 // You don't need to worry about this too much since bevy will compute the correct UVs for you.
 #import bevy_core_pipeline::fullscreen_vertex_shader::FullscreenVertexOutput
+#import bevy_render::view::{View, ColorGrading}
+#import bevy_render::color_operations::{hsv_to_rgb, rgb_to_hsv}
 
 #import "shaders/random.wgsl"::{rngNextFloat, randomUnitVec3}
 #import "shaders/const.wgsl"::{PI, INF}
+#import "shaders/scene.wgsl"::{Ray, trace_path, sample_unit_disk, pixel_spread_angle, white_furnace, catch_shadows, MAX_CLIP_PLANES}
+#import "shaders/scene.wgsl"::{clip_planes as scene_clip_planes, clip_plane_count as scene_clip_plane_count}
+#ifdef ENVIRONMENT_MAP
+// Aliased, imported names also replace fields of the same name like the one in `Camera`
+#import "shaders/scene.wgsl"::{environment_intensity as scene_environment_intensity}
+#endif
+#ifdef PATH_DEBUG
+#import "shaders/scene.wgsl"::{path_debug, path_debug_recording}
+#endif
 
 @group(0) @binding(0) var screen_texture: texture_2d<f32>;
 @group(0) @binding(1) var texture_sampler: sampler;
@@ -34,8 +45,7 @@ struct RaytraceLevel {
 @group(0) @binding(5) var<uniform> camera: Camera;
 struct Camera {
     sample_count: u32,
-    bounce_count: u32,
-    // 0 -> perspective; 1 -> orthographic
+    // 0 -> perspective; 1 -> orthographic; 2 -> panoramic; 3 -> equidistant fisheye; 4 -> equisolid fisheye
     projection_type: u32,
     near: f32,
     far: f32,
@@ -44,6 +54,54 @@ struct Camera {
     position: vec3<f32>,
     direction: vec3<f32>,
     up: vec3<f32>,
+    // Scales the environment cubemap, 0.0 if the camera has none
+    environment_intensity: f32,
+    // Roughness added per bounce after the first diffuse one, 0.0 turns path regularization off
+    regularization: f32,
+    // When paths end early, see `PathTermination`
+    min_throughput: f32,
+    max_specular_bounces: u32,
+    // 1 if paths that run out of bounces sample the irradiance volume
+    irradiance_fallback: u32,
+    // A byte per type of ray, see `RaytraceRayMasks`
+    ray_masks: u32,
+    // 1 if the frames get accumulated or the camera is paused, sample_count is then only the samples of this frame
+    progressive: u32,
+    // The samples already in the accumulation, 0 starts over.
+    // For checkerboard cameras the frames in the history, 0 if there is no last frame to reproject
+    accumulated_samples: u32,
+    // Thin lens, an aperture_radius of 0.0 is a pinhole
+    focus_distance: f32,
+    aperture_radius: f32,
+    // Below 3 the aperture is round
+    aperture_blades: u32,
+    blade_rotation: f32,
+    // 0 -> box; 1 -> tent; 2 -> gaussian; 3 -> blackman-harris
+    pixel_filter: u32,
+    // Bevy's FogSettings, 0 -> none; 1 -> linear; 2 -> exponential; 3 -> exponential squared; 4 -> atmospheric
+    fog_falloff: u32,
+    // Linear rgb, the alpha scales the whole fog
+    fog_color: vec4<f32>,
+    // Start and end of linear fog, the density in x for the exponential ones and the extinction for atmospheric fog
+    fog_parameters: vec3<f32>,
+    fog_inscattering: vec3<f32>,
+    // 1 if only the pixels matching the parity get traced this frame
+    checkerboard: u32,
+    checkerboard_parity: u32,
+    // The exposure of the camera relative to bevy's default one, the traced lights and sky are made for the default
+    exposure: f32,
+    // The heatmap drawn over the image, 0 -> none; 1 -> samples; 2 -> error, see `RaytraceConvergenceOverlay`
+    overlay: u32,
+    // The max_samples or max_error of the overlay
+    overlay_scale: f32,
+    // The inverse of bevy's view projection, with the temporal jitter
+    world_from_clip: mat4x4<f32>,
+    // The matrices of the last frame, for reprojecting what the camera saw then
+    previous_view_from_world: mat4x4<f32>,
+    previous_clip_from_world: mat4x4<f32>,
+    // The `RaytraceClipPlanes` in the space of the render origin
+    clip_planes: array<vec4<f32>, MAX_CLIP_PLANES>,
+    clip_plane_count: u32,
 }
 
 @group(0) @binding(6) var<uniform> window: Window;
@@ -53,58 +111,116 @@ struct Window {
     _padding: vec2<f32>,
 }
 
-@group(1) @binding(0) var<storage, read> model_buffer: array<Model>;
-struct Model {
-    position: vec3<f32>,
-    radius: f32,
-    material_id: u32,
-}
+// The running average of the color and depth of progressive cameras, read from one and written to the other.
+// Checkerboard cameras keep their last frame in them
+@group(0) @binding(10) var accumulation_history: texture_2d<f32>;
+@group(0) @binding(11) var accumulation_output: texture_storage_2d<rgba32float, write>;
 
-@group(1) @binding(1) var<storage, read> material_buffer: array<Material>;
-struct Material {
-    // Doubles as diffuse albedo for non-metallic, specular for metallic and a mix for everything in between
-    base_color: vec3<f32>,
-    // 0.0 for dielectric materials, 1.0 for metallic
-    metallic: f32,
-    // 0.0 -> very glossy
-    roughness: f32, // "Fuzzy Reflection"
-    // Specular intensity for non-metals
-    reflectance: f32, // unused for now
-    // Index of refraction
-    ior: f32,
-    // transmission through a material via refraction
-    specular_transmission: f32
-}
+// Bevy's view of the camera, only its color grading is used
+@group(0) @binding(14) var<uniform> view: View;
 
-@group(1) @binding(2) var<storage, read> bvh_buffer: array<BVHNode>;
-struct BVHNode {
-    bounds_min: vec3<f32>,
-    bounds_max: vec3<f32>,
-    // is the model_index if it is a leaf node (model_count > 0)
-    // otherwise the first child index (second child directly after that
-    index: u32,
-    model_count: u32,
-}
+// The running average of the squared luminance next to the accumulation, the spread of the samples for the error overlay
+@group(0) @binding(15) var moments_history: texture_2d<f32>;
+@group(0) @binding(16) var moments_output: texture_storage_2d<r32float, write>;
+
+// Rec. 709, like bevy's tonemapping_luminance
+const LUMINANCE: vec3<f32> = vec3<f32>(0.2126, 0.7152, 0.0722);
+// Half the width of the blend between shadows and midtones and between midtones and highlights
+const GRADING_LEVEL_MARGIN: f32 = 0.1;
 
 var<private> rng_state: u32;
 
+#ifdef NAN_DEBUG
+// How many paths came back with NaN or infinite radiance this frame, over all cameras
+@group(0) @binding(13) var<storage, read_write> non_finite_paths: atomic<u32>;
+// The ones of the current pixel, they are left out of its average and the pixel is drawn magenta
+var<private> pixel_non_finite_paths: u32 = 0u;
+
+// WGSL has no isnan or isinf, both have all bits of the exponent set
+fn is_finite(value: vec3<f32>) -> bool {
+    let exponent = vec3<u32>(0x7f800000u);
+    return all((bitcast<vec3<u32>>(value) & exponent) != exponent);
+}
+#endif
+
 // TODO: Investigate Performance of distance based insertion and other box distance function
 
+// The depth goes into the depth buffer, so transmissive and transparent meshes and gizmos drawn after the main pass,
+// and cameras rendering over this one, layer with the traced objects by their depth
+struct FragmentOutput {
+    @location(0) color: vec4<f32>,
+    @builtin(frag_depth) depth: f32,
+}
+
+// 0 is at the far plane, 1 at the near plane, which keeps the meshes out wherever the traced image doesn't match the rasterized one
+var<private> output_depth: f32 = 1.0;
+
 @fragment
-fn fragment(in: FullscreenVertexOutput) -> @location(0) vec4<f32> {
+fn fragment(in: FullscreenVertexOutput) -> FragmentOutput {
+    let color = shade(in);
+    return FragmentOutput(color, output_depth);
+}
+
+fn shade(in: FullscreenVertexOutput) -> vec4<f32> {
     rng_state = u32((window.random_seed * 10000.0) * (in.uv.x * 402.0) * (in.uv.y * 31.5)) ;
+    // 0 is at far plane, 1 at near plane
+    let depth = textureSample(depth_texture, depth_sampler, in.uv);
     // Skip Raytracing
     if settings.level == 0 {
+        set_output_depth(depth);
         return textureSample(screen_texture, texture_sampler, in.uv);
     }
 
-    let raytrace_result = trace_multisampled(in.uv, &rng_state);
-        
-    // combine option
-    if settings.level == 1 || settings.level == 2 {
-        // 0 is at far plane, 1 at near plane
-        let depth = textureSample(depth_texture, depth_sampler, in.uv);
+#ifdef ENVIRONMENT_MAP
+    // The white furnace replaces the environment with its own uniform light
+    scene_environment_intensity = select(camera.environment_intensity, 0.0, white_furnace());
+#endif
+    pixel_spread_angle = camera_pixel_spread();
+    catch_shadows = true;
+    scene_clip_planes = camera.clip_planes;
+    scene_clip_plane_count = camera.clip_plane_count;
+#ifdef PATH_DEBUG
+    // Only the pixel picked with the path debugger records its path
+    path_debug_recording = all(vec2<i32>(in.position.xy) == path_debug.pixel);
+#endif
+
+    var raytrace_result: RaytraceResult;
+    if camera.progressive != 0 {
+        raytrace_result = trace_accumulated(in.uv, vec2<i32>(in.position.xy), &rng_state);
+        // Paused checkerboard cameras hold their last frame, gaps included, for the resolve pass
+        if camera.checkerboard != 0 && raytrace_result.depth < 0.0 {
+            set_output_depth(depth);
+            return vec4<f32>(0.0, 0.0, 0.0, 0.0);
+        }
+    } else if camera.checkerboard != 0 {
+        raytrace_result = trace_checkerboard(in.uv, vec2<i32>(in.position.xy), &rng_state);
+        // Left transparent for the resolve pass, meshes in front of the rasterized image still cover it
+        if raytrace_result.depth < 0.0 {
+            set_output_depth(depth);
+            return vec4<f32>(0.0, 0.0, 0.0, 0.0);
+        }
+    } else {
+        raytrace_result = trace_multisampled(in.uv, &rng_state);
+    }
 
+    let overlay_heat = convergence_heat(raytrace_result);
+
+    // The accumulation stays in linear radiance, so it doesn't have to start over when the exposure or grading changes
+    raytrace_result.color = display_color(raytrace_result.color);
+
+#ifdef NAN_DEBUG
+    if pixel_non_finite_paths > 0u {
+        atomicAdd(&non_finite_paths, pixel_non_finite_paths);
+        return vec4<f32>(1.0, 0.0, 1.0, 1.0);
+    }
+#endif
+
+    if camera.overlay != 0u {
+        raytrace_result.color = mix(raytrace_result.color, heatmap(overlay_heat), 0.5);
+    }
+        
+    // combine option, only possible when the raytraced projection matches the rasterized one
+    if (settings.level == 1 || settings.level == 2) && camera.projection_type == 0 {
         var raytraced_depth = raytrace_result.depth;
         if raytraced_depth > camera.far {
             raytraced_depth = -1.0;
@@ -112,6 +228,7 @@ fn fragment(in: FullscreenVertexOutput) -> @location(0) vec4<f32> {
             raytraced_depth = camera.near / raytraced_depth;
         }
 
+        set_output_depth(max(depth, raytraced_depth));
         if depth > raytraced_depth {
             return textureSample(screen_texture, texture_sampler, in.uv);
         } else {
@@ -122,300 +239,418 @@ fn fragment(in: FullscreenVertexOutput) -> @location(0) vec4<f32> {
     return vec4<f32>(raytrace_result.color, 1.0);
 }
 
-struct Ray {
-    origin: vec3<f32>,
-    direction: vec3<f32>,
-}
-
-fn ray_at(ray: Ray, t: f32) -> vec3<f32> {
-    return ray.origin + t * ray.direction;
+fn set_output_depth(depth: f32) {
+    output_depth = depth;
 }
 
 struct RaytraceResult {
     color: vec3<f32>,
     depth: f32,
+    // The average squared luminance of the samples
+    moment: f32,
+}
+
+// How far the pixel is from converged for the overlay, 0.0 is done and 1.0 needs the most samples
+fn convergence_heat(result: RaytraceResult) -> f32 {
+    let samples = f32(max(select(camera.sample_count, camera.accumulated_samples + camera.sample_count, camera.progressive != 0u), 1u));
+    if camera.overlay == 1u {
+        return 1.0 - saturate(log2(samples) / log2(camera.overlay_scale));
+    }
+
+    // The standard error of the mean from the variance of the samples, relative to the mean
+    let mean = dot(result.color, LUMINANCE);
+    let variance = max(result.moment - mean * mean, 0.0);
+    let relative_error = sqrt(variance / samples) / max(mean, 1e-4);
+    // Two decades below max_error
+    return saturate(log2(max(relative_error / camera.overlay_scale, 1e-8)) / log2(100.0) + 1.0);
+}
+
+// Blue through cyan, green and yellow to red
+fn heatmap(heat: f32) -> vec3<f32> {
+    return saturate(vec3<f32>(1.5) - abs(4.0 * heat - vec3<f32>(3.0, 2.0, 1.0)));
 }
 
 fn random_ray_from_uv(uv: vec2<f32>, state: ptr<private, u32>) -> Ray {
-    let rand_square = vec2<f32>(rngNextFloat(state) - 0.5, rngNextFloat(state) - 0.5);
+    let pixel_offset = sample_pixel_filter(state);
     let height = f32(window.height);
     let width = f32(window.height) * camera.aspect;
-    let delta_u = (1.0 / width) * rand_square.x;
-    let delta_v = (1.0 / height) * rand_square.y;
-
-    let ndc_x = (uv.x * 2.0 - 1.0) + delta_u;
-    let ndc_y = (1.0 - uv.y * 2.0) + delta_v;
+    let delta_u = (1.0 / width) * pixel_offset.x;
+    let delta_v = (1.0 / height) * pixel_offset.y;
 
     let right = cross(camera.direction, camera.up);
 
-    let scale = tan(camera.fov * 0.5);
+    if camera.projection_type == 2 {
+        return Ray(camera.position, panoramic_direction(uv + vec2<f32>(delta_u, delta_v), right));
+    }
 
-    let ray_direction = normalize(camera.direction + (ndc_x * camera.aspect * scale * right) + (ndc_y * scale * camera.up));
+    // The ndc span 2 units across the screen, so a pixel is twice as wide as in uv
+    let ndc_x = (uv.x * 2.0 - 1.0) + 2.0 * delta_u;
+    let ndc_y = (1.0 - uv.y * 2.0) + 2.0 * delta_v;
 
-    return Ray(camera.position, ray_direction);
-}
+    if camera.projection_type == 3 || camera.projection_type == 4 {
+        return Ray(camera.position, fisheye_direction(vec2<f32>(ndc_x * camera.aspect, ndc_y), right));
+    }
 
-// default camera is at 0.0, 0.0, 5.0, looking at 0 with up as Y | Pass this as uniform data
-fn trace_multisampled(uv: vec2<f32>, state: ptr<private, u32>) -> RaytraceResult {
-    var total_result: RaytraceResult = RaytraceResult(vec3<f32>(0.0, 0.0, 0.0), 0.0);
-    for (var sample_index: u32 = 0; sample_index < camera.sample_count; sample_index++) {
-        let ray = random_ray_from_uv(uv, state);
-        let sample_result = raytrace(ray, state);
+    let ray_direction = camera_direction(vec2<f32>(ndc_x, ndc_y));
 
-        total_result.color += sample_result.color;
-        total_result.depth += sample_result.depth;
+    if camera.aperture_radius > 0.0 {
+        return thin_lens_ray(ray_direction, right, state);
     }
 
-    let averaged_color = total_result.color.rgb / (f32(camera.sample_count));
-    let averaged_depth = total_result.depth / f32(camera.sample_count);
-    return RaytraceResult(averaged_color, averaged_depth);
+    return Ray(camera.position, ray_direction);
 }
 
-fn raytrace(base_ray: Ray, state: ptr<private, u32>) -> RaytraceResult {
-    var ray = base_ray;
+// Through the point on the near plane the rasterizer projects to `ndc`, bevy's near plane is at a depth of 1
+fn camera_direction(ndc: vec2<f32>) -> vec3<f32> {
+    let near_point = camera.world_from_clip * vec4<f32>(ndc, 1.0, 1.0);
+    return normalize(near_point.xyz / near_point.w - camera.position);
+}
 
-    var fallback_far: f32;
-    if settings.level == 1 {
-        fallback_far = camera.far + 10.0;
-    } else {
-        fallback_far = camera.far - 1.0;
+// The offset of a primary ray from the pixel center in pixels, distributed like the pixel filter
+fn sample_pixel_filter(state: ptr<private, u32>) -> vec2<f32> {
+    switch camera.pixel_filter {
+        case 1u: {
+            return vec2<f32>(sample_tent(state), sample_tent(state));
+        }
+        case 2u, 3u: {
+            return vec2<f32>(sample_bell_filter(state), sample_bell_filter(state));
+        }
+        default: {
+            return vec2<f32>(rngNextFloat(state) - 0.5, rngNextFloat(state) - 0.5);
+        }
     }
+}
 
-    var first_depth: f32 = INF;
-    var ray_color: vec3<f32> = vec3<f32>(1.0, 1.0, 1.0);
-    var lightSourceColor: vec3<f32> = vec3<f32>(0.0, 0.0, 0.0);
+// Inverts the cumulative distribution of both halves of the tent
+fn sample_tent(state: ptr<private, u32>) -> f32 {
+    let u = 2.0 * rngNextFloat(state);
+    if u < 1.0 {
+        return sqrt(u) - 1.0;
+    }
+    return 1.0 - sqrt(2.0 - u);
+}
 
-    var bounce_count: u32 = 0;
-    for (; bounce_count <= camera.bounce_count; bounce_count++) {
-        let hit = raycast(ray);
+// The gaussian and blackman-harris filters have no simple inverse, so candidates are thrown away in proportion to their weight.
+// Both peak at 1.0 in the center, about 40% of the candidates are kept
+fn sample_bell_filter(state: ptr<private, u32>) -> f32 {
+    let gaussian = camera.pixel_filter == 2u;
+    let radius = select(2.0, 1.5, gaussian);
 
-        // Setting the depth for depth buffer comparison, this might have to be a early return at some point
-        if bounce_count == 0 {
-            first_depth = hit.distance;
-        }
+    var x = 0.0;
+    for (var attempt = 0u; attempt < 32u; attempt++) {
+        x = (2.0 * rngNextFloat(state) - 1.0) * radius;
 
-        // The background
-        if hit.distance == INF {
-            lightSourceColor = background_gradient(ray);
-            break;
+        var weight: f32;
+        if gaussian {
+            // Standard deviation of 0.5
+            weight = exp(-2.0 * x * x);
+        } else {
+            let t = PI * (x / radius + 1.0);
+            weight = 0.35875 - 0.48829 * cos(t) + 0.14128 * cos(2.0 * t) - 0.01168 * cos(3.0 * t);
         }
 
-        var attenuation: vec3<f32>;
-        let absorbed = scatter(&ray, &attenuation, hit, state);
-
-        // rays getting absorbed
-        if absorbed {
+        if rngNextFloat(state) < weight {
             break;
         }
-
-        ray_color *= attenuation;
-    }
-
-    // A extra bounce could be added -> the background break wasn't hit
-    if bounce_count == camera.bounce_count + 1 {
-        ray_color = vec3<f32>(0.0, 0.0, 0.0);
     }
+    return x;
+}
 
-    if first_depth == INF {
-        first_depth = fallback_far;
+// The angle between neighbouring pixels, where the ray cones for texture filtering start
+fn camera_pixel_spread() -> f32 {
+    let height = f32(window.height);
+    switch camera.projection_type {
+        case 2u: {
+            // The latitude covers half a turn
+            return PI / height;
+        }
+        case 3u, 4u: {
+            return camera.fov / height;
+        }
+        default: {
+            return 2.0 * tan(camera.fov * 0.5) / height;
+        }
     }
-
-    return RaytraceResult(linear_to_gamma_Vec3(ray_color * lightSourceColor), first_depth);
 }
 
-fn linear_to_gamma_Vec3(in: vec3<f32>) -> vec3<f32> {
-    return vec3<f32>(sqrt(in.x), sqrt(in.y), sqrt(in.z));
+// Moves the origin across the aperture, while the point on the focus plane stays where the pinhole ray hits it
+fn thin_lens_ray(direction: vec3<f32>, right: vec3<f32>, state: ptr<private, u32>) -> Ray {
+    let focus_point = camera.position + direction * (camera.focus_distance / dot(direction, camera.direction));
+    let lens = sample_aperture(state) * camera.aperture_radius;
+    let origin = camera.position + lens.x * right + lens.y * camera.up;
+    return Ray(origin, normalize(focus_point - origin));
 }
 
-// returns wether the ray was absorbed
-fn scatter(scattered: ptr<function, Ray>, attenuation: ptr<function, vec3<f32>>, hit: HitInfo, state: ptr<private, u32>) -> bool {
-    let material = material_buffer[hit.material];
-
-    if rngNextFloat(state) < material.metallic {
-        // metallic interaction
-        
-        // reflection and roughness 
-        let reflected = normalize(reflect((*scattered).direction, hit.normal)) + (material.roughness * randomUnitVec3(state));
-
-        // setting return values
-        *scattered = Ray(hit.position, reflected);
-        *attenuation = material.base_color;
+// A uniform point on the unit disk, or on the regular polygon inscribed in it
+fn sample_aperture(state: ptr<private, u32>) -> vec2<f32> {
+    if camera.aperture_blades < 3u {
+        return sample_unit_disk(state);
+    }
 
-        // Discard below surface
-        return dot((*scattered).direction, hit.normal) < 0;
-    } else {
-        // non-metallic interaction
+    // One of the triangles between the center and two neighbouring corners, they all have the same area
+    let blades = f32(camera.aperture_blades);
+    let triangle = min(floor(rngNextFloat(state) * blades), blades - 1.0);
+    let corner_angle = 2.0 * PI / blades;
+    let start = camera.blade_rotation + triangle * corner_angle;
+    let first = vec2<f32>(cos(start), sin(start));
+    let second = vec2<f32>(cos(start + corner_angle), sin(start + corner_angle));
+
+    // Points beyond the diagonal of the parallelogram get folded back into the triangle
+    var u = rngNextFloat(state);
+    var v = rngNextFloat(state);
+    if u + v > 1.0 {
+        u = 1.0 - u;
+        v = 1.0 - v;
+    }
+    return first * u + second * v;
+}
 
-        if rngNextFloat(state) < material.specular_transmission {
-            // Specular transmission
+// Equirectangular mapping, the center of the image looks along the camera direction
+fn panoramic_direction(uv: vec2<f32>, right: vec3<f32>) -> vec3<f32> {
+    let longitude = (uv.x - 0.5) * 2.0 * PI;
+    let latitude = (0.5 - uv.y) * PI;
 
-            var ri: f32;
-            if hit.front_face {
-                // inside
-                ri = 1.0 / material.ior;
-            } else {
-                // outside
-                ri = material.ior;
-            }
+    let horizontal = sin(longitude) * right + cos(longitude) * camera.direction;
+    return normalize(cos(latitude) * horizontal + sin(latitude) * camera.up);
+}
 
-            let unit_direction = normalize((*scattered).direction);
+// The field of view spans the image height, the corners continue the mapping beyond it
+fn fisheye_direction(point: vec2<f32>, right: vec3<f32>) -> vec3<f32> {
+    let radius = length(point);
+    if radius < 1e-6 {
+        return camera.direction;
+    }
 
-            let cos_theta = min(dot(-unit_direction, hit.normal), 1.0);
-            let sin_theta = sqrt(1.0 - cos_theta * cos_theta);
+    var theta: f32;
+    if camera.projection_type == 3 {
+        // equidistant
+        theta = radius * camera.fov * 0.5;
+    } else {
+        // equisolid
+        theta = 2.0 * asin(min(radius * sin(camera.fov * 0.25), 1.0));
+    }
+    theta = min(theta, PI);
 
-            let cannot_refract = ri * sin_theta > 1.0;
-            var direction: vec3<f32>;
+    let side = (point.x / radius) * right + (point.y / radius) * camera.up;
+    return normalize(cos(theta) * camera.direction + sin(theta) * side);
+}
 
-            if cannot_refract || reflectance(cos_theta, ri) > rngNextFloat(state) {
-                direction = reflect(unit_direction, hit.normal);
-            } else {
-                direction = refract(unit_direction, hit.normal, ri);
-            }
+// default camera is at 0.0, 0.0, 5.0, looking at 0 with up as Y | Pass this as uniform data
+fn trace_multisampled(uv: vec2<f32>, state: ptr<private, u32>) -> RaytraceResult {
+    var total_result: RaytraceResult = RaytraceResult(vec3<f32>(0.0, 0.0, 0.0), 0.0, 0.0);
+    for (var sample_index: u32 = 0; sample_index < camera.sample_count; sample_index++) {
+        let ray = random_ray_from_uv(uv, state);
+        let sample_result = raytrace(ray, state);
 
-            // setting return values
-            *scattered = Ray(hit.position, direction);
-            *attenuation = vec3<f32>(1.0, 1.0, 1.0);
+        total_result.color += sample_result.color;
+        total_result.depth += sample_result.depth;
+        total_result.moment += sample_result.moment;
+    }
 
-            // A refrected ray always continues on
-            return false;
-        } else {
-            // normal diffuse
+    let averaged_color = total_result.color.rgb / (f32(camera.sample_count));
+    let averaged_depth = total_result.depth / f32(camera.sample_count);
+    let averaged_moment = total_result.moment / f32(camera.sample_count);
+    return RaytraceResult(averaged_color, averaged_depth, averaged_moment);
+}
 
-            // diffuse and roughness
-            var scatter_direction = hit.normal + randomUnitVec3(state) + (material.roughness * randomUnitVec3(state));
+// Blends this frame's samples into the average of the previous frames, once converged the average is shown as is
+fn trace_accumulated(uv: vec2<f32>, pixel: vec2<i32>, state: ptr<private, u32>) -> RaytraceResult {
+    let history = textureLoad(accumulation_history, pixel, 0);
+    let history_moment = textureLoad(moments_history, pixel, 0).r;
+    if camera.sample_count == 0 {
+        return RaytraceResult(history.rgb, history.a, history_moment);
+    }
 
-            if vec3_near_zero(scatter_direction) {
-                scatter_direction = hit.normal;
-            }
+    let current = trace_multisampled(uv, state);
+    let weight = f32(camera.sample_count) / f32(camera.accumulated_samples + camera.sample_count);
+    let color = mix(history.rgb, current.color, weight);
+    let depth = mix(history.a, current.depth, weight);
+    let moment = mix(history_moment, current.moment, weight);
 
-            // setting return values
-            *scattered = Ray(hit.position, scatter_direction);
-            *attenuation = material.base_color;
+    textureStore(accumulation_output, pixel, vec4<f32>(color, depth));
+    textureStore(moments_output, pixel, vec4<f32>(moment, 0.0, 0.0, 0.0));
+    return RaytraceResult(color, depth, moment);
+}
 
-            // Discard below surface
-            return dot((*scattered).direction, hit.normal) < 0;
-        }
+// Traces the pixels of one color of the checkerboard, the others reuse what the last frame saw there.
+// Pixels that can't be reprojected get a negative depth, the resolve pass fills them in from their traced neighbours
+fn trace_checkerboard(uv: vec2<f32>, pixel: vec2<i32>, state: ptr<private, u32>) -> RaytraceResult {
+    var result = RaytraceResult(vec3<f32>(0.0, 0.0, 0.0), -1.0, 0.0);
+    if u32(pixel.x + pixel.y) % 2u == camera.checkerboard_parity {
+        result = trace_multisampled(uv, state);
+    } else {
+        reproject(uv, pixel, &result);
     }
-}
 
-struct HitInfo {
-    distance: f32,
-    position: vec3<f32>,
-    normal: vec3<f32>,
-    material: u32,
-    front_face: bool,
+    textureStore(accumulation_output, pixel, vec4<f32>(result.color, result.depth));
+    textureStore(moments_output, pixel, vec4<f32>(result.moment, 0.0, 0.0, 0.0));
+    return result;
 }
 
-// These parameters are just random guesses, investigate what the algorithm actually does
-const STACKSIZE: i32 = 32;
-const MAX_MODELS_PER_NODE: i32 = 8;
+// Finds where the surface behind the pixel was on screen last frame, guessing its distance from what the pixel saw last frame.
+// Fails where the guess is off, like at disocclusions, and for surfaces that were off screen
+fn reproject(uv: vec2<f32>, pixel: vec2<i32>, result: ptr<function, RaytraceResult>) -> bool {
+    if camera.accumulated_samples == 0 || camera.projection_type != 0 {
+        return false;
+    }
 
-fn raycast(ray: Ray) -> HitInfo {
-    var closest = HitInfo(INF, vec3<f32>(0.0, 0.0, 0.0), vec3<f32>(0.0, 0.0, 0.0), 0, true);
+    let ndc = vec2<f32>(uv.x * 2.0 - 1.0, 1.0 - uv.y * 2.0);
+    let distance = textureLoad(accumulation_history, pixel, 0).a;
+    let position = camera.position + camera_direction(ndc) * distance;
 
-    var stack: array<u32, STACKSIZE> = array<u32, STACKSIZE>();
+    // The w of bevy's perspective projection is the depth in front of the camera
+    let previous_clip = camera.previous_clip_from_world * vec4<f32>(position, 1.0);
+    if previous_clip.w <= camera.near {
+        return false;
+    }
 
-    var stack_index = 1;
+    let previous_ndc = previous_clip.xy / previous_clip.w;
+    if any(abs(previous_ndc) > vec2<f32>(1.0)) {
+        return false;
+    }
 
-    while stack_index > 0 && stack_index < STACKSIZE {
-        stack_index--;
-        let next = stack[stack_index];
-        let bvh_node = bvh_buffer[next];
+    let size = vec2<f32>(textureDimensions(accumulation_history));
+    let previous_uv = vec2<f32>(previous_ndc.x * 0.5 + 0.5, 0.5 - previous_ndc.y * 0.5);
+    let previous_pixel = min(vec2<i32>(previous_uv * size), vec2<i32>(size) - 1);
+    let previous = textureLoad(accumulation_history, previous_pixel, 0);
 
-        if bvh_node.model_count > 0 {
-            raycast_against_range(ray, bvh_node.index, bvh_node.model_count, &closest);
-        } else {
-            // TODO: Consider distance based insertion
-            let node_1 = bvh_buffer[bvh_node.index];
-            let dst_1 = ray_bounding_dst(ray, node_1.bounds_min, node_1.bounds_max);
-            if dst_1 != INF && dst_1 < closest.distance {
-                stack[stack_index] = bvh_node.index;
-                stack_index++;
-            }
-
-            let node_2 = bvh_buffer[bvh_node.index + 1];
-            let dst_2 = ray_bounding_dst(ray, node_2.bounds_min, node_2.bounds_max);
-            if dst_2 != INF && dst_2 < closest.distance {
-                stack[stack_index] = bvh_node.index + 1;
-                stack_index++;
-            }
-        }
+    // The surface seen there last frame has to be the one the guess ended up on
+    let expected = length((camera.previous_view_from_world * vec4<f32>(position, 1.0)).xyz);
+    if abs(previous.a - expected) > 0.02 * expected {
+        return false;
     }
 
-    return closest;
+    *result = RaytraceResult(previous.rgb, distance, textureLoad(moments_history, previous_pixel, 0).r);
+    return true;
 }
 
-fn raycast_against_range(ray: Ray, start_index: u32, amount: u32, closest: ptr<function, HitInfo>) {
-    for (var model_index: u32 = start_index; model_index < start_index + amount; model_index++) {
-        let model = model_buffer[model_index];
+fn raytrace(base_ray: Ray, state: ptr<private, u32>) -> RaytraceResult {
+    var fallback_far: f32;
+    if settings.level == 1 {
+        fallback_far = camera.far + 10.0;
+    } else {
+        fallback_far = camera.far - 1.0;
+    }
 
-        let hit_distance = hit_sphere(model, ray);
-        if hit_distance != -1.0 && hit_distance > 0.001 {
-            if hit_distance < (*closest).distance {
-                let hit_position = ray_at(ray, hit_distance);
-                let normal = normalize(hit_position - model.position);
+    let path = trace_path(base_ray, #{MAX_BOUNCES}u, #{SPECTRAL}, camera.regularization, camera.min_throughput, camera.max_specular_bounces, camera.irradiance_fallback != 0u, camera.ray_masks, state);
+#ifdef PATH_DEBUG
+    // The other samples of the pixel would add their vertices after the first path's
+    path_debug_recording = false;
+#endif
+    var radiance = path.radiance;
+    if !white_furnace() {
+        radiance = distance_fog(radiance, path.first_distance * length(base_ray.direction));
+    }
 
-                *closest = HitInfo(hit_distance, hit_position, normal, model.material_id, dot(ray.direction, normal) < 0.0);
-            }
-        }
+    var first_depth = path.first_distance;
+    if first_depth == INF {
+        first_depth = fallback_far;
     }
-}
 
-fn background_gradient(ray: Ray) -> vec3<f32> {
-    let unit: vec3<f32> = normalize(ray.direction);
-    let a: f32 = 0.5 * (unit.y + 1.0);
-    let color: vec3<f32> = (1.0 - a) * vec3<f32>(1.0, 1.0, 1.0) + a * vec3<f32>(0.5, 0.7, 1.0);
-    return color;
-}
+#ifdef NAN_DEBUG
+    if !is_finite(radiance) {
+        pixel_non_finite_paths += 1u;
+        return RaytraceResult(vec3<f32>(0.0, 0.0, 0.0), first_depth, 0.0);
+    }
+#endif
 
-fn hit_sphere(sphere: Model, ray: Ray) -> f32 {
-    let oc: vec3<f32> = sphere.position - ray.origin;
-    let a = dot(ray.direction, ray.direction);
-    let h = dot(ray.direction, oc);
-    let c = dot(oc, oc) - sphere.radius * sphere.radius;
-    let discriminant = h * h - a * c;
+    let luminance = dot(radiance, LUMINANCE);
+    return RaytraceResult(radiance, first_depth, luminance * luminance);
+}
 
-    if discriminant < 0.0 {
-        return -1.0;
+// The fog formulas of bevy's fog.wgsl, so the traced scene fades into the fog like the rasterized one.
+// Rays that miss the scene are infinitely far away and end up fully fogged.
+// The glow around directional lights is left out, the traced scene doesn't have any
+fn distance_fog(color: vec3<f32>, distance: f32) -> vec3<f32> {
+    let fog_color = camera.fog_color;
+    var amount = 0.0;
+    switch camera.fog_falloff {
+        case 1u: {
+            let start = camera.fog_parameters.x;
+            let end = camera.fog_parameters.y;
+            amount = 1.0 - clamp((end - distance) / (end - start), 0.0, 1.0);
+        }
+        case 2u: {
+            amount = 1.0 - exp(-distance * camera.fog_parameters.x);
+        }
+        case 3u: {
+            let distance_times_density = distance * camera.fog_parameters.x;
+            amount = 1.0 - exp(-distance_times_density * distance_times_density);
+        }
+        case 4u: {
+            let extinction = 1.0 - exp(-distance * camera.fog_parameters);
+            let inscattering = 1.0 - exp(-distance * camera.fog_inscattering);
+            return color * (1.0 - extinction * fog_color.a) + fog_color.rgb * inscattering * fog_color.a;
+        }
+        default: {}
     }
 
-    return (h - sqrt(discriminant)) / a;
+    return mix(color, fog_color.rgb, amount * fog_color.a);
 }
 
-// TODO: Look into other algorithms / pre-computing the inverse of the direction
-// https://tavianator.com/2011/ray_box.html (There is also a newer version)
-fn ray_bounding_dst(ray: Ray, box_min: vec3<f32>, box_max: vec3<f32>) -> f32 {
-    let t_min = (box_min - ray.origin) * (1.0 / ray.direction);
-    let t_max = (box_max - ray.origin) * (1.0 / ray.direction);
-    let t1 = min(t_min, t_max);
-    let t2 = max(t_min, t_max);
-    let t_near = max(max(t1.x, t1.y), t1.z);
-    let t_far = min(min(t2.x, t2.y), t2.z);
-
-    let hit = t_far >= t_near && t_far > 0.0;
-    let dst = select(INF, select(0.0, t_near, t_near > 0.0), hit);
-    return dst;
+// The exposure of the camera, and for output after tonemapping its color grading and the gamma that stands in for tonemapping
+fn display_color(radiance: vec3<f32>) -> vec3<f32> {
+    let color = radiance * camera.exposure;
+#ifdef LINEAR_OUTPUT
+    // Bevy's tonemapping pass grades and tonemaps it like the rasterized image
+    return color;
+#else
+    var grading = view.color_grading;
+    let graded = linear_to_gamma_Vec3(color_grading(color, &grading));
+    // Bevy's post saturation comes after tonemapping
+    return mix(vec3<f32>(dot(graded, LUMINANCE)), graded, grading.post_saturation);
+#endif
 }
 
-fn reflect(vector: vec3<f32>, normal: vec3<f32>) -> vec3<f32> {
-    return vector - 2 * dot(vector, normal) * normal;
-}
+// Bevy's color grading of tonemapping_shared.wgsl up to the tonemapping, the shader defs picking its parts are left out.
+// With the default `ColorGrading` it doesn't change anything
+fn color_grading(in: vec3<f32>, grading: ptr<function, ColorGrading>) -> vec3<f32> {
+    var color = max(in, vec3<f32>(0.0));
 
-fn refract(vector: vec3<f32>, normal: vec3<f32>, etai_over_etat: f32) -> vec3<f32> {
-    let cos_theta = min(dot(-vector, normal), 1.0);
-    let r_out_perp = etai_over_etat * (vector + cos_theta * normal);
-    let r_out_parallel = -sqrt(abs(1.0 - dot(r_out_perp, r_out_perp))) * normal;
-    return r_out_perp + r_out_parallel;
-}
+    if (*grading).hue != 0.0 {
+        var hsv = rgb_to_hsv(color);
+        hsv.r = (hsv.r + (*grading).hue) % (2.0 * PI);
+        color = hsv_to_rgb(hsv);
+    }
+
+    // White balance, the matrix comes from the temperature and tint
+    color = max((*grading).balance * color, vec3<f32>(0.0));
+
+    // Shadows, midtones and highlights blend into each other around the ends of the midtone range
+    let level = (color.r + color.g + color.b) / 3.0;
+    let midtone_range = (*grading).midtone_range;
+    var levels = vec3<f32>(0.0);
+    if level < midtone_range.x - GRADING_LEVEL_MARGIN {
+        levels.x = 1.0;
+    } else if level < midtone_range.x + GRADING_LEVEL_MARGIN {
+        levels.y = (level - midtone_range.x) * 0.5 / GRADING_LEVEL_MARGIN + 0.5;
+        levels.z = 1.0 - levels.y;
+    } else if level < midtone_range.y - GRADING_LEVEL_MARGIN {
+        levels.y = 1.0;
+    } else if level < midtone_range.y + GRADING_LEVEL_MARGIN {
+        levels.z = (level - midtone_range.y) * 0.5 / GRADING_LEVEL_MARGIN + 0.5;
+        levels.y = 1.0 - levels.z;
+    } else {
+        levels.z = 1.0;
+    }
 
-fn reflectance(cosine: f32, refraction_index: f32) -> f32 {
-    // Use Schlick's approximation for reflectance.
-    var r0 = (1.0 - refraction_index) / (1.0 + refraction_index);
-    r0 = r0 * r0;
-    return r0 + (1.0 - r0) * pow((1.0 - cosine), 5.0);
+    let contrast = dot(levels, (*grading).contrast);
+    let saturation = dot(levels, (*grading).saturation);
+    let gamma = dot(levels, (*grading).gamma);
+    let gain = dot(levels, (*grading).gain);
+    let lift = dot(levels, (*grading).lift);
+
+    let luminance = dot(color, LUMINANCE);
+    color = luminance + saturation * (color - luminance);
+    color = 0.5 + (color - 0.5) * contrast;
+    // ASC CDL, like powsafe in bevy the sign is kept for negative values
+    let lifted = color * gain + lift;
+    color = sign(lifted) * pow(abs(lifted), vec3<f32>(1.0 / gamma));
+
+    color *= exp2((*grading).exposure);
+    return max(color, vec3<f32>(0.0));
 }
 
-fn vec3_near_zero(vector: vec3<f32>) -> bool {
-    let s = 1e-8;
-    return abs(vector.x) < s && abs(vector.y) < s && abs(vector.z) < s;
+fn linear_to_gamma_Vec3(in: vec3<f32>) -> vec3<f32> {
+    return vec3<f32>(sqrt(in.x), sqrt(in.y), sqrt(in.z));
 }
@@ -0,0 +1,2081 @@
+// Everything needed to trace rays through the scene, the geometry buffers are always bound to group 1
+#import "shaders/random.wgsl"::{rngNextFloat, randomUnitVec3}
+#import "shaders/const.wgsl"::{PI, INF}
+#import "shaders/texture.wgsl"::procedural_texture
+#import "shaders/atmosphere.wgsl"::{atmosphere, aerial_transmittance}
+#import "shaders/bsdf.wgsl"::BsdfInput
+// Generated at startup from the registered custom BSDFs
+#import bevyray::custom_bsdf::custom_scatter
+#ifdef CAUSTICS
+#import "shaders/caustics.wgsl"::{Caustics, Photon, PHOTONS_PER_CELL, photon_cell}
+#endif
+#ifdef ENVIRONMENT_MAP
+#import "shaders/environment.wgsl"::{ENVIRONMENT_CDF_RESOLUTION, cube_face_direction, cube_face_coordinates, cube_face_jacobian, power_heuristic}
+#endif
+
+@group(1) @binding(0) var<storage, read> model_buffer: array<Model>;
+struct Model {
+    // Into the space the primitive is defined in, like the unit sphere for spheres
+    local_from_world: mat4x4<f32>,
+    material_id: u32,
+    // Picks the intersection in raycast_against_range, the BVH holds every primitive type
+    primitive: u32,
+    // Into the buffer of the primitive type, unused for spheres. Billboards keep how they face the ray in it
+    index: u32,
+    // Rays only hit the model if their mask shares a bit with this one
+    mask: u32,
+    // What didn't fit into the translation of local_from_world, for models with a precise position
+    translation_low: vec3<f32>,
+    precise_position: u32,
+}
+
+// Have to match the constants in extract.rs
+const PRIMITIVE_SPHERE: u32 = 0u;
+const PRIMITIVE_HEIGHTFIELD: u32 = 1u;
+const PRIMITIVE_BILLBOARD: u32 = 2u;
+const PRIMITIVE_CUBOID: u32 = 3u;
+const PRIMITIVE_PLANE: u32 = 4u;
+
+// Have to match `BillboardFacing`
+const BILLBOARD_RAY: u32 = 0u;
+const BILLBOARD_UPRIGHT: u32 = 1u;
+
+@group(1) @binding(1) var<storage, read> material_buffer: array<Material>;
+struct Material {
+    // Doubles as diffuse albedo for non-metallic, specular for metallic and a mix for everything in between
+    base_color: vec3<f32>,
+    // 0.0 for dielectric materials, 1.0 for metallic
+    metallic: f32,
+    // Perceptual roughness, clamped like bevy does, the GGX alpha is its square
+    roughness: f32,
+    // F0 of the glossy coat of dielectrics, from the reflectance like in bevy.
+    // Composable modules can't have members ending in a digit
+    normal_reflectance: f32,
+    // Index of refraction
+    ior: f32,
+    // transmission through a material via refraction
+    specular_transmission: f32,
+    // Cauchy B coefficient in µm², how much the ior changes with the wavelength in spectral mode
+    dispersion: f32,
+    // 1 for surfaces without volume like soap bubbles or window panes, light passes through them without bending
+    thin_walled: u32,
+    // Procedural texture applied to the base color, see texture.wgsl
+    texture: u32,
+    texture_scale: f32,
+    texture_octaves: u32,
+    // The odd cells of the checker texture
+    texture_color: vec3<f32>,
+    // 0 for the built in BSDF, otherwise the tag of a registered custom BSDF plus one
+    custom_bsdf: u32,
+    // SHADOW_CASTER_OFF, SHADOW_RECEIVER_OFF and SHADOW_CATCHER, taken from the object instead of the material
+    shadow_flags: u32,
+    // The groups of the lights that light the object directly, from the object as well
+    light_link: u32,
+    // Radiance the surface gives off on its own, not clamped so hdr cameras can bloom on it
+    emissive: vec3<f32>,
+    // 0 for none, otherwise the first of the decal texels the image starts at plus one. Multiplied into the base color
+    image: u32,
+    // Texels of the image with a lower alpha are cut out
+    alpha_cutoff: f32,
+}
+
+@group(1) @binding(7) var<storage, read> light_buffer: array<Light>;
+struct Light {
+    position: vec3<f32>,
+    range: f32,
+    // Luminous intensity times the color
+    intensity: vec3<f32>,
+    // 0 -> point; 1 -> spot
+    kind: u32,
+    forward: vec3<f32>,
+    spot_scale: f32,
+    right: vec3<f32>,
+    spot_offset: f32,
+    // Index of the first sample of the IES profile in the light data, NO_LIGHT_DATA if the light has none
+    ies_offset: u32,
+    // Index of the first texel of the cookie in the light data, NO_LIGHT_DATA if the light has none
+    cookie_offset: u32,
+    // 1 / tan(outer angle) of spot lights, maps the cone onto the cookie
+    cookie_scale: f32,
+    // 0.0 for point lights, otherwise the size of the sphere or disk
+    radius: f32,
+    // LIGHT_SHAPE_SPHERE or LIGHT_SHAPE_DISK, the disk faces along forward
+    shape: u32,
+    // The light only lights the surfaces whose material shares a group with it
+    link: u32,
+}
+
+const LIGHT_SHAPE_SPHERE: u32 = 0u;
+const LIGHT_SHAPE_DISK: u32 = 1u;
+
+// The IES profiles and cookies of all lights back to back.
+// IES profiles are resampled to a grid of vertical angles from the forward direction and horizontal angles around it,
+// cookies to a square of rgb texels
+@group(1) @binding(8) var<storage, read> light_data: array<f32>;
+const IES_VERTICAL_SAMPLES: u32 = 64u;
+const IES_HORIZONTAL_SAMPLES: u32 = 32u;
+const COOKIE_RESOLUTION: u32 = 128u;
+const NO_LIGHT_DATA: u32 = 0xffffffffu;
+
+const SHADOW_CASTER_OFF: u32 = 1u;
+const SHADOW_RECEIVER_OFF: u32 = 2u;
+const SHADOW_CATCHER: u32 = 4u;
+
+@group(1) @binding(2) var<storage, read> bvh_buffer: array<BVHNode>;
+struct BVHNode {
+    bounds_min: vec3<f32>,
+    bounds_max: vec3<f32>,
+    // is the first index into bvh_primitive_buffer if it is a leaf node (model_count > 0)
+    // otherwise the first child index (second child directly after that
+    index: u32,
+    model_count: u32,
+}
+
+// The models of the leaves, models keep their place in the model buffer while the BVH gets rebuilt around them
+@group(1) @binding(13) var<storage, read> bvh_primitive_buffer: array<u32>;
+
+@group(1) @binding(3) var<storage, read> fog_buffer: array<FogVolume>;
+struct FogVolume {
+    // The volume is a unit cube or a sphere with a diameter of 1 in local space
+    local_from_world: mat4x4<f32>,
+    scattering_color: vec3<f32>,
+    density: f32,
+    // 0 -> box; 1 -> sphere
+    shape: u32,
+}
+
+@group(1) @binding(4) var<storage, read> heightfield_buffer: array<Heightfield>;
+@group(1) @binding(5) var<storage, read> height_buffer: array<f32>;
+// The heights that didn't fit into the first buffer, a heightfield always stays within a single one
+@group(1) @binding(9) var<storage, read> height_buffer_1: array<f32>;
+@group(1) @binding(10) var<storage, read> height_buffer_2: array<f32>;
+@group(1) @binding(11) var<storage, read> height_buffer_3: array<f32>;
+// The heightfield covers the unit square on xz in the local space of its model, with heights from 0.0 to 1.0
+struct Heightfield {
+    // Amount of height samples along x and z
+    resolution: vec2<u32>,
+    // Index of the first sample in the height buffer, the samples are stored row by row
+    offset: u32,
+    // Which of the height buffers the samples are in
+    chunk: u32,
+}
+
+@group(1) @binding(12) var<storage, read> portal_buffer: array<Portal>;
+struct Portal {
+    // The portal is the unit square on xy in local space
+    local_from_world: mat4x4<f32>,
+    // Carries points and directions at the portal over to its target
+    target_from_world: mat4x4<f32>,
+    // Multiplies the light coming through, mirrors are portals reflecting onto themselves with the color of their metal
+    tint: vec3<f32>,
+}
+
+const NO_PORTAL: u32 = 0xffffffffu;
+
+@group(1) @binding(14) var<storage, read> decal_buffer: array<Decal>;
+struct Decal {
+    // The decal is the unit cube in local space, projected along forward
+    local_from_world: mat4x4<f32>,
+    forward: vec3<f32>,
+    // Index of the first texel in the decal texels
+    texel_offset: u32,
+}
+
+// The images of all decals and billboards back to back, each resampled to a square of linear rgba texels
+@group(1) @binding(15) var<storage, read> decal_texels: array<vec4<f32>>;
+const DECAL_RESOLUTION: u32 = 128u;
+
+struct PortalHit {
+    distance: f32,
+    // NO_PORTAL if there is no portal before the surface
+    index: u32,
+}
+
+@group(1) @binding(6) var<uniform> sky: Sky;
+struct Sky {
+    // Points towards the sun
+    sun_direction: vec3<f32>,
+    sun_intensity: f32,
+    // Meters of air per world unit for the aerial perspective, 0.0 turns it off
+    aerial_perspective_scale: f32,
+    // 0 -> gradient; 1 -> atmosphere
+    model: u32,
+    // The radiance of the white furnace, 0.0 while it is off
+    furnace_radiance: f32,
+}
+
+// In the white furnace every material is white and the only light is the uniform sky
+fn white_furnace() -> bool {
+    return sky.furnace_radiance > 0.0;
+}
+
+#ifdef ENVIRONMENT_MAP
+// The Skybox or EnvironmentMapLight cubemap of the camera, only bound for the main raytracing pipeline
+@group(0) @binding(7) var environment_map: texture_cube<f32>;
+@group(0) @binding(8) var environment_sampler: sampler;
+// Cumulative distribution over the texels of the environment map, weighted by their brightness.
+// Holds a single element if the environment can't be importance sampled
+@group(0) @binding(9) var<storage, read> environment_cdf: array<f32>;
+// Set per camera before tracing, 0.0 if the camera has no environment
+var<private> environment_intensity: f32 = 0.0;
+#endif
+
+// What the path debugger reads back, the vertices of the path through one pixel.
+// Has to match the PathDebugData in path_debug.rs
+const MAX_PATH_VERTICES: u32 = 32u;
+// The kinds of vertices, portals show up twice, once where the path enters and once where it comes out
+const PATH_CAMERA: u32 = 0u;
+const PATH_SURFACE: u32 = 1u;
+const PATH_FOG: u32 = 2u;
+const PATH_PORTAL_ENTRY: u32 = 3u;
+const PATH_PORTAL_EXIT: u32 = 4u;
+const PATH_MISS: u32 = 5u;
+// How far along the ray a miss gets drawn
+const PATH_MISS_LENGTH: f32 = 100.0;
+struct PathVertex {
+    position: vec3<f32>,
+    kind: u32,
+    // What the path carries when it arrives here, a NaN shows up in this first
+    throughput: vec3<f32>,
+}
+struct PathDebug {
+    // Negative if no pixel is recorded
+    pixel: vec2<i32>,
+    vertex_count: u32,
+    vertices: array<PathVertex, MAX_PATH_VERTICES>,
+}
+
+#ifdef PATH_DEBUG
+// The pixel picked with the path debugger, only bound for the main raytracing pipeline
+@group(0) @binding(12) var<storage, read_write> path_debug: PathDebug;
+// Set per pixel before tracing, only the first path of the picked pixel gets recorded
+var<private> path_debug_recording: bool = false;
+
+fn record_path_vertex(position: vec3<f32>, kind: u32, throughput: vec3<f32>) {
+    let index = path_debug.vertex_count;
+    if !path_debug_recording || index >= MAX_PATH_VERTICES {
+        return;
+    }
+
+    path_debug.vertices[index] = PathVertex(position, kind, throughput);
+    path_debug.vertex_count = index + 1u;
+}
+#else
+fn record_path_vertex(position: vec3<f32>, kind: u32, throughput: vec3<f32>) {}
+#endif
+
+#ifdef CAUSTICS
+// The caustic photon map, only bound for the main raytracing pipeline
+@group(2) @binding(0) var<uniform> caustics: Caustics;
+@group(2) @binding(1) var<storage, read> photon_counts: array<u32>;
+@group(2) @binding(2) var<storage, read> photons: array<Photon>;
+
+// Density estimation of the photons around a point, returns the incoming flux per area
+fn gather_caustics(position: vec3<f32>) -> vec3<f32> {
+    let cell_size = caustics.gather_radius * 2.0;
+    // The gather sphere always lies within the 2x2x2 cells closest to the position
+    let base = vec3<i32>(floor(position / cell_size - 0.5));
+
+    var flux = vec3<f32>(0.0, 0.0, 0.0);
+    for (var index = 0; index < 8; index++) {
+        let offset = vec3<i32>(index & 1, (index >> 1) & 1, (index >> 2) & 1);
+        let cell = photon_cell(base + offset);
+        let count = min(photon_counts[cell], PHOTONS_PER_CELL);
+
+        for (var slot: u32 = 0; slot < count; slot++) {
+            let photon = photons[cell * PHOTONS_PER_CELL + slot];
+            let offset_to_photon = photon.position - position;
+            // Hash collisions can bring in photons from far away cells, they get filtered here
+            if dot(offset_to_photon, offset_to_photon) < caustics.gather_radius * caustics.gather_radius {
+                flux += photon.power;
+            }
+        }
+    }
+
+    return flux / (PI * caustics.gather_radius * caustics.gather_radius);
+}
+#endif
+
+#ifdef DENSITY_VOLUME
+// A heterogeneous volume with the density read from a 3d texture, only bound for the main raytracing pipeline
+@group(3) @binding(0) var<uniform> density_volume: DensityVolume;
+@group(3) @binding(1) var density_texture: texture_3d<f32>;
+@group(3) @binding(2) var density_sampler: sampler;
+struct DensityVolume {
+    // The texture fills the unit cube in local space
+    local_from_world: mat4x4<f32>,
+    scattering_color: vec3<f32>,
+    // The density a texel value of 1.0 maps to, it is the upper bound for delta tracking
+    max_density: f32,
+    enabled: u32,
+}
+
+const MAX_DELTA_TRACKING_STEPS: u32 = 128u;
+
+// Delta tracking through the volume, returns the distance of the scattering event or INF if there is none before max_distance
+fn sample_density_volume(ray: Ray, max_distance: f32, albedo: ptr<function, vec3<f32>>, state: ptr<private, u32>) -> f32 {
+    if density_volume.enabled == 0 || density_volume.max_density <= 0.0 {
+        return INF;
+    }
+
+    let local_ray = Ray(
+        (density_volume.local_from_world * vec4<f32>(ray.origin, 1.0)).xyz,
+        (density_volume.local_from_world * vec4<f32>(ray.direction, 0.0)).xyz
+    );
+
+    let range = ray_box_range(local_ray, vec3<f32>(-0.5), vec3<f32>(0.5));
+    let end = min(range.y, max_distance);
+    var distance = max(range.x, 0.0);
+    let majorant = density_volume.max_density * length(ray.direction);
+
+    for (var step: u32 = 0; step < MAX_DELTA_TRACKING_STEPS; step++) {
+        distance -= log(1.0 - rngNextFloat(state)) / majorant;
+        if distance >= end {
+            return INF;
+        }
+
+        // Tentative collisions become real ones with the ratio of the actual density to the majorant
+        let uvw = ray_at(local_ray, distance) + 0.5;
+        let density = textureSampleLevel(density_texture, density_sampler, uvw, 0.0).r;
+        if rngNextFloat(state) < density {
+            *albedo = density_volume.scattering_color;
+            return distance;
+        }
+    }
+
+    return INF;
+}
+#endif
+
+#ifdef IRRADIANCE_VOLUME
+// The bevy irradiance volume paths fall back to once they run out of bounces, shares the bind group of the density volume
+@group(3) @binding(3) var<uniform> irradiance_volume: IrradianceVolume;
+@group(3) @binding(4) var irradiance_texture: texture_3d<f32>;
+@group(3) @binding(5) var irradiance_sampler: sampler;
+struct IrradianceVolume {
+    // The probes fill the unit cube in local space
+    local_from_world: mat4x4<f32>,
+    intensity: f32,
+    enabled: u32,
+}
+
+// The ambient cube of the probes around the position, evaluated towards the direction like bevy does for diffuse surfaces.
+// A volume with resolution (x, y, z) is a (x, 2y, 3z) texture: the second dimension picks the positive or negative side,
+// the third one the axis. Positions outside of the volume get no light
+fn sample_irradiance_volume(position: vec3<f32>, direction: vec3<f32>) -> vec3<f32> {
+    if irradiance_volume.enabled == 0u {
+        return vec3<f32>(0.0, 0.0, 0.0);
+    }
+
+    let local = (irradiance_volume.local_from_world * vec4<f32>(position, 1.0)).xyz;
+    if any(abs(local) > vec3<f32>(0.5)) {
+        return vec3<f32>(0.0, 0.0, 0.0);
+    }
+
+    // Kept on the outermost probes, so the filtering doesn't blend in the neighbouring side or axis
+    let resolution = vec3<f32>(textureDimensions(irradiance_texture) / vec3<u32>(1u, 2u, 3u));
+    let half_texel = 0.5 / resolution;
+    let uvw = clamp(local + 0.5, half_texel, 1.0 - half_texel) / vec3<f32>(1.0, 2.0, 3.0);
+    let negative = select(vec3<f32>(0.0), vec3<f32>(0.5), direction < vec3<f32>(0.0));
+
+    let x = textureSampleLevel(irradiance_texture, irradiance_sampler, uvw + vec3<f32>(0.0, negative.x, 0.0), 0.0).rgb;
+    let y = textureSampleLevel(irradiance_texture, irradiance_sampler, uvw + vec3<f32>(0.0, negative.y, 1.0 / 3.0), 0.0).rgb;
+    let z = textureSampleLevel(irradiance_texture, irradiance_sampler, uvw + vec3<f32>(0.0, negative.z, 2.0 / 3.0), 0.0).rgb;
+    let weights = direction * direction;
+    return (weights.x * x + weights.y * y + weights.z * z) * irradiance_volume.intensity;
+}
+#endif
+
+struct Ray {
+    origin: vec3<f32>,
+    direction: vec3<f32>,
+}
+
+fn ray_at(ray: Ray, t: f32) -> vec3<f32> {
+    return ray.origin + t * ray.direction;
+}
+
+// The wavelength in nm carried by the current path, 0.0 if the path isn't spectral
+var<private> path_wavelength: f32 = 0.0;
+
+// The least roughness specular interactions of the current path have, it grows along regularized paths
+var<private> path_min_roughness: f32 = 0.0;
+
+// The instance mask of every model, rays with it hit them all. Models that are `RaytraceReflectionOnly`
+// have their groups in the second byte, so only the masks of secondary rays reach them
+const ALL_INSTANCES: u32 = 0xffffu;
+// Every type of ray in trace_path hits every model
+const ALL_RAY_MASKS: u32 = 0xffffffu;
+// What the shadow rays of the current path hit, see `RaytraceRayMasks`
+var<private> shadow_ray_mask: u32 = ALL_INSTANCES;
+
+// Only the paths of cameras treat shadow catchers as the backplate, for everything else they are regular surfaces
+var<private> catch_shadows: bool = false;
+
+// The `RaytraceClipPlanes` of the camera, set before tracing. Every ray ignores hits on the side the normal in xyz points to,
+// w is the signed distance of the origin to the plane. Has to match the constant in mod.rs
+const MAX_CLIP_PLANES: u32 = 4u;
+var<private> clip_planes: array<vec4<f32>, MAX_CLIP_PLANES>;
+var<private> clip_plane_count: u32 = 0u;
+
+// The glossy lobe the last interaction picked, next event estimation evaluates the lights for it
+struct GlossyLobe {
+    // Towards where the path came from
+    view: vec3<f32>,
+    // Facing the view
+    normal: vec3<f32>,
+    // F0
+    normal_reflectance: vec3<f32>,
+    roughness: f32,
+    // One over the chance the lobe got picked, 0.0 if the last interaction wasn't glossy
+    weight: f32,
+}
+var<private> glossy_lobe: GlossyLobe;
+
+// The angle a pixel covers, set per camera before tracing. Paths start as a cone this wide,
+// which gets wider with every rough bounce. 0.0 for everything that doesn't trace from a camera
+var<private> pixel_spread_angle: f32 = 0.0;
+
+// The width of the path's cone at the current hit, textures are filtered over it so they don't shimmer in the distance
+var<private> path_footprint: f32 = 0.0;
+
+// The visible range the wavelengths are sampled from
+const MIN_WAVELENGTH: f32 = 380.0;
+const MAX_WAVELENGTH: f32 = 720.0;
+
+// Index of refraction at the current wavelength, the material ior is taken to be the one at the sodium D line
+fn ior_at_wavelength(material: Material) -> f32 {
+    if path_wavelength == 0.0 {
+        return material.ior;
+    }
+
+    let wavelength = path_wavelength / 1000.0;
+    let reference = 0.5893;
+    return material.ior + material.dispersion * (1.0 / (wavelength * wavelength) - 1.0 / (reference * reference));
+}
+
+// Rough rgb response to a single wavelength, made of one gaussian per channel.
+// Every channel is normalized to average out to 1.0 over the sampled range, so white light stays white.
+fn wavelength_to_rgb(wavelength: f32) -> vec3<f32> {
+    let center = vec3<f32>(610.0, 545.0, 455.0);
+    let width = vec3<f32>(45.0, 40.0, 35.0);
+    let offset = (vec3<f32>(wavelength) - center) / width;
+    let response = exp(-0.5 * offset * offset);
+    return response * (MAX_WAVELENGTH - MIN_WAVELENGTH) / (width * sqrt(2.0 * PI));
+}
+
+struct PathResult {
+    radiance: vec3<f32>,
+    // distance to the first hit, INF if the background was hit directly
+    first_distance: f32,
+}
+
+// Follows a single path through the scene, this is shared between everything that needs to trace rays
+// Spectral paths carry a single random wavelength, which makes dispersion possible at the cost of more noise.
+// Regularization raises the roughness of every interaction after the first diffuse one by this amount,
+// which blurs specular-diffuse-specular paths enough to be found without fireflies, 0.0 keeps the paths unbiased.
+// Paths with a throughput below min_throughput get russian roulette and chains of more than max_specular_bounces
+// specular interactions in a row end, see `PathTermination`. With irradiance_fallback the paths that run out of bounces
+// take the light of the irradiance volume at their last hit
+// The masks of the camera, shadow and reflected rays are packed into the bytes of ray_masks, see `RaytraceRayMasks`
+fn trace_path(base_ray: Ray, max_bounces: u32, spectral: bool, regularization: f32, min_throughput: f32, max_specular_bounces: u32, irradiance_fallback: bool, ray_masks: u32, state: ptr<private, u32>) -> PathResult {
+    var ray = base_ray;
+    // Rays from the camera stay camera rays through portals, until they scatter somewhere
+    var ray_mask = ray_masks & 0xffu;
+    shadow_ray_mask = secondary_ray_mask((ray_masks >> 8u) & 0xffu);
+    var camera_ray = catch_shadows;
+    // Reflections off shadow catchers only add the traced objects, the backplate already reflects everything else
+    var catcher_reflection = false;
+    var specular_bounces = 0u;
+    path_min_roughness = 0.0;
+    path_footprint = 0.0;
+    // Curved surfaces also change the spread, but only the roughness is taken into account
+    var cone_spread = pixel_spread_angle;
+
+    path_wavelength = 0.0;
+    if spectral {
+        path_wavelength = mix(MIN_WAVELENGTH, MAX_WAVELENGTH, rngNextFloat(state));
+    }
+
+    var first_depth: f32 = INF;
+    var ray_color: vec3<f32> = vec3<f32>(1.0, 1.0, 1.0);
+    var lightSourceColor: vec3<f32> = vec3<f32>(0.0, 0.0, 0.0);
+
+#ifdef CAUSTICS
+    // Light reaching diffuse surfaces through specular bounces comes from the photon map instead
+    var caustic_light = vec3<f32>(0.0, 0.0, 0.0);
+    var after_diffuse = false;
+    var last_diffuse = false;
+#endif
+
+    // Light of the light sources and the environment map sampled directly at diffuse hits, plus emissive surfaces
+    var direct_light = vec3<f32>(0.0, 0.0, 0.0);
+#ifdef ENVIRONMENT_MAP
+    // Density of the direction the path continued in after the last diffuse hit, 0.0 after anything else
+    var last_bsdf_pdf = 0.0;
+#endif
+
+    record_path_vertex(ray.origin, PATH_CAMERA, ray_color);
+
+    var bounce_count: u32 = 0;
+    for (; bounce_count <= max_bounces; bounce_count++) {
+        let hit = raycast_scene(ray, ray_mask, false);
+        // A portal in front of the surface takes the path somewhere else, its distance is the one to the surface otherwise
+        let portal = raycast_portals(ray, hit.distance);
+
+        // Setting the depth for depth buffer comparison, this might have to be a early return at some point
+        if bounce_count == 0 {
+            first_depth = portal.distance;
+        }
+
+        // Light shafts, the light the fog along the whole way to the surface scatters towards the path
+        direct_light += ray_color * sample_fog_lights(ray, portal.distance, state);
+
+        // Rays can scatter inside fog volumes before they reach the surface
+        var fog_albedo: vec3<f32>;
+        var fog_distance = sample_fog(ray, portal.distance, &fog_albedo, state);
+#ifdef DENSITY_VOLUME
+        // The density volume only has to be tracked up to the closest scattering event so far
+        var volume_albedo: vec3<f32>;
+        let volume_distance = sample_density_volume(ray, min(fog_distance, portal.distance), &volume_albedo, state);
+        if volume_distance < fog_distance {
+            fog_distance = volume_distance;
+            fog_albedo = volume_albedo;
+        }
+#endif
+        if white_furnace() {
+            fog_albedo = vec3<f32>(1.0, 1.0, 1.0);
+        }
+        if fog_distance < portal.distance {
+            ray = Ray(ray_at(ray, fog_distance), randomUnitVec3(state));
+            ray_mask = secondary_ray_mask((ray_masks >> 16u) & 0xffu);
+            camera_ray = false;
+            catcher_reflection = false;
+            record_path_vertex(ray.origin, PATH_FOG, ray_color);
+            ray_color *= fog_albedo;
+#ifdef ENVIRONMENT_MAP
+            last_bsdf_pdf = 0.0;
+#endif
+#ifdef CAUSTICS
+            // The photon map isn't gathered in fog, so light arriving here has to come from the path itself
+            after_diffuse = false;
+            last_diffuse = false;
+#endif
+            continue;
+        }
+
+        // Passing through a portal takes up a bounce, so facing mirrors end after max_bounces
+        if portal.index != NO_PORTAL {
+            path_footprint += cone_spread * portal.distance * length(ray.direction);
+            record_path_vertex(ray_at(ray, portal.distance), PATH_PORTAL_ENTRY, ray_color);
+            ray = pass_through_portal(ray, portal);
+            ray_color *= portal_buffer[portal.index].tint;
+            record_path_vertex(ray.origin, PATH_PORTAL_EXIT, ray_color);
+#ifdef ENVIRONMENT_MAP
+            // Portals block the environment samples, so the environment seen through one only comes from the path
+            last_bsdf_pdf = 0.0;
+#endif
+            continue;
+        }
+
+        // The background
+        if hit.distance == INF {
+            record_path_vertex(ray.origin + normalize(ray.direction) * PATH_MISS_LENGTH, PATH_MISS, ray_color);
+            lightSourceColor = sky_radiance(ray);
+#ifdef ENVIRONMENT_MAP
+            // This direction could also have been sampled from the environment, the two share its light
+            if last_bsdf_pdf > 0.0 && environment_importance_sampled() {
+                lightSourceColor *= power_heuristic(last_bsdf_pdf, environment_pdf(ray.direction));
+            } else if environment_intensity > 0.0 {
+                // Seen directly or through mirrors, a smaller mip covers the cone without aliasing.
+                // Paths the environment is sampled for stay on the full resolution, or the two strategies would disagree
+                lightSourceColor = environment_radiance_level(ray.direction, environment_level(cone_spread));
+            }
+#endif
+            if catcher_reflection {
+                lightSourceColor = vec3<f32>(0.0, 0.0, 0.0);
+            }
+#ifdef CAUSTICS
+            if caustics.enabled != 0 && after_diffuse && !last_diffuse {
+                lightSourceColor = vec3<f32>(0.0, 0.0, 0.0);
+            }
+#endif
+            break;
+        }
+
+        path_footprint += cone_spread * hit.distance * length(ray.direction);
+        record_path_vertex(hit.position, PATH_SURFACE, ray_color);
+
+        if camera_ray && (material_buffer[hit.material].shadow_flags & SHADOW_CATCHER) != 0u {
+            catch_shadow(&ray, &ray_color, &camera_ray, &catcher_reflection, hit, state);
+            if !camera_ray {
+                ray_mask = secondary_ray_mask((ray_masks >> 16u) & 0xffu);
+            }
+            continue;
+        }
+
+        ray_mask = secondary_ray_mask((ray_masks >> 16u) & 0xffu);
+        camera_ray = false;
+        catcher_reflection = false;
+
+        // Emissive surfaces aren't sampled as lights, so paths only pick them up by hitting them.
+        // They aren't in the photon map either, so caustics don't hide them
+        if !white_furnace() {
+            direct_light += ray_color * material_buffer[hit.material].emissive;
+        }
+
+        var attenuation: vec3<f32>;
+        var diffuse: bool;
+        let absorbed = scatter(&ray, &attenuation, &diffuse, hit, state);
+
+        // A rough guess of the lobes, diffuse bounces spread over about a radian and glossy ones with their squared roughness
+        if diffuse {
+            cone_spread += 1.0;
+        } else {
+            let roughness = max(material_buffer[hit.material].roughness, path_min_roughness);
+            cone_spread += roughness * roughness;
+        }
+
+        if regularization > 0.0 && (diffuse || path_min_roughness > 0.0) {
+            path_min_roughness = min(path_min_roughness + regularization, 1.0);
+        }
+
+        if diffuse {
+            direct_light += ray_color * attenuation * sample_lights(hit, false, state);
+        } else if glossy_lobe.weight > 0.0 {
+            direct_light += ray_color * sample_lights(hit, true, state);
+        }
+
+#ifdef ENVIRONMENT_MAP
+        last_bsdf_pdf = 0.0;
+        if diffuse && environment_importance_sampled() {
+            // After the last bounce the path can't reach the environment anymore, so the direct sample gets all of its light
+            direct_light += ray_color * attenuation * sample_environment_light(hit, bounce_count == max_bounces, state);
+            last_bsdf_pdf = max(dot(normalize(ray.direction), hit.normal), 0.0) / PI;
+        }
+#endif
+
+#ifdef CAUSTICS
+        if caustics.enabled != 0 && diffuse {
+            // Lambertian BRDF applied to the gathered flux
+            caustic_light += ray_color * attenuation / PI * gather_caustics(hit.position);
+        }
+        after_diffuse = after_diffuse || diffuse;
+        last_diffuse = diffuse;
+#endif
+
+        // rays getting absorbed
+        if absorbed {
+            break;
+        }
+
+        ray_color *= attenuation;
+
+        specular_bounces = select(specular_bounces + 1u, 0u, diffuse);
+        if specular_bounces > max_specular_bounces {
+            break;
+        }
+
+        // Dim paths continue with a chance matching their throughput and make up for the ones that ended
+        let throughput = max(ray_color.r, max(ray_color.g, ray_color.b));
+        if throughput < min_throughput {
+            let survival = throughput / min_throughput;
+            if rngNextFloat(state) >= survival {
+                break;
+            }
+            ray_color /= survival;
+        }
+    }
+
+    // A extra bounce could be added -> the background break wasn't hit
+    if bounce_count == max_bounces + 1 {
+#ifdef IRRADIANCE_VOLUME
+        if irradiance_fallback && !white_furnace() {
+            // The ray already left the last hit, the volume stands in for the light it would have found
+            direct_light += ray_color * sample_irradiance_volume(ray.origin, normalize(ray.direction));
+        }
+#endif
+        ray_color = vec3<f32>(0.0, 0.0, 0.0);
+    }
+
+#ifdef CAUSTICS
+    var radiance = ray_color * lightSourceColor + caustic_light;
+#else
+    var radiance = ray_color * lightSourceColor;
+#endif
+    radiance += direct_light;
+
+    // Distant surfaces fade into the haze of the atmosphere
+    if sky.model == 1 && sky.aerial_perspective_scale > 0.0 && first_depth != INF {
+        let transmittance = aerial_transmittance(first_depth * length(base_ray.direction) * sky.aerial_perspective_scale);
+        let horizon = normalize(vec3<f32>(base_ray.direction.x, max(base_ray.direction.y, 0.0), base_ray.direction.z));
+        radiance = radiance * transmittance + sky_radiance(Ray(base_ray.origin, horizon)) * (1.0 - transmittance);
+    }
+
+    if spectral {
+        radiance *= wavelength_to_rgb(path_wavelength);
+    }
+
+    return PathResult(radiance, first_depth);
+}
+
+// Camera rays either pass through a shadow catcher to the backplate, dimmed by the shadows on it, or reflect off it
+// with the chance of its fresnel term. Back faces let them through untouched, so closed catchers only receive on the outside
+fn catch_shadow(ray: ptr<function, Ray>, ray_color: ptr<function, vec3<f32>>, camera_ray: ptr<function, bool>, catcher_reflection: ptr<function, bool>, hit: HitInfo, state: ptr<private, u32>) {
+    let direction = normalize((*ray).direction);
+    if !hit.front_face {
+        *ray = Ray(hit.position, direction);
+        return;
+    }
+
+    let material = material_buffer[hit.material];
+    let reflection_chance = fresnel(vec3<f32>(material.normal_reflectance), abs(dot(direction, hit.normal))).x;
+    if rngNextFloat(state) < reflection_chance {
+        let reflected = reflect(direction, hit.normal) + material.roughness * randomUnitVec3(state);
+        *ray = Ray(hit.position, normalize(reflected));
+        *camera_ray = false;
+        *catcher_reflection = true;
+        return;
+    }
+
+    // Makes up for the paths that got reflected, the backplate behind the catcher keeps its brightness
+    *ray_color *= catcher_visibility(hit, state) / (1.0 - reflection_chance);
+    *ray = Ray(hit.position, direction);
+}
+
+// How much of the light reaching a shadow catcher isn't blocked, from one sample of every light it is linked to and one of the sky.
+// Both are weighted by how much they would brighten a diffuse surface there
+fn catcher_visibility(hit: HitInfo, state: ptr<private, u32>) -> f32 {
+    let light_link = material_buffer[hit.material].light_link;
+
+    var unshadowed = 0.0;
+    var visible = 0.0;
+    for (var light_index: u32 = 0; light_index < arrayLength(&light_buffer); light_index++) {
+        let light = light_buffer[light_index];
+        if (light.link & light_link) == 0u {
+            continue;
+        }
+
+        let light_sample = sample_light(light, hit.position, state);
+        let cos_theta = dot(light_sample.direction, hit.normal);
+        if cos_theta <= 0.0 {
+            continue;
+        }
+
+        let weight = luminance(light_sample.light) * cos_theta / PI;
+        unshadowed += weight;
+        if !occluded(Ray(hit.position, light_sample.direction), light_sample.distance) {
+            visible += weight;
+        }
+    }
+
+    // Cosine weighted, so the sky counts with its radiance alone
+    let sky_direction = normalize(hit.normal + randomUnitVec3(state));
+    let sky_weight = luminance(sky_radiance(Ray(hit.position, sky_direction)));
+    unshadowed += sky_weight;
+    if !occluded(Ray(hit.position, sky_direction), INF) {
+        visible += sky_weight;
+    }
+
+    if unshadowed <= 0.0 {
+        return 1.0;
+    }
+    return visible / unshadowed;
+}
+
+fn luminance(color: vec3<f32>) -> f32 {
+    return dot(color, vec3<f32>(0.2126, 0.7152, 0.0722));
+}
+
+// returns wether the ray was absorbed, diffuse tells apart diffuse from specular interactions
+fn scatter(scattered: ptr<function, Ray>, attenuation: ptr<function, vec3<f32>>, diffuse: ptr<function, bool>, hit: HitInfo, state: ptr<private, u32>) -> bool {
+    let material = material_buffer[hit.material];
+    let image_color = material_image(material, hit.uv).rgb;
+    let textured = procedural_texture(material.texture, material.texture_scale, material.texture_octaves, material.base_color * image_color, material.texture_color, hit.position, path_footprint);
+    var base_color = apply_decals(textured, hit);
+    if white_furnace() {
+        base_color = vec3<f32>(1.0, 1.0, 1.0);
+    }
+    let roughness = max(material.roughness, path_min_roughness);
+    *diffuse = false;
+    glossy_lobe.weight = 0.0;
+
+    if material.custom_bsdf != 0 {
+        let input = BsdfInput((*scattered).direction, hit.position, hit.normal, hit.front_face, hit.uv, base_color, material.metallic, roughness, ior_at_wavelength(material));
+        let output = custom_scatter(material.custom_bsdf - 1, input, state);
+
+        *scattered = Ray(hit.position, output.direction);
+        *attenuation = output.attenuation;
+        *diffuse = output.diffuse;
+        return output.absorbed;
+    }
+
+    // Facing the incoming ray
+    let normal = select(-hit.normal, hit.normal, hit.front_face);
+    let view = -normalize((*scattered).direction);
+
+    // Bevy's metallic workflow, metals reflect with their base color as F0 and have no other lobe.
+    // Picking one or the other at random averages to the blend bevy does for metallic values in between
+    if rngNextFloat(state) < material.metallic {
+        return scatter_glossy(scattered, attenuation, hit.position, view, normal, base_color, roughness, 1.0, state);
+    }
+
+    if rngNextFloat(state) < material.specular_transmission {
+        // Specular transmission
+
+        let ior = ior_at_wavelength(material);
+        let thin_walled = material.thin_walled != 0;
+        var ri: f32;
+        // A thin wall is a single interface that is always entered from the outside
+        if hit.front_face || thin_walled {
+            // inside
+            ri = 1.0 / ior;
+        } else {
+            // outside
+            ri = ior;
+        }
+
+        let unit_direction = -view;
+
+        // Rough surfaces refract around a microfacet normal, which turns them into frosted glass
+        let microfacet = sample_ggx_normal(normal, unit_direction, roughness, state);
+
+        let cos_theta = min(dot(-unit_direction, microfacet), 1.0);
+        let sin_theta = sqrt(1.0 - cos_theta * cos_theta);
+
+        let cannot_refract = ri * sin_theta > 1.0;
+        let reflected = cannot_refract || reflectance(cos_theta, ri) > rngNextFloat(state);
+        var direction: vec3<f32>;
+
+        if reflected {
+            direction = reflect(unit_direction, microfacet);
+        } else if thin_walled {
+            // The rough reflection mirrored to the other side, which keeps the direction for smooth walls
+            direction = reflect(reflect(unit_direction, microfacet), normal);
+        } else {
+            direction = refract(unit_direction, microfacet, ri);
+        }
+
+        // setting return values
+        *scattered = Ray(hit.position, direction);
+        // Like in bevy the transmitted light takes on the base color, the reflection doesn't
+        *attenuation = select(base_color, vec3<f32>(1.0, 1.0, 1.0), reflected);
+
+        // A ray leaving on the wrong side of the surface is blocked by the neighbouring microfacets
+        return reflected == (dot(direction, normal) < 0.0);
+    }
+
+    // Dielectrics have a glossy coat with the F0 of their reflectance on top of the diffuse lobe.
+    // The coat is picked as often as it reflects on average, the diffuse lobe gets the light it lets through
+    let f0 = vec3<f32>(material.normal_reflectance);
+    let glossy_chance = saturate(specular_albedo(f0, roughness, dot(normal, view)).g);
+    if rngNextFloat(state) < glossy_chance {
+        return scatter_glossy(scattered, attenuation, hit.position, view, normal, f0, roughness, 1.0 / glossy_chance, state);
+    }
+
+    // Lambertian diffuse, bevy's Burley diffuse is within a few percent of it but doesn't conserve energy
+    *diffuse = true;
+    var scatter_direction = hit.normal + randomUnitVec3(state);
+
+    if vec3_near_zero(scatter_direction) {
+        scatter_direction = hit.normal;
+    }
+
+    // setting return values
+    *scattered = Ray(hit.position, scatter_direction);
+    *attenuation = base_color;
+
+    // Discard below surface
+    return dot((*scattered).direction, hit.normal) < 0;
+}
+
+// GGX reflection with the Fresnel, visibility and multiscattering terms bevy uses,
+// `weight` is one over the chance the lobe got picked
+fn scatter_glossy(scattered: ptr<function, Ray>, attenuation: ptr<function, vec3<f32>>, position: vec3<f32>, view: vec3<f32>, normal: vec3<f32>, f0: vec3<f32>, roughness: f32, weight: f32, state: ptr<private, u32>) -> bool {
+    let microfacet = sample_ggx_normal(normal, -view, roughness, state);
+    let direction = reflect(-view, microfacet);
+    *scattered = Ray(position, direction);
+    glossy_lobe = GlossyLobe(view, normal, f0, roughness, weight);
+
+    // Discard below surface
+    let n_dot_l = dot(normal, direction);
+    if n_dot_l <= 0.0 {
+        *attenuation = vec3<f32>(0.0, 0.0, 0.0);
+        return true;
+    }
+
+    // The microfacet normal follows D * NdotH, which leaves the other terms of the BRDF over the pdf of the direction
+    let n_dot_v = max(dot(normal, view), 0.0001);
+    let v_dot_h = max(dot(view, microfacet), 0.0001);
+    let n_dot_h = max(dot(normal, microfacet), 0.0001);
+    let visibility = smith_ggx_visibility(roughness * roughness, n_dot_v, n_dot_l);
+    *attenuation = 4.0 * visibility * n_dot_l * v_dot_h / n_dot_h * fresnel(f0, v_dot_h) * multiscatter_compensation(f0, roughness, n_dot_v) * weight;
+    return false;
+}
+
+// The glossy BRDF of the last interaction times the cosine towards a light, over the chance the lobe got picked
+fn glossy_light_weight(direction: vec3<f32>) -> vec3<f32> {
+    let n_dot_l = dot(glossy_lobe.normal, direction);
+    let n_dot_v = max(dot(glossy_lobe.normal, glossy_lobe.view), 0.0001);
+    if n_dot_l <= 0.0 {
+        return vec3<f32>(0.0, 0.0, 0.0);
+    }
+
+    let half_vector = normalize(direction + glossy_lobe.view);
+    let n_dot_h = saturate(dot(glossy_lobe.normal, half_vector));
+    let l_dot_h = saturate(dot(direction, half_vector));
+    let alpha = glossy_lobe.roughness * glossy_lobe.roughness;
+    let brdf = ggx_distribution(alpha, n_dot_h) * smith_ggx_visibility(alpha, n_dot_v, n_dot_l) * fresnel(glossy_lobe.normal_reflectance, l_dot_h) * multiscatter_compensation(glossy_lobe.normal_reflectance, glossy_lobe.roughness, n_dot_v);
+    return brdf * n_dot_l * glossy_lobe.weight;
+}
+
+// The following match bevy's pbr_lighting.wgsl, with the perceptual roughness already clamped like bevy does on the CPU.
+// alpha is the squared perceptual roughness
+fn ggx_distribution(alpha: f32, n_dot_h: f32) -> f32 {
+    let a = n_dot_h * alpha;
+    let k = alpha / (1.0 - n_dot_h * n_dot_h + a * a);
+    return k * k / PI;
+}
+
+// Height correlated Smith, already divided by 4 NdotV NdotL
+fn smith_ggx_visibility(alpha: f32, n_dot_v: f32, n_dot_l: f32) -> f32 {
+    let a2 = alpha * alpha;
+    let lambda_v = n_dot_l * sqrt((n_dot_v - a2 * n_dot_v) * n_dot_v + a2);
+    let lambda_l = n_dot_v * sqrt((n_dot_l - a2 * n_dot_l) * n_dot_l + a2);
+    return 0.5 / (lambda_v + lambda_l);
+}
+
+// Schlick with the F90 bevy uses, which darkens the grazing reflections of an F0 below 2%
+fn fresnel(f0: vec3<f32>, cosine: f32) -> vec3<f32> {
+    let f90 = saturate(dot(f0, vec3<f32>(50.0 * 0.33)));
+    return f0 + (f90 - f0) * pow(1.0 - cosine, 5.0);
+}
+
+// Bevy's scale and bias approximation of the split sum
+fn f_ab(roughness: f32, n_dot_v: f32) -> vec2<f32> {
+    let c0 = vec4<f32>(-1.0, -0.0275, -0.572, 0.022);
+    let c1 = vec4<f32>(1.0, 0.0425, 1.04, -0.04);
+    let r = roughness * c0 + c1;
+    let a004 = min(r.x * r.x, exp2(-9.28 * n_dot_v)) * r.x + r.y;
+    return vec2<f32>(-1.04, 1.04) * a004 + r.zw;
+}
+
+// The light a single scattering GGX surface loses, added back like bevy does
+fn multiscatter_compensation(f0: vec3<f32>, roughness: f32, n_dot_v: f32) -> vec3<f32> {
+    return 1.0 + f0 * (1.0 / f_ab(roughness, n_dot_v).x - 1.0);
+}
+
+// How much light the glossy lobe reflects in total when seen from this angle
+fn specular_albedo(f0: vec3<f32>, roughness: f32, n_dot_v: f32) -> vec3<f32> {
+    let ab = f_ab(roughness, max(n_dot_v, 0.0001));
+    return (f0 * ab.x + ab.y) * multiscatter_compensation(f0, roughness, max(n_dot_v, 0.0001));
+}
+
+struct HitInfo {
+    distance: f32,
+    position: vec3<f32>,
+    normal: vec3<f32>,
+    material: u32,
+    front_face: bool,
+    // Texture coordinates of the hit, every primitive defines its own mapping
+    uv: vec2<f32>,
+}
+
+// These parameters are just random guesses, investigate what the algorithm actually does
+const MAX_MODELS_PER_NODE: i32 = 8;
+
+// Fragment shaders have no workgroup memory, so the short stack stays private to the invocation, but a few entries
+// instead of one per level keep it in registers. Has to be a power of two
+const SHORT_STACK_SIZE: u32 = 4u;
+// The bit of the root in the restart trail, every level below it has the next lower bit. Has to match RESTART_TRAIL_LEVELS in bvh.rs
+const ROOT_LEVEL: u32 = 0x80000000u;
+
+struct StackEntry {
+    node: u32,
+    // The bit of its level in the restart trail
+    level: u32,
+    // To the bounds of the node, the entry gets skipped once something closer was hit
+    distance: f32,
+}
+
+// Shadow and reflected rays hit the reflection only models of the groups in their mask as well
+fn secondary_ray_mask(mask: u32) -> u32 {
+    return mask | (mask << 8u);
+}
+
+// Hits every model, whatever its instance mask
+fn raycast(ray: Ray) -> HitInfo {
+    return raycast_scene(ray, ALL_INSTANCES, false);
+}
+
+// Wether something that casts shadows blocks the ray before it travels max_distance, portals always do
+fn occluded(ray: Ray, max_distance: f32) -> bool {
+    return raycast_scene(ray, shadow_ray_mask, true).distance < max_distance || raycast_portals(ray, max_distance).index != NO_PORTAL;
+}
+
+// The closest portal the ray passes through before max_distance
+fn raycast_portals(ray: Ray, max_distance: f32) -> PortalHit {
+    var closest = PortalHit(max_distance, NO_PORTAL);
+    for (var portal_index: u32 = 0; portal_index < arrayLength(&portal_buffer); portal_index++) {
+        let portal = portal_buffer[portal_index];
+        // The transform is affine, so the ray parameter is the same in local space
+        let origin = (portal.local_from_world * vec4<f32>(ray.origin, 1.0)).xyz;
+        let direction = (portal.local_from_world * vec4<f32>(ray.direction, 0.0)).xyz;
+        if direction.z == 0.0 {
+            continue;
+        }
+
+        let distance = -origin.z / direction.z;
+        let position = origin + distance * direction;
+        if distance > 0.001 && distance < closest.distance && all(abs(position.xy) <= vec2<f32>(0.5)) && !clipped(ray_at(ray, distance)) {
+            closest = PortalHit(distance, portal_index);
+        }
+    }
+
+    return closest;
+}
+
+// Continues the ray at the target of the portal, from the same spot relative to it
+fn pass_through_portal(ray: Ray, hit: PortalHit) -> Ray {
+    let portal = portal_buffer[hit.index];
+    return Ray(
+        (portal.target_from_world * vec4<f32>(ray_at(ray, hit.distance), 1.0)).xyz,
+        (portal.target_from_world * vec4<f32>(ray.direction, 0.0)).xyz
+    );
+}
+
+// Shadow rays pass through everything that doesn't cast shadows, every ray through the models that share no bit with its mask
+// The clip planes cut the ray down to the part between them, which is traced on its own
+fn raycast_scene(ray: Ray, mask: u32, shadow: bool) -> HitInfo {
+    let miss = HitInfo(INF, vec3<f32>(0.0, 0.0, 0.0), vec3<f32>(0.0, 0.0, 0.0), 0, true, vec2<f32>(0.0, 0.0));
+    let range = clip_range(ray);
+    if range.x >= range.y {
+        return miss;
+    }
+
+    let clipped_ray = Ray(ray_at(ray, range.x), ray.direction);
+    let max_distance = range.y - range.x;
+    var closest = miss;
+    closest.distance = max_distance;
+
+    raycast_bvh_restart_trail(clipped_ray, mask, shadow, &closest);
+
+    if closest.distance >= max_distance {
+        return miss;
+    }
+    closest.distance += range.x;
+    return closest;
+}
+
+// The range of the ray parameter on the kept side of every clip plane, empty if start >= end
+fn clip_range(ray: Ray) -> vec2<f32> {
+    var range = vec2<f32>(0.0, INF);
+    for (var plane_index = 0u; plane_index < clip_plane_count; plane_index++) {
+        let plane = clip_planes[plane_index];
+        let side = dot(plane.xyz, ray.origin) + plane.w;
+        let slope = dot(plane.xyz, ray.direction);
+        if slope == 0.0 {
+            if side > 0.0 {
+                return vec2<f32>(INF, 0.0);
+            }
+            continue;
+        }
+
+        let crossing = -side / slope;
+        if slope > 0.0 {
+            range.y = min(range.y, crossing);
+        } else {
+            range.x = max(range.x, crossing);
+        }
+    }
+    return range;
+}
+
+fn clipped(position: vec3<f32>) -> bool {
+    for (var plane_index = 0u; plane_index < clip_plane_count; plane_index++) {
+        let plane = clip_planes[plane_index];
+        if dot(plane.xyz, position) + plane.w > 0.0 {
+            return true;
+        }
+    }
+    return false;
+}
+
+// Walks the BVH near child first with a short stack of the far children. Entries that don't fit push out the oldest ones,
+// when the stack runs dry the walk restarts from the root and follows the restart trail back to where it was.
+// A bit per level in the trail tells whether the first child on the way down is done, so restarts skip finished subtrees
+// (Laine 2010, Restart Trail for Stackless BVH Traversal). Restarts have to take the same way down, so the children are
+// picked by their bounds alone and only afterwards skipped when something closer was hit
+fn raycast_bvh_restart_trail(ray: Ray, mask: u32, shadow: bool, closest: ptr<function, HitInfo>) {
+    var stack: array<StackEntry, SHORT_STACK_SIZE>;
+    // Counts up without wrapping, the entries are at its value modulo the size
+    var stack_top = 0u;
+    var stack_count = 0u;
+
+    var node = 0u;
+    var level = ROOT_LEVEL;
+    var trail = 0u;
+
+    loop {
+        let bvh_node = bvh_buffer[node];
+
+        if bvh_node.model_count > 0 {
+            raycast_against_range(ray, bvh_node.index, bvh_node.model_count, mask, shadow, closest);
+        // The BVH ends where the trail does, `limit_depth` in bvh.rs turns the inner nodes on its last level into leaves
+        } else if level > 1u {
+            let dst_1 = ray_bounding_dst(ray, bvh_buffer[bvh_node.index].bounds_min, bvh_buffer[bvh_node.index].bounds_max);
+            let dst_2 = ray_bounding_dst(ray, bvh_buffer[bvh_node.index + 1].bounds_min, bvh_buffer[bvh_node.index + 1].bounds_max);
+
+            if dst_1 != INF || dst_2 != INF {
+                level = level >> 1u;
+                // Ties keep the order of the BVH, so every restart sorts them the same way
+                let near_second = dst_2 < dst_1;
+                let near = select(bvh_node.index, bvh_node.index + 1, near_second);
+                let far = select(bvh_node.index + 1, bvh_node.index, near_second);
+                let near_dst = min(dst_1, dst_2);
+                let far_dst = max(dst_1, dst_2);
+
+                var next = far;
+                var next_dst = far_dst;
+                if dst_1 == INF || dst_2 == INF {
+                    // The only child is handled like a second one, once it is done so is this node
+                    trail |= level;
+                    next = near;
+                    next_dst = near_dst;
+                } else if (trail & level) == 0u {
+                    stack[stack_top % SHORT_STACK_SIZE] = StackEntry(far, level, far_dst);
+                    stack_top++;
+                    stack_count = min(stack_count + 1u, SHORT_STACK_SIZE);
+                    next = near;
+                    next_dst = near_dst;
+                }
+
+                if next_dst < (*closest).distance {
+                    node = next;
+                    continue;
+                }
+            }
+        }
+
+        // Marks the node at the current level as done, which moves on to its sibling or carries the bit up to a parent
+        // that is done as well. Repeats while the popped entries are behind something closer
+        var popped = false;
+        while !popped {
+            trail &= 0u - level;
+            trail += level;
+            if (trail & ROOT_LEVEL) != 0u {
+                return;
+            }
+
+            if stack_count == 0u {
+                node = 0u;
+                level = ROOT_LEVEL;
+                popped = true;
+            } else {
+                stack_top--;
+                stack_count--;
+                let entry = stack[stack_top % SHORT_STACK_SIZE];
+                node = entry.node;
+                level = entry.level;
+                popped = entry.distance < (*closest).distance;
+            }
+        }
+    }
+}
+
+// Marches through the cells of the heightfield that the ray passes over, testing the two triangles of each cell
+fn raycast_heightfield(ray: Ray, local_ray: Ray, model: Model, closest: ptr<function, HitInfo>) {
+    let heightfield = heightfield_buffer[model.index];
+    if heightfield.resolution.x < 2 || heightfield.resolution.y < 2 {
+        return;
+    }
+
+    let range = ray_box_range(local_ray, vec3<f32>(-0.5, 0.0, -0.5), vec3<f32>(0.5, 1.0, 0.5));
+    let start = max(range.x, 0.001);
+    let end = min(range.y, (*closest).distance);
+    if start >= end {
+        return;
+    }
+
+    // Everything from here on is in cell units
+    let cells = vec2<f32>(heightfield.resolution - 1u);
+    let grid_origin = (ray_at(local_ray, start).xz + 0.5) * cells;
+    let grid_direction = local_ray.direction.xz * cells;
+
+    var cell = clamp(vec2<i32>(floor(grid_origin)), vec2<i32>(0), vec2<i32>(cells) - 1);
+    let step = vec2<i32>(sign(grid_direction));
+    let moving = abs(grid_direction) > vec2<f32>(1e-8);
+    // How far along the ray one cell is and how far the next cell boundary is from the start
+    let delta = select(vec2<f32>(INF), abs(1.0 / grid_direction), moving);
+    let boundary = vec2<f32>(cell) + select(vec2<f32>(0.0), vec2<f32>(1.0), step > vec2<i32>(0));
+    var next = select(vec2<f32>(INF), (boundary - grid_origin) / grid_direction, moving);
+
+    let max_steps = heightfield.resolution.x + heightfield.resolution.y;
+    for (var steps: u32 = 0; steps < max_steps; steps++) {
+        let p00 = heightfield_vertex(heightfield, cells, cell);
+        let p10 = heightfield_vertex(heightfield, cells, cell + vec2<i32>(1, 0));
+        let p01 = heightfield_vertex(heightfield, cells, cell + vec2<i32>(0, 1));
+        let p11 = heightfield_vertex(heightfield, cells, cell + vec2<i32>(1, 1));
+
+        var distance = ray_triangle(local_ray, p00, p10, p11);
+        var normal = cross(p11 - p00, p10 - p00);
+        let distance_2 = ray_triangle(local_ray, p00, p11, p01);
+        if distance_2 < distance {
+            distance = distance_2;
+            normal = cross(p01 - p00, p11 - p00);
+        }
+
+        if distance >= start && distance < end {
+            let world_normal = model_normal(model, normal);
+            // Planar mapping, the texture is stretched over the whole heightfield
+            let uv = ray_at(local_ray, distance).xz + 0.5;
+            *closest = HitInfo(distance, ray_at(ray, distance), world_normal, model.material_id, dot(ray.direction, world_normal) < 0.0, uv);
+            return;
+        }
+
+        // The ray leaves the heightfield or gets blocked by something closer before reaching the next cell
+        if start + min(next.x, next.y) > end {
+            return;
+        }
+
+        // Step into the neighbouring cell the ray reaches first
+        if next.x < next.y {
+            next.x += delta.x;
+            cell.x += step.x;
+        } else {
+            next.y += delta.y;
+            cell.y += step.y;
+        }
+
+        if any(cell < vec2<i32>(0)) || any(cell >= vec2<i32>(cells)) {
+            return;
+        }
+    }
+}
+
+fn heightfield_vertex(heightfield: Heightfield, cells: vec2<f32>, cell: vec2<i32>) -> vec3<f32> {
+    let index = heightfield.offset + u32(cell.y) * heightfield.resolution.x + u32(cell.x);
+    let xz = vec2<f32>(cell) / cells - 0.5;
+    return vec3<f32>(xz.x, height_sample(heightfield.chunk, index), xz.y);
+}
+
+fn height_sample(chunk: u32, index: u32) -> f32 {
+    switch chunk {
+        case 1u: {
+            return height_buffer_1[index];
+        }
+        case 2u: {
+            return height_buffer_2[index];
+        }
+        case 3u: {
+            return height_buffer_3[index];
+        }
+        default: {
+            return height_buffer[index];
+        }
+    }
+}
+
+// Möller–Trumbore intersection, returns the ray parameter of the hit or INF
+fn ray_triangle(ray: Ray, a: vec3<f32>, b: vec3<f32>, c: vec3<f32>) -> f32 {
+    let edge_1 = b - a;
+    let edge_2 = c - a;
+    let p = cross(ray.direction, edge_2);
+    let determinant = dot(edge_1, p);
+    if determinant == 0.0 {
+        return INF;
+    }
+
+    let inverse = 1.0 / determinant;
+    let s = ray.origin - a;
+    let u = dot(s, p) * inverse;
+    if u < 0.0 || u > 1.0 {
+        return INF;
+    }
+
+    let q = cross(s, edge_1);
+    let v = dot(ray.direction, q) * inverse;
+    if v < 0.0 || u + v > 1.0 {
+        return INF;
+    }
+
+    let distance = dot(edge_2, q) * inverse;
+    if distance <= 0.0 {
+        return INF;
+    }
+    return distance;
+}
+
+fn raycast_against_range(ray: Ray, start_index: u32, amount: u32, mask: u32, shadow: bool, closest: ptr<function, HitInfo>) {
+    for (var primitive_index: u32 = start_index; primitive_index < start_index + amount; primitive_index++) {
+        let model = model_buffer[bvh_primitive_buffer[primitive_index]];
+        if (model.mask & mask) == 0u {
+            continue;
+        }
+        if shadow && (material_buffer[model.material_id].shadow_flags & SHADOW_CASTER_OFF) != 0u {
+            continue;
+        }
+
+        // Every primitive is intersected in its local space. The ray parameter stays the same there as long as the
+        // direction isn't normalized, so the distances of all of them can be compared
+        let local_ray = Ray(
+            (model.local_from_world * vec4<f32>(ray.origin, 1.0)).xyz,
+            (model.local_from_world * vec4<f32>(ray.direction, 0.0)).xyz
+        );
+        switch model.primitive {
+            // Heightfields have their own acceleration structure in the grid of their cells
+            case PRIMITIVE_HEIGHTFIELD: {
+                raycast_heightfield(ray, local_ray, model, closest);
+            }
+            case PRIMITIVE_BILLBOARD: {
+                raycast_billboard(ray, local_ray, model, closest);
+            }
+            case PRIMITIVE_CUBOID: {
+                raycast_cuboid(ray, local_ray, model, closest);
+            }
+            case PRIMITIVE_PLANE: {
+                raycast_plane(ray, local_ray, model, closest);
+            }
+            case PRIMITIVE_SPHERE, default: {
+                raycast_sphere(ray, local_ray, model, closest);
+            }
+        }
+    }
+}
+
+// The unit sphere around the origin of the model, scaled and rotated with it
+fn raycast_sphere(ray: Ray, local_ray: Ray, model: Model, closest: ptr<function, HitInfo>) {
+    var hit_distance: f32;
+    if model.precise_position != 0u {
+        hit_distance = hit_unit_sphere_precise(ray.origin, local_ray.direction, model);
+    } else {
+        hit_distance = hit_unit_sphere(local_ray);
+    }
+    if hit_distance != -1.0 && hit_distance > 0.001 {
+        if hit_distance < (*closest).distance {
+            let local_normal = ray_at(local_ray, hit_distance);
+            let normal = model_normal(model, local_normal);
+
+            // The texture turns with the sphere
+            *closest = HitInfo(hit_distance, ray_at(ray, hit_distance), normal, model.material_id, dot(ray.direction, normal) < 0.0, sphere_uv(local_normal));
+        }
+    }
+}
+
+// The unit quad around the origin of the model, turned towards the ray in local space. Upright ones only turn around local up.
+// Its texture coordinates go from the top left to the bottom right, seen from the ray
+fn raycast_billboard(ray: Ray, local_ray: Ray, model: Model, closest: ptr<function, HitInfo>) {
+    var facing = -local_ray.direction;
+    if model.index == BILLBOARD_UPRIGHT {
+        facing.y = 0.0;
+    }
+    // Upright billboards are seen edge on from straight above
+    if dot(facing, facing) < 1e-12 {
+        return;
+    }
+    facing = normalize(facing);
+
+    let hit_distance = dot(local_ray.origin, facing) / dot(local_ray.direction, -facing);
+    if hit_distance <= 0.001 || hit_distance >= (*closest).distance {
+        return;
+    }
+
+    // Its top points as close to local up as it can
+    var right = cross(vec3<f32>(0.0, 1.0, 0.0), facing);
+    if dot(right, right) < 1e-12 {
+        right = vec3<f32>(1.0, 0.0, 0.0);
+    }
+    right = normalize(right);
+    let up = cross(facing, right);
+    let local = ray_at(local_ray, hit_distance);
+    let uv = vec2<f32>(dot(local, right) + 0.5, 0.5 - dot(local, up));
+    if any(uv < vec2<f32>(0.0)) || any(uv > vec2<f32>(1.0)) {
+        return;
+    }
+
+    let material = material_buffer[model.material_id];
+    if material_image(material, uv).a < material.alpha_cutoff {
+        return;
+    }
+
+    *closest = HitInfo(hit_distance, ray_at(ray, hit_distance), model_normal(model, facing), model.material_id, true, uv);
+}
+
+// The unit cube around the origin of the model, from -0.5 to 0.5 on every axis.
+// Each face gets the texture coordinates of the two axes along it
+fn raycast_cuboid(ray: Ray, local_ray: Ray, model: Model, closest: ptr<function, HitInfo>) {
+    let inverse_direction = 1.0 / local_ray.direction;
+    let t0 = (vec3<f32>(-0.5) - local_ray.origin) * inverse_direction;
+    let t1 = (vec3<f32>(0.5) - local_ray.origin) * inverse_direction;
+    let t_near = max(max(min(t0.x, t1.x), min(t0.y, t1.y)), min(t0.z, t1.z));
+    let t_far = min(min(max(t0.x, t1.x), max(t0.y, t1.y)), max(t0.z, t1.z));
+    if t_near > t_far {
+        return;
+    }
+
+    // Rays starting inside, like refracted ones, leave through the far side
+    var hit_distance = t_near;
+    if hit_distance <= 0.001 {
+        hit_distance = t_far;
+    }
+    if hit_distance <= 0.001 || hit_distance >= (*closest).distance {
+        return;
+    }
+
+    // The face is on the axis the hit is furthest out along
+    let local = ray_at(local_ray, hit_distance);
+    let distance_to_face = abs(local);
+    var local_normal: vec3<f32>;
+    var uv: vec2<f32>;
+    if distance_to_face.x >= distance_to_face.y && distance_to_face.x >= distance_to_face.z {
+        local_normal = vec3<f32>(sign(local.x), 0.0, 0.0);
+        uv = local.zy;
+    } else if distance_to_face.y >= distance_to_face.z {
+        local_normal = vec3<f32>(0.0, sign(local.y), 0.0);
+        uv = local.xz;
+    } else {
+        local_normal = vec3<f32>(0.0, 0.0, sign(local.z));
+        uv = local.xy;
+    }
+
+    let normal = model_normal(model, local_normal);
+    *closest = HitInfo(hit_distance, ray_at(ray, hit_distance), normal, model.material_id, dot(ray.direction, normal) < 0.0, uv + 0.5);
+}
+
+// The unit square around the origin of the model with local up as its normal, hit from both sides like bevy's `Plane3d`
+fn raycast_plane(ray: Ray, local_ray: Ray, model: Model, closest: ptr<function, HitInfo>) {
+    if abs(local_ray.direction.y) < 1e-12 {
+        return;
+    }
+
+    let hit_distance = -local_ray.origin.y / local_ray.direction.y;
+    if hit_distance <= 0.001 || hit_distance >= (*closest).distance {
+        return;
+    }
+
+    let local = ray_at(local_ray, hit_distance);
+    if abs(local.x) > 0.5 || abs(local.z) > 0.5 {
+        return;
+    }
+
+    let normal = model_normal(model, vec3<f32>(0.0, 1.0, 0.0));
+    *closest = HitInfo(hit_distance, ray_at(ray, hit_distance), normal, model.material_id, dot(ray.direction, normal) < 0.0, local.xz + 0.5);
+}
+
+// Normals are transformed with the inverse transpose, which is the transposed local_from_world
+fn model_normal(model: Model, local_normal: vec3<f32>) -> vec3<f32> {
+    let normal_transform = transpose(mat3x3<f32>(
+        model.local_from_world[0].xyz,
+        model.local_from_world[1].xyz,
+        model.local_from_world[2].xyz
+    ));
+    return normalize(normal_transform * local_normal);
+}
+
+// Samples where the ray scatters inside the fog volumes, INF if it doesn't scatter before max_distance
+// The volumes are homogeneous, so every volume the ray passes through gets an exponentially distributed free flight distance
+fn sample_fog(ray: Ray, max_distance: f32, albedo: ptr<function, vec3<f32>>, state: ptr<private, u32>) -> f32 {
+    var closest = INF;
+    let ray_length = length(ray.direction);
+
+    for (var fog_index: u32 = 0; fog_index < arrayLength(&fog_buffer); fog_index++) {
+        let fog = fog_buffer[fog_index];
+        if fog.density <= 0.0 {
+            continue;
+        }
+
+        let range = fog_range(fog, ray, max_distance);
+        if range.x >= range.y {
+            continue;
+        }
+
+        let free_flight = -log(1.0 - rngNextFloat(state)) / (fog.density * ray_length);
+        let distance = range.x + free_flight;
+        if distance < range.y && distance < closest {
+            closest = distance;
+            *albedo = fog.scattering_color;
+        }
+    }
+
+    return closest;
+}
+
+// The part of the ray inside the fog volume between its origin and max_distance, empty if start >= end
+fn fog_range(fog: FogVolume, ray: Ray, max_distance: f32) -> vec2<f32> {
+    // The ray parameter stays the same in local space as long as the direction isn't normalized
+    let local_ray = Ray(
+        (fog.local_from_world * vec4<f32>(ray.origin, 1.0)).xyz,
+        (fog.local_from_world * vec4<f32>(ray.direction, 0.0)).xyz
+    );
+
+    var range: vec2<f32>;
+    if fog.shape == 0 {
+        range = ray_box_range(local_ray, vec3<f32>(-0.5), vec3<f32>(0.5));
+    } else {
+        range = ray_sphere_range(local_ray, 0.5);
+    }
+    return vec2<f32>(max(range.x, 0.0), min(range.y, max_distance));
+}
+
+// How much of the light makes it through the fog volumes from the ray origin to max_distance
+fn fog_transmittance(ray: Ray, max_distance: f32) -> f32 {
+    let ray_length = length(ray.direction);
+    var optical_depth = 0.0;
+    for (var fog_index: u32 = 0; fog_index < arrayLength(&fog_buffer); fog_index++) {
+        let fog = fog_buffer[fog_index];
+        let range = fog_range(fog, ray, max_distance);
+        if fog.density > 0.0 && range.x < range.y {
+            optical_depth += fog.density * (range.y - range.x) * ray_length;
+        }
+    }
+    return exp(-optical_depth);
+}
+
+// Light of the lights scattered back along the ray by the fog volumes before max_distance, for light shafts.
+// The free flights of the paths scatter at random spots and can't hit the lights, so this single scattering is sampled directly.
+// Equiangular sampling puts the samples close to the light, where most of the scattered light comes from, so shafts converge quickly
+fn sample_fog_lights(ray: Ray, max_distance: f32, state: ptr<private, u32>) -> vec3<f32> {
+    let light_count = arrayLength(&light_buffer);
+    if light_count == 0u || white_furnace() {
+        return vec3<f32>(0.0, 0.0, 0.0);
+    }
+
+    let ray_length = length(ray.direction);
+    let direction = ray.direction / ray_length;
+    var radiance = vec3<f32>(0.0, 0.0, 0.0);
+    for (var fog_index: u32 = 0; fog_index < arrayLength(&fog_buffer); fog_index++) {
+        let fog = fog_buffer[fog_index];
+        let range = fog_range(fog, ray, max_distance) * ray_length;
+        if fog.density <= 0.0 || range.x >= range.y {
+            continue;
+        }
+
+        // One light per volume, picked uniformly
+        let light_index = min(u32(rngNextFloat(state) * f32(light_count)), light_count - 1u);
+        let light = light_buffer[light_index];
+
+        // The angle to the light is sampled uniformly between the ends of the range, seen from the closest point of the ray to the light
+        let closest = dot(light.position - ray.origin, direction);
+        let light_distance = max(length(light.position - (ray.origin + direction * closest)), 1e-4);
+        let angle_start = atan((range.x - closest) / light_distance);
+        let angle_end = atan((range.y - closest) / light_distance);
+        let offset = light_distance * tan(mix(angle_start, angle_end, rngNextFloat(state)));
+        let pdf = light_distance / ((angle_end - angle_start) * (light_distance * light_distance + offset * offset));
+        let distance = closest + offset;
+        let position = ray.origin + direction * distance;
+
+        let light_sample = sample_light(light, position, state);
+        if all(light_sample.light == vec3<f32>(0.0)) || occluded(Ray(position, light_sample.direction), light_sample.distance) {
+            continue;
+        }
+
+        // The fog scatters evenly in all directions
+        let transmittance = fog_transmittance(Ray(ray.origin, direction), distance) * fog_transmittance(Ray(position, light_sample.direction), light_sample.distance);
+        let scattering = fog.scattering_color * fog.density / (4.0 * PI);
+        radiance += scattering * transmittance * light_sample.light * f32(light_count) / pdf;
+    }
+    return radiance;
+}
+
+// Entry and exit of the ray with a box, the exit is smaller than the entry if it misses
+fn ray_box_range(ray: Ray, box_min: vec3<f32>, box_max: vec3<f32>) -> vec2<f32> {
+    let t_min = (box_min - ray.origin) / ray.direction;
+    let t_max = (box_max - ray.origin) / ray.direction;
+    let t1 = min(t_min, t_max);
+    let t2 = max(t_min, t_max);
+    return vec2<f32>(max(max(t1.x, t1.y), t1.z), min(min(t2.x, t2.y), t2.z));
+}
+
+// Entry and exit of the ray with a sphere around the origin, the exit is smaller than the entry if it misses
+fn ray_sphere_range(ray: Ray, radius: f32) -> vec2<f32> {
+    let a = dot(ray.direction, ray.direction);
+    let h = dot(ray.direction, -ray.origin);
+    let c = dot(ray.origin, ray.origin) - radius * radius;
+    let discriminant = h * h - a * c;
+
+    if discriminant < 0.0 {
+        return vec2<f32>(INF, -INF);
+    }
+
+    let root = sqrt(discriminant);
+    return vec2<f32>((h - root) / a, (h + root) / a);
+}
+
+// Spherical mapping, u goes around the y axis and v from the top (0.0) to the bottom (1.0)
+fn sphere_uv(normal: vec3<f32>) -> vec2<f32> {
+    let u = 0.5 + atan2(normal.z, normal.x) / (2.0 * PI);
+    let v = acos(clamp(normal.y, -1.0, 1.0)) / PI;
+    return vec2<f32>(u, v);
+}
+
+// The light coming from rays that leave the scene
+fn sky_radiance(ray: Ray) -> vec3<f32> {
+    if white_furnace() {
+        return vec3<f32>(sky.furnace_radiance);
+    }
+#ifdef ENVIRONMENT_MAP
+    if environment_intensity > 0.0 {
+        return environment_radiance(ray.direction);
+    }
+#endif
+    if sky.model == 1 {
+        return atmosphere(ray.direction, normalize(sky.sun_direction)) * sky.sun_intensity;
+    }
+    return background_gradient(ray);
+}
+
+#ifdef ENVIRONMENT_MAP
+fn environment_radiance(direction: vec3<f32>) -> vec3<f32> {
+    return environment_radiance_level(direction, 0.0);
+}
+
+fn environment_radiance_level(direction: vec3<f32>, level: f32) -> vec3<f32> {
+    return textureSampleLevel(environment_map, environment_sampler, direction, level).rgb * environment_intensity;
+}
+
+// The mip whose texels are as wide as a cone with this spread, a face covers a quarter turn
+fn environment_level(spread: f32) -> f32 {
+    let texel_angle = PI * 0.5 / f32(textureDimensions(environment_map).x);
+    let level = log2(max(spread / texel_angle, 1.0));
+    return min(level, f32(textureNumLevels(environment_map) - 1u));
+}
+
+fn environment_importance_sampled() -> bool {
+    let cells = 6u * ENVIRONMENT_CDF_RESOLUTION * ENVIRONMENT_CDF_RESOLUTION;
+    return environment_intensity > 0.0 && arrayLength(&environment_cdf) == cells;
+}
+
+// Picks a direction towards the bright parts of the environment map, following the cdf
+fn sample_environment(state: ptr<private, u32>, pdf: ptr<function, f32>) -> vec3<f32> {
+    let resolution = ENVIRONMENT_CDF_RESOLUTION;
+    let u = rngNextFloat(state);
+
+    // The first cell whose cumulative value is above u
+    var low = 0u;
+    var high = arrayLength(&environment_cdf) - 1u;
+    while low < high {
+        let middle = (low + high) / 2u;
+        if environment_cdf[middle] > u {
+            high = middle;
+        } else {
+            low = middle + 1u;
+        }
+    }
+
+    var probability = environment_cdf[low];
+    if low > 0u {
+        probability -= environment_cdf[low - 1u];
+    }
+
+    let face = low / (resolution * resolution);
+    let texel = vec2<f32>(f32(low % resolution), f32((low / resolution) % resolution));
+    let st = (texel + vec2<f32>(rngNextFloat(state), rngNextFloat(state))) / f32(resolution) * 2.0 - 1.0;
+
+    // Every cell covers 4 / resolution² of the face
+    *pdf = probability * f32(resolution * resolution) / 4.0 * cube_face_jacobian(st);
+    return normalize(cube_face_direction(face, st.x, st.y));
+}
+
+// The density sample_environment picks a direction with
+fn environment_pdf(direction: vec3<f32>) -> f32 {
+    let resolution = ENVIRONMENT_CDF_RESOLUTION;
+    var st: vec2<f32>;
+    let face = cube_face_coordinates(direction, &st);
+    let texel = min(vec2<u32>((st * 0.5 + 0.5) * f32(resolution)), vec2<u32>(resolution - 1u));
+    let cell = (face * resolution + texel.y) * resolution + texel.x;
+
+    var probability = environment_cdf[cell];
+    if cell > 0u {
+        probability -= environment_cdf[cell - 1u];
+    }
+    return probability * f32(resolution * resolution) / 4.0 * cube_face_jacobian(st);
+}
+
+// Next event estimation for the Lambertian lobe at a hit, the light reaching it from the environment divided by the albedo
+fn sample_environment_light(hit: HitInfo, only_strategy: bool, state: ptr<private, u32>) -> vec3<f32> {
+    var pdf: f32;
+    let direction = sample_environment(state, &pdf);
+    let cos_theta = dot(direction, hit.normal);
+    if cos_theta <= 0.0 || pdf <= 0.0 {
+        return vec3<f32>(0.0, 0.0, 0.0);
+    }
+
+    // Fog is ignored for these shadow rays
+    let receives_shadows = (material_buffer[hit.material].shadow_flags & SHADOW_RECEIVER_OFF) == 0u;
+    if receives_shadows && occluded(Ray(hit.position, direction), INF) {
+        return vec3<f32>(0.0, 0.0, 0.0);
+    }
+
+    var weight = 1.0;
+    if !only_strategy {
+        weight = power_heuristic(pdf, cos_theta / PI);
+    }
+    return environment_radiance(direction) * cos_theta / PI / pdf * weight;
+}
+#endif
+
+// Next event estimation for the Lambertian lobe at a hit, the light reaching it from all light sources divided by the albedo.
+// With `glossy` it is the light the glossy lobe of the last interaction reflects instead.
+// Lights with a radius are sampled at a random point, which gives them soft shadows
+fn sample_lights(hit: HitInfo, glossy: bool, state: ptr<private, u32>) -> vec3<f32> {
+    if white_furnace() {
+        return vec3<f32>(0.0, 0.0, 0.0);
+    }
+    let receives_shadows = (material_buffer[hit.material].shadow_flags & SHADOW_RECEIVER_OFF) == 0u;
+    let light_link = material_buffer[hit.material].light_link;
+
+    var light_sum = vec3<f32>(0.0, 0.0, 0.0);
+    for (var light_index: u32 = 0; light_index < arrayLength(&light_buffer); light_index++) {
+        let light = light_buffer[light_index];
+        if (light.link & light_link) == 0u {
+            continue;
+        }
+
+        let light_sample = sample_light(light, hit.position, state);
+        let cos_theta = dot(light_sample.direction, hit.normal);
+        if cos_theta <= 0.0 || all(light_sample.light == vec3<f32>(0.0)) {
+            continue;
+        }
+
+        if receives_shadows && occluded(Ray(hit.position, light_sample.direction), light_sample.distance) {
+            continue;
+        }
+
+        if glossy {
+            light_sum += light_sample.light * glossy_light_weight(light_sample.direction);
+        } else {
+            light_sum += light_sample.light * cos_theta / PI;
+        }
+    }
+    return light_sum;
+}
+
+struct LightSample {
+    // Normalized, from the lit position towards the sampled point on the light
+    direction: vec3<f32>,
+    distance: f32,
+    // Intensity times falloff, what arrives at the position without shadows
+    light: vec3<f32>,
+}
+
+// A point on the light seen from the position, with the light it sends there
+fn sample_light(light: Light, position: vec3<f32>, state: ptr<private, u32>) -> LightSample {
+    var to_light = light.position - position;
+    let center_distance_squared = dot(to_light, to_light);
+    // How much light reaches the position compared to a point light of the same intensity at distance 1.0
+    var falloff = 1.0 / center_distance_squared;
+    if light.radius > 0.0 {
+        let radius_squared = light.radius * light.radius;
+        if light.shape == LIGHT_SHAPE_DISK {
+            let up = cross(light.right, light.forward);
+            let disk = sample_unit_disk(state) * light.radius;
+            to_light += light.right * disk.x + up * disk.y;
+            let distance_squared = dot(to_light, to_light);
+            let cos_light = dot(light.forward, -to_light / sqrt(distance_squared));
+            // A one sided Lambertian emitter with the same total output as the point light
+            falloff = 4.0 * max(cos_light, 0.0) / distance_squared;
+        } else if center_distance_squared > radius_squared {
+            // Uniformly sampling the cone the sphere covers, the sphere has the radiance of intensity / (π r²)
+            let cos_max = sqrt(1.0 - radius_squared / center_distance_squared);
+            let cos_sample = 1.0 - rngNextFloat(state) * (1.0 - cos_max);
+            let sin_sample = sqrt(max(1.0 - cos_sample * cos_sample, 0.0));
+            let phi = 2.0 * PI * rngNextFloat(state);
+
+            let axis = to_light / sqrt(center_distance_squared);
+            var tangent = cross(axis, vec3<f32>(0.0, 1.0, 0.0));
+            if dot(tangent, tangent) < 0.001 {
+                tangent = cross(axis, vec3<f32>(1.0, 0.0, 0.0));
+            }
+            tangent = normalize(tangent);
+            let bitangent = cross(axis, tangent);
+            let direction = sin_sample * cos(phi) * tangent + sin_sample * sin(phi) * bitangent + cos_sample * axis;
+
+            // The near side of the sphere in the sampled direction
+            let center_distance = sqrt(center_distance_squared);
+            let surface_distance = center_distance * cos_sample - sqrt(max(radius_squared - center_distance_squared * sin_sample * sin_sample, 0.0));
+            to_light = direction * surface_distance;
+            falloff = 2.0 * (1.0 - cos_max) / radius_squared;
+        }
+    }
+
+    let distance = length(to_light);
+    let direction = to_light / distance;
+    if falloff <= 0.0 {
+        return LightSample(direction, distance, vec3<f32>(0.0, 0.0, 0.0));
+    }
+
+    // The same smooth cutoff at the range as in bevy
+    let range_factor = center_distance_squared / (light.range * light.range);
+    let range_window = saturate(1.0 - range_factor * range_factor);
+    var intensity = light.intensity * range_window * range_window;
+
+    if light.kind == 1u {
+        let spot = saturate(dot(light.forward, -direction) * light.spot_scale + light.spot_offset);
+        intensity *= spot * spot;
+    }
+
+    if light.ies_offset != NO_LIGHT_DATA {
+        intensity *= ies_intensity(light, -direction);
+    }
+
+    if light.cookie_offset != NO_LIGHT_DATA {
+        intensity *= cookie_color(light, -direction);
+    }
+
+    return LightSample(direction, distance, intensity * falloff);
+}
+
+// Uniformly distributed point on the unit disk
+fn sample_unit_disk(state: ptr<private, u32>) -> vec2<f32> {
+    let radius = sqrt(rngNextFloat(state));
+    let angle = 2.0 * PI * rngNextFloat(state);
+    return vec2<f32>(cos(angle), sin(angle)) * radius;
+}
+
+// The IES profile of a light in a direction leaving it, bilinearly interpolated
+fn ies_intensity(light: Light, direction: vec3<f32>) -> f32 {
+    let up = cross(light.right, light.forward);
+    let vertical = acos(clamp(dot(direction, light.forward), -1.0, 1.0)) / PI;
+    var horizontal = atan2(dot(direction, up), dot(direction, light.right)) / (2.0 * PI);
+    horizontal = fract(horizontal + 1.0);
+
+    let v = vertical * f32(IES_VERTICAL_SAMPLES - 1u);
+    let h = horizontal * f32(IES_HORIZONTAL_SAMPLES);
+    let v0 = min(u32(v), IES_VERTICAL_SAMPLES - 1u);
+    let v1 = min(v0 + 1u, IES_VERTICAL_SAMPLES - 1u);
+    let h0 = u32(h) % IES_HORIZONTAL_SAMPLES;
+    let h1 = (h0 + 1u) % IES_HORIZONTAL_SAMPLES;
+
+    let row_0 = light.ies_offset + h0 * IES_VERTICAL_SAMPLES;
+    let row_1 = light.ies_offset + h1 * IES_VERTICAL_SAMPLES;
+    let a = mix(light_data[row_0 + v0], light_data[row_0 + v1], fract(v));
+    let b = mix(light_data[row_1 + v0], light_data[row_1 + v1], fract(v));
+    return mix(a, b, fract(h));
+}
+
+// The color the cookie of a spot light projects in a direction leaving it, bilinearly interpolated
+fn cookie_color(light: Light, direction: vec3<f32>) -> vec3<f32> {
+    let depth = dot(direction, light.forward);
+    if depth <= 0.0 {
+        return vec3<f32>(0.0, 0.0, 0.0);
+    }
+
+    // The image is seen looking along the light, with its top towards the light's up direction
+    let up = cross(light.right, light.forward);
+    let plane = vec2<f32>(dot(direction, light.right), -dot(direction, up)) / depth * light.cookie_scale;
+    let texel = (plane * 0.5 + 0.5) * f32(COOKIE_RESOLUTION) - 0.5;
+    if any(texel < vec2<f32>(-0.5)) || any(texel > vec2<f32>(f32(COOKIE_RESOLUTION) - 0.5)) {
+        return vec3<f32>(0.0, 0.0, 0.0);
+    }
+
+    let base = vec2<i32>(floor(texel));
+    let blend = texel - floor(texel);
+    let a = mix(cookie_texel(light, base), cookie_texel(light, base + vec2<i32>(1, 0)), blend.x);
+    let b = mix(cookie_texel(light, base + vec2<i32>(0, 1)), cookie_texel(light, base + vec2<i32>(1, 1)), blend.x);
+    return mix(a, b, blend.y);
+}
+
+fn cookie_texel(light: Light, texel: vec2<i32>) -> vec3<f32> {
+    let clamped = vec2<u32>(clamp(texel, vec2<i32>(0), vec2<i32>(i32(COOKIE_RESOLUTION) - 1)));
+    let index = light.cookie_offset + (clamped.y * COOKIE_RESOLUTION + clamped.x) * 3u;
+    return vec3<f32>(light_data[index], light_data[index + 1u], light_data[index + 2u]);
+}
+
+// Blends the decals the hit is inside of over the base color, later ones on top
+fn apply_decals(base_color: vec3<f32>, hit: HitInfo) -> vec3<f32> {
+    var color = base_color;
+    for (var decal_index: u32 = 0; decal_index < arrayLength(&decal_buffer); decal_index++) {
+        let decal = decal_buffer[decal_index];
+        let local = (decal.local_from_world * vec4<f32>(hit.position, 1.0)).xyz;
+        // Surfaces facing away from the decal don't get it
+        if any(abs(local) > vec3<f32>(0.5)) || dot(hit.normal, decal.forward) >= 0.0 {
+            continue;
+        }
+
+        // The image is seen looking along the decal, with its top towards local up
+        let texel = vec2<f32>(local.x + 0.5, 0.5 - local.y) * f32(DECAL_RESOLUTION) - 0.5;
+        let base = vec2<i32>(floor(texel));
+        let blend = texel - floor(texel);
+        let a = mix(decal_texel(decal, base), decal_texel(decal, base + vec2<i32>(1, 0)), blend.x);
+        let b = mix(decal_texel(decal, base + vec2<i32>(0, 1)), decal_texel(decal, base + vec2<i32>(1, 1)), blend.x);
+        let decal_color = mix(a, b, blend.y);
+        color = mix(color, decal_color.rgb, decal_color.a);
+    }
+    return color;
+}
+
+fn decal_texel(decal: Decal, texel: vec2<i32>) -> vec4<f32> {
+    return image_texel(decal.texel_offset, texel);
+}
+
+fn image_texel(texel_offset: u32, texel: vec2<i32>) -> vec4<f32> {
+    let clamped = vec2<u32>(clamp(texel, vec2<i32>(0), vec2<i32>(i32(DECAL_RESOLUTION) - 1)));
+    return decal_texels[texel_offset + clamped.y * DECAL_RESOLUTION + clamped.x];
+}
+
+// The image of the material at the texture coordinates, bilinearly filtered. White for materials without one
+fn material_image(material: Material, uv: vec2<f32>) -> vec4<f32> {
+    if material.image == 0u {
+        return vec4<f32>(1.0);
+    }
+
+    let texel = uv * f32(DECAL_RESOLUTION) - 0.5;
+    let base = vec2<i32>(floor(texel));
+    let blend = texel - floor(texel);
+    let offset = material.image - 1u;
+    let a = mix(image_texel(offset, base), image_texel(offset, base + vec2<i32>(1, 0)), blend.x);
+    let b = mix(image_texel(offset, base + vec2<i32>(0, 1)), image_texel(offset, base + vec2<i32>(1, 1)), blend.x);
+    return mix(a, b, blend.y);
+}
+
+fn background_gradient(ray: Ray) -> vec3<f32> {
+    let unit: vec3<f32> = normalize(ray.direction);
+    let a: f32 = 0.5 * (unit.y + 1.0);
+    let color: vec3<f32> = (1.0 - a) * vec3<f32>(1.0, 1.0, 1.0) + a * vec3<f32>(0.5, 0.7, 1.0);
+    return color;
+}
+
+fn hit_unit_sphere(ray: Ray) -> f32 {
+    let oc: vec3<f32> = -ray.origin;
+    let a = dot(ray.direction, ray.direction);
+    let h = dot(ray.direction, oc);
+    let c = dot(oc, oc) - 1.0;
+    let discriminant = h * h - a * c;
+
+    if discriminant < 0.0 {
+        return -1.0;
+    }
+
+    return (h - sqrt(discriminant)) / a;
+}
+
+// Floats as the sum of a high and a low part, for the few places that need more than a float can hold
+struct FloatFloat {
+    high: f32,
+    low: f32,
+}
+
+struct FloatFloat3 {
+    high: vec3<f32>,
+    low: vec3<f32>,
+}
+
+fn two_sum(a: f32, b: f32) -> FloatFloat {
+    let sum = a + b;
+    let b_part = sum - a;
+    return FloatFloat(sum, (a - (sum - b_part)) + (b - b_part));
+}
+
+fn two_sum_vec(a: vec3<f32>, b: vec3<f32>) -> FloatFloat3 {
+    let sum = a + b;
+    let b_part = sum - a;
+    return FloatFloat3(sum, (a - (sum - b_part)) + (b - b_part));
+}
+
+// Dekker's exact product, it doesn't rely on a fused multiply add
+fn two_product_vec(a: vec3<f32>, b: vec3<f32>) -> FloatFloat3 {
+    let product = a * b;
+    let a_split = split_vec(a);
+    let b_split = split_vec(b);
+    let error = ((a_split.high * b_split.high - product) + a_split.high * b_split.low + a_split.low * b_split.high) + a_split.low * b_split.low;
+    return FloatFloat3(product, error);
+}
+
+// Into two halves of the mantissa, their products are exact
+fn split_vec(a: vec3<f32>) -> FloatFloat3 {
+    let scaled = 4097.0 * a;
+    let high = scaled - (scaled - a);
+    return FloatFloat3(high, a - high);
+}
+
+// Like hit_unit_sphere, for models with a precise position. The local origin is a float-float from the low part of the translation,
+// as right above a huge sphere it is about as long as the radius with the distance to the surface far below the precision of a float.
+// Its length is taken in float-float as well, after that the numbers are small enough for floats again
+fn hit_unit_sphere_precise(origin: vec3<f32>, local_direction: vec3<f32>, model: Model) -> f32 {
+    let rotated = (model.local_from_world * vec4<f32>(origin, 0.0)).xyz;
+    let translated = two_sum_vec(model.local_from_world[3].xyz, rotated);
+    let local_origin = FloatFloat3(translated.high, translated.low + model.translation_low);
+
+    // The squared length minus one, summed up from the largest parts to the smallest
+    let squares = two_product_vec(local_origin.high, local_origin.high);
+    let xy = two_sum(squares.high.x, squares.high.y);
+    let xyz = two_sum(xy.high, squares.high.z);
+    let surface = two_sum(xyz.high, -1.0);
+    let c = surface.high + (surface.low + xyz.low + xy.low + dot(squares.low, vec3<f32>(1.0)) + 2.0 * dot(local_origin.high, local_origin.low));
+
+    let a = dot(local_direction, local_direction);
+    let h = -(dot(local_direction, local_origin.high) + dot(local_direction, local_origin.low));
+    let discriminant = h * h - a * c;
+    if discriminant < 0.0 {
+        return -1.0;
+    }
+
+    // Towards the sphere the closer hit is the difference of two almost equal numbers, so it is divided out instead
+    let root = sqrt(discriminant);
+    if h > 0.0 {
+        return c / (h + root);
+    }
+    return (h - root) / a;
+}
+
+// TODO: Look into other algorithms / pre-computing the inverse of the direction
+// https://tavianator.com/2011/ray_box.html (There is also a newer version)
+fn ray_bounding_dst(ray: Ray, box_min: vec3<f32>, box_max: vec3<f32>) -> f32 {
+    let t_min = (box_min - ray.origin) * (1.0 / ray.direction);
+    let t_max = (box_max - ray.origin) * (1.0 / ray.direction);
+    let t1 = min(t_min, t_max);
+    let t2 = max(t_min, t_max);
+    let t_near = max(max(t1.x, t1.y), t1.z);
+    let t_far = min(min(t2.x, t2.y), t2.z);
+
+    let hit = t_far >= t_near && t_far > 0.0;
+    let dst = select(INF, select(0.0, t_near, t_near > 0.0), hit);
+    return dst;
+}
+
+fn reflect(vector: vec3<f32>, normal: vec3<f32>) -> vec3<f32> {
+    return vector - 2 * dot(vector, normal) * normal;
+}
+
+fn refract(vector: vec3<f32>, normal: vec3<f32>, etai_over_etat: f32) -> vec3<f32> {
+    let cos_theta = min(dot(-vector, normal), 1.0);
+    let r_out_perp = etai_over_etat * (vector + cos_theta * normal);
+    let r_out_parallel = -sqrt(abs(1.0 - dot(r_out_perp, r_out_perp))) * normal;
+    return r_out_perp + r_out_parallel;
+}
+
+// Samples a microfacet normal from the GGX distribution, a roughness of 0.0 always returns the normal
+fn sample_ggx_normal(normal: vec3<f32>, incoming: vec3<f32>, roughness: f32, state: ptr<private, u32>) -> vec3<f32> {
+    let alpha = roughness * roughness;
+    let u = rngNextFloat(state);
+    let cos_theta = sqrt((1.0 - u) / (1.0 + (alpha * alpha - 1.0) * u));
+    let sin_theta = sqrt(max(1.0 - cos_theta * cos_theta, 0.0));
+    let phi = 2.0 * PI * rngNextFloat(state);
+
+    var tangent = cross(normal, vec3<f32>(0.0, 1.0, 0.0));
+    if dot(tangent, tangent) < 0.001 {
+        tangent = cross(normal, vec3<f32>(1.0, 0.0, 0.0));
+    }
+    tangent = normalize(tangent);
+    let bitangent = cross(normal, tangent);
+
+    let microfacet = normalize(sin_theta * cos(phi) * tangent + sin_theta * sin(phi) * bitangent + cos_theta * normal);
+
+    // Microfacets facing away from the ray can't be hit
+    if dot(-incoming, microfacet) <= 0.0 {
+        return normal;
+    }
+    return microfacet;
+}
+
+fn reflectance(cosine: f32, refraction_index: f32) -> f32 {
+    // Use Schlick's approximation for reflectance.
+    var r0 = (1.0 - refraction_index) / (1.0 + refraction_index);
+    r0 = r0 * r0;
+    return r0 + (1.0 - r0) * pow((1.0 - cosine), 5.0);
+}
+
+fn vec3_near_zero(vector: vec3<f32>) -> bool {
+    let s = 1e-8;
+    return abs(vector.x) < s && abs(vector.y) < s && abs(vector.z) < s;
+}
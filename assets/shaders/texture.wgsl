@@ -0,0 +1,117 @@
+// Procedural textures, evaluated in world space so they don't depend on texture coordinates
+// The noise follows the book "Ray Tracing: The Next Week", but hashes the lattice instead of using permutation tables
+
+// 0 -> none; 1 -> checker; 2 -> value noise; 3 -> perlin noise; 4 -> turbulence
+// The footprint is the width of the area the ray covers, details smaller than it get averaged out instead of aliasing
+fn procedural_texture(texture: u32, scale: f32, octaves: u32, base_color: vec3<f32>, second_color: vec3<f32>, position: vec3<f32>, footprint: f32) -> vec3<f32> {
+    let point = position * scale;
+    // In lattice cells
+    let width = footprint * scale;
+
+    switch texture {
+        case 1u: {
+            return mix(base_color, second_color, filtered_checker(point, width));
+        }
+        case 2u: {
+            return base_color * mix(0.5, value_noise(point), noise_detail(width));
+        }
+        case 3u: {
+            // Perlin noise is in -1..1
+            return base_color * 0.5 * (1.0 + perlin_noise(point) * noise_detail(width));
+        }
+        case 4u: {
+            return base_color * turbulence(point, octaves, width);
+        }
+        default: {
+            return base_color;
+        }
+    }
+}
+
+// The share of odd cells in a box of the given width around the point, 0.0 or 1.0 for a single point.
+// Box filtered square waves along every axis, following https://iquilezles.org/articles/checkerfiltering/
+fn filtered_checker(point: vec3<f32>, width: f32) -> f32 {
+    let w = vec3<f32>(max(width, 1e-4));
+    let square = 2.0 * (abs(fract((point - 0.5 * w) * 0.5) - 0.5) - abs(fract((point + 0.5 * w) * 0.5) - 0.5)) / w;
+    return 0.5 - 0.5 * square.x * square.y * square.z;
+}
+
+// How much of the noise survives a footprint of this many cells, the features are about a cell large
+fn noise_detail(width: f32) -> f32 {
+    return 1.0 - smoothstep(0.5, 1.0, width);
+}
+
+fn hash_lattice(cell: vec3<i32>) -> u32 {
+    var hash = u32(cell.x) * 73856093u ^ u32(cell.y) * 19349663u ^ u32(cell.z) * 83492791u;
+    // PCG style mixing, like the random number generator
+    hash = hash * 747796405u + 2891336453u;
+    hash = ((hash >> ((hash >> 28u) + 4u)) ^ hash) * 277803737u;
+    return (hash >> 22u) ^ hash;
+}
+
+fn hash_to_float(hash: u32) -> f32 {
+    return f32(hash) / f32(0xffffffffu);
+}
+
+fn hash_to_gradient(hash: u32) -> vec3<f32> {
+    let x = hash_to_float(hash);
+    let y = hash_to_float(hash * 747796405u + 1u);
+    let z = hash_to_float(hash * 2891336453u + 2u);
+    return normalize(vec3<f32>(x, y, z) * 2.0 - 1.0 + vec3<f32>(1e-6));
+}
+
+// Hermite smoothing of the interpolation weights
+fn fade(t: vec3<f32>) -> vec3<f32> {
+    return t * t * (3.0 - 2.0 * t);
+}
+
+// Random values on the lattice, interpolated in between, in 0..1
+fn value_noise(point: vec3<f32>) -> f32 {
+    let cell = vec3<i32>(floor(point));
+    let weight = fade(fract(point));
+
+    var result = 0.0;
+    for (var corner = 0; corner < 8; corner++) {
+        let offset = vec3<i32>(corner & 1, (corner >> 1) & 1, (corner >> 2) & 1);
+        let blend = mix(1.0 - weight, weight, vec3<f32>(offset));
+        result += blend.x * blend.y * blend.z * hash_to_float(hash_lattice(cell + offset));
+    }
+    return result;
+}
+
+// Random gradients on the lattice, in -1..1
+fn perlin_noise(point: vec3<f32>) -> f32 {
+    let cell = vec3<i32>(floor(point));
+    let local = fract(point);
+    let weight = fade(local);
+
+    var result = 0.0;
+    for (var corner = 0; corner < 8; corner++) {
+        let offset = vec3<i32>(corner & 1, (corner >> 1) & 1, (corner >> 2) & 1);
+        let blend = mix(1.0 - weight, weight, vec3<f32>(offset));
+        let gradient = hash_to_gradient(hash_lattice(cell + offset));
+        result += blend.x * blend.y * blend.z * dot(gradient, local - vec3<f32>(offset));
+    }
+    return result;
+}
+
+// Sum of perlin noise with doubling frequency and halving weight, octaves finer than the footprint fade out
+fn turbulence(point: vec3<f32>, octaves: u32, width: f32) -> f32 {
+    var result = 0.0;
+    var sample_point = point;
+    var weight = 1.0;
+    var octave_width = width;
+
+    for (var octave: u32 = 0; octave < octaves; octave++) {
+        let detail = noise_detail(octave_width);
+        if detail == 0.0 {
+            break;
+        }
+        result += weight * detail * perlin_noise(sample_point);
+        weight *= 0.5;
+        sample_point *= 2.0;
+        octave_width *= 2.0;
+    }
+
+    return abs(result);
+}
@@ -1,8 +1,22 @@
 //#![warn(clippy::pedantic)]
 #![allow(clippy::type_complexity)]
 #![allow(clippy::too_many_lines)]
+#![allow(clippy::too_many_arguments)]
+// The `ShaderType` derive of encase 0.8, which bevy 0.14 depends on, checks every field with a function that
+// newer compilers report as never used. Nothing else in the crate is meant to be dead
+#![allow(dead_code)]
 
-use bevy::prelude::*;
+use bevy::{
+    core_pipeline::{bloom::BloomSettings, tonemapping::Tonemapping},
+    prelude::*,
+    render::{
+        camera::Exposure,
+        render_asset::RenderAssetUsages,
+        render_resource::{Extent3d, TextureDimension, TextureFormat},
+        view::screenshot::ScreenshotManager,
+    },
+    window::PrimaryWindow,
+};
 use bevy_flycam::{FlyCam, NoCameraPlayerPlugin};
 use bevy_inspector_egui::quick::WorldInspectorPlugin;
 
@@ -12,9 +26,30 @@ use bevy_mod_picking::{
 };
 use bevy_transform_gizmo::TransformGizmoPlugin;
 use rand::random;
-use raytracing::{RaytracePlugin, RaytracedCamera, RaytracedSphere, Raytracing};
+use raytracing::{
+    BillboardFacing, BvhRebuildPolicy, BvhStats, CameraCut, CaptureCubemap, ClipPlane,
+    FogVolumeShape, HoveredRaytracedEntity, IesProfile, PathTermination, PathVertexKind, PbrtScene,
+    PixelFilter, Quality, RaytraceAutoExposure, RaytraceBsdfAppExt, RaytraceCapabilities,
+    RaytraceCaustics, RaytraceClipPlanes, RaytraceConvergenceOverlay, RaytraceCubemapCapture,
+    RaytraceCulling, RaytraceDecal, RaytraceDensityVolume, RaytraceDepthOfField, RaytraceDiskLight,
+    RaytraceDispersion, RaytraceFogVolume, RaytraceFrameStats, RaytraceHideMesh,
+    RaytraceIesProfile, RaytraceInstanceMask, RaytraceLightCookie, RaytraceLightLink,
+    RaytraceLightmapBake, RaytraceLod, RaytraceMaterialId, RaytraceMaterialOverride,
+    RaytraceMaterialPalette, RaytraceMemoryBudget, RaytraceMirror, RaytraceMode, RaytraceNanDebug,
+    RaytraceNanReport, RaytraceOutput, RaytraceParticles, RaytracePathDebugger, RaytracePaused,
+    RaytracePbrtScene, RaytracePickingPlugin, RaytracePlugin, RaytracePortal,
+    RaytracePrecisePosition, RaytracePrimitiveMesh, RaytraceProbeGrid, RaytraceProgress,
+    RaytraceProjection, RaytraceRayBudget, RaytraceRayMasks, RaytraceRecordedPath,
+    RaytraceReflectionOnly, RaytraceSampling, RaytraceSceneDirty, RaytraceSceneState,
+    RaytraceShadowCatcher, RaytraceSky, RaytraceStatic, RaytraceTexture, RaytraceWhiteFurnace,
+    RaytracedBillboard, RaytracedCamera, RaytracedHeightfield, RaytracedSphere, Raytracing,
+    RebuildBvh, RenderFinished, RenderSaved, RenderToFile, SetRaytraceMode,
+};
+use scene_file::{load_or_exit, spawn_scene_file, SceneFile};
+use std::sync::{Arc, Mutex};
 
 mod raytracing;
+mod scene_file;
 
 // NOTE: Depth blending still doesnt work properly
 /*
@@ -29,20 +64,107 @@ Vulkan backend for easier renderdoc investigation:
                 ..default()
             })
 */
+// Tag of the example custom BSDF
+const IRIDESCENT_BSDF: u32 = 0;
+// The furnace check allows this much difference to the background per channel, out of 255
+const FURNACE_TOLERANCE: u8 = 2;
+// and fails once more than this share of the pixels is off by more than that
+const FURNACE_MAX_DEVIATING: f32 = 0.001;
+// The instance mask group of the glowing sphere, L takes it out of the reflected rays
+const GLOW_INSTANCES: u8 = 0b10;
+// The light linking group of the downlight
+const DOWNLIGHT_LINK: u32 = 0b10;
+
 fn main() {
-    App::new()
-        .add_plugins((
-            DefaultPlugins,
-            RaytracePlugin,
-            WorldInspectorPlugin::new(),
-            DefaultPickingPlugins,
-            TransformGizmoPlugin::default(),
-            NoCameraPlayerPlugin,
-        ))
-        .add_systems(Startup, (setup, modify_raycast_backend))
-        .add_systems(Update, sync_picking_radius)
-        .add_systems(Last, remove_transform_gizmo_clear)
-        .run();
+    let mut app = App::new();
+    app.add_plugins((
+        DefaultPlugins,
+        RaytracePlugin,
+        RaytracePickingPlugin,
+        WorldInspectorPlugin::new(),
+        DefaultPickingPlugins,
+        TransformGizmoPlugin::default(),
+        NoCameraPlayerPlugin,
+    ))
+    .register_raytrace_bsdf(IRIDESCENT_BSDF, "shaders/bsdf/iridescent.wgsl")
+    .add_systems(Startup, modify_raycast_backend)
+    .add_systems(
+        Update,
+        (
+            sync_picking_radius,
+            toggle_cubemap_capture,
+            toggle_caustics,
+            toggle_sky,
+            toggle_final_render,
+            toggle_depth_of_field,
+            log_memory_budget,
+            log_frame_stats,
+            rebuild_bvh,
+            restart_accumulation,
+            toggle_pause,
+            (
+                toggle_glow_reflections,
+                toggle_shadow_catcher,
+                toggle_irradiance_fallback,
+                cycle_convergence_overlay,
+                (toggle_sparks, simulate_sparks),
+                toggle_section_view,
+            ),
+            (render_to_file, log_saved_renders),
+            log_scene_edits,
+            show_hovered_entity,
+            (toggle_path_debugger, log_recorded_path),
+            (toggle_nan_debug, log_nan_report),
+            toggle_white_furnace,
+            toggle_linear_output,
+            toggle_auto_exposure,
+        ),
+    );
+
+    // The control panel of the tracer, next to the world inspector
+    #[cfg(feature = "ui")]
+    app.add_plugins(raytracing::RaytraceUiPlugin);
+
+    // A scene file given on the command line replaces the built in scene, PBRT scenes get imported.
+    // `--white-furnace` renders spheres of every kind of material in the white furnace instead and exits with an error if they don't match the background
+    match std::env::args().nth(1) {
+        Some(flag) if flag == "--white-furnace" => {
+            app.insert_resource(RaytraceWhiteFurnace {
+                enabled: true,
+                // Grey, so surfaces that gain energy don't get clipped to the same white as the background
+                radiance: 0.5,
+            })
+            .init_resource::<FurnaceScreenshot>()
+            .add_systems(Startup, setup_white_furnace)
+            .add_systems(Update, check_white_furnace);
+        }
+        Some(path) if path.ends_with(".pbrt") => {
+            let mut scene = Some(load_or_exit(&path, PbrtScene::parse));
+            // Nothing moves in imported scenes, the BVH is built once when the spheres show up
+            app.insert_resource(BvhRebuildPolicy::Manual);
+            app.add_systems(
+                Startup,
+                move |mut scenes: ResMut<Assets<PbrtScene>>, mut commands: Commands| {
+                    if let Some(scene) = scene.take() {
+                        commands.spawn((
+                            SpatialBundle::default(),
+                            RaytracePbrtScene(scenes.add(scene)),
+                            Name::new("PBRT Scene"),
+                        ));
+                    }
+                },
+            );
+        }
+        Some(path) => {
+            app.insert_resource(load_or_exit(&path, SceneFile::parse))
+                .add_systems(Startup, spawn_scene_file);
+        }
+        None => {
+            app.add_systems(Startup, setup);
+        }
+    }
+
+    app.run();
 }
 
 /// Set up a simple 3D scene
@@ -50,6 +172,8 @@ fn setup(
     mut commands: Commands,
     mut meshes: ResMut<Assets<Mesh>>,
     mut materials: ResMut<Assets<StandardMaterial>>,
+    mut images: ResMut<Assets<Image>>,
+    asset_server: Res<AssetServer>,
 ) {
     // camera
     commands.spawn((
@@ -58,21 +182,48 @@ fn setup(
                 .looking_at(Vec3::default(), Vec3::Y),
             camera: Camera {
                 clear_color: Color::WHITE.into(),
+                // The traced image goes in linear before bloom and tonemapping, H switches it back to after them
+                hdr: true,
                 ..default()
             },
             ..default()
         },
         Name::new("Raytraced Camera"),
+        // Medium keeps some path regularization, the glass spheres cause a lot of fireflies on the ground otherwise.
+        // The gaussian filter keeps the edges of the small spheres from shimmering in the final render
         RaytracedCamera {
-            level: Raytracing::FallbackRaytraced,
-            sample_count: 4,
-            bounces: 4,
+            pixel_filter: PixelFilter::Gaussian,
+            output: RaytraceOutput::LinearHdr,
+            ..RaytracedCamera::preset(Quality::Medium)
+        },
+        // The glowing sphere and the highlights on the metal spheres bleed into their surroundings
+        BloomSettings::NATURAL,
+        // A hexagonal aperture focused on the big spheres, closed until B is pressed
+        RaytraceDepthOfField {
+            focus_distance: 5.0,
+            aperture_radius: 0.0,
+            blades: 6,
+            blade_rotation: 0.0,
+        },
+        // Haze over the distant hills, the rasterized and the traced scene share it
+        FogSettings {
+            color: Color::srgb(0.85, 0.9, 0.95),
+            falloff: FogFalloff::Linear {
+                start: 15.0,
+                end: 60.0,
+            },
+            ..default()
+        },
+        // Nothing shows through the fog past its end, so nothing beyond it needs to be traced
+        RaytraceCulling {
+            max_distance: 80.0,
+            ..default()
         },
         bevy_transform_gizmo::GizmoPickSource::default(),
         FlyCam,
     ));
 
-    // cube
+    // cube, traced as a box since its mesh is a `Cuboid`
     commands.spawn((
         PbrBundle {
             mesh: meshes.add(Cuboid::default()),
@@ -80,10 +231,365 @@ fn setup(
             transform: Transform::from_xyz(0.0, 0.5, 0.0),
             ..default()
         },
+        RaytracePrimitiveMesh,
+        bevy_mod_picking::PickableBundle::default(),
+        bevy_transform_gizmo::GizmoTransformable,
+    ));
+
+    // a smaller cube next to it that stays rasterized, blended with the traced scene by depth
+    commands.spawn((
+        PbrBundle {
+            mesh: meshes.add(Cuboid::from_length(0.3)),
+            material: materials.add(Color::srgb(0.3, 0.6, 0.4)),
+            transform: Transform::from_xyz(1.0, 0.15, -0.8),
+            ..default()
+        },
+        Name::new("Rasterized Cube"),
+    ));
+
+    // rasterized floor tile with a baked lightmap, plane UVs don't overlap so they double as lightmap UVs
+    let mut floor = Plane3d::default().mesh().size(3.0, 3.0).build();
+    if let Some(uvs) = floor.attribute(Mesh::ATTRIBUTE_UV_0).cloned() {
+        floor.insert_attribute(Mesh::ATTRIBUTE_UV_1, uvs);
+    }
+    commands.spawn((
+        PbrBundle {
+            mesh: meshes.add(floor),
+            material: materials.add(Color::srgb(0.9, 0.9, 0.9)),
+            transform: Transform::from_xyz(-2.0, 0.01, 2.0),
+            ..default()
+        },
+        RaytraceLightmapBake::new(64, &mut images),
+        Name::new("Lightmapped Floor"),
+    ));
+
+    // a disk shaped downlight with a measured profile, shining onto the lightmapped floor
+    let downlight: Handle<IesProfile> = asset_server.load("lights/downlight.ies");
+    commands.spawn((
+        PointLightBundle {
+            point_light: PointLight {
+                intensity: 200_000.0,
+                radius: 0.15,
+                ..default()
+            },
+            transform: Transform::from_xyz(-2.0, 3.0, 2.0).looking_to(Vec3::NEG_Y, Vec3::Z),
+            ..default()
+        },
+        RaytraceIesProfile(downlight),
+        RaytraceDiskLight,
+        RaytraceLightLink(DOWNLIGHT_LINK),
+        Name::new("IES Downlight"),
+    ));
+
+    // a sphere shaded by a custom BSDF, standing on the lightmapped floor
+    let sphere_material = materials.add(StandardMaterial {
+        base_color: Color::srgb(0.1, 0.1, 0.15),
+        perceptual_roughness: 0.05,
+        ..default()
+    });
+    commands.spawn((
+        PbrBundle {
+            mesh: meshes.add(Sphere::new(1.0)),
+            material: sphere_material,
+            transform: Transform::from_xyz(-2.0, 0.5, 2.0).with_scale(Vec3::splat(0.5)),
+            ..default()
+        },
+        RaytracedSphere { radius: 0.5 },
+        RaytraceHideMesh,
+        RaytraceMaterialOverride {
+            custom_bsdf: Some(IRIDESCENT_BSDF),
+            ..default()
+        },
+        // Left out by the downlight right above it, which only lights the floor around it
+        RaytraceLightLink(!DOWNLIGHT_LINK),
+        bevy_mod_picking::PickableBundle::default(),
+        bevy_transform_gizmo::GizmoTransformable,
+    ));
+
+    // a glowing sphere next to the cube, bright enough to bloom
+    let glow_material = materials.add(StandardMaterial {
+        base_color: Color::BLACK,
+        emissive: LinearRgba::rgb(8.0, 3.0, 0.8),
+        ..default()
+    });
+    commands.spawn((
+        PbrBundle {
+            mesh: meshes.add(Sphere::new(1.0)),
+            material: glow_material,
+            transform: Transform::from_xyz(1.2, 0.25, 1.2).with_scale(Vec3::splat(0.25)),
+            ..default()
+        },
+        RaytracedSphere { radius: 0.25 },
+        RaytraceHideMesh,
+        RaytraceInstanceMask(GLOW_INSTANCES),
+        Name::new("Glowing Sphere"),
         bevy_mod_picking::PickableBundle::default(),
         bevy_transform_gizmo::GizmoTransformable,
     ));
 
+    // a big sphere behind the camera, which only shows up in reflections and the light it blocks
+    commands.spawn((
+        PbrBundle {
+            mesh: meshes.add(Sphere::new(1.0)),
+            material: materials.add(Color::srgb(0.2, 0.4, 0.8)),
+            transform: Transform::from_xyz(0.0, 1.0, 9.0).with_scale(Vec3::splat(1.5)),
+            ..default()
+        },
+        RaytracedSphere { radius: 1.5 },
+        RaytraceHideMesh,
+        RaytraceReflectionOnly,
+        Name::new("Reflection Only Sphere"),
+    ));
+
+    // dynamic irradiance probes around the center of the scene, giving the rasterized meshes indirect light
+    commands.spawn((
+        SpatialBundle::from_transform(
+            Transform::from_xyz(0.0, 1.5, 0.0).with_scale(Vec3::new(12.0, 3.0, 12.0)),
+        ),
+        RaytraceProbeGrid::new(UVec3::new(8, 3, 8), &mut images),
+        Name::new("Probe Grid"),
+    ));
+
+    // a patch of mist behind the big spheres
+    commands.spawn((
+        SpatialBundle::from_transform(
+            Transform::from_xyz(0.0, 1.0, -3.0).with_scale(Vec3::new(6.0, 2.0, 2.0)),
+        ),
+        RaytraceFogVolume {
+            shape: FogVolumeShape::Sphere,
+            density: 0.8,
+            scattering_color: Color::srgb(0.9, 0.9, 0.95),
+        },
+        Name::new("Fog Volume"),
+    ));
+
+    // a procedural puff of smoke, standing in for a baked simulation
+    let size = 32;
+    let density = (0..size * size * size)
+        .map(|index| {
+            let voxel = UVec3::new(index % size, index / size % size, index / (size * size));
+            let position = voxel.as_vec3() / (size - 1) as f32 * 2.0 - 1.0;
+            let falloff = (1.0 - position.length()).max(0.0);
+            (falloff * random::<f32>() * 255.0) as u8
+        })
+        .collect();
+    let density = Image::new(
+        Extent3d {
+            width: size,
+            height: size,
+            depth_or_array_layers: size,
+        },
+        TextureDimension::D3,
+        density,
+        TextureFormat::R8Unorm,
+        RenderAssetUsages::default(),
+    );
+    commands.spawn((
+        SpatialBundle::from_transform(
+            Transform::from_xyz(2.5, 1.0, 2.0).with_scale(Vec3::splat(1.5)),
+        ),
+        RaytraceDensityVolume {
+            density: images.add(density),
+            max_density: 8.0,
+            scattering_color: Color::srgb(0.6, 0.6, 0.6),
+        },
+        Name::new("Smoke Volume"),
+    ));
+
+    // rolling hills in the distance, traced straight from the heightmap
+    let size = 128;
+    let heights = (0..size * size)
+        .flat_map(|index| {
+            let position = Vec2::new((index % size) as f32, (index / size) as f32) / size as f32;
+            let hills = (position.x * 9.0).sin() * (position.y * 7.0).cos() * 0.3
+                + (position.x * 23.0 + position.y * 17.0).sin() * 0.1;
+            (hills + 0.5).to_le_bytes()
+        })
+        .collect();
+    let heightmap = Image::new(
+        Extent3d {
+            width: size,
+            height: size,
+            depth_or_array_layers: 1,
+        },
+        TextureDimension::D2,
+        heights,
+        TextureFormat::R32Float,
+        RenderAssetUsages::default(),
+    );
+    commands.spawn((
+        SpatialBundle::from_transform(Transform::from_xyz(0.0, -0.5, -16.0)),
+        RaytracedHeightfield {
+            heightmap: images.add(heightmap),
+            scale: Vec3::new(30.0, 3.0, 12.0),
+        },
+        // Half the resolution once the camera is further away than 15 units, a quarter beyond 30
+        RaytraceLod {
+            distances: vec![15.0, 30.0],
+        },
+        materials.add(StandardMaterial {
+            base_color: Color::srgb(0.3, 0.5, 0.2),
+            metallic: 0.0,
+            ..default()
+        }),
+        Name::new("Heightfield"),
+    ));
+
+    // a mirror on the left, the portal sends rays back out of a target flipped through it
+    let mirror = commands
+        .spawn((
+            SpatialBundle::from_transform(
+                Transform::from_xyz(-8.0, 1.5, 0.0)
+                    .with_rotation(Quat::from_rotation_y(std::f32::consts::FRAC_PI_2))
+                    .with_scale(Vec3::new(8.0, 3.0, 1.0)),
+            ),
+            Name::new("Mirror"),
+        ))
+        .id();
+    let reflection = commands
+        .spawn((
+            SpatialBundle::from_transform(Transform::from_scale(Vec3::new(1.0, 1.0, -1.0))),
+            Name::new("Mirror Reflection"),
+        ))
+        .set_parent(mirror)
+        .id();
+    commands
+        .entity(mirror)
+        .insert(RaytracePortal { target: reflection });
+
+    // a gold mirror facing it on the right, reflected exactly without a target entity
+    commands.spawn((
+        SpatialBundle::from_transform(
+            Transform::from_xyz(8.0, 1.5, 0.0)
+                .with_rotation(Quat::from_rotation_y(-std::f32::consts::FRAC_PI_2))
+                .with_scale(Vec3::new(8.0, 3.0, 1.0)),
+        ),
+        RaytraceMirror {
+            tint: Color::srgb(1.0, 0.78, 0.34),
+        },
+        Name::new("Gold Mirror"),
+    ));
+
+    // a spot light falling through window blinds onto the cube
+    let size = 64;
+    let blinds = (0..size * size)
+        .map(|index| if index / size % 8 < 5 { 255 } else { 0 })
+        .collect();
+    let blinds = Image::new(
+        Extent3d {
+            width: size,
+            height: size,
+            depth_or_array_layers: 1,
+        },
+        TextureDimension::D2,
+        blinds,
+        TextureFormat::R8Unorm,
+        RenderAssetUsages::default(),
+    );
+    commands.spawn((
+        SpotLightBundle {
+            spot_light: SpotLight {
+                intensity: 400_000.0,
+                outer_angle: 0.5,
+                inner_angle: 0.4,
+                ..default()
+            },
+            transform: Transform::from_xyz(3.0, 4.0, -3.0).looking_at(Vec3::ZERO, Vec3::Y),
+            ..default()
+        },
+        RaytraceLightCookie(images.add(blinds)),
+        Name::new("Window Blinds"),
+    ));
+
+    // a target painted onto the ground, only the traced image has it
+    let size = 64;
+    let target = (0..size * size)
+        .flat_map(|index| {
+            let offset = Vec2::new((index % size) as f32, (index / size) as f32) + 0.5;
+            let distance = offset.distance(Vec2::splat(size as f32 / 2.0)) / (size as f32 / 2.0);
+            match (distance * 4.0) as u32 {
+                0 | 2 => [200, 30, 30, 255],
+                1 | 3 => [240, 240, 240, 255],
+                _ => [0, 0, 0, 0],
+            }
+        })
+        .collect();
+    let target = Image::new(
+        Extent3d {
+            width: size,
+            height: size,
+            depth_or_array_layers: 1,
+        },
+        TextureDimension::D2,
+        target,
+        TextureFormat::Rgba8UnormSrgb,
+        RenderAssetUsages::default(),
+    );
+    commands.spawn((
+        SpatialBundle::from_transform(
+            Transform::from_xyz(2.5, 0.0, 2.5)
+                .looking_to(Vec3::NEG_Y, Vec3::Z)
+                .with_scale(Vec3::new(1.5, 1.5, 0.5)),
+        ),
+        RaytraceDecal(images.add(target)),
+        Name::new("Target Decal"),
+    ));
+
+    // impostor pine trees, a trunk and a triangle of leaves with everything around cut out
+    let size = 64;
+    let pine = (0..size * size)
+        .flat_map(|index| {
+            let (x, y) = (
+                (index % size) as f32 / size as f32,
+                (index / size) as f32 / size as f32,
+            );
+            if y < 0.8 && (x - 0.5).abs() < y * 0.4 {
+                [40, 110, 50, 255]
+            } else if y >= 0.8 && (x - 0.5).abs() < 0.06 {
+                [90, 60, 30, 255]
+            } else {
+                [0, 0, 0, 0]
+            }
+        })
+        .collect();
+    let pine = images.add(Image::new(
+        Extent3d {
+            width: size,
+            height: size,
+            depth_or_array_layers: 1,
+        },
+        TextureDimension::D2,
+        pine,
+        TextureFormat::Rgba8UnormSrgb,
+        RenderAssetUsages::default(),
+    ));
+    let pine_material = materials.add(StandardMaterial {
+        perceptual_roughness: 1.0,
+        ..default()
+    });
+    for (index, position) in [
+        Vec3::new(-5.0, 0.75, -4.0),
+        Vec3::new(-6.0, 1.0, -2.5),
+        Vec3::new(-4.0, 0.6, -5.5),
+    ]
+    .into_iter()
+    .enumerate()
+    {
+        commands.spawn((
+            SpatialBundle::from_transform(Transform::from_translation(position)),
+            pine_material.clone(),
+            RaytracedBillboard {
+                image: pine.clone(),
+                // centered on the position, so twice its height puts the trunk on the ground
+                size: Vec2::new(0.75, 1.0) * position.y * 2.0,
+                alpha_cutoff: 0.5,
+                facing: BillboardFacing::Upright,
+            },
+            RaytraceStatic,
+            Name::new(format!("Pine Billboard {index}")),
+        ));
+    }
+
     let ground_material = materials.add(StandardMaterial {
         base_color: Color::srgb(0.5, 0.5, 0.5),
         metallic: 0.0,
@@ -94,10 +600,22 @@ fn setup(
             mesh: meshes.add(Sphere::new(1.0)),
             material: ground_material,
             transform: Transform::from_xyz(0.0, -1000.0, 0.0),
-            visibility: Visibility::Hidden,
             ..default()
         },
         RaytracedSphere { radius: 1000.0 },
+        RaytraceHideMesh,
+        Ground,
+        // The ground never moves, so dragging the other spheres around doesn't rebuild its part of the BVH
+        RaytraceStatic,
+        // Far larger than everything standing on it, so contact shadows need more than a float at its radius
+        RaytracePrecisePosition,
+        RaytraceMaterialOverride {
+            texture: RaytraceTexture::Checker {
+                scale: 1.0,
+                odd_color: Color::srgb(0.2, 0.3, 0.1),
+            },
+            ..default()
+        },
         bevy_mod_picking::PickableBundle::default(),
         bevy_transform_gizmo::GizmoTransformable,
     ));
@@ -127,10 +645,10 @@ fn setup(
                             mesh: meshes.add(Sphere::new(1.0)),
                             material: sphere_material,
                             transform: center,
-                            visibility: Visibility::Hidden,
                             ..default()
                         },
                         RaytracedSphere { radius: 0.2 },
+                        RaytraceHideMesh,
                         bevy_mod_picking::PickableBundle::default(),
                         bevy_transform_gizmo::GizmoTransformable,
                     ));
@@ -149,19 +667,22 @@ fn setup(
                             mesh: meshes.add(Sphere::new(1.0)),
                             material: sphere_material,
                             transform: center,
-                            visibility: Visibility::Hidden,
                             ..default()
                         },
                         RaytracedSphere { radius: 0.2 },
+                        RaytraceHideMesh,
                         bevy_mod_picking::PickableBundle::default(),
                         bevy_transform_gizmo::GizmoTransformable,
                     ));
                 } else {
-                    // glass
+                    // glass, anywhere from clear to frosted
+                    let roughness = random::<f32>() * 0.5;
                     let sphere_material = materials.add(StandardMaterial {
                         metallic: 0.0,
+                        perceptual_roughness: roughness,
                         ior: 1.5,
                         specular_transmission: 1.0,
+                        thickness: 0.4,
                         ..default()
                     });
                     commands.spawn((
@@ -169,10 +690,10 @@ fn setup(
                             mesh: meshes.add(Sphere::new(1.0)),
                             material: sphere_material,
                             transform: center,
-                            visibility: Visibility::Hidden,
                             ..default()
                         },
                         RaytracedSphere { radius: 0.2 },
+                        RaytraceHideMesh,
                         bevy_mod_picking::PickableBundle::default(),
                         bevy_transform_gizmo::GizmoTransformable,
                     ));
@@ -181,11 +702,39 @@ fn setup(
         }
     }
 
+    // a ring of pebbles around the field, picking from a palette of a few materials instead of owning one each
+    commands.insert_resource(RaytraceMaterialPalette(vec![
+        materials.add(Color::srgb(0.45, 0.42, 0.38)),
+        materials.add(Color::srgb(0.25, 0.24, 0.22)),
+        materials.add(StandardMaterial {
+            base_color: Color::srgb(0.8, 0.8, 0.75),
+            perceptual_roughness: 0.2,
+            ..default()
+        }),
+    ]));
+    let pebbles = 720;
+    for pebble in 0..pebbles {
+        let angle = pebble as f32 / pebbles as f32 * std::f32::consts::TAU;
+        let radius = 0.03 + 0.03 * random::<f32>();
+        commands.spawn((
+            SpatialBundle::from_transform(Transform::from_xyz(
+                13.0 * angle.cos(),
+                radius,
+                13.0 * angle.sin(),
+            )),
+            RaytracedSphere { radius },
+            RaytraceMaterialId(random::<u32>() % 3),
+            RaytraceStatic,
+        ));
+    }
+
     // big spheres
     let sphere_material = materials.add(StandardMaterial {
         metallic: 0.0,
+        perceptual_roughness: 0.0,
         ior: 1.5,
         specular_transmission: 1.0,
+        thickness: 2.0,
         ..default()
     });
     commands.spawn((
@@ -193,10 +742,12 @@ fn setup(
             mesh: meshes.add(Sphere::new(1.0)),
             material: sphere_material,
             transform: Transform::from_xyz(0.0, 1.0, 0.0),
-            visibility: Visibility::Hidden,
             ..default()
         },
         RaytracedSphere { radius: 1.0 },
+        RaytraceHideMesh,
+        // Flint glass, only visible with spectral rendering enabled on the camera
+        RaytraceDispersion { cauchy_b: 0.01 },
         bevy_mod_picking::PickableBundle::default(),
         bevy_transform_gizmo::GizmoTransformable,
     ));
@@ -211,10 +762,17 @@ fn setup(
             mesh: meshes.add(Sphere::new(1.0)),
             material: sphere_material,
             transform: Transform::from_xyz(-4.0, 1.0, 0.0),
-            visibility: Visibility::Hidden,
             ..default()
         },
         RaytracedSphere { radius: 1.0 },
+        RaytraceHideMesh,
+        RaytraceMaterialOverride {
+            texture: RaytraceTexture::Turbulence {
+                scale: 4.0,
+                octaves: 7,
+            },
+            ..default()
+        },
         bevy_mod_picking::PickableBundle::default(),
         bevy_transform_gizmo::GizmoTransformable,
     ));
@@ -230,32 +788,15 @@ fn setup(
             mesh: meshes.add(Sphere::new(1.0)),
             material: sphere_material,
             transform: Transform::from_xyz(4.0, 1.0, 0.0),
-            visibility: Visibility::Hidden,
             ..default()
         },
         RaytracedSphere { radius: 1.0 },
+        RaytraceHideMesh,
         bevy_mod_picking::PickableBundle::default(),
         bevy_transform_gizmo::GizmoTransformable,
     ));
 }
 
-// The gizmo camera copies the main camera, but the clear color messes up the modified render pipeline
-fn remove_transform_gizmo_clear(
-    mut gizmo_cam: Query<
-        &mut Camera,
-        (
-            With<bevy_transform_gizmo::InternalGizmoCamera>,
-            Without<bevy_transform_gizmo::GizmoPickSource>,
-        ),
-    >,
-) {
-    let Ok(mut gizmo_cam) = gizmo_cam.get_single_mut() else {
-        return;
-    };
-
-    gizmo_cam.clear_color = ClearColorConfig::None;
-}
-
 // Make raycast picking ignore standart visibility
 fn modify_raycast_backend(mut settings: ResMut<RaycastBackendSettings>) {
     settings.raycast_visibility = RaycastVisibility::Ignore;
@@ -269,3 +810,769 @@ fn sync_picking_radius(
         transform.scale = Vec3::splat(sphere.radius);
     }
 }
+
+// Pressing C captures the traced scene around the camera into a cubemap, pressing it again captures it again.
+// Shift+C removes the capture
+fn toggle_cubemap_capture(
+    keys: Res<ButtonInput<KeyCode>>,
+    camera: Query<&GlobalTransform, With<RaytracedCamera>>,
+    captures: Query<Entity, With<RaytraceCubemapCapture>>,
+    mut images: ResMut<Assets<Image>>,
+    mut cmd: Commands,
+) {
+    if !keys.just_pressed(KeyCode::KeyC) {
+        return;
+    }
+
+    if let Ok(capture) = captures.get_single() {
+        if keys.any_pressed([KeyCode::ShiftLeft, KeyCode::ShiftRight]) {
+            cmd.entity(capture).despawn_recursive();
+        } else {
+            cmd.trigger(CaptureCubemap { capture });
+        }
+        return;
+    }
+
+    let Some(camera) = camera.iter().next() else {
+        return;
+    };
+
+    let settings = RaytracedCamera {
+        level: Raytracing::Pure,
+        sample_count: 1,
+        bounces: 4,
+        projection: RaytraceProjection::Camera,
+        spectral: false,
+        regularization: 0.1,
+        termination: PathTermination::default(),
+        sampling: RaytraceSampling::EveryFrame,
+        pixel_filter: PixelFilter::Box,
+        output: RaytraceOutput::Tonemapped,
+    };
+
+    cmd.spawn((
+        SpatialBundle::from_transform(Transform::from_translation(camera.translation())),
+        RaytraceCubemapCapture::new(256, settings, &mut images),
+        Name::new("Cubemap Capture"),
+    ));
+}
+
+// Pressing E lets the exposure of the camera follow the traced image, turning it off goes back to bevy's default exposure
+fn toggle_auto_exposure(
+    cameras: Query<(Entity, Has<RaytraceAutoExposure>), With<FlyCam>>,
+    keys: Res<ButtonInput<KeyCode>>,
+    mut commands: Commands,
+) {
+    if !keys.just_pressed(KeyCode::KeyE) {
+        return;
+    }
+
+    for (camera, auto_exposure) in &cameras {
+        if auto_exposure {
+            commands
+                .entity(camera)
+                .remove::<RaytraceAutoExposure>()
+                .insert(Exposure::default());
+        } else {
+            commands
+                .entity(camera)
+                .insert(RaytraceAutoExposure::default());
+        }
+    }
+}
+
+// Pressing H switches between drawing the traced image after tonemapping and handing it over as linear HDR before it
+fn toggle_linear_output(
+    mut cameras: Query<&mut RaytracedCamera, With<FlyCam>>,
+    keys: Res<ButtonInput<KeyCode>>,
+) {
+    if !keys.just_pressed(KeyCode::KeyH) {
+        return;
+    }
+
+    for mut camera in &mut cameras {
+        camera.output = match camera.output {
+            RaytraceOutput::Tonemapped => RaytraceOutput::LinearHdr,
+            RaytraceOutput::LinearHdr => RaytraceOutput::Tonemapped,
+        };
+    }
+}
+
+// Pressing P toggles the caustic photon map
+fn toggle_caustics(keys: Res<ButtonInput<KeyCode>>, mut caustics: ResMut<RaytraceCaustics>) {
+    if keys.just_pressed(KeyCode::KeyP) {
+        caustics.enabled = !caustics.enabled;
+    }
+}
+
+// Pressing F switches between an interactive preview and a final render with 256 samples
+fn toggle_final_render(
+    keys: Res<ButtonInput<KeyCode>>,
+    cameras: Query<(Entity, &RaytracedCamera), With<FlyCam>>,
+    progress: Res<RaytraceProgress>,
+    mut modes: EventWriter<SetRaytraceMode>,
+    mut finished: EventReader<RenderFinished>,
+) {
+    for (camera, settings) in &cameras {
+        if keys.just_pressed(KeyCode::KeyF) {
+            let mode = match settings.sampling {
+                RaytraceSampling::EveryFrame | RaytraceSampling::Checkerboard => {
+                    RaytraceMode::Final {
+                        samples: 256,
+                        samples_per_frame: 1,
+                    }
+                }
+                RaytraceSampling::Progressive { .. } => RaytraceMode::Interactive,
+            };
+            modes.send(SetRaytraceMode { camera, mode });
+        }
+    }
+
+    for event in finished.read() {
+        if let Some(progress) = progress.get(event.camera) {
+            info!("Final render finished with {} samples", progress.samples);
+        }
+    }
+}
+
+// Pressing Y stops tracing and keeps the last image on screen until it is pressed again
+fn toggle_pause(
+    keys: Res<ButtonInput<KeyCode>>,
+    cameras: Query<(Entity, Has<RaytracePaused>), With<FlyCam>>,
+    mut commands: Commands,
+) {
+    if !keys.just_pressed(KeyCode::KeyY) {
+        return;
+    }
+
+    for (camera, paused) in &cameras {
+        if paused {
+            commands.entity(camera).remove::<RaytracePaused>();
+        } else {
+            commands.entity(camera).insert(RaytracePaused);
+        }
+    }
+}
+
+// Pressing Z cuts away the front halves of the three big spheres to look inside them
+fn toggle_section_view(
+    keys: Res<ButtonInput<KeyCode>>,
+    mut cameras: Query<(Entity, Option<&mut RaytraceClipPlanes>), With<FlyCam>>,
+    mut commands: Commands,
+) {
+    if !keys.just_pressed(KeyCode::KeyZ) {
+        return;
+    }
+
+    let cut = ClipPlane {
+        point: Vec3::ZERO,
+        normal: Vec3::Z,
+    };
+    for (camera, clip_planes) in &mut cameras {
+        // Emptied instead of removed, so the accumulation sees the change
+        match clip_planes {
+            Some(mut clip_planes) if !clip_planes.0.is_empty() => clip_planes.0.clear(),
+            Some(mut clip_planes) => clip_planes.0.push(cut),
+            None => {
+                commands
+                    .entity(camera)
+                    .insert(RaytraceClipPlanes(vec![cut]));
+            }
+        }
+    }
+}
+
+// Pressing L hides the glowing sphere from reflected rays, it stays visible but stops lighting the scene and showing up in mirrors
+fn toggle_glow_reflections(
+    keys: Res<ButtonInput<KeyCode>>,
+    cameras: Query<(Entity, Option<&RaytraceRayMasks>), With<FlyCam>>,
+    mut commands: Commands,
+) {
+    if !keys.just_pressed(KeyCode::KeyL) {
+        return;
+    }
+
+    for (camera, ray_masks) in &cameras {
+        let mut ray_masks = ray_masks.copied().unwrap_or_default();
+        ray_masks.reflection ^= GLOW_INSTANCES;
+        commands.entity(camera).insert(ray_masks);
+    }
+}
+
+// A fountain of glowing sparks, spawned and removed with Q
+#[derive(Component, Default)]
+struct Sparks {
+    velocities: Vec<Vec3>,
+    ages: Vec<f32>,
+}
+
+const SPARK_COUNT: usize = 200;
+const SPARK_LIFETIME: f32 = 1.5;
+
+fn toggle_sparks(
+    keys: Res<ButtonInput<KeyCode>>,
+    sparks: Query<Entity, With<Sparks>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+    mut commands: Commands,
+) {
+    if !keys.just_pressed(KeyCode::KeyQ) {
+        return;
+    }
+
+    if let Ok(entity) = sparks.get_single() {
+        commands.entity(entity).despawn();
+        return;
+    }
+    commands.spawn((
+        materials.add(StandardMaterial {
+            perceptual_roughness: 0.4,
+            ..default()
+        }),
+        SpatialBundle::from_transform(Transform::from_xyz(-2.0, 0.0, 2.0)),
+        RaytraceParticles::default(),
+        Sparks::default(),
+        Name::new("Sparks"),
+    ));
+}
+
+// Moves the sparks on the CPU and hands them to the tracer, they cool down from yellow to a dim red while they fall
+fn simulate_sparks(time: Res<Time>, mut sparks: Query<(&mut Sparks, &mut RaytraceParticles)>) {
+    let delta = time.delta_seconds();
+    for (mut sparks, mut particles) in &mut sparks {
+        let Sparks { velocities, ages } = &mut *sparks;
+        let mut positions = std::mem::take(&mut particles.positions);
+        positions.resize(velocities.len(), Vec3::ZERO);
+        particles.clear();
+
+        while velocities.len() < SPARK_COUNT {
+            velocities.push(Vec3::new(
+                random::<f32>() - 0.5,
+                3.0 + random::<f32>() * 2.0,
+                random::<f32>() - 0.5,
+            ));
+            ages.push(random::<f32>() * SPARK_LIFETIME);
+            positions.push(Vec3::ZERO);
+        }
+
+        for ((velocity, age), position) in velocities
+            .iter_mut()
+            .zip(ages.iter_mut())
+            .zip(&mut positions)
+        {
+            *age += delta;
+            velocity.y -= 9.81 * delta;
+            *position += *velocity * delta;
+            if *age > SPARK_LIFETIME || position.y < 0.0 {
+                *age = 0.0;
+                *position = Vec3::ZERO;
+                *velocity = Vec3::new(
+                    random::<f32>() - 0.5,
+                    3.0 + random::<f32>() * 2.0,
+                    random::<f32>() - 0.5,
+                );
+            }
+
+            let heat = 1.0 - *age / SPARK_LIFETIME;
+            let glow = LinearRgba::rgb(20.0, 8.0 * heat, 1.0 * heat * heat) * heat;
+            particles.push(*position, 0.02, LinearRgba::BLACK, glow);
+        }
+    }
+}
+
+// The big sphere the scene stands on, O turns it into a shadow catcher
+#[derive(Component)]
+struct Ground;
+
+// Pressing O leaves only the shadows and reflections on the ground, over the sky behind it
+fn toggle_shadow_catcher(
+    keys: Res<ButtonInput<KeyCode>>,
+    grounds: Query<(Entity, Has<RaytraceShadowCatcher>), With<Ground>>,
+    mut commands: Commands,
+) {
+    if !keys.just_pressed(KeyCode::KeyO) {
+        return;
+    }
+
+    for (ground, catcher) in &grounds {
+        if catcher {
+            commands.entity(ground).remove::<RaytraceShadowCatcher>();
+        } else {
+            commands.entity(ground).insert(RaytraceShadowCatcher);
+        }
+    }
+}
+
+// Pressing U lets paths that run out of bounces take the light of the probe grid instead of ending dark
+fn toggle_irradiance_fallback(
+    keys: Res<ButtonInput<KeyCode>>,
+    mut cameras: Query<&mut RaytracedCamera, With<FlyCam>>,
+) {
+    if !keys.just_pressed(KeyCode::KeyU) {
+        return;
+    }
+
+    for mut camera in &mut cameras {
+        camera.termination.irradiance_fallback = !camera.termination.irradiance_fallback;
+    }
+}
+
+// Pressing J cycles the overlay of the camera through the samples per pixel, the estimated error and none
+fn cycle_convergence_overlay(
+    keys: Res<ButtonInput<KeyCode>>,
+    cameras: Query<(Entity, Option<&RaytraceConvergenceOverlay>), With<FlyCam>>,
+    mut commands: Commands,
+) {
+    if !keys.just_pressed(KeyCode::KeyJ) {
+        return;
+    }
+
+    for (camera, overlay) in &cameras {
+        match overlay {
+            // The final render of F goes up to 256 samples
+            None => commands
+                .entity(camera)
+                .insert(RaytraceConvergenceOverlay::Samples { max_samples: 256 }),
+            Some(RaytraceConvergenceOverlay::Samples { .. }) => commands
+                .entity(camera)
+                .insert(RaytraceConvergenceOverlay::Error { max_error: 0.1 }),
+            Some(RaytraceConvergenceOverlay::Error { .. }) => commands
+                .entity(camera)
+                .remove::<RaytraceConvergenceOverlay>(
+            ),
+        };
+    }
+}
+
+// Pressing I renders the view with 4096 samples into a file, the camera can't be moved until it is saved.
+// With ctrl held it renders an 8K poster in the background instead, the window keeps its size and the camera stays free.
+// Not shift, the flycam descends while it is held
+fn render_to_file(
+    keys: Res<ButtonInput<KeyCode>>,
+    cameras: Query<Entity, With<FlyCam>>,
+    mut commands: Commands,
+) {
+    if !keys.just_pressed(KeyCode::KeyI) {
+        return;
+    }
+
+    let poster = keys.any_pressed([KeyCode::ControlLeft, KeyCode::ControlRight]);
+    for camera in &cameras {
+        commands.trigger(RenderToFile {
+            camera,
+            samples: if poster { 256 } else { 4096 },
+            path: if poster {
+                format!("bevyray-{}-8k.png", camera.index()).into()
+            } else {
+                format!("bevyray-{}.png", camera.index()).into()
+            },
+            resolution: poster.then_some(UVec2::new(7680, 4320)),
+        });
+    }
+}
+
+fn log_saved_renders(mut saved: EventReader<RenderSaved>) {
+    for render in saved.read() {
+        match &render.result {
+            Ok(()) => info!("Saved the render to {}", render.path.display()),
+            Err(error) => warn!(
+                "Could not save the render to {}: {error}",
+                render.path.display()
+            ),
+        }
+    }
+}
+
+// Pressing X throws away the accumulated samples, like after a cut to another shot.
+// With ctrl held the whole scene counts as edited, which restarts the lightmap bakes as well.
+// Not shift, the flycam descends while it is held
+fn restart_accumulation(
+    keys: Res<ButtonInput<KeyCode>>,
+    cameras: Query<Entity, With<FlyCam>>,
+    mut cuts: EventWriter<CameraCut>,
+    mut scene: ResMut<RaytraceSceneState>,
+) {
+    if !keys.just_pressed(KeyCode::KeyX) {
+        return;
+    }
+
+    if keys.any_pressed([KeyCode::ControlLeft, KeyCode::ControlRight]) {
+        scene.mark_dirty();
+    } else {
+        cuts.send_batch(cameras.iter().map(|camera| CameraCut { camera }));
+    }
+}
+
+fn log_scene_edits(mut edits: EventReader<RaytraceSceneDirty>) {
+    if edits.read().count() > 0 {
+        debug!("Traced scene changed, restarting accumulation");
+    }
+}
+
+// The window title names whatever is under the cursor in the traced image
+fn show_hovered_entity(
+    hovered: Res<HoveredRaytracedEntity>,
+    names: Query<&Name>,
+    mut windows: Query<&mut Window, With<PrimaryWindow>>,
+) {
+    if !hovered.is_changed() {
+        return;
+    }
+
+    let Ok(mut window) = windows.get_single_mut() else {
+        return;
+    };
+    window.title = match hovered.entity {
+        Some(entity) => match names.get(entity) {
+            Ok(name) => format!("bevyray - {name} ({:.1}m)", hovered.distance),
+            Err(_) => format!("bevyray - {entity} ({:.1}m)", hovered.distance),
+        },
+        None => "bevyray".to_string(),
+    };
+}
+
+// Pressing G turns on the path debugger, right clicking a pixel then draws the path of its first sample
+fn toggle_path_debugger(
+    keys: Res<ButtonInput<KeyCode>>,
+    mut debugger: ResMut<RaytracePathDebugger>,
+) {
+    if keys.just_pressed(KeyCode::KeyG) {
+        debugger.enabled = !debugger.enabled;
+    }
+}
+
+fn log_recorded_path(path: Res<RaytraceRecordedPath>) {
+    let Some(camera) = path.camera.filter(|_| path.is_changed()) else {
+        return;
+    };
+
+    info!("Path through pixel {} of {camera}:", path.pixel);
+    for vertex in &path.vertices {
+        let kind = match vertex.kind {
+            PathVertexKind::Camera => "camera",
+            PathVertexKind::Surface => "surface",
+            PathVertexKind::Fog => "fog",
+            PathVertexKind::PortalEntry => "portal entry",
+            PathVertexKind::PortalExit => "portal exit",
+            PathVertexKind::Miss => "miss",
+        };
+        let note = if vertex.throughput.is_finite() {
+            ""
+        } else {
+            " <- not finite"
+        };
+        info!(
+            "  {kind} at {} carrying {}{note}",
+            vertex.position, vertex.throughput
+        );
+    }
+}
+
+// Pressing N draws pixels with NaN paths magenta and reports how many there are
+fn toggle_nan_debug(keys: Res<ButtonInput<KeyCode>>, mut debug: ResMut<RaytraceNanDebug>) {
+    if keys.just_pressed(KeyCode::KeyN) {
+        debug.enabled = !debug.enabled;
+    }
+}
+
+fn log_nan_report(report: Res<RaytraceNanReport>) {
+    if report.is_changed() && report.non_finite_paths > 0 {
+        warn!(
+            "{} paths had NaN or infinite radiance",
+            report.non_finite_paths
+        );
+    }
+}
+
+// Pressing V puts the scene into the white furnace, where anything that stands out from the background loses or gains energy
+fn toggle_white_furnace(
+    keys: Res<ButtonInput<KeyCode>>,
+    mut furnace: ResMut<RaytraceWhiteFurnace>,
+) {
+    if keys.just_pressed(KeyCode::KeyV) {
+        furnace.enabled = !furnace.enabled;
+    }
+}
+
+/// A row of spheres with every kind of material, for the white furnace check
+fn setup_white_furnace(
+    mut commands: Commands,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+) {
+    // No regularization and plenty of bounces, so only the BSDFs themselves change the energy of the paths
+    let mut camera = RaytracedCamera {
+        level: Raytracing::Pure,
+        bounces: RaytracedCamera::MAX_BOUNCES,
+        regularization: 0.0,
+        ..default()
+    };
+    camera.set_mode(RaytraceMode::Final {
+        samples: 256,
+        samples_per_frame: 4,
+    });
+    commands.spawn((
+        Camera3dBundle {
+            transform: Transform::from_xyz(0.0, 0.0, 10.0).looking_at(Vec3::ZERO, Vec3::Y),
+            tonemapping: Tonemapping::None,
+            ..default()
+        },
+        camera,
+        Name::new("White Furnace Camera"),
+    ));
+
+    let diffuse = StandardMaterial {
+        metallic: 0.0,
+        ..default()
+    };
+    let metal = StandardMaterial {
+        metallic: 1.0,
+        perceptual_roughness: 0.0,
+        ..default()
+    };
+    let glass = StandardMaterial {
+        metallic: 0.0,
+        perceptual_roughness: 0.0,
+        ior: 1.5,
+        specular_transmission: 1.0,
+        thickness: 1.6,
+        ..default()
+    };
+    let spheres = [
+        (diffuse.clone(), None),
+        (
+            StandardMaterial {
+                metallic: 0.5,
+                perceptual_roughness: 0.5,
+                ..default()
+            },
+            None,
+        ),
+        (metal.clone(), None),
+        (
+            StandardMaterial {
+                perceptual_roughness: 0.5,
+                ..metal
+            },
+            None,
+        ),
+        (glass.clone(), None),
+        (
+            StandardMaterial {
+                perceptual_roughness: 0.3,
+                ..glass.clone()
+            },
+            None,
+        ),
+        (
+            // Thin walled
+            StandardMaterial {
+                thickness: 0.0,
+                ..glass
+            },
+            None,
+        ),
+        (diffuse, Some(IRIDESCENT_BSDF)),
+    ];
+
+    let count = spheres.len();
+    for (index, (material, custom_bsdf)) in spheres.into_iter().enumerate() {
+        let x = (index as f32 - (count - 1) as f32 / 2.0) * 1.5;
+        let mut sphere = commands.spawn((
+            PbrBundle {
+                mesh: meshes.add(Sphere::new(1.0)),
+                material: materials.add(material),
+                transform: Transform::from_xyz(x, 0.0, 0.0).with_scale(Vec3::splat(0.6)),
+                ..default()
+            },
+            RaytracedSphere { radius: 0.6 },
+            RaytraceHideMesh,
+        ));
+        if custom_bsdf.is_some() {
+            sphere.insert(RaytraceMaterialOverride {
+                custom_bsdf,
+                ..default()
+            });
+        }
+    }
+}
+
+// Filled in by the screenshot callback
+#[derive(Resource, Clone, Default)]
+struct FurnaceScreenshot(Arc<Mutex<Option<Image>>>);
+
+// Takes a screenshot once the furnace render finished and exits with the verdict
+fn check_white_furnace(
+    mut finished: EventReader<RenderFinished>,
+    windows: Query<Entity, With<PrimaryWindow>>,
+    mut screenshots: ResMut<ScreenshotManager>,
+    screenshot: Res<FurnaceScreenshot>,
+    mut exit: EventWriter<AppExit>,
+) {
+    if finished.read().count() > 0 {
+        if let Ok(window) = windows.get_single() {
+            let shared = screenshot.clone();
+            let requested = screenshots.take_screenshot(window, move |image| {
+                *shared
+                    .0
+                    .lock()
+                    .expect("Could not get screenshot out of mutex") = Some(image);
+            });
+            if let Err(error) = requested {
+                warn!("Could not take the white furnace screenshot: {error}");
+            }
+        }
+    }
+
+    let Some(image) = screenshot
+        .0
+        .lock()
+        .expect("Could not get screenshot out of mutex")
+        .take()
+    else {
+        return;
+    };
+    exit.send(white_furnace_verdict(&image));
+}
+
+// Every pixel is compared to the top left one, which sees the furnace directly.
+// The channel order doesn't matter, the background is grey
+fn white_furnace_verdict(image: &Image) -> AppExit {
+    let Some(background) = image.data.get(0..3) else {
+        error!("The white furnace screenshot is empty");
+        return AppExit::error();
+    };
+
+    let pixels = image.data.chunks_exact(4);
+    let total = pixels.len();
+    let deviating = pixels
+        .filter(|pixel| {
+            pixel[..3]
+                .iter()
+                .zip(background)
+                .any(|(channel, background)| channel.abs_diff(*background) > FURNACE_TOLERANCE)
+        })
+        .count();
+    let share = deviating as f32 / total as f32;
+
+    if share > FURNACE_MAX_DEVIATING {
+        error!(
+            "White furnace failed, {deviating} of {total} pixels differ from the background by more than {FURNACE_TOLERANCE}"
+        );
+        AppExit::error()
+    } else {
+        info!("White furnace passed, {deviating} of {total} pixels differ from the background by more than {FURNACE_TOLERANCE}");
+        AppExit::Success
+    }
+}
+
+// Pressing B opens and closes the aperture of the camera lens
+fn toggle_depth_of_field(
+    keys: Res<ButtonInput<KeyCode>>,
+    mut lenses: Query<&mut RaytraceDepthOfField, With<FlyCam>>,
+) {
+    if !keys.just_pressed(KeyCode::KeyB) {
+        return;
+    }
+
+    for mut lens in &mut lenses {
+        lens.aperture_radius = if lens.aperture_radius > 0.0 { 0.0 } else { 0.1 };
+    }
+}
+
+// Pressing M logs how much GPU memory the traced scene takes up, and what the GPU offers the tracer
+fn log_memory_budget(
+    keys: Res<ButtonInput<KeyCode>>,
+    budget: Res<RaytraceMemoryBudget>,
+    capabilities: Res<RaytraceCapabilities>,
+) {
+    if !keys.just_pressed(KeyCode::KeyM) {
+        return;
+    }
+
+    info!(
+        "{}: {} storage buffers of up to {} KiB, ray queries {}",
+        capabilities.adapter,
+        capabilities.max_storage_buffers_per_shader_stage,
+        capabilities.max_storage_buffer_binding_size / 1024,
+        if capabilities.ray_query {
+            "available"
+        } else {
+            "unavailable"
+        }
+    );
+
+    for usage in &budget.usages {
+        info!(
+            "{}: {} KiB in {} chunks",
+            usage.name,
+            usage.bytes / 1024,
+            usage.chunks
+        );
+    }
+    info!(
+        "{} KiB in total, at most {} KiB per chunk, {} objects left out",
+        budget.total_bytes() / 1024,
+        budget.max_binding_size / 1024,
+        budget.dropped_objects
+    );
+}
+
+// Pressing T logs what the tracer did in the last frame and how often the accumulation restarted since the last time,
+// and how good the last BVH build was
+fn log_frame_stats(
+    keys: Res<ButtonInput<KeyCode>>,
+    stats: Res<RaytraceFrameStats>,
+    ray_budget: Res<RaytraceRayBudget>,
+    bvh_stats: Res<BvhStats>,
+    mut frames: EventReader<RaytraceFrameStats>,
+    mut resets: Local<u32>,
+) {
+    *resets += frames
+        .read()
+        .filter(|frame| frame.cameras.iter().any(|camera| camera.reset))
+        .count() as u32;
+    if !keys.just_pressed(KeyCode::KeyT) {
+        return;
+    }
+
+    for camera in &stats.cameras {
+        info!(
+            "{}: about {} rays in at least {} passes, {} samples per pixel",
+            camera.camera,
+            camera.rays,
+            camera.rays.div_ceil(ray_budget.max_rays_per_pass).max(1),
+            camera.samples
+        );
+    }
+    info!(
+        "{} rays in total, BVH depth {}, {} restarts",
+        stats.total_rays(),
+        stats.bvh_depth,
+        std::mem::take(&mut *resets)
+    );
+    info!(
+        "BVH: SAH cost {:.2}, {} nodes, {} leaves by size {:?}, built in {:?}",
+        bvh_stats.sah_cost,
+        bvh_stats.node_count,
+        bvh_stats.leaf_count(),
+        bvh_stats.leaf_sizes,
+        bvh_stats.build_time
+    );
+}
+
+// Pressing R rebuilds the BVH, needed for moved spheres when the `BvhRebuildPolicy` is manual
+fn rebuild_bvh(keys: Res<ButtonInput<KeyCode>>, mut rebuild: EventWriter<RebuildBvh>) {
+    if keys.just_pressed(KeyCode::KeyR) {
+        rebuild.send(RebuildBvh);
+    }
+}
+
+// Pressing K switches between the gradient and the atmosphere sky
+fn toggle_sky(keys: Res<ButtonInput<KeyCode>>, mut sky: ResMut<RaytraceSky>) {
+    if keys.just_pressed(KeyCode::KeyK) {
+        *sky = match *sky {
+            RaytraceSky::Gradient => RaytraceSky::atmosphere(Vec3::new(0.4, 0.3, -1.0)),
+            RaytraceSky::Atmosphere { .. } => RaytraceSky::Gradient,
+        };
+    }
+}
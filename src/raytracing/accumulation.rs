@@ -0,0 +1,441 @@
+use std::sync::{Arc, Mutex};
+
+use bevy::{
+    prelude::*,
+    render::{
+        camera::ExtractedCamera,
+        render_resource::{
+            Extent3d, PipelineCache, TextureDescriptor, TextureDimension, TextureFormat,
+            TextureUsages, TextureView, TextureViewDescriptor,
+        },
+        renderer::RenderDevice,
+        Extract, ExtractSchedule, Render, RenderApp, RenderSet,
+    },
+    utils::HashMap,
+};
+
+use super::{
+    dirty::RaytraceSceneDirty,
+    extract::{CameraExtract, PipelineExtract},
+    memory::MemoryReport,
+    pipeline::{queue_raytrace_pipelines, ViewRaytracePipelines},
+    watchdog::RaytraceRayBudget,
+    RaytraceClipPlanes, RaytraceCulling, RaytraceDepthOfField, RaytraceMode, RaytraceRayMasks,
+    RaytraceSampling, RaytracedCamera,
+};
+
+// Has to match the format of the accumulation textures in raytrace.wgsl
+pub(super) const ACCUMULATION_FORMAT: TextureFormat = TextureFormat::Rgba32Float;
+// The average of the squared luminance next to the accumulation, for the error estimate of `RaytraceConvergenceOverlay`
+pub(super) const MOMENTS_FORMAT: TextureFormat = TextureFormat::R32Float;
+
+pub struct RaytraceAccumulationPlugin;
+
+impl Plugin for RaytraceAccumulationPlugin {
+    fn build(&self, app: &mut App) {
+        // Both worlds share the same progress, the render world fills it in and the main world reads it
+        let progress = RaytraceProgress::default();
+        app.insert_resource(progress.clone())
+            .register_type::<RaytracePaused>()
+            .register_type::<RaytraceConvergenceOverlay>()
+            .add_event::<SetRaytraceMode>()
+            .add_event::<RenderFinished>()
+            .add_event::<CameraCut>()
+            .add_systems(Update, (apply_raytrace_modes, send_render_finished).chain());
+
+        let Some(render_app) = app.get_sub_app_mut(RenderApp) else {
+            return;
+        };
+
+        render_app
+            .insert_resource(progress)
+            .init_resource::<ViewAccumulations>()
+            .add_systems(ExtractSchedule, extract_accumulation)
+            .add_systems(
+                Render,
+                // Once the views have their pipelines, frames before they are compiled don't count
+                prepare_accumulation
+                    .in_set(RenderSet::Queue)
+                    .after(queue_raytrace_pipelines),
+            );
+    }
+}
+
+// How far the progressive cameras are, by their entity
+#[derive(Resource, Clone, Default)]
+pub struct RaytraceProgress(Arc<Mutex<HashMap<Entity, SampleProgress>>>);
+
+impl RaytraceProgress {
+    // None for cameras that aren't progressive or haven't been rendered yet
+    pub fn get(&self, camera: Entity) -> Option<SampleProgress> {
+        self.0
+            .lock()
+            .expect("Could not get raytrace progress out of mutex")
+            .get(&camera)
+            .copied()
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct SampleProgress {
+    // The samples per pixel accumulated since the last change
+    pub samples: u32,
+    // The `sample_count` of the camera
+    pub target: u32,
+}
+
+impl SampleProgress {
+    // Once converged the camera keeps showing the accumulated image without tracing anything
+    pub fn converged(&self) -> bool {
+        self.samples >= self.target
+    }
+}
+
+// Switches `camera` into `mode`, see `RaytraceMode`
+#[derive(Event, Clone, Copy, Debug)]
+pub struct SetRaytraceMode {
+    pub camera: Entity,
+    pub mode: RaytraceMode,
+}
+
+// Throws away what `camera` accumulated so far, including the last frame checkerboard cameras reproject.
+// Moving the camera or changing the scene already does this, it's meant for jumps between shots
+// that look continuous to the accumulation, like swapping the scene under a camera that stays put
+#[derive(Event, Clone, Copy, Debug)]
+pub struct CameraCut {
+    pub camera: Entity,
+}
+
+// Stops tracing for this camera, it keeps showing the last image until the component is removed.
+// Changes to the camera or the scene in the meantime are only picked up once it resumes, which starts the accumulation over.
+// A camera without an image to show yet, like right after a resize, still traces a single frame to hold on to
+#[derive(Component, Reflect, Clone, Copy, Default)]
+pub struct RaytracePaused;
+
+// Draws a heatmap over the traced image of a camera, from blue where a pixel is done to red where it still needs samples.
+// It goes on top of the finished image and doesn't restart the accumulation, so it can be toggled at any time
+#[derive(Component, Reflect, Clone, Copy, PartialEq, Debug)]
+pub enum RaytraceConvergenceOverlay {
+    // The samples in every pixel on a log scale, red with a single one and blue with `max_samples`
+    Samples { max_samples: u32 },
+    // The estimated relative standard error of the luminance in every pixel, from the spread of its samples.
+    // Red at `max_error` and above, blue at a hundredth of it. Cameras that don't accumulate only have the samples of the frame
+    Error { max_error: f32 },
+}
+
+// Sent once a progressive camera has accumulated all of its samples, again after every restart
+#[derive(Event, Clone, Copy, Debug)]
+pub struct RenderFinished {
+    pub camera: Entity,
+}
+
+fn apply_raytrace_modes(
+    mut events: EventReader<SetRaytraceMode>,
+    mut cameras: Query<&mut RaytracedCamera>,
+) {
+    for event in events.read() {
+        if let Ok(mut camera) = cameras.get_mut(event.camera) {
+            camera.set_mode(event.mode);
+        }
+    }
+}
+
+fn send_render_finished(
+    cameras: Query<Entity, With<RaytracedCamera>>,
+    progress: Res<RaytraceProgress>,
+    mut finished: EventWriter<RenderFinished>,
+    mut converged: Local<Vec<Entity>>,
+) {
+    let previous = std::mem::take(&mut *converged);
+    for camera in &cameras {
+        if !progress
+            .get(camera)
+            .is_some_and(|progress| progress.converged())
+        {
+            continue;
+        }
+
+        if !previous.contains(&camera) {
+            finished.send(RenderFinished { camera });
+        }
+        converged.push(camera);
+    }
+}
+
+pub struct ViewAccumulation {
+    progressive: bool,
+    // Checkerboard cameras keep their last frame in the textures instead of an average
+    checkerboard: bool,
+    // Paused cameras hold on to what is in the textures, cameras that trace every frame only get textures while paused
+    paused: bool,
+    // Something changed while the camera was paused, it starts over once it resumes
+    stale: bool,
+    // The frames in the history for checkerboard cameras
+    samples: u32,
+    target: u32,
+    samples_per_frame: u32,
+    size: UVec2,
+    // The running average of all frames, the shader reads one and writes the other
+    textures: Option<[TextureView; 2]>,
+    // Same for the squared luminance
+    moments: Option<[TextureView; 2]>,
+    current: usize,
+}
+
+// The accumulation of every progressive and checkerboard camera, this outlives the view entities which get cleared every frame
+#[derive(Resource, Default, Deref, DerefMut)]
+pub struct ViewAccumulations(HashMap<Entity, ViewAccumulation>);
+
+// The textures of this frame, the node binds a fallback for cameras without them
+#[derive(Component)]
+pub struct AccumulationTextures {
+    pub history: TextureView,
+    pub output: TextureView,
+    pub moments_history: TextureView,
+    pub moments_output: TextureView,
+}
+
+fn extract_accumulation(
+    mut accumulations: ResMut<ViewAccumulations>,
+    progress: Res<RaytraceProgress>,
+    cameras: Extract<
+        Query<(
+            Entity,
+            Ref<RaytracedCamera>,
+            Has<RaytracePaused>,
+            Ref<GlobalTransform>,
+            Ref<Projection>,
+            Option<Ref<RaytraceDepthOfField>>,
+            Option<Ref<FogSettings>>,
+            Option<Ref<RaytraceCulling>>,
+            Option<Ref<RaytraceRayMasks>>,
+            Option<Ref<RaytraceClipPlanes>>,
+        )>,
+    >,
+    scene_dirty: Extract<Res<Events<RaytraceSceneDirty>>>,
+    cuts: Extract<Res<Events<CameraCut>>>,
+    shader_events: Extract<Res<Events<AssetEvent<Shader>>>>,
+) {
+    let scene_changed = scene_dirty.iter_current_update_events().next().is_some();
+    // Hot reloaded shaders trace a different image
+    let shader_changed = shader_events
+        .iter_current_update_events()
+        .any(|event| matches!(event, AssetEvent::Modified { .. }));
+    let cut_cameras = cuts
+        .iter_current_update_events()
+        .map(|cut| cut.camera)
+        .collect::<Vec<_>>();
+
+    let mut accumulated = Vec::new();
+    for (
+        entity,
+        camera,
+        paused,
+        transform,
+        projection,
+        lens,
+        fog,
+        culling,
+        ray_masks,
+        clip_planes,
+    ) in &cameras
+    {
+        let (checkerboard, samples_per_frame) = match camera.sampling {
+            RaytraceSampling::EveryFrame if paused => (false, 1),
+            RaytraceSampling::EveryFrame => continue,
+            RaytraceSampling::Progressive { samples_per_frame } => (false, samples_per_frame),
+            RaytraceSampling::Checkerboard => (true, 1),
+        };
+        accumulated.push(entity);
+
+        let accumulation = accumulations
+            .entry(entity)
+            .or_insert_with(|| ViewAccumulation {
+                progressive: false,
+                checkerboard,
+                paused,
+                stale: false,
+                samples: 0,
+                target: 0,
+                samples_per_frame: 0,
+                size: UVec2::ZERO,
+                textures: None,
+                moments: None,
+                current: 0,
+            });
+
+        // Checkerboard cameras reproject their last frame when something moves instead of starting over
+        let settings_changed = camera.is_changed()
+            || shader_changed
+            || cut_cameras.contains(&entity)
+            || projection.is_changed()
+            || lens.is_some_and(|lens| lens.is_changed())
+            || fog.is_some_and(|fog| fog.is_changed())
+            || culling.is_some_and(|culling| culling.is_changed())
+            || ray_masks.is_some_and(|ray_masks| ray_masks.is_changed())
+            || clip_planes.is_some_and(|clip_planes| clip_planes.is_changed());
+        let view_changed = scene_changed || transform.is_changed();
+        if paused {
+            accumulation.stale |= settings_changed || view_changed;
+        } else {
+            if settings_changed || (view_changed && !checkerboard) || accumulation.stale {
+                accumulation.samples = 0;
+            }
+            accumulation.stale = false;
+        }
+        accumulation.progressive = matches!(camera.sampling, RaytraceSampling::Progressive { .. });
+        accumulation.checkerboard = checkerboard;
+        accumulation.paused = paused;
+        accumulation.target = camera.sample_count;
+        accumulation.samples_per_frame = samples_per_frame.max(1);
+    }
+
+    accumulations.retain(|entity, _| accumulated.contains(entity));
+    progress
+        .0
+        .lock()
+        .expect("Could not get raytrace progress out of mutex")
+        // Only progressive cameras converge, so the others have no progress
+        .retain(|entity, _| {
+            accumulated.contains(entity)
+                && accumulations
+                    .get(entity)
+                    .is_some_and(|accumulation| accumulation.progressive)
+        });
+}
+
+// Decides how many samples every progressive camera traces this frame and hands out its textures
+pub(super) fn prepare_accumulation(
+    mut accumulations: ResMut<ViewAccumulations>,
+    progress: Res<RaytraceProgress>,
+    mut views: Query<(
+        Entity,
+        &mut CameraExtract,
+        &ExtractedCamera,
+        &PipelineExtract,
+        Option<&ViewRaytracePipelines>,
+    )>,
+    pipeline_cache: Res<PipelineCache>,
+    ray_budget: Res<RaytraceRayBudget>,
+    render_device: Res<RenderDevice>,
+    memory_report: Res<MemoryReport>,
+    mut commands: Commands,
+) {
+    let mut progress = progress
+        .0
+        .lock()
+        .expect("Could not get raytrace progress out of mutex");
+
+    for (entity, mut camera, extracted_camera, pipeline, pipelines) in &mut views {
+        // Frames before the shader is compiled don't render anything, so they shouldn't count
+        let ready = pipelines.is_some_and(|pipelines| {
+            pipeline_cache
+                .get_render_pipeline(pipelines.pipeline)
+                .is_some()
+        });
+
+        let Some(accumulation) = accumulations.get_mut(&entity) else {
+            continue;
+        };
+        let Some(size) = extracted_camera.physical_target_size else {
+            continue;
+        };
+
+        if accumulation.textures.is_none() || accumulation.size != size {
+            let texture = |label, format| {
+                render_device
+                    .create_texture(&TextureDescriptor {
+                        label: Some(label),
+                        size: Extent3d {
+                            width: size.x,
+                            height: size.y,
+                            depth_or_array_layers: 1,
+                        },
+                        mip_level_count: 1,
+                        sample_count: 1,
+                        dimension: TextureDimension::D2,
+                        format,
+                        usage: TextureUsages::TEXTURE_BINDING | TextureUsages::STORAGE_BINDING,
+                        view_formats: &[],
+                    })
+                    .create_view(&TextureViewDescriptor::default())
+            };
+            accumulation.textures = Some([
+                texture("raytrace_accumulation_a", ACCUMULATION_FORMAT),
+                texture("raytrace_accumulation_b", ACCUMULATION_FORMAT),
+            ]);
+            accumulation.moments = Some([
+                texture("raytrace_moments_a", MOMENTS_FORMAT),
+                texture("raytrace_moments_b", MOMENTS_FORMAT),
+            ]);
+            accumulation.size = size;
+            accumulation.samples = 0;
+        }
+
+        // More would be too much for a pass even in the smallest tiles, the rest waits for the next frames
+        let samples_per_frame = accumulation
+            .samples_per_frame
+            .min(ray_budget.max_frame_samples(pipeline.bounces));
+        let frame_samples = if !ready {
+            0
+        } else if accumulation.paused {
+            // Only traced while there is nothing to show yet, cameras that don't accumulate trace all of their samples at once
+            match (accumulation.samples, accumulation.progressive) {
+                (0, true) => samples_per_frame.min(accumulation.target),
+                (0, false) => accumulation.target,
+                _ => 0,
+            }
+        } else if accumulation.checkerboard {
+            // Every frame writes a whole image, the count only tells if there is a last frame
+            1
+        } else {
+            samples_per_frame.min(accumulation.target.saturating_sub(accumulation.samples))
+        };
+
+        if accumulation.paused {
+            camera.hold(accumulation.samples, frame_samples);
+        } else if accumulation.checkerboard {
+            camera.reproject(accumulation.samples, accumulation.samples > 0);
+        } else {
+            camera.accumulate(accumulation.samples, frame_samples);
+        }
+
+        let (Some(textures), Some(moments)) = (&accumulation.textures, &accumulation.moments)
+        else {
+            continue;
+        };
+        commands.entity(entity).insert(AccumulationTextures {
+            history: textures[accumulation.current].clone(),
+            output: textures[1 - accumulation.current].clone(),
+            moments_history: moments[accumulation.current].clone(),
+            moments_output: moments[1 - accumulation.current].clone(),
+        });
+
+        // Once converged nothing gets written, so the average stays where it is
+        if frame_samples > 0 {
+            accumulation.current = 1 - accumulation.current;
+            accumulation.samples += frame_samples;
+        }
+
+        if !accumulation.progressive {
+            continue;
+        }
+        progress.insert(
+            entity,
+            SampleProgress {
+                samples: accumulation.samples,
+                target: accumulation.target,
+            },
+        );
+    }
+
+    // Two textures of each format for every camera
+    let texel_size = u64::from(ACCUMULATION_FORMAT.block_copy_size(None).unwrap_or(16))
+        + u64::from(MOMENTS_FORMAT.block_copy_size(None).unwrap_or(4));
+    let bytes = accumulations
+        .values()
+        .filter(|accumulation| accumulation.textures.is_some())
+        .map(|accumulation| 2 * texel_size * accumulation.size.as_u64vec2().element_product())
+        .sum();
+    memory_report.record("accumulation textures", bytes, 1);
+}
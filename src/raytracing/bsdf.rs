@@ -0,0 +1,94 @@
+use std::{collections::BTreeMap, fmt::Write};
+
+use bevy::prelude::*;
+
+// The module the path tracer imports its custom BSDFs from, it gets generated once all of them are registered
+const CUSTOM_BSDF_SHADER_HANDLE: Handle<Shader> =
+    Handle::weak_from_u128(0x6b1f_2d57_94c3_4e0a_b8a1_3f0c_5d27_e914);
+
+pub struct RaytraceBsdfPlugin;
+
+impl Plugin for RaytraceBsdfPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<RaytraceCustomBsdfs>();
+    }
+
+    fn finish(&self, app: &mut App) {
+        let world = app.world_mut();
+        let source = world.resource::<RaytraceCustomBsdfs>().dispatch_source();
+
+        // Shaders added directly to the assets don't load their imports, so the BSDF modules are loaded here
+        let asset_server = world.resource::<AssetServer>().clone();
+        let mut bsdfs = world.resource_mut::<RaytraceCustomBsdfs>();
+        let bsdfs = &mut *bsdfs;
+        bsdfs._shaders = bsdfs
+            .paths
+            .values()
+            .map(|path| asset_server.load(path.clone()))
+            .collect();
+
+        world.resource_mut::<Assets<Shader>>().insert(
+            &CUSTOM_BSDF_SHADER_HANDLE,
+            Shader::from_wgsl(source, "bevyray/custom_bsdf.wgsl"),
+        );
+    }
+}
+
+// The custom BSDFs by their tag, with the asset path of the module implementing them
+#[derive(Resource, Default)]
+pub struct RaytraceCustomBsdfs {
+    paths: BTreeMap<u32, String>,
+    // Keeps the modules loaded
+    _shaders: Vec<Handle<Shader>>,
+}
+
+impl RaytraceCustomBsdfs {
+    // A switch over all tags, calling the scatter function of the matching module
+    fn dispatch_source(&self) -> String {
+        let mut source = String::from(
+            "#define_import_path bevyray::custom_bsdf\n\n#import \"shaders/bsdf.wgsl\"::{BsdfInput, BsdfOutput}\n",
+        );
+
+        for (tag, path) in &self.paths {
+            writeln!(
+                source,
+                "#import \"{path}\"::{{scatter as bsdf_{tag}_scatter}}"
+            )
+            .unwrap();
+        }
+
+        source.push_str(
+            "\nfn custom_scatter(tag: u32, input: BsdfInput, state: ptr<private, u32>) -> BsdfOutput {\n    switch tag {\n",
+        );
+        for tag in self.paths.keys() {
+            writeln!(
+                source,
+                "        case {tag}u: {{\n            return bsdf_{tag}_scatter(input, state);\n        }}"
+            )
+            .unwrap();
+        }
+        // Unknown tags absorb everything, which makes them easy to spot
+        source.push_str(
+            "        default: {\n            return BsdfOutput(input.direction, vec3<f32>(0.0), true, false);\n        }\n    }\n}\n",
+        );
+
+        source
+    }
+}
+
+pub trait RaytraceBsdfAppExt {
+    // Registers a WGSL module implementing a custom BSDF for `tag`, see bsdf.wgsl for the interface it has to follow.
+    // Objects use it by setting `custom_bsdf` on their `RaytraceMaterialOverride` to the same tag.
+    // This has to happen before the app runs, as the path tracer is compiled against all registered BSDFs.
+    fn register_raytrace_bsdf(&mut self, tag: u32, shader: impl Into<String>) -> &mut Self;
+}
+
+impl RaytraceBsdfAppExt for App {
+    fn register_raytrace_bsdf(&mut self, tag: u32, shader: impl Into<String>) -> &mut Self {
+        self.world_mut()
+            .get_resource_or_insert_with(RaytraceCustomBsdfs::default)
+            .paths
+            .insert(tag, shader.into());
+        self
+    }
+}
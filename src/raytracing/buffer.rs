@@ -0,0 +1,226 @@
+use std::{num::NonZeroU64, ops::Range};
+
+use bevy::render::{
+    render_resource::{
+        encase::{self, internal::WriteInto},
+        BindingResource, Buffer, BufferBinding, BufferDescriptor, BufferUsages, ShaderSize,
+        ShaderType,
+    },
+    renderer::{RenderDevice, RenderQueue},
+};
+
+// A storage buffer over a runtime sized array that is kept between frames.
+// The vector on the CPU and the buffer on the GPU keep their contents and capacity, the buffer only ever grows.
+// Every write is compared with the element it replaces, and only the elements that differ get serialized and uploaded,
+// which is mostly the objects that moved
+pub struct SceneBuffer<T: ShaderType + ShaderSize + WriteInto + PartialEq> {
+    values: Vec<T>,
+    // The elements written since the last `clear`, the ones after it are dropped on upload
+    len: usize,
+    label: &'static str,
+    buffer: Option<Buffer>,
+    // The elements that differ from the buffer, merged into ranges on upload
+    dirty: Vec<Range<usize>>,
+    // The elements the buffer holds and the binding covers
+    uploaded_len: usize,
+    scratch: Vec<u8>,
+}
+
+impl<T: ShaderType + ShaderSize + WriteInto + PartialEq> SceneBuffer<T> {
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    // Replaces the contents, only the elements that differ from the ones they replace get uploaded
+    pub fn set(&mut self, values: impl IntoIterator<Item = T>) {
+        self.clear();
+        self.extend(values);
+    }
+
+    pub fn push(&mut self, value: T) {
+        self.update(self.len, value);
+    }
+
+    pub fn extend(&mut self, values: impl IntoIterator<Item = T>) {
+        for value in values {
+            self.push(value);
+        }
+    }
+
+    // Starts writing the contents anew from the first element, the old ones stay to be compared with
+    pub fn clear(&mut self) {
+        self.len = 0;
+    }
+
+    // Writes the element at `index`, growing the contents by one if it is right after them
+    pub fn update(&mut self, index: usize, value: T) {
+        self.len = self.len.max(index + 1);
+        match self.values.get_mut(index) {
+            Some(old) if *old == value => return,
+            Some(old) => *old = value,
+            None => {
+                debug_assert_eq!(index, self.values.len(), "Scene buffers can't have gaps");
+                self.values.push(value);
+            }
+        }
+        self.mark_dirty(index);
+    }
+
+    // Sets how many elements there are, the new ones start out as the default
+    pub fn resize(&mut self, len: usize)
+    where
+        T: Default,
+    {
+        self.len = len;
+        while self.values.len() < len {
+            self.mark_dirty(self.values.len());
+            self.values.push(T::default());
+        }
+    }
+
+    fn mark_dirty(&mut self, index: usize) {
+        match self.dirty.last_mut() {
+            Some(range) if range.end == index => range.end += 1,
+            _ => self.dirty.push(index..index + 1),
+        }
+    }
+
+    // The size of the data that is bound, an empty array still takes up one element
+    pub fn size(&self) -> u64 {
+        Self::stride() * self.len.max(1) as u64
+    }
+
+    // The distance between two elements in the buffer
+    fn stride() -> u64 {
+        T::METADATA.alignment().round_up(T::SHADER_SIZE.get())
+    }
+
+    pub fn write_buffer(&mut self, render_device: &RenderDevice, render_queue: &RenderQueue) {
+        self.values.truncate(self.len);
+        let dirty = std::mem::take(&mut self.dirty);
+
+        let size = self.size();
+        let capacity = self.buffer.as_ref().map_or(0, |buffer| buffer.size());
+        match &self.buffer {
+            Some(buffer) if capacity >= size => {
+                for range in merge_ranges(dirty, self.len) {
+                    self.scratch.clear();
+                    encase::StorageBuffer::new(&mut self.scratch)
+                        .write(&self.values[range.clone()])
+                        .expect("Could not write scene buffer");
+                    render_queue.write_buffer(
+                        buffer,
+                        range.start as u64 * Self::stride(),
+                        &self.scratch,
+                    );
+                }
+            }
+            _ => {
+                // Growing to the next power of two, so a few more objects don't need another buffer right away
+                let capacity = size
+                    .next_power_of_two()
+                    .min(render_device.limits().max_buffer_size)
+                    .max(size);
+                let buffer = render_device.create_buffer(&BufferDescriptor {
+                    label: Some(self.label),
+                    size: capacity,
+                    usage: BufferUsages::COPY_DST | BufferUsages::STORAGE,
+                    mapped_at_creation: false,
+                });
+                // A new buffer starts out empty, so everything goes into it
+                self.scratch.clear();
+                encase::StorageBuffer::new(&mut self.scratch)
+                    .write(&self.values)
+                    .expect("Could not write scene buffer");
+                render_queue.write_buffer(&buffer, 0, &self.scratch);
+                self.buffer = Some(buffer);
+            }
+        }
+
+        self.uploaded_len = self.len;
+    }
+
+    // Only binds the part in use, so `arrayLength` in the shaders doesn't count the spare capacity
+    pub fn binding(&self) -> Option<BindingResource<'_>> {
+        Some(BindingResource::Buffer(BufferBinding {
+            buffer: self.buffer.as_ref()?,
+            offset: 0,
+            size: NonZeroU64::new(Self::stride() * self.uploaded_len.max(1) as u64),
+        }))
+    }
+}
+
+impl<T: ShaderType + ShaderSize + WriteInto + PartialEq> Default for SceneBuffer<T> {
+    fn default() -> Self {
+        Self {
+            values: Vec::new(),
+            len: 0,
+            // Names the buffer after what it holds in graphics debuggers
+            label: std::any::type_name::<T>(),
+            buffer: None,
+            dirty: Vec::new(),
+            uploaded_len: 0,
+            scratch: Vec::new(),
+        }
+    }
+}
+
+// Sorts the written ranges and merges the ones that overlap or touch, leaving out what lies past `len`
+fn merge_ranges(mut ranges: Vec<Range<usize>>, len: usize) -> Vec<Range<usize>> {
+    ranges.sort_unstable_by_key(|range| range.start);
+    let mut merged: Vec<Range<usize>> = Vec::new();
+    for range in ranges {
+        let range = range.start.min(len)..range.end.min(len);
+        if range.is_empty() {
+            continue;
+        }
+
+        match merged.last_mut() {
+            Some(last) if last.end >= range.start => last.end = last.end.max(range.end),
+            _ => merged.push(range),
+        }
+    }
+    merged
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{merge_ranges, SceneBuffer};
+
+    #[test]
+    fn only_changed_elements_are_uploaded() {
+        let mut buffer = SceneBuffer::<u32>::default();
+        buffer.set([1, 2, 3, 4]);
+        assert_eq!(
+            merge_ranges(std::mem::take(&mut buffer.dirty), buffer.len()),
+            vec![0..4]
+        );
+
+        // Written again in the next frame, with two elements changed and one added
+        buffer.set([1, 5, 3, 6, 7]);
+        assert_eq!(
+            merge_ranges(std::mem::take(&mut buffer.dirty), buffer.len()),
+            vec![1..2, 3..5]
+        );
+
+        // Out of order, like objects in their slots
+        buffer.update(4, 8);
+        buffer.update(0, 9);
+        buffer.update(3, 10);
+        buffer.update(2, 3);
+        assert_eq!(
+            merge_ranges(std::mem::take(&mut buffer.dirty), buffer.len()),
+            vec![0..1, 3..5]
+        );
+
+        // Shrinking leaves nothing to upload, elements written past the end don't count
+        buffer.update(4, 11);
+        buffer.set([9, 5]);
+        assert!(merge_ranges(std::mem::take(&mut buffer.dirty), buffer.len()).is_empty());
+        assert_eq!(buffer.size(), 2 * 4);
+    }
+}
@@ -0,0 +1,1144 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use bevy::{
+    prelude::*,
+    render::{Extract, ExtractSchedule, Render, RenderApp, RenderSet},
+    utils::Instant,
+};
+use obvhs::{aabb::Aabb, ploc::build_ploc, Boundable};
+
+use super::{
+    extract::{prepare_buffers, BVHNode},
+    origin::RenderOrigin,
+    RaytraceParticles, RaytracedBillboard, RaytracedCuboid, RaytracedHeightfield, RaytracedPlane,
+    RaytracedSphere,
+};
+
+// Has to match the bits of the restart trail in scene.wgsl, one per level of the BVH
+const RESTART_TRAIL_LEVELS: u32 = 32;
+// The relative costs of testing the bounds of a node and intersecting a model, for `BvhStats::sah_cost`
+const TRAVERSAL_COST: f32 = 1.0;
+const INTERSECTION_COST: f32 = 1.0;
+// Models with bounds this many times the surface area of the median ones are kept out of the partitions, see `BvhCache::nodes`
+const OVERSIZED_AREA: f32 = 256.0;
+
+pub struct RaytraceBvhPlugin;
+
+impl Plugin for RaytraceBvhPlugin {
+    fn build(&self, app: &mut App) {
+        // Both worlds share the stats, the render world fills them in after a build and the main world takes them
+        let pending = PendingBvhStats::default();
+        app.register_type::<BvhRebuildPolicy>()
+            .register_type::<RaytraceStatic>()
+            .register_type::<BvhStats>()
+            .init_resource::<BvhRebuildPolicy>()
+            .init_resource::<BvhStats>()
+            .insert_resource(pending.clone())
+            .add_event::<RebuildBvh>()
+            .add_systems(First, update_bvh_stats);
+
+        let Some(render_app) = app.get_sub_app_mut(RenderApp) else {
+            return;
+        };
+
+        render_app
+            .init_resource::<BvhCache>()
+            .insert_resource(pending)
+            .add_systems(ExtractSchedule, extract_bvh_rebuild)
+            .add_systems(
+                Render,
+                record_bvh_stats
+                    .in_set(RenderSet::PrepareResources)
+                    .after(prepare_buffers),
+            );
+    }
+}
+
+// The quality of the BVH over the traced spheres and heightfields as of its last build, to compare how the scene is laid out
+// and to find degenerate ones, like a huge ground sphere whose bounds overlap everything else.
+// Only updated when a part of the BVH is built anew, moved models refitted in between don't change it.
+// Filled in by the render world, so it lags a frame behind with pipelined rendering
+#[derive(Resource, Reflect, Clone, Default, PartialEq, Debug)]
+#[reflect(Resource)]
+pub struct BvhStats {
+    // The expected cost of a ray through the BVH by the surface area heuristic, in model intersections.
+    // Lower is better, a BVH that didn't help at all costs as much as there are models
+    pub sah_cost: f32,
+    // The longest path from the root to a leaf, 0 for an empty scene
+    pub max_depth: u32,
+    pub node_count: u32,
+    // The amount of leaves by the amount of models in them, starting with leaves holding a single one
+    pub leaf_sizes: Vec<u32>,
+    // How long updating both parts of the BVH took in the frame of the build, refits of the part that was kept included
+    pub build_time: Duration,
+}
+
+impl BvhStats {
+    fn new(nodes: &[BVHNode], depth: u32, build_time: Duration) -> Self {
+        let mut stats = Self {
+            max_depth: depth,
+            node_count: nodes.len() as u32,
+            build_time,
+            ..default()
+        };
+        let Some(root) = nodes.first() else {
+            return stats;
+        };
+
+        // A ray hitting the root hits any node with the chance of their surface areas
+        let root_area = surface_area(root).max(f32::EPSILON);
+        for node in nodes {
+            let chance = surface_area(node) / root_area;
+            if node.model_count == 0 {
+                stats.sah_cost += chance * TRAVERSAL_COST;
+                continue;
+            }
+
+            stats.sah_cost += chance * node.model_count as f32 * INTERSECTION_COST;
+            let size = node.model_count as usize;
+            if stats.leaf_sizes.len() < size {
+                stats.leaf_sizes.resize(size, 0);
+            }
+            stats.leaf_sizes[size - 1] += 1;
+        }
+        stats
+    }
+
+    pub fn leaf_count(&self) -> u32 {
+        self.leaf_sizes.iter().sum()
+    }
+}
+
+fn surface_area(node: &BVHNode) -> f32 {
+    let size = (node.bounds_max - node.bounds_min).max(Vec3::ZERO);
+    2.0 * (size.x * size.y + size.y * size.z + size.z * size.x)
+}
+
+// Holds the stats of the last build until the main world takes them
+#[derive(Resource, Clone, Default)]
+struct PendingBvhStats(Arc<Mutex<Option<BvhStats>>>);
+
+fn update_bvh_stats(pending: Res<PendingBvhStats>, mut stats: ResMut<BvhStats>) {
+    let built = pending
+        .0
+        .lock()
+        .expect("Could not get BVH stats out of mutex")
+        .take();
+    if let Some(built) = built {
+        stats.set_if_neq(built);
+    }
+}
+
+fn record_bvh_stats(pending: Res<PendingBvhStats>, mut cache: ResMut<BvhCache>) {
+    if let Some(built) = cache.built.take() {
+        *pending
+            .0
+            .lock()
+            .expect("Could not get BVH stats out of mutex") = Some(built);
+    }
+}
+
+// When the BVH over the traced objects gets rebuilt, spheres, cuboids, planes, heightfields, billboards and particles alike.
+// While it is kept, moved objects are refitted into it, which gets slower to trace the further they move from where the BVH was built.
+// Whatever the policy, adding or removing objects rebuilds the part of the BVH they are in, and the `RenderOrigin` snapping
+// to another cell rebuilds all of it, as every model moves with it.
+// The policy is for the dynamic objects, the ones marked with `RaytraceStatic` are only refitted when one of them changes
+#[derive(Resource, Reflect, Clone, Copy, PartialEq, Eq, Debug, Default)]
+#[reflect(Resource)]
+pub enum BvhRebuildPolicy {
+    // Every frame, for scenes where something is always moving
+    EveryFrame,
+    // Only when objects come or go, moved objects are refitted
+    #[default]
+    OnChange,
+    // Additionally only when a `RebuildBvh` event is sent, for static scenes. Nothing is refitted, moved objects are cut off
+    // at their old bounds until objects come or go, the render origin moves or the event is sent
+    Manual,
+    // Every n frames, moving objects are refitted in between
+    EveryNFrames(u32),
+}
+
+// Rebuilds the BVH in the next frame, whatever the `BvhRebuildPolicy` is
+#[derive(Event, Clone, Copy, Debug, Default)]
+pub struct RebuildBvh;
+
+// Puts a traced object into the static part of the BVH, which is kept while only dynamic ones move.
+// Meant for the bulk of a scene that never moves, so a few moving objects don't rebuild the BVH over all of them
+#[derive(Component, Reflect, Clone, Copy, Default)]
+pub struct RaytraceStatic;
+
+// The last BVHs of the static and the dynamic objects, kept around while the policy and the objects don't ask for new ones
+#[derive(Resource, Default)]
+pub struct BvhCache {
+    rebuild_static: bool,
+    rebuild_dynamic: bool,
+    // Moved spheres are fitted into the kept BVH instead of sticking out of it
+    refit_static: bool,
+    refit_dynamic: bool,
+    frames_since_rebuild: u32,
+    static_partition: BvhPartition,
+    dynamic_partition: BvhPartition,
+    // Of the merged BVH the last `nodes` call returned
+    depth: u32,
+    // The stats of the last build until they are sent to the main world
+    built: Option<BvhStats>,
+    // The bounds of the kept nodes are relative to it
+    origin: RenderOrigin,
+}
+
+#[derive(Default)]
+struct BvhPartition {
+    entities: Vec<Entity>,
+    nodes: Vec<BVHNode>,
+    // The models of the leaves by their slot in the model buffer, the leaves point at ranges of this
+    primitives: Vec<u32>,
+}
+
+impl BvhPartition {
+    // Returns wether the nodes were built anew
+    fn update<T: Boundable>(
+        &mut self,
+        rebuild: bool,
+        refit: bool,
+        (entities, slots): (Vec<Entity>, Vec<u32>),
+        models: &[T],
+    ) -> bool {
+        if !rebuild && self.entities == entities {
+            if refit && !self.nodes.is_empty() {
+                refit_node(&mut self.nodes, &self.primitives, models, 0);
+            }
+            return false;
+        }
+
+        let aabbs = slots
+            .iter()
+            .map(|&slot| models[slot as usize].aabb())
+            .collect::<Vec<_>>();
+        let (nodes, order) = build_bvh(&aabbs);
+        self.nodes = nodes;
+        self.primitives = order
+            .into_iter()
+            .map(|index| slots[index as usize])
+            .collect();
+        self.entities = entities;
+        true
+    }
+}
+
+fn extract_bvh_rebuild(
+    mut cache: ResMut<BvhCache>,
+    policy: Extract<Res<BvhRebuildPolicy>>,
+    events: Extract<Res<Events<RebuildBvh>>>,
+    changed: Extract<
+        Query<
+            Has<RaytraceStatic>,
+            (
+                Or<(
+                    With<RaytracedSphere>,
+                    With<RaytracedCuboid>,
+                    With<RaytracedPlane>,
+                    With<RaytracedHeightfield>,
+                    With<RaytraceParticles>,
+                    With<RaytracedBillboard>,
+                )>,
+                Or<(
+                    Changed<GlobalTransform>,
+                    Changed<RaytracedSphere>,
+                    Changed<RaytracedCuboid>,
+                    Changed<RaytracedPlane>,
+                    Changed<RaytracedHeightfield>,
+                    Changed<RaytraceParticles>,
+                    Changed<RaytracedBillboard>,
+                    Changed<RaytraceStatic>,
+                )>,
+            ),
+        >,
+    >,
+) {
+    cache.frames_since_rebuild += 1;
+
+    let requested = events.iter_current_update_events().next().is_some() || policy.is_changed();
+    let static_changed = changed.iter().any(|is_static| is_static);
+    let dynamic_changed = changed.iter().any(|is_static| !is_static);
+    let manual = **policy == BvhRebuildPolicy::Manual;
+
+    cache.rebuild_static = requested;
+    cache.rebuild_dynamic = requested
+        || match **policy {
+            BvhRebuildPolicy::EveryFrame => true,
+            BvhRebuildPolicy::OnChange | BvhRebuildPolicy::Manual => false,
+            BvhRebuildPolicy::EveryNFrames(frames) => cache.frames_since_rebuild >= frames,
+        };
+    cache.refit_static = static_changed && !manual;
+    cache.refit_dynamic = dynamic_changed && !manual;
+}
+
+impl BvhCache {
+    // The models all move with the origin, so the whole BVH is built anew around them, whatever the policy is
+    pub(super) fn rebase(&mut self, origin: RenderOrigin) {
+        if origin != self.origin {
+            self.origin = origin;
+            self.rebuild_static = true;
+            self.rebuild_dynamic = true;
+        }
+    }
+
+    // The nodes over these models and the slots their leaves point at.
+    // The static nodes and models come first, the dynamic ones right after.
+    // Each part is only built anew if the policy asks for it or its models aren't the same anymore, otherwise it is refitted.
+    // Models far bigger than the rest, like a huge sphere as the ground, would overlap every node they end up in.
+    // They get a leaf of their own right below the root instead, with bounds that always follow them
+    pub fn nodes<T: Boundable>(
+        &mut self,
+        static_models: (Vec<Entity>, Vec<u32>),
+        dynamic_models: (Vec<Entity>, Vec<u32>),
+        models: &[T],
+    ) -> (Vec<BVHNode>, Vec<u32>) {
+        let start = Instant::now();
+        let max_area = oversized_area(models, static_models.1.iter().chain(&dynamic_models.1));
+        let (static_models, static_oversized) = split_oversized(static_models, models, max_area);
+        let (dynamic_models, dynamic_oversized) = split_oversized(dynamic_models, models, max_area);
+        let oversized = [static_oversized, dynamic_oversized].concat();
+
+        let static_built = self.static_partition.update(
+            self.rebuild_static,
+            self.refit_static,
+            static_models,
+            models,
+        );
+        let dynamic_built = self.dynamic_partition.update(
+            self.rebuild_dynamic,
+            self.refit_dynamic,
+            dynamic_models,
+            models,
+        );
+        if dynamic_built {
+            self.frames_since_rebuild = 0;
+        }
+
+        let partitions = merge_partitions(
+            &self.static_partition.nodes,
+            &self.dynamic_partition.nodes,
+            self.static_partition.primitives.len() as u32,
+        );
+        let nodes = merge_partitions(
+            &partitions,
+            &oversized_leaf(&oversized, models),
+            (self.static_partition.primitives.len() + self.dynamic_partition.primitives.len())
+                as u32,
+        );
+        let mut primitives = self
+            .static_partition
+            .primitives
+            .iter()
+            .chain(&self.dynamic_partition.primitives)
+            .chain(&oversized)
+            .copied()
+            .collect();
+        let nodes = limit_depth(nodes, &mut primitives, RESTART_TRAIL_LEVELS);
+        self.depth = tree_depth(&nodes);
+        if static_built || dynamic_built {
+            self.built = Some(BvhStats::new(&nodes, self.depth, start.elapsed()));
+        }
+        (nodes, primitives)
+    }
+
+    // The levels of nodes on the longest path from the root to a leaf, 0 without any spheres
+    pub fn depth(&self) -> u32 {
+        self.depth
+    }
+}
+
+// Models with bounds bigger than this don't go into the partitions, nothing is too big for scenes with only a few models
+fn oversized_area<'a, T: Boundable>(models: &[T], slots: impl Iterator<Item = &'a u32>) -> f32 {
+    let mut areas = slots
+        .map(|&slot| models[slot as usize].aabb().half_area())
+        .collect::<Vec<_>>();
+    if areas.len() < 4 {
+        return f32::INFINITY;
+    }
+
+    let middle = areas.len() / 2;
+    let (_, median, _) = areas.select_nth_unstable_by(middle, f32::total_cmp);
+    *median * OVERSIZED_AREA
+}
+
+// Takes the models with bounds bigger than `max_area` out of a partition, returning their slots
+fn split_oversized<T: Boundable>(
+    (entities, slots): (Vec<Entity>, Vec<u32>),
+    models: &[T],
+    max_area: f32,
+) -> ((Vec<Entity>, Vec<u32>), Vec<u32>) {
+    let (kept, oversized): (Vec<_>, Vec<_>) = entities
+        .into_iter()
+        .zip(slots)
+        .partition(|&(_, slot)| models[slot as usize].aabb().half_area() <= max_area);
+    (
+        kept.into_iter().unzip(),
+        oversized.into_iter().map(|(_, slot)| slot).collect(),
+    )
+}
+
+// A single leaf over all oversized models, they overlap each other and everything else anyway
+fn oversized_leaf<T: Boundable>(slots: &[u32], models: &[T]) -> Vec<BVHNode> {
+    if slots.is_empty() {
+        return Vec::new();
+    }
+
+    let aabb = slots.iter().fold(Aabb::INVALID, |aabb, &slot| {
+        aabb.union(&models[slot as usize].aabb())
+    });
+    vec![BVHNode {
+        bounds_min: aabb.min.into(),
+        bounds_max: aabb.max.into(),
+        index: 0,
+        model_count: slots.len() as u32,
+    }]
+}
+
+// scene.wgsl walks the BVH with a short stack and restarts from the root when it runs dry, with one bit per level
+// remembering which subtrees are done, so it can't go deeper than there are bits. Inner nodes on the last level get
+// turned into leaves over every model below them, which get a range of their own at the end of the primitives.
+// The nodes below them are left out, the others keep their order with their children next to each other
+fn limit_depth(nodes: Vec<BVHNode>, primitives: &mut Vec<u32>, levels: u32) -> Vec<BVHNode> {
+    if tree_depth(&nodes) <= levels {
+        return nodes;
+    }
+
+    let mut limited = vec![nodes[0].clone()];
+    let mut stack = vec![(0, 1)];
+    while let Some((index, level)) = stack.pop() {
+        let node = limited[index].clone();
+        if node.model_count > 0 {
+            continue;
+        }
+
+        if level == levels {
+            let below = models_below(&nodes, primitives, node.index);
+            limited[index].index = primitives.len() as u32;
+            limited[index].model_count = below.len() as u32;
+            primitives.extend(below);
+            continue;
+        }
+
+        let first = limited.len();
+        limited.push(nodes[node.index as usize].clone());
+        limited.push(nodes[node.index as usize + 1].clone());
+        limited[index].index = first as u32;
+        stack.push((first, level + 1));
+        stack.push((first + 1, level + 1));
+    }
+    limited
+}
+
+// The models of the leaves below both children starting at `first_child`
+fn models_below(nodes: &[BVHNode], primitives: &[u32], first_child: u32) -> Vec<u32> {
+    let mut models = Vec::new();
+    let mut stack = vec![first_child, first_child + 1];
+    while let Some(index) = stack.pop() {
+        let node = &nodes[index as usize];
+        if node.model_count > 0 {
+            let start = node.index as usize;
+            models.extend_from_slice(&primitives[start..start + node.model_count as usize]);
+        } else {
+            stack.extend([node.index, node.index + 1]);
+        }
+    }
+    models
+}
+
+fn tree_depth(nodes: &[BVHNode]) -> u32 {
+    if nodes.is_empty() {
+        return 0;
+    }
+
+    let mut depth = 0;
+    let mut stack = vec![(0, 1)];
+    while let Some((index, level)) = stack.pop() {
+        let node = &nodes[index as usize];
+        depth = depth.max(level);
+        if node.model_count == 0 {
+            stack.push((node.index, level + 1));
+            stack.push((node.index + 1, level + 1));
+        }
+    }
+    depth
+}
+
+// Two root scheme: node 0 is an inner node with the roots of both partitions as its children in 1 and 2,
+// followed by the remaining static nodes and then the remaining dynamic nodes.
+// The traversal starts at node 0 without testing its bounds, so it needs no changes for this.
+// With one of the partitions empty the other one is used on its own
+fn merge_partitions(
+    static_nodes: &[BVHNode],
+    dynamic_nodes: &[BVHNode],
+    dynamic_primitive_offset: u32,
+) -> Vec<BVHNode> {
+    let Some(static_root) = static_nodes.first() else {
+        return dynamic_nodes
+            .iter()
+            .map(|node| relocate(node, 0, dynamic_primitive_offset))
+            .collect();
+    };
+    let Some(dynamic_root) = dynamic_nodes.first() else {
+        return static_nodes.to_vec();
+    };
+
+    // Child indices are never 0, so the children of both partitions end up past the two roots
+    let static_offset = 2;
+    let dynamic_offset = static_offset + static_nodes.len() as u32 - 1;
+
+    let mut nodes = Vec::with_capacity(1 + static_nodes.len() + dynamic_nodes.len());
+    nodes.push(BVHNode {
+        bounds_min: static_root.bounds_min.min(dynamic_root.bounds_min),
+        bounds_max: static_root.bounds_max.max(dynamic_root.bounds_max),
+        index: 1,
+        model_count: 0,
+    });
+    nodes.push(relocate(static_root, static_offset, 0));
+    nodes.push(relocate(
+        dynamic_root,
+        dynamic_offset,
+        dynamic_primitive_offset,
+    ));
+    nodes.extend(
+        static_nodes[1..]
+            .iter()
+            .map(|node| relocate(node, static_offset, 0)),
+    );
+    nodes.extend(
+        dynamic_nodes[1..]
+            .iter()
+            .map(|node| relocate(node, dynamic_offset, dynamic_primitive_offset)),
+    );
+    nodes
+}
+
+// Moves a node of a partition to its place in the merged BVH, leaves point at primitives and inner nodes at other nodes
+fn relocate(node: &BVHNode, node_offset: u32, primitive_offset: u32) -> BVHNode {
+    let offset = if node.model_count > 0 {
+        primitive_offset
+    } else {
+        node_offset
+    };
+
+    BVHNode {
+        index: node.index + offset,
+        ..node.clone()
+    }
+}
+
+// Fits the bounds of a node and everything below it to where the models are now, the tree itself stays the same
+fn refit_node<T: Boundable>(
+    nodes: &mut [BVHNode],
+    primitives: &[u32],
+    models: &[T],
+    index: usize,
+) -> Aabb {
+    let node = &nodes[index];
+    let aabb = if node.model_count > 0 {
+        let start = node.index as usize;
+        primitives[start..start + node.model_count as usize]
+            .iter()
+            .fold(Aabb::INVALID, |aabb, &slot| {
+                aabb.union(&models[slot as usize].aabb())
+            })
+    } else {
+        let child = node.index as usize;
+        let first = refit_node(nodes, primitives, models, child);
+        first.union(&refit_node(nodes, primitives, models, child + 1))
+    };
+
+    nodes[index].bounds_min = aabb.min.into();
+    nodes[index].bounds_max = aabb.max.into();
+    aabb
+}
+
+// TODO: Look into optimizer/presorting/switching algorithm and what these limits are
+// Returns the nodes and the order of the primitives the leaves point into
+fn build_bvh(aabbs: &[Aabb]) -> (Vec<BVHNode>, Vec<u32>) {
+    if aabbs.is_empty() {
+        return (Vec::new(), Vec::new());
+    }
+
+    let bvh = build_ploc::<24>(
+        aabbs,
+        (0u32..(aabbs.len() as u32)).collect::<Vec<_>>(),
+        obvhs::ploc::SortPrecision::U64,
+        0,
+    );
+
+    let nodes = bvh
+        .nodes
+        .into_iter()
+        .map(|node| BVHNode {
+            bounds_min: node.aabb.min.into(),
+            bounds_max: node.aabb.max.into(),
+            index: node.first_index,
+            model_count: node.prim_count,
+        })
+        .collect();
+    (nodes, bvh.primitive_indices)
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::prelude::*;
+    use obvhs::{aabb::Aabb, Boundable};
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    use super::{limit_depth, tree_depth, BVHNode, BvhCache, BvhStats, RESTART_TRAIL_LEVELS};
+
+    // Like in scene.wgsl
+    const SHORT_STACK_SIZE: usize = 4;
+    const ROOT_LEVEL: u32 = 1 << (RESTART_TRAIL_LEVELS - 1);
+
+    #[derive(Clone, Copy)]
+    struct TestSphere {
+        center: Vec3,
+        radius: f32,
+    }
+
+    impl Boundable for TestSphere {
+        fn aabb(&self) -> Aabb {
+            Aabb::new(
+                (self.center - self.radius).into(),
+                (self.center + self.radius).into(),
+            )
+        }
+    }
+
+    impl TestSphere {
+        // Like `hit_sphere` in scene.wgsl, including the minimum distance
+        fn hit(&self, origin: Vec3, direction: Vec3) -> Option<f32> {
+            let offset = origin - self.center;
+            let b = offset.dot(direction);
+            let c = offset.length_squared() - self.radius * self.radius;
+            let discriminant = b * b - c;
+            if discriminant < 0.0 {
+                return None;
+            }
+            let distance = -b - discriminant.sqrt();
+            (distance > 0.001).then_some(distance)
+        }
+    }
+
+    // Like `ray_bounding_dst` in scene.wgsl, the distance to where the ray enters the bounds
+    fn hit_bounds(node: &BVHNode, origin: Vec3, direction: Vec3) -> Option<f32> {
+        let t_min = (node.bounds_min - origin) / direction;
+        let t_max = (node.bounds_max - origin) / direction;
+        let near = t_min.min(t_max).max_element();
+        let far = t_min.max(t_max).min_element();
+        (far >= near.max(0.0)).then_some(near.max(0.0))
+    }
+
+    // Leaf models against a ray, keeping the closest hit like `raycast_against_range` in scene.wgsl
+    fn hit_leaf(
+        node: &BVHNode,
+        primitives: &[u32],
+        spheres: &[TestSphere],
+        origin: Vec3,
+        direction: Vec3,
+        closest: &mut Option<(f32, u32)>,
+    ) {
+        let start = node.index as usize;
+        for &slot in &primitives[start..start + node.model_count as usize] {
+            let Some(distance) = spheres[slot as usize].hit(origin, direction) else {
+                continue;
+            };
+            if closest.is_none_or(|(closest, _)| distance < closest) {
+                *closest = Some((distance, slot));
+            }
+        }
+    }
+
+    // Traverses the nodes with a stack that holds every node still to visit, the reference for what the BVH contains.
+    // Returns the distance and slot of the closest hit
+    fn traverse(
+        nodes: &[BVHNode],
+        primitives: &[u32],
+        spheres: &[TestSphere],
+        origin: Vec3,
+        direction: Vec3,
+    ) -> Option<(f32, u32)> {
+        let mut closest: Option<(f32, u32)> = None;
+        if nodes.is_empty() {
+            return closest;
+        }
+
+        let mut stack = vec![0];
+        while let Some(index) = stack.pop() {
+            let node = &nodes[index as usize];
+            if node.model_count > 0 {
+                hit_leaf(node, primitives, spheres, origin, direction, &mut closest);
+                continue;
+            }
+
+            for child in [node.index, node.index + 1] {
+                let Some(distance) = hit_bounds(&nodes[child as usize], origin, direction) else {
+                    continue;
+                };
+                if closest.is_none_or(|(closest, _)| distance < closest) {
+                    stack.push(child);
+                }
+            }
+        }
+        closest
+    }
+
+    // `raycast_bvh_restart_trail` in scene.wgsl step by step, with its short stack and the restart trail
+    fn traverse_restart_trail(
+        nodes: &[BVHNode],
+        primitives: &[u32],
+        spheres: &[TestSphere],
+        origin: Vec3,
+        direction: Vec3,
+    ) -> Option<(f32, u32)> {
+        let mut closest: Option<(f32, u32)> = None;
+        if nodes.is_empty() {
+            return closest;
+        }
+        let closest_distance =
+            |closest: &Option<(f32, u32)>| closest.map_or(f32::INFINITY, |(distance, _)| distance);
+        let bounds_distance = |index: u32| {
+            hit_bounds(&nodes[index as usize], origin, direction).unwrap_or(f32::INFINITY)
+        };
+
+        // The node, the bit of its level and the distance to its bounds
+        let mut stack = [(0, 0, 0.0); SHORT_STACK_SIZE];
+        let mut stack_top = 0;
+        let mut stack_count = 0;
+
+        let mut node = 0;
+        let mut level = ROOT_LEVEL;
+        let mut trail = 0u32;
+
+        loop {
+            let bvh_node = &nodes[node as usize];
+
+            if bvh_node.model_count > 0 {
+                hit_leaf(
+                    bvh_node,
+                    primitives,
+                    spheres,
+                    origin,
+                    direction,
+                    &mut closest,
+                );
+            } else if level > 1 {
+                let dst_1 = bounds_distance(bvh_node.index);
+                let dst_2 = bounds_distance(bvh_node.index + 1);
+
+                if dst_1 != f32::INFINITY || dst_2 != f32::INFINITY {
+                    level >>= 1;
+                    let near_second = dst_2 < dst_1;
+                    let (near, far) = if near_second {
+                        (bvh_node.index + 1, bvh_node.index)
+                    } else {
+                        (bvh_node.index, bvh_node.index + 1)
+                    };
+                    let near_dst = dst_1.min(dst_2);
+                    let far_dst = dst_1.max(dst_2);
+
+                    let mut next = far;
+                    let mut next_dst = far_dst;
+                    if dst_1 == f32::INFINITY || dst_2 == f32::INFINITY {
+                        trail |= level;
+                        next = near;
+                        next_dst = near_dst;
+                    } else if trail & level == 0 {
+                        stack[stack_top % SHORT_STACK_SIZE] = (far, level, far_dst);
+                        stack_top += 1;
+                        stack_count = (stack_count + 1).min(SHORT_STACK_SIZE);
+                        next = near;
+                        next_dst = near_dst;
+                    }
+
+                    if next_dst < closest_distance(&closest) {
+                        node = next;
+                        continue;
+                    }
+                }
+            }
+
+            let mut popped = false;
+            while !popped {
+                trail &= level.wrapping_neg();
+                trail = trail.wrapping_add(level);
+                if trail & ROOT_LEVEL != 0 {
+                    return closest;
+                }
+
+                if stack_count == 0 {
+                    node = 0;
+                    level = ROOT_LEVEL;
+                    popped = true;
+                } else {
+                    stack_top -= 1;
+                    stack_count -= 1;
+                    let (entry_node, entry_level, entry_distance) =
+                        stack[stack_top % SHORT_STACK_SIZE];
+                    node = entry_node;
+                    level = entry_level;
+                    popped = entry_distance < closest_distance(&closest);
+                }
+            }
+        }
+    }
+
+    // Nodes over the slots in their order, with the first child of every inner node getting as many of them as `split`
+    // says. For trees the builder never makes, like deep and lopsided ones
+    fn split_tree(
+        spheres: &[TestSphere],
+        slots: &[u32],
+        split: &mut impl FnMut(usize) -> usize,
+    ) -> Vec<BVHNode> {
+        fn fill(
+            nodes: &mut Vec<BVHNode>,
+            index: usize,
+            start: usize,
+            end: usize,
+            spheres: &[TestSphere],
+            slots: &[u32],
+            split: &mut impl FnMut(usize) -> usize,
+        ) {
+            let aabb = slots[start..end].iter().fold(Aabb::INVALID, |aabb, &slot| {
+                aabb.union(&spheres[slot as usize].aabb())
+            });
+            nodes[index].bounds_min = aabb.min.into();
+            nodes[index].bounds_max = aabb.max.into();
+            if end - start == 1 {
+                nodes[index].index = start as u32;
+                nodes[index].model_count = 1;
+                return;
+            }
+
+            let middle = start + split(end - start).clamp(1, end - start - 1);
+            let first = nodes.len();
+            nodes.extend([nodes[index].clone(), nodes[index].clone()]);
+            nodes[index].index = first as u32;
+            fill(nodes, first, start, middle, spheres, slots, split);
+            fill(nodes, first + 1, middle, end, spheres, slots, split);
+        }
+
+        let mut nodes = vec![BVHNode {
+            bounds_min: Vec3::ZERO,
+            bounds_max: Vec3::ZERO,
+            index: 0,
+            model_count: 0,
+        }];
+        fill(&mut nodes, 0, 0, slots.len(), spheres, slots, split);
+        nodes
+    }
+
+    fn brute_force(
+        spheres: &[TestSphere],
+        slots: &[u32],
+        origin: Vec3,
+        direction: Vec3,
+    ) -> Option<(f32, u32)> {
+        slots
+            .iter()
+            .filter_map(|&slot| Some((spheres[slot as usize].hit(origin, direction)?, slot)))
+            .min_by(|a, b| a.0.total_cmp(&b.0))
+    }
+
+    fn random_spheres(rng: &mut StdRng, count: usize) -> Vec<TestSphere> {
+        (0..count)
+            .map(|_| TestSphere {
+                center: Vec3::new(
+                    rng.gen_range(-20.0..20.0),
+                    rng.gen_range(-20.0..20.0),
+                    rng.gen_range(-20.0..20.0),
+                ),
+                radius: rng.gen_range(0.1..2.0),
+            })
+            .collect()
+    }
+
+    fn partition(slots: impl IntoIterator<Item = u32>) -> (Vec<Entity>, Vec<u32>) {
+        slots
+            .into_iter()
+            .map(|slot| (Entity::from_raw(slot), slot))
+            .unzip()
+    }
+
+    // Compares the traversal with every sphere tested on its own, for rays through the whole volume of the spheres
+    fn assert_matches_brute_force(
+        rng: &mut StdRng,
+        nodes: &[BVHNode],
+        primitives: &[u32],
+        spheres: &[TestSphere],
+        slots: &[u32],
+    ) {
+        for _ in 0..500 {
+            let origin = Vec3::new(
+                rng.gen_range(-30.0..30.0),
+                rng.gen_range(-30.0..30.0),
+                rng.gen_range(-30.0..30.0),
+            );
+            let target = Vec3::new(
+                rng.gen_range(-20.0..20.0),
+                rng.gen_range(-20.0..20.0),
+                rng.gen_range(-20.0..20.0),
+            );
+            let direction = (target - origin).normalize();
+
+            let expected = brute_force(spheres, slots, origin, direction).map(|(_, slot)| slot);
+            let traversed = traverse(nodes, primitives, spheres, origin, direction);
+            let restarted = traverse_restart_trail(nodes, primitives, spheres, origin, direction);
+            assert_eq!(
+                expected,
+                traversed.map(|(_, slot)| slot),
+                "ray from {origin} along {direction}"
+            );
+            assert_eq!(
+                expected,
+                restarted.map(|(_, slot)| slot),
+                "restart trail for the ray from {origin} along {direction}"
+            );
+        }
+    }
+
+    // Every leaf points at valid primitives and every sphere shows up in exactly one leaf
+    fn assert_leaves_cover(nodes: &[BVHNode], primitives: &[u32], slots: &[u32]) {
+        let mut reached = nodes
+            .iter()
+            .filter(|node| node.model_count > 0)
+            .flat_map(|node| {
+                let start = node.index as usize;
+                primitives[start..start + node.model_count as usize].to_vec()
+            })
+            .collect::<Vec<_>>();
+        reached.sort_unstable();
+        let mut expected = slots.to_vec();
+        expected.sort_unstable();
+        assert_eq!(reached, expected);
+    }
+
+    #[test]
+    fn bounds_contain_their_spheres() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let spheres = random_spheres(&mut rng, 64);
+        let (nodes, primitives) =
+            BvhCache::default().nodes(partition([]), partition(0..spheres.len() as u32), &spheres);
+
+        for node in nodes.iter().filter(|node| node.model_count > 0) {
+            let start = node.index as usize;
+            for &slot in &primitives[start..start + node.model_count as usize] {
+                let aabb = spheres[slot as usize].aabb();
+                assert!(node.bounds_min.cmple(aabb.min.into()).all());
+                assert!(node.bounds_max.cmpge(aabb.max.into()).all());
+            }
+        }
+    }
+
+    #[test]
+    fn traversal_matches_brute_force() {
+        let mut rng = StdRng::seed_from_u64(2);
+        let spheres = random_spheres(&mut rng, 200);
+        let slots = (0..spheres.len() as u32).collect::<Vec<_>>();
+        let (nodes, primitives) =
+            BvhCache::default().nodes(partition([]), partition(slots.clone()), &spheres);
+
+        assert_leaves_cover(&nodes, &primitives, &slots);
+        assert_matches_brute_force(&mut rng, &nodes, &primitives, &spheres, &slots);
+    }
+
+    #[test]
+    fn merged_partitions_match_brute_force() {
+        let mut rng = StdRng::seed_from_u64(3);
+        let spheres = random_spheres(&mut rng, 150);
+        // Interleaved slots with a gap at the end, like after spheres were removed
+        let static_slots = (0..140).filter(|slot| slot % 3 == 0).collect::<Vec<_>>();
+        let dynamic_slots = (0..140).filter(|slot| slot % 3 != 0).collect::<Vec<_>>();
+        let (nodes, primitives) = BvhCache::default().nodes(
+            partition(static_slots.clone()),
+            partition(dynamic_slots.clone()),
+            &spheres,
+        );
+
+        let slots = [static_slots, dynamic_slots].concat();
+        assert_leaves_cover(&nodes, &primitives, &slots);
+        assert_matches_brute_force(&mut rng, &nodes, &primitives, &spheres, &slots);
+    }
+
+    #[test]
+    fn merged_depth_adds_a_level() {
+        let mut rng = StdRng::seed_from_u64(5);
+        let spheres = random_spheres(&mut rng, 100);
+        let depth = |static_slots: Vec<u32>, dynamic_slots: Vec<u32>| {
+            let mut cache = BvhCache::default();
+            cache.nodes(partition(static_slots), partition(dynamic_slots), &spheres);
+            cache.depth()
+        };
+
+        assert_eq!(depth(vec![], vec![]), 0);
+        assert_eq!(depth(vec![], vec![0]), 1);
+        let static_depth = depth((0..30).collect(), vec![]);
+        let dynamic_depth = depth(vec![], (30..100).collect());
+        assert_eq!(
+            depth((0..30).collect(), (30..100).collect()),
+            1 + static_depth.max(dynamic_depth)
+        );
+    }
+
+    #[test]
+    fn huge_models_get_their_own_leaf() {
+        let mut rng = StdRng::seed_from_u64(7);
+        let mut spheres = random_spheres(&mut rng, 100);
+        // Like the ground of the example
+        spheres.push(TestSphere {
+            center: Vec3::new(0.0, -1000.0, 0.0),
+            radius: 1000.0,
+        });
+        let static_slots = (0..101).filter(|slot| slot % 2 == 0).collect::<Vec<_>>();
+        let dynamic_slots = (0..101).filter(|slot| slot % 2 != 0).collect::<Vec<_>>();
+        let (nodes, primitives) = BvhCache::default().nodes(
+            partition(static_slots.clone()),
+            partition(dynamic_slots.clone()),
+            &spheres,
+        );
+
+        let root = &nodes[0];
+        let leaf = &nodes[root.index as usize + 1];
+        assert_eq!(leaf.model_count, 1);
+        assert_eq!(primitives[leaf.index as usize], 100);
+
+        let slots = [static_slots, dynamic_slots].concat();
+        assert_leaves_cover(&nodes, &primitives, &slots);
+        assert_matches_brute_force(&mut rng, &nodes, &primitives, &spheres, &slots);
+    }
+
+    #[test]
+    fn stats_count_every_model_once() {
+        let mut rng = StdRng::seed_from_u64(6);
+        let spheres = random_spheres(&mut rng, 120);
+        let mut cache = BvhCache::default();
+        let (nodes, _) = cache.nodes(
+            partition(0..40),
+            partition(40..spheres.len() as u32),
+            &spheres,
+        );
+
+        let stats = cache.built.take().unwrap();
+        let models = stats
+            .leaf_sizes
+            .iter()
+            .enumerate()
+            .map(|(size, &leaves)| (size as u32 + 1) * leaves)
+            .sum::<u32>();
+        assert_eq!(models, spheres.len() as u32);
+        assert_eq!(stats.node_count, nodes.len() as u32);
+        assert_eq!(stats.max_depth, cache.depth());
+        // Better than testing every sphere, worse than testing none
+        assert!(stats.sah_cost > 1.0 && stats.sah_cost < spheres.len() as f32);
+
+        // Nothing was built in a frame that only keeps the BVH
+        cache.nodes(
+            partition(0..40),
+            partition(40..spheres.len() as u32),
+            &spheres,
+        );
+        assert!(cache.built.is_none());
+    }
+
+    #[test]
+    fn stats_of_a_single_leaf() {
+        let leaf = BVHNode {
+            bounds_min: Vec3::ZERO,
+            bounds_max: Vec3::ONE,
+            index: 0,
+            model_count: 3,
+        };
+        let stats = BvhStats::new(&[leaf], 1, default());
+        assert_eq!(stats.sah_cost, 3.0);
+        assert_eq!(stats.leaf_sizes, vec![0, 0, 1]);
+        assert_eq!(stats.leaf_count(), 1);
+    }
+
+    #[test]
+    fn restart_trail_walks_lopsided_trees() {
+        let mut rng = StdRng::seed_from_u64(8);
+        let spheres = random_spheres(&mut rng, 300);
+        let slots = (0..spheres.len() as u32).collect::<Vec<_>>();
+
+        // A chain as deep as the trail reaches, and trees split at random, which have long and short branches
+        let chain_slots = &slots[..RESTART_TRAIL_LEVELS as usize];
+        let chain = split_tree(&spheres, chain_slots, &mut |_| 1);
+        assert_eq!(tree_depth(&chain), RESTART_TRAIL_LEVELS);
+        assert_matches_brute_force(&mut rng, &chain, chain_slots, &spheres, chain_slots);
+        // Random rays seldom find the spheres at the bottom, so every sphere gets one aimed at it
+        for &slot in chain_slots {
+            let origin = Vec3::new(50.0, 40.0, 30.0);
+            let direction = (spheres[slot as usize].center - origin).normalize();
+            assert_eq!(
+                brute_force(&spheres, chain_slots, origin, direction),
+                traverse_restart_trail(&chain, chain_slots, &spheres, origin, direction),
+                "ray at {slot}"
+            );
+        }
+
+        for _ in 0..4 {
+            let mut primitives = slots.clone();
+            let nodes = split_tree(&spheres, &slots, &mut |len| rng.gen_range(1..len));
+            let nodes = limit_depth(nodes, &mut primitives, RESTART_TRAIL_LEVELS);
+            assert_leaves_cover(&nodes, &primitives, &slots);
+            assert_matches_brute_force(&mut rng, &nodes, &primitives, &spheres, &slots);
+        }
+    }
+
+    #[test]
+    fn deep_trees_end_where_the_trail_does() {
+        let mut rng = StdRng::seed_from_u64(9);
+        let spheres = random_spheres(&mut rng, 100);
+        let slots = (0..spheres.len() as u32).collect::<Vec<_>>();
+
+        // Every split peels off a single sphere, or half of them
+        for split in [|_| 1, |len: usize| len - 1, |len: usize| len / 2] {
+            let mut split = split;
+            let mut primitives = slots.clone();
+            let nodes = split_tree(&spheres, &slots, &mut split);
+            let nodes = limit_depth(nodes, &mut primitives, RESTART_TRAIL_LEVELS);
+            assert!(tree_depth(&nodes) <= RESTART_TRAIL_LEVELS);
+            assert_leaves_cover(&nodes, &primitives, &slots);
+            assert_matches_brute_force(&mut rng, &nodes, &primitives, &spheres, &slots);
+        }
+    }
+
+    #[test]
+    fn refit_follows_moved_spheres() {
+        let mut rng = StdRng::seed_from_u64(4);
+        let mut spheres = random_spheres(&mut rng, 100);
+        let static_slots = (0..30).collect::<Vec<_>>();
+        let dynamic_slots = (30..100).collect::<Vec<_>>();
+
+        let mut cache = BvhCache::default();
+        cache.nodes(
+            partition(static_slots.clone()),
+            partition(dynamic_slots.clone()),
+            &spheres,
+        );
+
+        for sphere in &mut spheres[30..] {
+            sphere.center += Vec3::new(
+                rng.gen_range(-10.0..10.0),
+                rng.gen_range(-10.0..10.0),
+                rng.gen_range(-10.0..10.0),
+            );
+        }
+        cache.refit_dynamic = true;
+        let (nodes, primitives) = cache.nodes(
+            partition(static_slots.clone()),
+            partition(dynamic_slots.clone()),
+            &spheres,
+        );
+
+        let slots = [static_slots, dynamic_slots].concat();
+        assert_leaves_cover(&nodes, &primitives, &slots);
+        assert_matches_brute_force(&mut rng, &nodes, &primitives, &spheres, &slots);
+    }
+}
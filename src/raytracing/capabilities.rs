@@ -0,0 +1,156 @@
+use bevy::{
+    prelude::*,
+    render::{
+        render_resource::{TextureFormat, TextureUsages},
+        renderer::{RenderAdapter, RenderAdapterInfo, RenderDevice},
+        RenderApp,
+    },
+};
+use wgpu::{DownlevelFlags, Features};
+
+use super::{
+    accumulation::{ACCUMULATION_FORMAT, MOMENTS_FORMAT},
+    RaytracedCamera, Raytracing,
+};
+
+// The storage buffers the raytrace pipeline binds in its fragment shader, across all of its bind groups
+const REQUIRED_STORAGE_BUFFERS: u32 = 20;
+// The accumulation and its moments
+const REQUIRED_STORAGE_TEXTURES: u32 = 2;
+
+pub struct RaytraceCapabilitiesPlugin;
+
+impl Plugin for RaytraceCapabilitiesPlugin {
+    fn build(&self, app: &mut App) {
+        app.register_type::<RaytraceCapabilities>()
+            .add_systems(Update, downgrade_unsupported_cameras);
+    }
+
+    fn finish(&self, app: &mut App) {
+        let Some(render_app) = app.get_sub_app(RenderApp) else {
+            return;
+        };
+
+        let world = render_app.world();
+        let (Some(render_device), Some(render_adapter), Some(adapter_info)) = (
+            world.get_resource::<RenderDevice>(),
+            world.get_resource::<RenderAdapter>(),
+            world.get_resource::<RenderAdapterInfo>(),
+        ) else {
+            return;
+        };
+        let capabilities = RaytraceCapabilities::new(render_device, render_adapter, adapter_info);
+        if !capabilities.supported() {
+            warn!(
+                "{} can't run the raytracer, traced cameras show the rasterized scene instead: {}",
+                capabilities.adapter,
+                capabilities.missing.join(", ")
+            );
+        }
+
+        app.insert_resource(capabilities.clone());
+        if let Some(render_app) = app.get_sub_app_mut(RenderApp) {
+            render_app.insert_resource(capabilities);
+        }
+    }
+}
+
+// What the GPU the app renders with offers the tracer, read once the renderer is set up.
+// On devices missing something the tracer needs, traced cameras fall back to `Raytracing::FallbackRaster` with a warning
+// and no raytrace pipeline gets compiled, so they show the rasterized scene instead of failing
+#[derive(Resource, Reflect, Clone, Default, Debug)]
+#[reflect(Resource)]
+pub struct RaytraceCapabilities {
+    pub adapter: String,
+    // Bigger buffers get split into chunks of this size, see `RaytraceMemoryBudget`
+    pub max_storage_buffer_binding_size: u64,
+    pub max_storage_buffers_per_shader_stage: u32,
+    pub max_storage_textures_per_shader_stage: u32,
+    // Fragment shaders may write to storage buffers and textures, which WebGL2 doesn't allow
+    pub fragment_writable_storage: bool,
+    // The formats of the accumulation can be written as storage textures
+    pub accumulation_formats: bool,
+    // Hardware ray queries, the tracer doesn't use them yet
+    pub ray_query: bool,
+    // What keeps the tracer from running, empty if nothing does
+    pub missing: Vec<String>,
+}
+
+impl RaytraceCapabilities {
+    fn new(
+        render_device: &RenderDevice,
+        render_adapter: &RenderAdapter,
+        adapter_info: &RenderAdapterInfo,
+    ) -> Self {
+        let limits = render_device.limits();
+        let fragment_writable_storage = render_adapter
+            .get_downlevel_capabilities()
+            .flags
+            .contains(DownlevelFlags::FRAGMENT_WRITABLE_STORAGE);
+        let accumulation_formats =
+            [ACCUMULATION_FORMAT, MOMENTS_FORMAT]
+                .into_iter()
+                .all(|format: TextureFormat| {
+                    render_adapter
+                        .get_texture_format_features(format)
+                        .allowed_usages
+                        .contains(TextureUsages::STORAGE_BINDING)
+                });
+
+        let mut missing = Vec::new();
+        if !fragment_writable_storage {
+            missing.push("storage writes from fragment shaders".to_string());
+        }
+        if !accumulation_formats {
+            missing.push(format!(
+                "{ACCUMULATION_FORMAT:?} and {MOMENTS_FORMAT:?} storage textures"
+            ));
+        }
+        if limits.max_storage_buffers_per_shader_stage < REQUIRED_STORAGE_BUFFERS {
+            missing.push(format!(
+                "{REQUIRED_STORAGE_BUFFERS} storage buffers per shader stage, it has {}",
+                limits.max_storage_buffers_per_shader_stage
+            ));
+        }
+        if limits.max_storage_textures_per_shader_stage < REQUIRED_STORAGE_TEXTURES {
+            missing.push(format!(
+                "{REQUIRED_STORAGE_TEXTURES} storage textures per shader stage, it has {}",
+                limits.max_storage_textures_per_shader_stage
+            ));
+        }
+
+        Self {
+            adapter: adapter_info.name.clone(),
+            max_storage_buffer_binding_size: limits.max_storage_buffer_binding_size.into(),
+            max_storage_buffers_per_shader_stage: limits.max_storage_buffers_per_shader_stage,
+            max_storage_textures_per_shader_stage: limits.max_storage_textures_per_shader_stage,
+            fragment_writable_storage,
+            accumulation_formats,
+            ray_query: render_device.features().contains(Features::RAY_QUERY),
+            missing,
+        }
+    }
+
+    pub fn supported(&self) -> bool {
+        self.missing.is_empty()
+    }
+}
+
+fn downgrade_unsupported_cameras(
+    capabilities: Option<Res<RaytraceCapabilities>>,
+    mut cameras: Query<(Entity, &mut RaytracedCamera), Changed<RaytracedCamera>>,
+) {
+    if capabilities.is_none_or(|capabilities| capabilities.supported()) {
+        return;
+    }
+
+    for (entity, mut camera) in &mut cameras {
+        if matches!(
+            camera.level,
+            Raytracing::Pure | Raytracing::FallbackRaytraced
+        ) {
+            warn!("RaytracedCamera on {entity} can't be traced on this device, using Raytracing::FallbackRaster instead");
+            camera.level = Raytracing::FallbackRaster;
+        }
+    }
+}
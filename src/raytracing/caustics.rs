@@ -0,0 +1,320 @@
+use bevy::{
+    prelude::*,
+    render::{
+        graph::CameraDriverLabel,
+        render_graph::{Node, NodeRunError, RenderGraph, RenderGraphContext, RenderLabel},
+        render_resource::{
+            binding_types::{storage_buffer_sized, uniform_buffer},
+            BindGroup, BindGroupEntries, BindGroupLayout, BindGroupLayoutEntries, Buffer,
+            BufferDescriptor, BufferUsages, CachedComputePipelineId, ComputePassDescriptor,
+            ComputePipelineDescriptor, PipelineCache, ShaderStages, ShaderType, UniformBuffer,
+        },
+        renderer::{RenderContext, RenderDevice, RenderQueue},
+        Extract, ExtractSchedule, Render, RenderApp, RenderSet,
+    },
+};
+use rand::{thread_rng, Rng};
+
+use super::{
+    extract::CameraExtract,
+    memory::MemoryReport,
+    origin::RenderOrigin,
+    pipeline::{geometry_bind_group, RaytracingPipeline},
+    RaytraceWhiteFurnace,
+};
+
+// Has to match the constants in caustics.wgsl
+const PHOTON_CELLS: u64 = 65536;
+const PHOTONS_PER_CELL: u64 = 8;
+// Two vec3 with the alignment of storage buffers
+const PHOTON_SIZE: u64 = 32;
+const WORKGROUP_SIZE: u32 = 64;
+
+#[derive(Debug, Hash, PartialEq, Eq, Clone, RenderLabel)]
+pub struct CausticsEmitLabel;
+
+pub struct RaytraceCausticsPlugin;
+
+impl Plugin for RaytraceCausticsPlugin {
+    fn build(&self, app: &mut App) {
+        app.register_type::<RaytraceCaustics>()
+            .init_resource::<RaytraceCaustics>();
+
+        let Some(render_app) = app.get_sub_app_mut(RenderApp) else {
+            return;
+        };
+
+        render_app
+            .init_resource::<ExtractedCaustics>()
+            .add_systems(ExtractSchedule, extract_caustics)
+            .add_systems(
+                Render,
+                prepare_caustics.in_set(RenderSet::PrepareBindGroups),
+            );
+
+        // The photon map has to be complete before any camera gathers from it
+        let mut render_graph = render_app.world_mut().resource_mut::<RenderGraph>();
+        render_graph.add_node(CausticsEmitLabel, CausticsEmitNode);
+        render_graph.add_node_edge(CausticsEmitLabel, CameraDriverLabel);
+    }
+
+    fn finish(&self, app: &mut App) {
+        let Some(render_app) = app.get_sub_app_mut(RenderApp) else {
+            return;
+        };
+
+        // The gather side lives in the main pipeline, so it has to be initialized after it
+        render_app
+            .init_resource::<CausticsEmitPipeline>()
+            .init_resource::<CausticsBuffers>();
+    }
+}
+
+// Photon mapping for caustics, the light focused onto diffuse surfaces by glass and mirrors.
+// Path tracing from the camera rarely finds these paths, so every frame photons are shot from the sky
+// and stored where they land on diffuse surfaces after at least one specular bounce.
+// The raytraced cameras then estimate the caustic light from the photons around every diffuse hit.
+// Photons are only shot at the sphere given by `center` and `radius`, which should contain the caustic casting objects.
+#[derive(Resource, Reflect, Clone)]
+#[reflect(Resource)]
+pub struct RaytraceCaustics {
+    pub enabled: bool,
+    pub photon_count: u32,
+    // Photons within this distance contribute to a point, smaller values are sharper but noisier
+    pub gather_radius: f32,
+    pub center: Vec3,
+    pub radius: f32,
+}
+
+impl Default for RaytraceCaustics {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            photon_count: 65536,
+            gather_radius: 0.1,
+            center: Vec3::ZERO,
+            radius: 12.0,
+        }
+    }
+}
+
+#[derive(Clone, Default, ShaderType)]
+pub struct CausticsUniform {
+    enabled: u32,
+    photon_count: u32,
+    gather_radius: f32,
+    random_seed: f32,
+    center: Vec3,
+    radius: f32,
+}
+
+#[derive(Resource, Default, Deref)]
+pub struct ExtractedCaustics(CausticsUniform);
+
+fn extract_caustics(
+    mut extracted: ResMut<ExtractedCaustics>,
+    caustics: Extract<Res<RaytraceCaustics>>,
+    furnace: Extract<Res<RaytraceWhiteFurnace>>,
+    origin: Extract<Res<RenderOrigin>>,
+) {
+    let mut rng = thread_rng();
+    extracted.0 = CausticsUniform {
+        // The photons come from the lights, which the white furnace leaves out
+        enabled: (caustics.enabled && !furnace.enabled).into(),
+        photon_count: caustics.photon_count,
+        gather_radius: caustics.gather_radius.max(0.001),
+        random_seed: rng.gen_range(0.0..1.0),
+        center: origin.point(caustics.center),
+        radius: caustics.radius,
+    };
+}
+
+// The photon map is a hashed grid with a fixed amount of slots per cell
+#[derive(Resource)]
+pub struct CausticsBuffers {
+    uniform: UniformBuffer<CausticsUniform>,
+    photon_counts: Buffer,
+    photons: Buffer,
+    emit_bind_group: Option<BindGroup>,
+    pub(super) gather_bind_group: Option<BindGroup>,
+    // No photons are needed while every camera holds a converged image
+    emit: bool,
+}
+
+impl FromWorld for CausticsBuffers {
+    fn from_world(world: &mut World) -> Self {
+        let render_device = world.resource::<RenderDevice>();
+
+        let photon_counts = render_device.create_buffer(&BufferDescriptor {
+            label: Some("caustics_photon_counts"),
+            size: PHOTON_CELLS * 4,
+            usage: BufferUsages::STORAGE | BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        let photons = render_device.create_buffer(&BufferDescriptor {
+            label: Some("caustics_photons"),
+            size: PHOTON_CELLS * PHOTONS_PER_CELL * PHOTON_SIZE,
+            usage: BufferUsages::STORAGE,
+            mapped_at_creation: false,
+        });
+
+        Self {
+            uniform: UniformBuffer::default(),
+            photon_counts,
+            photons,
+            emit_bind_group: None,
+            gather_bind_group: None,
+            emit: false,
+        }
+    }
+}
+
+fn prepare_caustics(
+    extracted: Res<ExtractedCaustics>,
+    mut buffers: ResMut<CausticsBuffers>,
+    emit_pipeline: Res<CausticsEmitPipeline>,
+    raytrace_pipeline: Res<RaytracingPipeline>,
+    views: Query<&CameraExtract>,
+    render_device: Res<RenderDevice>,
+    render_queue: Res<RenderQueue>,
+    memory_report: Res<MemoryReport>,
+) {
+    let buffers = &mut *buffers;
+    buffers.emit = views.iter().any(|camera| !camera.holds());
+    memory_report.record("photon map", buffers.photons.size(), 1);
+    memory_report.record("photon counts", buffers.photon_counts.size(), 1);
+
+    buffers.uniform.set(extracted.0.clone());
+    buffers.uniform.write_buffer(&render_device, &render_queue);
+
+    let Some(uniform_binding) = buffers.uniform.binding() else {
+        return;
+    };
+
+    let entries = BindGroupEntries::sequential((
+        uniform_binding,
+        buffers.photon_counts.as_entire_binding(),
+        buffers.photons.as_entire_binding(),
+    ));
+
+    buffers.emit_bind_group = Some(render_device.create_bind_group(
+        "caustics_emit_bind_group",
+        &emit_pipeline.layout,
+        &entries,
+    ));
+    buffers.gather_bind_group = Some(render_device.create_bind_group(
+        "caustics_gather_bind_group",
+        &raytrace_pipeline.caustics_layout,
+        &entries,
+    ));
+}
+
+// Clears the photon map and fills it with this frame's photons
+pub struct CausticsEmitNode;
+
+impl Node for CausticsEmitNode {
+    fn run(
+        &self,
+        _graph: &mut RenderGraphContext,
+        render_context: &mut RenderContext,
+        world: &World,
+    ) -> Result<(), NodeRunError> {
+        let caustics = world.resource::<ExtractedCaustics>();
+        if caustics.enabled == 0 {
+            return Ok(());
+        }
+
+        let buffers = world.resource::<CausticsBuffers>();
+        if !buffers.emit {
+            return Ok(());
+        }
+
+        let Some(emit_bind_group) = &buffers.emit_bind_group else {
+            return Ok(());
+        };
+
+        let emit_pipeline = world.resource::<CausticsEmitPipeline>();
+        let pipeline_cache = world.resource::<PipelineCache>();
+        let Some(pipeline) = pipeline_cache.get_compute_pipeline(emit_pipeline.pipeline_id) else {
+            return Ok(());
+        };
+
+        let Some(buffer_bind_group) = geometry_bind_group(
+            world,
+            render_context.render_device(),
+            &emit_pipeline.buffer_layout,
+            "caustics_geometry_bind_group",
+        ) else {
+            return Ok(());
+        };
+
+        render_context
+            .command_encoder()
+            .clear_buffer(&buffers.photon_counts, 0, None);
+
+        let mut compute_pass =
+            render_context
+                .command_encoder()
+                .begin_compute_pass(&ComputePassDescriptor {
+                    label: Some("caustics_emit_pass"),
+                    timestamp_writes: None,
+                });
+
+        compute_pass.set_pipeline(pipeline);
+        compute_pass.set_bind_group(0, emit_bind_group, &[]);
+        compute_pass.set_bind_group(1, &buffer_bind_group, &[]);
+        compute_pass.dispatch_workgroups(caustics.photon_count.div_ceil(WORKGROUP_SIZE), 1, 1);
+
+        Ok(())
+    }
+}
+
+#[derive(Resource)]
+pub struct CausticsEmitPipeline {
+    layout: BindGroupLayout,
+    buffer_layout: BindGroupLayout,
+    pipeline_id: CachedComputePipelineId,
+}
+
+impl FromWorld for CausticsEmitPipeline {
+    fn from_world(world: &mut World) -> Self {
+        let render_device = world.resource::<RenderDevice>();
+
+        let layout = render_device.create_bind_group_layout(
+            "caustics_emit_bind_group_layout",
+            &BindGroupLayoutEntries::sequential(
+                ShaderStages::COMPUTE,
+                (
+                    // The caustics settings
+                    uniform_buffer::<CausticsUniform>(false),
+                    // The amount of photons in every cell
+                    storage_buffer_sized(false, None),
+                    // The photons
+                    storage_buffer_sized(false, None),
+                ),
+            ),
+        );
+
+        let buffer_layout = world.resource::<RaytracingPipeline>().buffer_layout.clone();
+
+        let shader = world.load_asset("shaders/caustics_emit.wgsl");
+
+        let pipeline_id = world
+            .resource_mut::<PipelineCache>()
+            .queue_compute_pipeline(ComputePipelineDescriptor {
+                label: Some("caustics_emit_pipeline".into()),
+                layout: vec![layout.clone(), buffer_layout.clone()],
+                push_constant_ranges: vec![],
+                shader,
+                shader_defs: vec![],
+                entry_point: "emit".into(),
+            });
+
+        Self {
+            layout,
+            buffer_layout,
+            pipeline_id,
+        }
+    }
+}
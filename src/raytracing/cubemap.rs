@@ -0,0 +1,350 @@
+use std::f32::consts::FRAC_PI_2;
+
+use bevy::{
+    core::FrameCount,
+    prelude::*,
+    render::{
+        camera::RenderTarget,
+        graph::CameraDriverLabel,
+        render_asset::{RenderAssetUsages, RenderAssets},
+        render_graph::{Node, NodeRunError, RenderGraph, RenderGraphContext, RenderLabel},
+        render_resource::{
+            Extent3d, ImageCopyTexture, Origin3d, TextureAspect, TextureDimension, TextureFormat,
+            TextureUsages, TextureViewDescriptor, TextureViewDimension,
+        },
+        renderer::RenderContext,
+        texture::{BevyDefault, GpuImage},
+        Extract, ExtractSchedule, RenderApp,
+    },
+};
+
+use super::{CameraCut, RaytraceMode, RaytraceProgress, RaytraceSampling, RaytracedCamera};
+
+// Forward and up direction of every face camera in the layer order wgpu expects (+X, -X, +Y, -Y, +Z, -Z)
+// Bevy samples cubemaps with a negated z coordinate, which is why the z faces look in the opposite direction
+const FACES: [(Vec3, Vec3); 6] = [
+    (Vec3::X, Vec3::Y),
+    (Vec3::NEG_X, Vec3::Y),
+    (Vec3::Y, Vec3::Z),
+    (Vec3::NEG_Y, Vec3::NEG_Z),
+    (Vec3::NEG_Z, Vec3::Y),
+    (Vec3::Z, Vec3::Y),
+];
+
+#[derive(Debug, Hash, PartialEq, Eq, Clone, RenderLabel)]
+pub struct CubemapCopyLabel;
+
+pub struct RaytraceCubemapPlugin;
+
+impl Plugin for RaytraceCubemapPlugin {
+    fn build(&self, app: &mut App) {
+        app.observe(start_cubemap_capture).add_systems(
+            Update,
+            (
+                spawn_face_cameras,
+                sync_face_cameras,
+                finish_cubemap_captures,
+                despawn_face_cameras,
+            )
+                .chain(),
+        );
+
+        let Some(render_app) = app.get_sub_app_mut(RenderApp) else {
+            return;
+        };
+
+        render_app
+            .init_resource::<ExtractedCubemapCaptures>()
+            .add_systems(ExtractSchedule, extract_cubemap_captures);
+
+        // The copy runs once after all cameras have been rendered, so it lives in the main graph instead of the 3d one
+        let mut render_graph = render_app.world_mut().resource_mut::<RenderGraph>();
+        render_graph.add_node(CubemapCopyLabel, CubemapCopyNode);
+        render_graph.add_node_edge(CameraDriverLabel, CubemapCopyLabel);
+    }
+}
+
+// Renders the traced scene around this entity into all six faces of `image`, accumulating the `sample_count` of its
+// settings. Once every face has all of its samples the face cameras stop rendering and the image keeps what they traced,
+// a `CaptureCubemap` or changing the settings captures it again.
+// The image has a cube texture view, so it can directly be used for reflection probes of the raster pipeline.
+// The entity needs a transform, the faces are aligned with its rotation.
+#[derive(Component, Clone)]
+pub struct RaytraceCubemapCapture {
+    pub image: Handle<Image>,
+    pub settings: RaytracedCamera,
+    faces: [Handle<Image>; 6],
+}
+
+impl RaytraceCubemapCapture {
+    pub fn new(size: u32, settings: RaytracedCamera, images: &mut Assets<Image>) -> Self {
+        let face_size = Extent3d {
+            width: size,
+            height: size,
+            depth_or_array_layers: 1,
+        };
+
+        let faces = std::array::from_fn(|_| {
+            let mut face = Image::new_fill(
+                face_size,
+                TextureDimension::D2,
+                &[0, 0, 0, 255],
+                TextureFormat::bevy_default(),
+                RenderAssetUsages::default(),
+            );
+            face.texture_descriptor.usage = TextureUsages::TEXTURE_BINDING
+                | TextureUsages::COPY_SRC
+                | TextureUsages::COPY_DST
+                | TextureUsages::RENDER_ATTACHMENT;
+            images.add(face)
+        });
+
+        let mut cube = Image::new_fill(
+            Extent3d {
+                depth_or_array_layers: 6,
+                ..face_size
+            },
+            TextureDimension::D2,
+            &[0, 0, 0, 255],
+            TextureFormat::bevy_default(),
+            RenderAssetUsages::default(),
+        );
+        cube.texture_descriptor.usage |= TextureUsages::COPY_DST;
+        cube.texture_view_descriptor = Some(TextureViewDescriptor {
+            dimension: Some(TextureViewDimension::Cube),
+            ..default()
+        });
+
+        Self {
+            image: images.add(cube),
+            settings,
+            faces,
+        }
+    }
+}
+
+// Captures the cubemap of the `RaytraceCubemapCapture` on `capture` again, for when the scene around it changed
+#[derive(Event, Clone, Copy, Debug)]
+pub struct CaptureCubemap {
+    pub capture: Entity,
+}
+
+// Marks the cameras spawned for the individual faces of a capture
+#[derive(Component)]
+pub struct CubemapFaceCamera;
+
+// On captures while their faces are being traced, since the frame they were started in
+#[derive(Component)]
+struct CapturingCubemap {
+    since: u32,
+}
+
+// The faces accumulate their samples even if the capture traces a fresh image every frame, so they have an end
+fn face_settings(settings: RaytracedCamera) -> RaytracedCamera {
+    let samples_per_frame = match settings.sampling {
+        RaytraceSampling::Progressive { samples_per_frame } => samples_per_frame,
+        RaytraceSampling::EveryFrame | RaytraceSampling::Checkerboard => settings.sample_count,
+    };
+    let mut face_settings = settings;
+    face_settings.set_mode(RaytraceMode::Final {
+        samples: settings.sample_count,
+        samples_per_frame,
+    });
+    face_settings
+}
+
+fn start_cubemap_capture(
+    trigger: Trigger<CaptureCubemap>,
+    captures: Query<&Children, With<RaytraceCubemapCapture>>,
+    mut face_cameras: Query<(Entity, &mut Camera), With<CubemapFaceCamera>>,
+    frame_count: Res<FrameCount>,
+    mut cuts: EventWriter<CameraCut>,
+    mut cmd: Commands,
+) {
+    let capture = trigger.event().capture;
+    let Ok(children) = captures.get(capture) else {
+        return;
+    };
+
+    // Starts over even if nothing the faces know about changed
+    let mut iter = face_cameras.iter_many_mut(children);
+    while let Some((face_camera, mut camera)) = iter.fetch_next() {
+        camera.is_active = true;
+        cuts.send(CameraCut {
+            camera: face_camera,
+        });
+    }
+    cmd.entity(capture).insert(CapturingCubemap {
+        since: frame_count.0,
+    });
+}
+
+fn spawn_face_cameras(
+    captures: Query<(Entity, &RaytraceCubemapCapture), Added<RaytraceCubemapCapture>>,
+    frame_count: Res<FrameCount>,
+    mut cmd: Commands,
+) {
+    for (entity, capture) in &captures {
+        cmd.entity(entity).insert(CapturingCubemap {
+            since: frame_count.0,
+        });
+        cmd.entity(entity).with_children(|parent| {
+            for (face, (forward, up)) in capture.faces.iter().zip(FACES) {
+                parent.spawn((
+                    Camera3dBundle {
+                        camera: Camera {
+                            target: RenderTarget::Image(face.clone()),
+                            ..default()
+                        },
+                        projection: Projection::Perspective(PerspectiveProjection {
+                            fov: FRAC_PI_2,
+                            aspect_ratio: 1.0,
+                            ..default()
+                        }),
+                        transform: Transform::IDENTITY.looking_to(forward, up),
+                        ..default()
+                    },
+                    face_settings(capture.settings),
+                    CubemapFaceCamera,
+                ));
+            }
+        });
+    }
+}
+
+// New settings trace a new image, so the capture starts again
+fn sync_face_cameras(
+    captures: Query<(Entity, &RaytraceCubemapCapture, &Children), Changed<RaytraceCubemapCapture>>,
+    mut face_cameras: Query<(&mut Camera, &mut RaytracedCamera), With<CubemapFaceCamera>>,
+    frame_count: Res<FrameCount>,
+    mut cmd: Commands,
+) {
+    for (entity, capture, children) in &captures {
+        let mut iter = face_cameras.iter_many_mut(children);
+        while let Some((mut camera, mut settings)) = iter.fetch_next() {
+            camera.is_active = true;
+            *settings = face_settings(capture.settings);
+        }
+        cmd.entity(entity).insert(CapturingCubemap {
+            since: frame_count.0,
+        });
+    }
+}
+
+// Stops the face cameras once all of them have their samples
+fn finish_cubemap_captures(
+    captures: Query<(Entity, &CapturingCubemap, &Children)>,
+    mut face_cameras: Query<(Entity, &mut Camera), With<CubemapFaceCamera>>,
+    progress: Res<RaytraceProgress>,
+    frame_count: Res<FrameCount>,
+    mut cmd: Commands,
+) {
+    for (entity, capturing, children) in &captures {
+        // The progress can still be from before the start for a frame while the render world is behind
+        if frame_count.0.wrapping_sub(capturing.since) < 2 {
+            continue;
+        }
+
+        let faces = face_cameras.iter_many(children).collect::<Vec<_>>();
+        let converged = faces.len() == FACES.len()
+            && faces.iter().all(|(face_camera, _)| {
+                progress
+                    .get(*face_camera)
+                    .is_some_and(|progress| progress.converged())
+            });
+        if !converged {
+            continue;
+        }
+
+        let mut iter = face_cameras.iter_many_mut(children);
+        while let Some((_, mut camera)) = iter.fetch_next() {
+            camera.is_active = false;
+        }
+        cmd.entity(entity).remove::<CapturingCubemap>();
+    }
+}
+
+fn despawn_face_cameras(
+    mut removed: RemovedComponents<RaytraceCubemapCapture>,
+    children: Query<&Children>,
+    face_cameras: Query<Entity, With<CubemapFaceCamera>>,
+    mut cmd: Commands,
+) {
+    for entity in removed.read() {
+        let Ok(children) = children.get(entity) else {
+            continue;
+        };
+
+        for face_camera in face_cameras.iter_many(children) {
+            cmd.entity(face_camera).despawn_recursive();
+        }
+    }
+}
+
+pub struct ExtractedCubemapCapture {
+    image: AssetId<Image>,
+    faces: [AssetId<Image>; 6],
+}
+
+#[derive(Resource, Default, Deref, DerefMut)]
+pub struct ExtractedCubemapCaptures(Vec<ExtractedCubemapCapture>);
+
+// Only the captures whose faces are being traced, the others already hold their image
+fn extract_cubemap_captures(
+    mut extracted: ResMut<ExtractedCubemapCaptures>,
+    captures: Extract<Query<&RaytraceCubemapCapture, With<CapturingCubemap>>>,
+) {
+    extracted.clear();
+    extracted.extend(captures.iter().map(|capture| ExtractedCubemapCapture {
+        image: capture.image.id(),
+        faces: std::array::from_fn(|index| capture.faces[index].id()),
+    }));
+}
+
+// Copies the rendered faces into the layers of the cubemap
+pub struct CubemapCopyNode;
+
+impl Node for CubemapCopyNode {
+    fn run(
+        &self,
+        _graph: &mut RenderGraphContext,
+        render_context: &mut RenderContext,
+        world: &World,
+    ) -> Result<(), NodeRunError> {
+        let captures = world.resource::<ExtractedCubemapCaptures>();
+        let images = world.resource::<RenderAssets<GpuImage>>();
+
+        for capture in captures.iter() {
+            let Some(cube) = images.get(capture.image) else {
+                continue;
+            };
+
+            for (layer, face) in capture.faces.iter().enumerate() {
+                let Some(face) = images.get(*face) else {
+                    continue;
+                };
+
+                render_context.command_encoder().copy_texture_to_texture(
+                    face.texture.as_image_copy(),
+                    ImageCopyTexture {
+                        texture: &cube.texture,
+                        mip_level: 0,
+                        origin: Origin3d {
+                            x: 0,
+                            y: 0,
+                            z: layer as u32,
+                        },
+                        aspect: TextureAspect::All,
+                    },
+                    Extent3d {
+                        width: face.size.x,
+                        height: face.size.y,
+                        depth_or_array_layers: 1,
+                    },
+                );
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,175 @@
+use bevy::{
+    prelude::*,
+    render::{
+        render_resource::ShaderType,
+        renderer::{RenderDevice, RenderQueue},
+        Extract, ExtractSchedule, Render, RenderApp, RenderSet,
+    },
+    utils::HashMap,
+};
+
+use super::{
+    buffer::SceneBuffer, light::resample_image, memory::MemoryReport, origin::RenderOrigin,
+    RaytracedBillboard,
+};
+
+// Has to match the constant in scene.wgsl, decal and billboard images get resampled to this many texels
+const DECAL_RESOLUTION: usize = 128;
+
+pub struct RaytraceDecalPlugin;
+
+impl Plugin for RaytraceDecalPlugin {
+    fn build(&self, app: &mut App) {
+        app.register_type::<RaytraceDecal>();
+
+        let Some(render_app) = app.get_sub_app_mut(RenderApp) else {
+            return;
+        };
+
+        render_app
+            .init_resource::<DecalBuffer>()
+            .init_resource::<DecalTexelBuffer>()
+            .init_resource::<DecalImageCache>()
+            .init_resource::<ImageTexelOffsets>()
+            .add_systems(ExtractSchedule, extract_decals)
+            .add_systems(Render, prepare_decals.in_set(RenderSet::PrepareResources));
+    }
+}
+
+// Projects an image onto the traced surfaces inside the unit cube of the transform, for stickers and bullet holes
+// that also show up in reflections and through portals. The image faces along the forward direction of the transform
+// with its top towards the up direction, scale the transform to size it and set how deep it reaches.
+// Its alpha blends it over the base color, surfaces facing away from it are left alone
+#[derive(Component, Reflect, Clone)]
+pub struct RaytraceDecal(pub Handle<Image>);
+
+#[derive(Clone, PartialEq, ShaderType)]
+pub struct Decal {
+    // The decal is the unit cube in local space
+    local_from_world: Mat4,
+    forward: Vec3,
+    // Index of the first texel in the decal texels
+    texel_offset: u32,
+}
+
+// Bound with the geometry, decals show up in every pass that shades surfaces
+#[derive(Resource, Default, Deref, DerefMut)]
+pub struct DecalBuffer(SceneBuffer<Decal>);
+
+// The linear rgba texels of every decal and billboard image in use back to back
+#[derive(Resource, Default, Deref, DerefMut)]
+pub struct DecalTexelBuffer(SceneBuffer<Vec4>);
+
+// Where the texels of every image in use start in the `DecalTexelBuffer`, images used more than once share them
+#[derive(Resource, Default, Deref, DerefMut)]
+pub struct ImageTexelOffsets(HashMap<AssetId<Image>, u32>);
+
+// The resampled texels of every decal image in use, they only get read back from the images when those change
+#[derive(Resource, Default, Deref, DerefMut)]
+struct DecalImageCache(HashMap<AssetId<Image>, Vec<Vec4>>);
+
+fn extract_decals(
+    mut decal_buffer: ResMut<DecalBuffer>,
+    mut texel_buffer: ResMut<DecalTexelBuffer>,
+    mut cache: ResMut<DecalImageCache>,
+    mut offsets: ResMut<ImageTexelOffsets>,
+    decals: Extract<Query<(&RaytraceDecal, &GlobalTransform)>>,
+    billboards: Extract<Query<&RaytracedBillboard>>,
+    images: Extract<Res<Assets<Image>>>,
+    image_events: Extract<Res<Events<AssetEvent<Image>>>>,
+    origin: Extract<Res<RenderOrigin>>,
+    memory_report: Res<MemoryReport>,
+) {
+    for event in image_events.iter_current_update_events() {
+        match *event {
+            AssetEvent::Modified { id }
+            | AssetEvent::Removed { id }
+            | AssetEvent::Unused { id } => {
+                cache.remove(&id);
+            }
+            _ => {}
+        }
+    }
+
+    decal_buffer.clear();
+    texel_buffer.clear();
+    offsets.clear();
+    for (decal, transform) in &decals {
+        let Some(texel_offset) = image_texels(
+            decal.0.id(),
+            &images,
+            &mut cache,
+            &mut offsets,
+            &mut texel_buffer,
+        ) else {
+            continue;
+        };
+
+        decal_buffer.push(Decal {
+            local_from_world: origin.local_from_render(transform.compute_matrix()),
+            forward: transform.forward().as_vec3(),
+            texel_offset,
+        });
+    }
+
+    // The billboards look up their offsets when they are added to the models
+    for billboard in &billboards {
+        image_texels(
+            billboard.image.id(),
+            &images,
+            &mut cache,
+            &mut offsets,
+            &mut texel_buffer,
+        );
+    }
+
+    memory_report.record("decals", decal_buffer.size(), 1);
+    memory_report.record("decal and billboard images", texel_buffer.size(), 1);
+}
+
+// Where the texels of the image start, adding them if they aren't in the buffer yet.
+// None until the image is loaded or if its format isn't supported
+fn image_texels(
+    id: AssetId<Image>,
+    images: &Assets<Image>,
+    cache: &mut DecalImageCache,
+    offsets: &mut ImageTexelOffsets,
+    texel_buffer: &mut DecalTexelBuffer,
+) -> Option<u32> {
+    if !cache.contains_key(&id) {
+        let image = images.get(id)?;
+        let Some(texels) = resample_image(image, DECAL_RESOLUTION) else {
+            warn!(
+                "Decal and billboard images with the format {:?} are not supported, they are left out",
+                image.texture_descriptor.format
+            );
+            // Cached anyway, so the warning doesn't repeat every frame
+            cache.insert(id, Vec::new());
+            return None;
+        };
+        cache.insert(
+            id,
+            texels.into_iter().map(|texel| texel.to_vec4()).collect(),
+        );
+    }
+
+    let texels = &cache[&id];
+    if texels.is_empty() {
+        return None;
+    }
+    Some(*offsets.entry(id).or_insert_with(|| {
+        let offset = texel_buffer.len() as u32;
+        texel_buffer.extend(texels.iter().copied());
+        offset
+    }))
+}
+
+fn prepare_decals(
+    mut decal_buffer: ResMut<DecalBuffer>,
+    mut texel_buffer: ResMut<DecalTexelBuffer>,
+    render_device: Res<RenderDevice>,
+    render_queue: Res<RenderQueue>,
+) {
+    decal_buffer.write_buffer(&render_device, &render_queue);
+    texel_buffer.write_buffer(&render_device, &render_queue);
+}
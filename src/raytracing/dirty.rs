@@ -0,0 +1,216 @@
+use bevy::{prelude::*, transform::TransformSystem};
+
+use super::{
+    RaytraceCaustics, RaytraceDecal, RaytraceDensityVolume, RaytraceDiskLight, RaytraceDispersion,
+    RaytraceFogVolume, RaytraceIesProfile, RaytraceInstanceMask, RaytraceLightCookie,
+    RaytraceLightLink, RaytraceMaterialId, RaytraceMaterialOverride, RaytraceMaterialPalette,
+    RaytraceMirror, RaytraceParticles, RaytracePortal, RaytracePrecisePosition,
+    RaytraceReflectionOnly, RaytraceShadowCatcher, RaytraceSky, RaytraceWhiteFurnace,
+    RaytracedBillboard, RaytracedCuboid, RaytracedHeightfield, RaytracedPlane, RaytracedSphere,
+};
+
+pub struct RaytraceDirtyPlugin;
+
+impl Plugin for RaytraceDirtyPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<RaytraceSceneState>()
+            .add_event::<RaytraceSceneDirty>()
+            .add_systems(
+                PostUpdate,
+                detect_scene_changes.after(TransformSystem::TransformPropagate),
+            );
+    }
+}
+
+// Sent in every frame the traced scene looks different than in the last one, because objects or lights got added,
+// moved or removed, materials changed or textures finished loading. Anything that accumulates over frames
+// should start over on it, the accumulation of the cameras and lightmap bakes already do
+#[derive(Event, Clone, Copy, Debug, Default)]
+pub struct RaytraceSceneDirty;
+
+// Edits the change detection can't see, like writing into a texture the scene samples from a custom render pass,
+// can be reported with `mark_dirty`, which sends a `RaytraceSceneDirty` this frame
+#[derive(Resource, Default)]
+pub struct RaytraceSceneState {
+    dirty: bool,
+}
+
+impl RaytraceSceneState {
+    pub fn mark_dirty(&mut self) {
+        self.dirty = true;
+    }
+}
+
+fn detect_scene_changes(
+    mut state: ResMut<RaytraceSceneState>,
+    mut dirty: EventWriter<RaytraceSceneDirty>,
+    // Added and moved objects, lights and materials that got swapped out
+    changed_objects: Query<
+        (),
+        (
+            Or<(
+                Changed<GlobalTransform>,
+                Changed<Handle<StandardMaterial>>,
+                Changed<RaytraceMaterialOverride>,
+                Changed<RaytracedSphere>,
+                Changed<RaytracedHeightfield>,
+                Changed<RaytraceFogVolume>,
+                Changed<RaytracePortal>,
+                Changed<RaytraceMirror>,
+                Changed<RaytraceDensityVolume>,
+                Changed<RaytraceDispersion>,
+                Changed<RaytraceDecal>,
+                Changed<RaytraceInstanceMask>,
+                Changed<RaytraceShadowCatcher>,
+                Changed<RaytracePrecisePosition>,
+                // Nested, a single `Or` only takes so many filters
+                Or<(
+                    Changed<PointLight>,
+                    Changed<SpotLight>,
+                    Changed<RaytraceIesProfile>,
+                    Changed<RaytraceLightCookie>,
+                    Changed<RaytraceDiskLight>,
+                    Changed<RaytraceLightLink>,
+                    Changed<RaytraceParticles>,
+                    Changed<RaytracedBillboard>,
+                    Changed<RaytraceReflectionOnly>,
+                    Changed<RaytracedCuboid>,
+                    Changed<RaytracedPlane>,
+                    Changed<RaytraceMaterialId>,
+                    Changed<InheritedVisibility>,
+                )>,
+            )>,
+            Or<(
+                With<Handle<StandardMaterial>>,
+                With<RaytraceMaterialId>,
+                With<RaytraceFogVolume>,
+                With<RaytracePortal>,
+                With<RaytraceMirror>,
+                With<RaytraceDensityVolume>,
+                With<PointLight>,
+                With<SpotLight>,
+                With<RaytraceDecal>,
+            )>,
+        ),
+    >,
+    mut removed_objects: (
+        // Nested, a system parameter tuple only takes so many
+        (
+            RemovedComponents<RaytracedSphere>,
+            RemovedComponents<RaytracedCuboid>,
+            RemovedComponents<RaytracedPlane>,
+        ),
+        RemovedComponents<RaytracedHeightfield>,
+        RemovedComponents<RaytraceFogVolume>,
+        RemovedComponents<RaytracePortal>,
+        RemovedComponents<RaytraceMirror>,
+        RemovedComponents<RaytraceDensityVolume>,
+        RemovedComponents<PointLight>,
+        RemovedComponents<SpotLight>,
+        RemovedComponents<RaytraceDecal>,
+        RemovedComponents<RaytraceInstanceMask>,
+        RemovedComponents<RaytraceLightLink>,
+        RemovedComponents<RaytraceShadowCatcher>,
+        RemovedComponents<RaytracePrecisePosition>,
+        RemovedComponents<RaytraceParticles>,
+        RemovedComponents<RaytracedBillboard>,
+        RemovedComponents<RaytraceReflectionOnly>,
+    ),
+    // Materials edited in place, like from the inspector, only matter if something traced uses them
+    mut material_events: EventReader<AssetEvent<StandardMaterial>>,
+    traced_materials: Query<
+        &Handle<StandardMaterial>,
+        Or<(
+            With<RaytracedSphere>,
+            With<RaytracedCuboid>,
+            With<RaytracedPlane>,
+            With<RaytracedHeightfield>,
+            With<RaytraceParticles>,
+            With<RaytracedBillboard>,
+        )>,
+    >,
+    palette: Res<RaytraceMaterialPalette>,
+    // Heightmaps, cookies, decals, density textures and environment maps that finished loading or got edited
+    mut image_events: EventReader<AssetEvent<Image>>,
+    sky: Res<RaytraceSky>,
+    caustics: Res<RaytraceCaustics>,
+    furnace: Res<RaytraceWhiteFurnace>,
+) {
+    // Every reader has to be drained, or the same events show up again next frame
+    let (
+        (spheres, cuboids, planes),
+        heightfields,
+        fog_volumes,
+        portals,
+        mirrors,
+        density_volumes,
+        point_lights,
+        spot_lights,
+        decals,
+        instance_masks,
+        light_links,
+        shadow_catchers,
+        precise_positions,
+        particles,
+        billboards,
+        reflection_only,
+    ) = &mut removed_objects;
+    let removed = [
+        spheres.read().count(),
+        cuboids.read().count(),
+        planes.read().count(),
+        heightfields.read().count(),
+        fog_volumes.read().count(),
+        portals.read().count(),
+        mirrors.read().count(),
+        density_volumes.read().count(),
+        point_lights.read().count(),
+        spot_lights.read().count(),
+        decals.read().count(),
+        instance_masks.read().count(),
+        light_links.read().count(),
+        shadow_catchers.read().count(),
+        precise_positions.read().count(),
+        particles.read().count(),
+        billboards.read().count(),
+        reflection_only.read().count(),
+    ]
+    .iter()
+    .any(|&count| count > 0);
+    let modified_materials = material_events
+        .read()
+        .filter_map(|event| match event {
+            AssetEvent::Modified { id } | AssetEvent::LoadedWithDependencies { id } => Some(*id),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    let materials_changed = !modified_materials.is_empty()
+        && traced_materials
+            .iter()
+            .chain(palette.iter())
+            .any(|material| modified_materials.contains(&material.id()));
+    let images_changed = image_events
+        .read()
+        .filter(|event| {
+            matches!(
+                event,
+                AssetEvent::Modified { .. } | AssetEvent::LoadedWithDependencies { .. }
+            )
+        })
+        .count()
+        > 0;
+
+    let manual = std::mem::take(&mut state.dirty);
+    if manual
+        || !changed_objects.is_empty()
+        || removed
+        || materials_changed
+        || images_changed
+        || palette.is_changed()
+        || sky.is_changed()
+        || caustics.is_changed()
+        || furnace.is_changed()
+    {
+        dirty.send(RaytraceSceneDirty);
+    }
+}
@@ -0,0 +1,261 @@
+use bevy::{
+    core_pipeline::Skybox,
+    pbr::environment_map::EnvironmentMapLight,
+    prelude::*,
+    render::{
+        render_resource::{StorageBuffer, TextureFormat},
+        renderer::{RenderDevice, RenderQueue},
+        Extract, ExtractSchedule, Render, RenderApp, RenderSet,
+    },
+    utils::HashMap,
+};
+
+use super::{extract::camera_environment, memory::MemoryReport, RaytracedCamera};
+
+// Has to match the constant in environment.wgsl, the cdf has this many cells along both sides of every face
+const ENVIRONMENT_CDF_RESOLUTION: u32 = 32;
+
+pub struct RaytraceEnvironmentPlugin;
+
+impl Plugin for RaytraceEnvironmentPlugin {
+    fn build(&self, app: &mut App) {
+        let Some(render_app) = app.get_sub_app_mut(RenderApp) else {
+            return;
+        };
+
+        render_app
+            .init_resource::<EnvironmentCdfCache>()
+            .add_systems(ExtractSchedule, extract_environment_cdfs)
+            .add_systems(
+                Render,
+                prepare_environment_cdfs.in_set(RenderSet::PrepareResources),
+            );
+    }
+
+    fn finish(&self, app: &mut App) {
+        let Some(render_app) = app.get_sub_app_mut(RenderApp) else {
+            return;
+        };
+
+        render_app.init_resource::<EnvironmentCdfBuffers>();
+    }
+}
+
+// The cdf of every environment map in use, built from the image data when it changes.
+// Maps that can't be read on the CPU get an empty cdf and are only reached by the paths themselves.
+#[derive(Resource, Default, Deref, DerefMut)]
+pub struct EnvironmentCdfCache(HashMap<AssetId<Image>, Vec<f32>>);
+
+fn extract_environment_cdfs(
+    mut cache: ResMut<EnvironmentCdfCache>,
+    mut buffers: ResMut<EnvironmentCdfBuffers>,
+    cameras: Extract<Query<(Option<&Skybox>, Option<&EnvironmentMapLight>), With<RaytracedCamera>>>,
+    images: Extract<Res<Assets<Image>>>,
+    image_events: Extract<Res<Events<AssetEvent<Image>>>>,
+) {
+    for event in image_events.iter_current_update_events() {
+        match *event {
+            AssetEvent::Modified { id }
+            | AssetEvent::Removed { id }
+            | AssetEvent::Unused { id } => {
+                cache.remove(&id);
+                buffers.buffers.remove(&id);
+            }
+            _ => {}
+        }
+    }
+
+    for (skybox, light) in &cameras {
+        let Some((id, _)) = camera_environment(skybox, light) else {
+            continue;
+        };
+        if cache.contains_key(&id) {
+            continue;
+        }
+
+        if let Some(image) = images.get(id) {
+            cache.insert(id, environment_cdf(image));
+        }
+    }
+}
+
+// Splits every face into cells and weights them by their brightness and the solid angle they cover
+fn environment_cdf(image: &Image) -> Vec<f32> {
+    let descriptor = &image.texture_descriptor;
+    if descriptor.array_layer_count() != 6 {
+        warn!("Environment maps have to be cubemaps to be importance sampled");
+        return Vec::new();
+    }
+
+    let (texel_size, luminance): (usize, fn(&[u8]) -> f32) = match descriptor.format {
+        TextureFormat::Rgba32Float => (16, |texel| {
+            luminance(
+                f32::from_le_bytes([texel[0], texel[1], texel[2], texel[3]]),
+                f32::from_le_bytes([texel[4], texel[5], texel[6], texel[7]]),
+                f32::from_le_bytes([texel[8], texel[9], texel[10], texel[11]]),
+            )
+        }),
+        TextureFormat::Rgba16Float => (8, |texel| {
+            luminance(
+                f16_to_f32(u16::from_le_bytes([texel[0], texel[1]])),
+                f16_to_f32(u16::from_le_bytes([texel[2], texel[3]])),
+                f16_to_f32(u16::from_le_bytes([texel[4], texel[5]])),
+            )
+        }),
+        TextureFormat::Rgb9e5Ufloat => (4, |texel| {
+            let bits = u32::from_le_bytes([texel[0], texel[1], texel[2], texel[3]]);
+            let scale = 2f32.powi((bits >> 27) as i32 - 24);
+            luminance(
+                (bits & 0x1ff) as f32 * scale,
+                ((bits >> 9) & 0x1ff) as f32 * scale,
+                ((bits >> 18) & 0x1ff) as f32 * scale,
+            )
+        }),
+        // Low dynamic range maps barely benefit, but they are cheap to support
+        TextureFormat::Rgba8Unorm | TextureFormat::Rgba8UnormSrgb => (4, |texel| {
+            luminance(
+                f32::from(texel[0]) / 255.0,
+                f32::from(texel[1]) / 255.0,
+                f32::from(texel[2]) / 255.0,
+            )
+        }),
+        format => {
+            warn!("Environment maps with the format {format:?} can't be importance sampled");
+            return Vec::new();
+        }
+    };
+
+    let size = descriptor.size.width as usize;
+    let resolution = ENVIRONMENT_CDF_RESOLUTION as usize;
+    // The faces are stored one after the other, each followed by its mips
+    let face_stride = image.data.len() / 6;
+    if size == 0 || face_stride < size * size * texel_size {
+        return Vec::new();
+    }
+
+    let mut weights = Vec::with_capacity(6 * resolution * resolution);
+    for face in 0..6 {
+        let data = &image.data[face * face_stride..];
+        for y in 0..resolution {
+            for x in 0..resolution {
+                let (x0, x1) = cell_range(x, resolution, size);
+                let (y0, y1) = cell_range(y, resolution, size);
+
+                let mut sum = 0.0;
+                for texel_y in y0..y1 {
+                    for texel_x in x0..x1 {
+                        let offset = (texel_y * size + texel_x) * texel_size;
+                        sum += luminance(&data[offset..offset + texel_size]).max(0.0);
+                    }
+                }
+                let average = sum / ((x1 - x0) * (y1 - y0)) as f32;
+
+                // Cells towards the corners of a face cover less of the sphere
+                let s = (x as f32 + 0.5) / resolution as f32 * 2.0 - 1.0;
+                let t = (y as f32 + 0.5) / resolution as f32 * 2.0 - 1.0;
+                let d = 1.0 + s * s + t * t;
+                weights.push(average / (d * d.sqrt()));
+            }
+        }
+    }
+
+    let total: f32 = weights.iter().sum();
+    if !total.is_finite() || total <= 0.0 {
+        return Vec::new();
+    }
+
+    // Filtering can bleed light into black cells, they keep a small chance so no light gets lost
+    let floor = total * 1e-3 / weights.len() as f32;
+    let total = total + floor * weights.len() as f32;
+    let mut cumulative = 0.0;
+    let mut cdf: Vec<f32> = weights
+        .into_iter()
+        .map(|weight| {
+            cumulative += (weight + floor) / total;
+            cumulative
+        })
+        .collect();
+    if let Some(last) = cdf.last_mut() {
+        *last = 1.0;
+    }
+    cdf
+}
+
+// The texels of a face covered by a cell, at least one if the face is smaller than the cdf
+fn cell_range(cell: usize, resolution: usize, size: usize) -> (usize, usize) {
+    let start = (cell * size / resolution).min(size - 1);
+    let end = ((cell + 1) * size / resolution).max(start + 1);
+    (start, end)
+}
+
+fn luminance(r: f32, g: f32, b: f32) -> f32 {
+    0.2126 * r + 0.7152 * g + 0.0722 * b
+}
+
+fn f16_to_f32(bits: u16) -> f32 {
+    let sign = if bits & 0x8000 == 0 { 1.0 } else { -1.0 };
+    let exponent = i32::from((bits >> 10) & 0x1f);
+    let mantissa = f32::from(bits & 0x3ff);
+    match exponent {
+        0 => sign * mantissa * 2f32.powi(-24),
+        // Infinity and NaN would break the cdf
+        31 => 0.0,
+        _ => sign * (1.0 + mantissa / 1024.0) * 2f32.powi(exponent - 15),
+    }
+}
+
+#[derive(Resource)]
+pub struct EnvironmentCdfBuffers {
+    buffers: HashMap<AssetId<Image>, StorageBuffer<Vec<f32>>>,
+    // Bound when the environment can't be importance sampled, a single element tells the shader so
+    fallback: StorageBuffer<Vec<f32>>,
+}
+
+impl EnvironmentCdfBuffers {
+    pub(super) fn get(&self, image: Option<AssetId<Image>>) -> &StorageBuffer<Vec<f32>> {
+        image
+            .and_then(|image| self.buffers.get(&image))
+            .unwrap_or(&self.fallback)
+    }
+}
+
+impl FromWorld for EnvironmentCdfBuffers {
+    fn from_world(world: &mut World) -> Self {
+        let mut fallback = StorageBuffer::from(vec![0.0]);
+        fallback.write_buffer(
+            world.resource::<RenderDevice>(),
+            world.resource::<RenderQueue>(),
+        );
+
+        Self {
+            buffers: HashMap::default(),
+            fallback,
+        }
+    }
+}
+
+fn prepare_environment_cdfs(
+    cache: Res<EnvironmentCdfCache>,
+    mut buffers: ResMut<EnvironmentCdfBuffers>,
+    render_device: Res<RenderDevice>,
+    render_queue: Res<RenderQueue>,
+    memory_report: Res<MemoryReport>,
+) {
+    let bytes = buffers
+        .buffers
+        .values()
+        .filter_map(|buffer| buffer.buffer())
+        .map(|buffer| buffer.size())
+        .sum();
+    memory_report.record("environment cdfs", bytes, 1);
+
+    for (id, cdf) in cache.iter() {
+        if cdf.is_empty() || buffers.buffers.contains_key(id) {
+            continue;
+        }
+
+        let mut buffer = StorageBuffer::from(cdf.clone());
+        buffer.write_buffer(&render_device, &render_queue);
+        buffers.buffers.insert(*id, buffer);
+    }
+}
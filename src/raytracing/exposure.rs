@@ -0,0 +1,496 @@
+use std::{
+    ops::RangeInclusive,
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+use bevy::{
+    core_pipeline::core_3d::graph::{Core3d, Node3d},
+    ecs::query::QueryItem,
+    prelude::*,
+    render::{
+        camera::Exposure,
+        render_graph::{
+            NodeRunError, RenderGraphApp, RenderGraphContext, RenderLabel, ViewNode, ViewNodeRunner,
+        },
+        render_resource::{
+            binding_types::{storage_buffer_sized, texture_2d, uniform_buffer},
+            BindGroupEntries, BindGroupLayout, BindGroupLayoutEntries, Buffer, BufferDescriptor,
+            BufferUsages, CachedComputePipelineId, ComputePassDescriptor,
+            ComputePipelineDescriptor, PipelineCache, ShaderStages, ShaderType, TextureSampleType,
+            UniformBuffer,
+        },
+        renderer::{RenderContext, RenderDevice, RenderQueue},
+        view::ViewTarget,
+        Extract, ExtractSchedule, Render, RenderApp, RenderSet,
+    },
+    utils::HashMap,
+};
+
+use super::{
+    pipeline::ViewRaytracePipelines, readback::Readback, RaytraceLinearLabel, RaytraceOutput,
+    RaytracedCamera,
+};
+
+// Has to match exposure_histogram.wgsl
+const HISTOGRAM_BINS: usize = 64;
+const WORKGROUP_SIZE: u32 = 16;
+// The luminance the average of the metered pixels is exposed to
+const MIDDLE_GRAY: f32 = 0.18;
+// Closer to the target than this the exposure stays put, so the metering noise of a few samples doesn't make it wander
+const EV100_TOLERANCE: f32 = 0.05;
+
+#[derive(Debug, Hash, PartialEq, Eq, Clone, RenderLabel)]
+pub struct RaytraceExposureLabel;
+
+pub struct RaytraceExposurePlugin;
+
+impl Plugin for RaytraceExposurePlugin {
+    fn build(&self, app: &mut App) {
+        // Both worlds share the targets, the render world fills them in and the main world moves the exposure towards them
+        let targets = ExposureTargets::default();
+        app.insert_resource(targets.clone())
+            .register_type::<RaytraceAutoExposure>()
+            .add_systems(Update, apply_auto_exposure);
+
+        let Some(render_app) = app.get_sub_app_mut(RenderApp) else {
+            return;
+        };
+
+        render_app
+            .insert_resource(targets)
+            .init_resource::<ViewExposures>()
+            .add_systems(ExtractSchedule, extract_auto_exposure)
+            .add_systems(
+                Render,
+                (
+                    prepare_exposure_histograms.in_set(RenderSet::PrepareResources),
+                    read_back_exposure_histograms.in_set(RenderSet::Cleanup),
+                ),
+            );
+    }
+
+    fn finish(&self, app: &mut App) {
+        let Some(render_app) = app.get_sub_app_mut(RenderApp) else {
+            return;
+        };
+
+        // Meters the linear traced image before bloom adds to it, the linear node is only added by `RaytracePlugin`
+        render_app
+            .init_resource::<ExposureHistogramPipeline>()
+            .add_render_graph_node::<ViewNodeRunner<ExposureHistogramNode>>(
+                Core3d,
+                RaytraceExposureLabel,
+            )
+            .add_render_graph_edges(
+                Core3d,
+                (
+                    RaytraceLinearLabel,
+                    RaytraceExposureLabel,
+                    Node3d::MotionBlur,
+                ),
+            );
+    }
+}
+
+// Sets the `Exposure` of the camera from a luminance histogram of the traced image, so the average ends up middle gray.
+// Bevy's own auto exposure only sees the rasterized scene, this also works for scenes only lit by traced lights
+// and emissive spheres. The histogram is taken before bloom, so it needs `RaytraceOutput::LinearHdr` on an hdr camera.
+// It is read back from the GPU, so the exposure follows a few frames late
+#[derive(Component, Reflect, Clone)]
+#[reflect(Component)]
+pub struct RaytraceAutoExposure {
+    // The luminances the histogram covers in stops, darker pixels are left out and brighter ones count as the brightest
+    pub range: RangeInclusive<f32>,
+    // The share of the darkest and the brightest pixels that are left out, like shadows and highlights
+    pub filter: RangeInclusive<f32>,
+    // The ev100 the exposure stays within
+    pub ev100_range: RangeInclusive<f32>,
+    // How fast the exposure adapts, in stops per second
+    pub speed_brighten: f32,
+    pub speed_darken: f32,
+    // Stops added on top of middle gray, positive values brighten the image
+    pub compensation: f32,
+}
+
+impl Default for RaytraceAutoExposure {
+    fn default() -> Self {
+        Self {
+            range: -8.0..=8.0,
+            filter: 0.1..=0.9,
+            ev100_range: -4.0..=18.0,
+            speed_brighten: 3.0,
+            speed_darken: 1.0,
+            compensation: 0.0,
+        }
+    }
+}
+
+impl RaytraceAutoExposure {
+    // The ev100 that exposes the metered pixels of `histogram` to middle gray, taken while the camera was at `ev100`.
+    // None if none of the pixels were bright enough to be metered
+    fn target_ev100(&self, histogram: &[u32; HISTOGRAM_BINS], ev100: f32) -> Option<f32> {
+        let (min, max) = (*self.range.start(), *self.range.end());
+        let total = histogram.iter().map(|&count| u64::from(count)).sum::<u64>();
+        let first = (total as f32 * self.filter.start()) as u64;
+        let last = (total as f32 * self.filter.end()) as u64;
+
+        // Only what lies between the filtered out parts of the cumulative histogram counts
+        let mut below = u64::from(histogram[0]);
+        let mut count = 0;
+        let mut sum = 0.0;
+        for (bin, &bin_count) in histogram.iter().enumerate().skip(1) {
+            let above = below + u64::from(bin_count);
+            let metered = above.clamp(first, last) - below.clamp(first, last);
+            below = above;
+
+            // The center of the bin, the first one holds everything below the range
+            let log_luminance =
+                min + (bin as f32 - 0.5) / (HISTOGRAM_BINS - 2) as f32 * (max - min);
+            sum += metered as f32 * log_luminance;
+            count += metered;
+        }
+        if count == 0 {
+            return None;
+        }
+
+        // Every stop the image is too bright raises the ev100 by one
+        let average = sum / count as f32;
+        let target = ev100 + average - (MIDDLE_GRAY.log2() + self.compensation);
+        Some(target.clamp(*self.ev100_range.start(), *self.ev100_range.end()))
+    }
+}
+
+// The latest target ev100 of every camera with auto exposure
+#[derive(Resource, Clone, Default)]
+struct ExposureTargets(Arc<Mutex<HashMap<Entity, f32>>>);
+
+impl ExposureTargets {
+    fn lock(&self) -> MutexGuard<'_, HashMap<Entity, f32>> {
+        self.0
+            .lock()
+            .expect("Could not get exposure targets out of mutex")
+    }
+}
+
+fn apply_auto_exposure(
+    mut cameras: Query<(Entity, &RaytraceAutoExposure, &mut Exposure)>,
+    targets: Res<ExposureTargets>,
+    time: Res<Time>,
+) {
+    let mut targets = targets.lock();
+    targets.retain(|entity, _| cameras.contains(*entity));
+
+    for (entity, auto_exposure, mut exposure) in &mut cameras {
+        let Some(&target) = targets.get(&entity) else {
+            continue;
+        };
+
+        let difference = target - exposure.ev100;
+        if difference.abs() < EV100_TOLERANCE {
+            continue;
+        }
+
+        // A higher ev100 darkens the image
+        let step = difference.clamp(
+            -auto_exposure.speed_brighten * time.delta_seconds(),
+            auto_exposure.speed_darken * time.delta_seconds(),
+        );
+        exposure.ev100 += step;
+    }
+}
+
+#[derive(Clone, Default, ShaderType)]
+struct HistogramUniform {
+    min_log_luminance: f32,
+    inv_log_luminance_range: f32,
+}
+
+pub struct ViewExposure {
+    settings: RaytraceAutoExposure,
+    // The ev100 of the camera this frame
+    ev100: f32,
+    uniform: UniformBuffer<HistogramUniform>,
+    histogram: Buffer,
+    readback: Readback,
+    // Set while this frame's pixels get counted, with the ev100 they are traced at
+    counting: Option<f32>,
+    // The ev100 of the histogram on its way back
+    read_ev100: f32,
+}
+
+// The histograms of the cameras with auto exposure, these outlive the view entities which get cleared every frame
+#[derive(Resource, Default, Deref, DerefMut)]
+pub struct ViewExposures(HashMap<Entity, ViewExposure>);
+
+fn extract_auto_exposure(
+    mut exposures: ResMut<ViewExposures>,
+    cameras: Extract<
+        Query<(
+            Entity,
+            &Camera,
+            &RaytracedCamera,
+            &RaytraceAutoExposure,
+            Option<&Exposure>,
+        )>,
+    >,
+    render_device: Res<RenderDevice>,
+) {
+    let mut metered = Vec::new();
+    for (entity, camera, raytraced_camera, auto_exposure, exposure) in &cameras {
+        // Tonemapped output is drawn after tonemapping, there is nothing linear left to meter
+        if !camera.is_active || !camera.hdr || raytraced_camera.output != RaytraceOutput::LinearHdr
+        {
+            continue;
+        }
+        metered.push(entity);
+
+        let ev100 = exposure.copied().unwrap_or_default().ev100;
+        let view = exposures.entry(entity).or_insert_with(|| {
+            let size = (HISTOGRAM_BINS * std::mem::size_of::<u32>()) as u64;
+            ViewExposure {
+                settings: auto_exposure.clone(),
+                ev100,
+                uniform: UniformBuffer::default(),
+                histogram: render_device.create_buffer(&BufferDescriptor {
+                    label: Some("exposure_histogram"),
+                    size,
+                    usage: BufferUsages::STORAGE | BufferUsages::COPY_DST | BufferUsages::COPY_SRC,
+                    mapped_at_creation: false,
+                }),
+                readback: Readback::new(&render_device, "exposure_histogram_readback", size),
+                counting: None,
+                read_ev100: ev100,
+            }
+        });
+        view.settings = auto_exposure.clone();
+        view.ev100 = ev100;
+    }
+
+    exposures.retain(|entity, _| metered.contains(entity));
+}
+
+// Only the frames that start while no histogram is on its way back get counted
+fn prepare_exposure_histograms(
+    mut exposures: ResMut<ViewExposures>,
+    render_device: Res<RenderDevice>,
+    render_queue: Res<RenderQueue>,
+) {
+    for view in exposures.values_mut() {
+        let (min, max) = (*view.settings.range.start(), *view.settings.range.end());
+        view.uniform.set(HistogramUniform {
+            min_log_luminance: min,
+            inv_log_luminance_range: 1.0 / (max - min).max(f32::EPSILON),
+        });
+        view.uniform.write_buffer(&render_device, &render_queue);
+
+        if view.counting.is_some() || view.readback.is_pending() {
+            continue;
+        }
+        render_queue.write_buffer(&view.histogram, 0, &[0; HISTOGRAM_BINS * 4]);
+        view.counting = Some(view.ev100);
+    }
+}
+
+fn read_back_exposure_histograms(
+    mut exposures: ResMut<ViewExposures>,
+    targets: Res<ExposureTargets>,
+    render_device: Res<RenderDevice>,
+    render_queue: Res<RenderQueue>,
+) {
+    let mut targets = targets.lock();
+    for (entity, view) in exposures.iter_mut() {
+        if let Some(ev100) = view.counting.take() {
+            view.read_ev100 = ev100;
+            view.readback
+                .start(&render_device, &render_queue, &view.histogram);
+            continue;
+        }
+
+        let Some(outcome) = view.readback.poll(&render_device) else {
+            continue;
+        };
+        let bytes = match outcome {
+            Ok(bytes) => bytes,
+            Err(error) => {
+                warn!("Could not read back the exposure histogram: {error}");
+                continue;
+            }
+        };
+
+        let mut histogram = [0; HISTOGRAM_BINS];
+        for (count, bytes) in histogram.iter_mut().zip(bytes.chunks_exact(4)) {
+            *count = u32::from_le_bytes(bytes.try_into().unwrap());
+        }
+        if let Some(target) = view.settings.target_ev100(&histogram, view.read_ev100) {
+            targets.insert(*entity, target);
+        }
+    }
+}
+
+// Counts the luminance of every pixel of the view into the histogram, right after the traced image is drawn
+#[derive(Default)]
+pub struct ExposureHistogramNode;
+
+impl ViewNode for ExposureHistogramNode {
+    type ViewQuery = (&'static ViewTarget, &'static ViewRaytracePipelines);
+
+    fn run(
+        &self,
+        graph: &mut RenderGraphContext,
+        render_context: &mut RenderContext,
+        (view_target, pipelines): QueryItem<Self::ViewQuery>,
+        world: &World,
+    ) -> Result<(), NodeRunError> {
+        if !pipelines.linear_output {
+            return Ok(());
+        }
+
+        let exposures = world.resource::<ViewExposures>();
+        let Some(view) = exposures.get(&graph.view_entity()) else {
+            return Ok(());
+        };
+        if view.counting.is_none() {
+            return Ok(());
+        }
+        let Some(uniform_binding) = view.uniform.binding() else {
+            return Ok(());
+        };
+
+        let histogram_pipeline = world.resource::<ExposureHistogramPipeline>();
+        let pipeline_cache = world.resource::<PipelineCache>();
+        let Some(pipeline) = pipeline_cache.get_compute_pipeline(histogram_pipeline.pipeline_id)
+        else {
+            return Ok(());
+        };
+
+        let bind_group = render_context.render_device().create_bind_group(
+            "exposure_histogram_bind_group",
+            &histogram_pipeline.layout,
+            &BindGroupEntries::sequential((
+                uniform_binding,
+                view_target.main_texture_view(),
+                view.histogram.as_entire_binding(),
+            )),
+        );
+
+        let size = view_target.main_texture().size();
+        let mut compute_pass =
+            render_context
+                .command_encoder()
+                .begin_compute_pass(&ComputePassDescriptor {
+                    label: Some("exposure_histogram_pass"),
+                    timestamp_writes: None,
+                });
+
+        compute_pass.set_pipeline(pipeline);
+        compute_pass.set_bind_group(0, &bind_group, &[]);
+        compute_pass.dispatch_workgroups(
+            size.width.div_ceil(WORKGROUP_SIZE),
+            size.height.div_ceil(WORKGROUP_SIZE),
+            1,
+        );
+
+        Ok(())
+    }
+}
+
+#[derive(Resource)]
+pub struct ExposureHistogramPipeline {
+    layout: BindGroupLayout,
+    pipeline_id: CachedComputePipelineId,
+}
+
+impl FromWorld for ExposureHistogramPipeline {
+    fn from_world(world: &mut World) -> Self {
+        let render_device = world.resource::<RenderDevice>();
+
+        let layout = render_device.create_bind_group_layout(
+            "exposure_histogram_bind_group_layout",
+            &BindGroupLayoutEntries::sequential(
+                ShaderStages::COMPUTE,
+                (
+                    // The range of the histogram
+                    uniform_buffer::<HistogramUniform>(false),
+                    // The linear image of the view
+                    texture_2d(TextureSampleType::Float { filterable: false }),
+                    // The pixels in every bin
+                    storage_buffer_sized(false, None),
+                ),
+            ),
+        );
+
+        let shader = world.load_asset("shaders/exposure_histogram.wgsl");
+
+        let pipeline_id = world
+            .resource_mut::<PipelineCache>()
+            .queue_compute_pipeline(ComputePipelineDescriptor {
+                label: Some("exposure_histogram_pipeline".into()),
+                layout: vec![layout.clone()],
+                push_constant_ranges: vec![],
+                shader,
+                shader_defs: vec![],
+                entry_point: "count_luminance".into(),
+            });
+
+        Self {
+            layout,
+            pipeline_id,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{RaytraceAutoExposure, HISTOGRAM_BINS, MIDDLE_GRAY};
+
+    // The bin a luminance ends up in, like in exposure_histogram.wgsl
+    fn bin(settings: &RaytraceAutoExposure, luminance: f32) -> usize {
+        let (min, max) = (*settings.range.start(), *settings.range.end());
+        if luminance < min.exp2() {
+            return 0;
+        }
+        let position = ((luminance.log2() - min) / (max - min)).clamp(0.0, 1.0);
+        (position * (HISTOGRAM_BINS - 2) as f32 + 1.0) as usize
+    }
+
+    #[test]
+    fn middle_gray_keeps_the_exposure() {
+        let settings = RaytraceAutoExposure::default();
+        let mut histogram = [0; HISTOGRAM_BINS];
+        histogram[bin(&settings, MIDDLE_GRAY)] = 1000;
+
+        let target = settings.target_ev100(&histogram, 9.7).unwrap();
+        // Within half a bin
+        assert!((target - 9.7).abs() < 0.15, "target ev100 {target}");
+    }
+
+    #[test]
+    fn four_times_too_bright_is_two_stops_darker() {
+        let settings = RaytraceAutoExposure::default();
+        let mut histogram = [0; HISTOGRAM_BINS];
+        histogram[bin(&settings, MIDDLE_GRAY * 4.0)] = 1000;
+
+        let target = settings.target_ev100(&histogram, 9.7).unwrap();
+        assert!((target - 11.7).abs() < 0.15, "target ev100 {target}");
+    }
+
+    #[test]
+    fn filtered_highlights_are_ignored() {
+        let settings = RaytraceAutoExposure::default();
+        let mut histogram = [0; HISTOGRAM_BINS];
+        histogram[bin(&settings, MIDDLE_GRAY)] = 950;
+        histogram[HISTOGRAM_BINS - 1] = 50;
+
+        let target = settings.target_ev100(&histogram, 9.7).unwrap();
+        assert!((target - 9.7).abs() < 0.15, "target ev100 {target}");
+    }
+
+    #[test]
+    fn black_image_has_no_target() {
+        let settings = RaytraceAutoExposure::default();
+        let mut histogram = [0; HISTOGRAM_BINS];
+        histogram[0] = 1000;
+
+        assert_eq!(settings.target_ev100(&histogram, 9.7), None);
+    }
+}
@@ -1,18 +1,50 @@
+use std::f32::consts::FRAC_1_SQRT_2;
+
 use bevy::{
-    ecs::query::QueryItem,
+    core_pipeline::Skybox,
+    ecs::{
+        entity::{EntityHashMap, EntityHashSet},
+        query::QueryItem,
+    },
     math::Vec3A,
+    pbr::{
+        environment_map::EnvironmentMapLight, FogFalloff, FogSettings, NotShadowCaster,
+        NotShadowReceiver,
+    },
     prelude::*,
     render::{
+        camera::{Exposure, ExtractedCamera, TemporalJitter},
         extract_component::{ExtractComponent, ExtractComponentPlugin, UniformComponentPlugin},
+        primitives::{Frustum, Sphere},
         render_asset::{RenderAsset, RenderAssetPlugin, RenderAssets},
-        render_resource::{ShaderType, StorageBuffer},
-        Render, RenderApp, RenderSet,
+        render_resource::{ShaderType, TextureFormat},
+        renderer::{RenderDevice, RenderQueue},
+        view::ExtractedView,
+        Extract, ExtractSchedule, Render, RenderApp, RenderSet,
     },
+    utils::HashMap,
 };
-use obvhs::{ploc::build_ploc, Boundable};
+use obvhs::Boundable;
 use rand::{thread_rng, Rng};
 
-use super::{RaytracedCamera, RaytracedSphere};
+use super::{
+    accumulation::{prepare_accumulation, RaytraceConvergenceOverlay},
+    buffer::SceneBuffer,
+    bvh::{BvhCache, RaytraceStatic},
+    decal::ImageTexelOffsets,
+    light::RaytraceLightLink,
+    memory::MemoryReport,
+    origin::RenderOrigin,
+    particles::RaytraceParticlesExtract,
+    render_to_file::RenderTile,
+    BillboardFacing, FisheyeMapping, FogVolumeShape, PixelFilter, RaytraceClipPlanes,
+    RaytraceCulling, RaytraceDepthOfField, RaytraceDispersion, RaytraceFogVolume,
+    RaytraceInstanceMask, RaytraceLod, RaytraceMaterialId, RaytraceMaterialOverride,
+    RaytraceMaterialPalette, RaytraceOutput, RaytracePrecisePosition, RaytraceProjection,
+    RaytraceRayMasks, RaytraceReflectionOnly, RaytraceSampling, RaytraceShadowCatcher,
+    RaytraceTexture, RaytraceTiling, RaytracedBillboard, RaytracedCamera, RaytracedCuboid,
+    RaytracedHeightfield, RaytracedPlane, RaytracedSphere, MAX_CLIP_PLANES,
+};
 
 pub struct RaytraceExtractPlugin;
 
@@ -24,11 +56,16 @@ impl Plugin for RaytraceExtractPlugin {
             // This makes it possible to control the effect from the main world.
             // This plugin will take care of extracting it automatically.
             ExtractComponentPlugin::<CameraExtract>::default(),
-            ExtractComponentPlugin::<WindowExtract>::default(),
             // Extracting the Geometry from the main world
-            ExtractComponentPlugin::<RaytracedSphereExtract>::default(),
+            ExtractComponentPlugin::<PrimitiveExtract>::default(),
+            ExtractComponentPlugin::<FogVolumeExtract>::default(),
+            ExtractComponentPlugin::<HeightfieldExtract>::default(),
+            ExtractComponentPlugin::<BillboardExtract>::default(),
+            ExtractComponentPlugin::<ViewCulling>::default(),
+            ExtractComponentPlugin::<RaytraceTiling>::default(),
             // Taking the handles along to populate the buffers
             ExtractComponentPlugin::<Handle<StandardMaterial>>::default(),
+            ExtractComponentPlugin::<RaytraceMaterialOverride>::default(),
             // The settings will also be the data used in the shader.
             // This plugin will prepare the component for the GPU by creating a uniform buffer
             // and writing the data to that buffer every frame.
@@ -47,12 +84,60 @@ impl Plugin for RaytraceExtractPlugin {
             .init_resource::<ModelBuffer>()
             .init_resource::<MaterialBuffer>()
             .init_resource::<BVHBuffer>()
-            .add_systems(Render, prepare_buffers.in_set(RenderSet::PrepareResources));
+            .init_resource::<BvhPrimitiveBuffer>()
+            .init_resource::<ModelSlots>()
+            .init_resource::<MaterialOwners>()
+            .init_resource::<FogBuffer>()
+            .init_resource::<HeightfieldBuffer>()
+            .init_resource::<HeightBuffer>()
+            .init_resource::<HeightmapCache>()
+            .add_systems(
+                ExtractSchedule,
+                (extract_heightmaps, extract_palette_materials),
+            )
+            .add_systems(
+                Render,
+                (
+                    // After the views got their `TemporalJitter` for this frame in `RenderSet::ManageViews`
+                    prepare_camera_matrices.in_set(RenderSet::Queue),
+                    // Once the accumulation decided if the view traces anything this frame
+                    prepare_window_uniforms
+                        .in_set(RenderSet::Queue)
+                        .after(prepare_accumulation),
+                    prepare_buffers.in_set(RenderSet::PrepareResources),
+                ),
+            );
     }
 }
 
-// This solution is fine for now, but cameras can also render to other places that aren't bound by this height
-// At that point the uniform position needs to be dynamic again and the extraction has to look different
+// Objects with a `RaytraceMaterialId` get the handle from the palette, like the others get their own extracted.
+// Cloning a handle only counts up a reference, the material asset stays shared
+fn extract_palette_materials(
+    palette: Extract<Res<RaytraceMaterialPalette>>,
+    objects: Extract<Query<(Entity, &RaytraceMaterialId), Without<Handle<StandardMaterial>>>>,
+    mut previous_len: Local<usize>,
+    mut commands: Commands,
+) {
+    let mut values = Vec::with_capacity(*previous_len);
+    values.extend(palette_materials(&palette, objects.iter()));
+    *previous_len = values.len();
+    commands.insert_or_spawn_batch(values);
+}
+
+// Indices past the end of the palette get no material, so the object is left out like one whose material isn't
+// prepared yet
+fn palette_materials<'a>(
+    palette: &'a RaytraceMaterialPalette,
+    objects: impl Iterator<Item = (Entity, &'a RaytraceMaterialId)> + 'a,
+) -> impl Iterator<Item = (Entity, Handle<StandardMaterial>)> + 'a {
+    objects.filter_map(|(entity, id)| {
+        let handle = palette.get(id.0 as usize)?;
+        Some((entity, handle.clone()))
+    })
+}
+
+// The size of what a raytraced camera draws into and the seed of its frame, one per view.
+// Windows, texture targets and viewports each get their own
 #[derive(Component, Default, Clone, ShaderType)]
 pub struct WindowExtract {
     random_seed: f32,
@@ -60,31 +145,39 @@ pub struct WindowExtract {
     _padding: Vec2,
 }
 
-impl ExtractComponent for WindowExtract {
-    type QueryData = &'static Window;
-
-    type QueryFilter = ();
-
-    type Out = Self;
+// Views that hold a converged image keep the seed of their last traced frame, so nothing about them changes until they trace again
+fn prepare_window_uniforms(
+    views: Query<(Entity, &ExtractedCamera, &CameraExtract)>,
+    mut seeds: Local<EntityHashMap<f32>>,
+    mut commands: Commands,
+) {
+    let mut rng = thread_rng();
+    seeds.retain(|entity, _| views.contains(*entity));
+    for (entity, camera, extract) in &views {
+        let Some(size) = camera.physical_viewport_size else {
+            continue;
+        };
 
-    fn extract_component(item: QueryItem<'_, Self::QueryData>) -> Option<Self::Out> {
-        // TODO: This is probably a bad idea but other solutions needed mutable acces
-        let mut rng = thread_rng();
-        let random_seed: f32 = rng.gen_range(0.0..1.0);
+        let seed = seeds
+            .entry(entity)
+            .or_insert_with(|| rng.gen_range(0.0..1.0));
+        if !extract.holds() {
+            *seed = rng.gen_range(0.0..1.0);
+        }
 
-        Some(WindowExtract {
-            random_seed,
-            height: item.physical_height(),
+        commands.entity(entity).insert(WindowExtract {
+            random_seed: *seed,
+            height: size.y,
             _padding: Vec2::default(),
-        })
+        });
     }
 }
 
 #[derive(Component, Default, Clone, ShaderType)]
 pub struct CameraExtract {
     sample_count: u32,
-    bounce_count: u32,
-    // 0 -> perspective; rest not supported
+    // 0 -> perspective; 1 -> orthographic (not supported); 2 -> panoramic;
+    // 3 -> equidistant fisheye; 4 -> equisolid fisheye
     projection: u32,
     near: f32,
     far: f32,
@@ -94,6 +187,117 @@ pub struct CameraExtract {
     position: Vec3,
     direction: Vec3,
     up: Vec3,
+    // Scales the environment cubemap, 0.0 if the camera has none
+    environment_intensity: f32,
+    regularization: f32,
+    min_throughput: f32,
+    max_specular_bounces: u32,
+    irradiance_fallback: u32,
+    // The `RaytraceRayMasks` with a byte for each type of ray
+    ray_masks: u32,
+    // 1 if the frames get accumulated or the camera is paused, `sample_count` is then only this frame's share of them
+    progressive: u32,
+    // The samples already in the accumulation, 0 starts over.
+    // For checkerboard cameras the frames in the history, 0 if there is no last frame to reproject
+    accumulated_samples: u32,
+    // Thin lens, an aperture_radius of 0.0 is a pinhole
+    focus_distance: f32,
+    aperture_radius: f32,
+    // Below 3 the aperture is round
+    aperture_blades: u32,
+    blade_rotation: f32,
+    // 0 -> box; 1 -> tent; 2 -> gaussian; 3 -> blackman-harris
+    pixel_filter: u32,
+    // The `FogSettings` of the camera, 0 -> none; 1 -> linear; 2 -> exponential; 3 -> exponential squared; 4 -> atmospheric
+    fog_falloff: u32,
+    // Linear rgb, the alpha scales the whole fog
+    fog_color: Vec4,
+    // Start and end of linear fog, the density in x for the exponential ones and the extinction for atmospheric fog
+    fog_parameters: Vec3,
+    fog_inscattering: Vec3,
+    // 1 if only the pixels matching the parity get traced this frame
+    checkerboard: u32,
+    checkerboard_parity: u32,
+    // The exposure of the camera relative to bevy's default one, applied to the finished pixels
+    exposure: f32,
+    // The `RaytraceConvergenceOverlay`, 0 -> none; 1 -> samples; 2 -> error.
+    // The scale is the max_samples or max_error of the overlay
+    overlay: u32,
+    overlay_scale: f32,
+    // The inverse of the view projection bevy rasterizes with, including the `TemporalJitter`.
+    // Rays of the `Camera` projection go through the pixels unprojected with it, so they match the rasterized image exactly
+    world_from_clip: Mat4,
+    // The matrices of the camera's last frame like bevy's `PreviousViewData`, for reprojecting what it saw then.
+    // The projection includes the jitter the last frame was traced with, for its first frame both are this frame's
+    previous_view_from_world: Mat4,
+    previous_clip_from_world: Mat4,
+    // Normal and signed distance of the origin of every `ClipPlane`, in world space until `prepare_camera_matrices`
+    clip_planes: [Vec4; MAX_CLIP_PLANES],
+    clip_plane_count: u32,
+}
+
+impl CameraExtract {
+    pub(super) fn accumulate(&mut self, accumulated_samples: u32, frame_samples: u32) {
+        self.accumulated_samples = accumulated_samples;
+        self.sample_count = frame_samples;
+    }
+
+    // Traces one half of the checkerboard, the other one gets reprojected from the last frame if there is one
+    pub(super) fn reproject(&mut self, frame: u32, has_history: bool) {
+        self.checkerboard_parity = frame % 2;
+        self.accumulated_samples = has_history.into();
+    }
+
+    // Shows what is in the accumulation like a converged progressive camera, only tracing into it while it's empty
+    pub(super) fn hold(&mut self, accumulated_samples: u32, frame_samples: u32) {
+        self.progressive = 1;
+        self.accumulate(accumulated_samples, frame_samples);
+    }
+
+    pub(super) fn checkerboard(&self) -> bool {
+        self.checkerboard != 0
+    }
+
+    // The samples per pixel of the image once this frame is traced
+    pub(super) fn samples(&self) -> u32 {
+        if self.progressive != 0 {
+            self.accumulated_samples + self.sample_count
+        } else {
+            self.sample_count
+        }
+    }
+
+    // Whether the view only shows what it accumulated this frame without tracing anything
+    pub(super) fn holds(&self) -> bool {
+        self.progressive != 0 && self.sample_count == 0
+    }
+
+    // Whether this frame starts from an empty image instead of adding to or reprojecting an earlier one
+    pub(super) fn starts_over(&self) -> bool {
+        (self.progressive != 0 || self.checkerboard()) && self.accumulated_samples == 0
+    }
+
+    // At most this many rays get traced this frame, every path running through all bounces with a shadow ray at each of them
+    pub(super) fn estimated_rays(&self, size: UVec2, bounces: u32) -> u64 {
+        let half = self.checkerboard() && self.progressive == 0;
+        let pixels = size.as_u64vec2().element_product() / if half { 2 } else { 1 };
+        pixels * u64::from(self.sample_count) * u64::from(bounces + 1) * 2
+    }
+}
+
+// The cubemap of the camera's `Skybox` or `EnvironmentMapLight`, traced rays that miss the scene sample it instead of the sky
+#[derive(Component, Default, Clone)]
+pub struct EnvironmentExtract {
+    pub image: Option<AssetId<Image>>,
+}
+
+// The settings compiled into the pipeline of the camera instead of being read from `CameraExtract`, see `RaytracePipelineKey`.
+// `linear_hdr` also picks which of the two graph nodes draws the camera, see `RaytraceOutput`
+#[derive(Component, Default, Clone, Copy)]
+pub struct PipelineExtract {
+    pub linear_hdr: bool,
+    pub bounces: u32,
+    pub spectral: bool,
 }
 
 // This is the component that will get passed to the shader
@@ -109,14 +313,88 @@ impl ExtractComponent for CameraExtract {
         &'static RaytracedCamera,
         &'static GlobalTransform,
         &'static Projection,
+        Option<&'static Skybox>,
+        Option<&'static EnvironmentMapLight>,
+        Option<&'static Exposure>,
+        Option<&'static RaytraceDepthOfField>,
+        Option<&'static FogSettings>,
+        Option<&'static RaytraceRayMasks>,
+        Option<&'static RaytraceConvergenceOverlay>,
+        Option<&'static RaytraceClipPlanes>,
     );
 
     type QueryFilter = ();
 
-    type Out = (RaytraceLevelExtract, CameraExtract);
+    type Out = (
+        RaytraceLevelExtract,
+        CameraExtract,
+        EnvironmentExtract,
+        PipelineExtract,
+    );
 
     fn extract_component(item: QueryItem<'_, Self::QueryData>) -> Option<Self::Out> {
         let camera = item.0;
+
+        // Both are in cd/m^2, the default exposure brings them into the range of the traced sky and lights.
+        // The camera's own exposure is applied to the whole traced image at the end, relative to the default one
+        let default_exposure = Exposure::default().exposure();
+        let exposure = item.5.map_or(default_exposure, Exposure::exposure);
+        let (environment, environment_intensity) = match camera_environment(item.3, item.4) {
+            Some((image, brightness)) => (Some(image), brightness * default_exposure),
+            None => (None, 0.0),
+        };
+
+        let lens = item.6.copied().unwrap_or(RaytraceDepthOfField {
+            aperture_radius: 0.0,
+            ..default()
+        });
+
+        // Same parameters as bevy's own fog shader, so traced and rasterized geometry fade alike
+        let (fog_falloff, fog_parameters, fog_inscattering) = match item
+            .7
+            .map(|fog| fog.falloff.clone())
+        {
+            None => (0, Vec3::ZERO, Vec3::ZERO),
+            Some(FogFalloff::Linear { start, end }) => (1, Vec3::new(start, end, 0.0), Vec3::ZERO),
+            Some(FogFalloff::Exponential { density }) => {
+                (2, Vec3::new(density, 0.0, 0.0), Vec3::ZERO)
+            }
+            Some(FogFalloff::ExponentialSquared { density }) => {
+                (3, Vec3::new(density, 0.0, 0.0), Vec3::ZERO)
+            }
+            Some(FogFalloff::Atmospheric {
+                extinction,
+                inscattering,
+            }) => (4, extinction, inscattering),
+        };
+        let fog_color = item
+            .7
+            .map_or(Vec4::ZERO, |fog| LinearRgba::from(fog.color).to_vec4());
+
+        let (overlay, overlay_scale) = match item.9.copied() {
+            None => (0, 0.0),
+            Some(RaytraceConvergenceOverlay::Samples { max_samples }) => {
+                (1, max_samples.max(2) as f32)
+            }
+            Some(RaytraceConvergenceOverlay::Error { max_error }) => {
+                (2, max_error.max(f32::EPSILON))
+            }
+        };
+
+        let mut clip_planes = [Vec4::ZERO; MAX_CLIP_PLANES];
+        let mut clip_plane_count = 0;
+        for plane in item.10.iter().flat_map(|planes| &planes.0) {
+            // Planes without a normal cut nothing
+            let Some(normal) = plane.normal.try_normalize() else {
+                continue;
+            };
+            if clip_plane_count == MAX_CLIP_PLANES {
+                break;
+            }
+            clip_planes[clip_plane_count] = normal.extend(-normal.dot(plane.point));
+            clip_plane_count += 1;
+        }
+
         let camera_extract = match *item.2 {
             Projection::Perspective(PerspectiveProjection {
                 fov,
@@ -131,10 +409,22 @@ impl ExtractComponent for CameraExtract {
                 let direction = transform.forward().as_vec3();
                 let up = transform.up().as_vec3();
 
+                let (projection, fov) = match camera.projection {
+                    RaytraceProjection::Camera => (0, fov),
+                    RaytraceProjection::Panoramic360 => (2, fov),
+                    RaytraceProjection::Fisheye {
+                        fov,
+                        mapping: FisheyeMapping::Equidistant,
+                    } => (3, fov),
+                    RaytraceProjection::Fisheye {
+                        fov,
+                        mapping: FisheyeMapping::Equisolid,
+                    } => (4, fov),
+                };
+
                 CameraExtract {
                     sample_count: camera.sample_count,
-                    bounce_count: camera.bounces,
-                    projection: 0,
+                    projection,
                     near,
                     far,
                     aspect: aspect_ratio,
@@ -142,6 +432,40 @@ impl ExtractComponent for CameraExtract {
                     position,
                     direction,
                     up,
+                    environment_intensity,
+                    regularization: camera.regularization,
+                    min_throughput: camera.termination.min_throughput,
+                    max_specular_bounces: camera.termination.max_specular_bounces,
+                    irradiance_fallback: camera.termination.irradiance_fallback.into(),
+                    ray_masks: item.8.copied().unwrap_or_default().packed(),
+                    progressive: matches!(camera.sampling, RaytraceSampling::Progressive { .. })
+                        .into(),
+                    accumulated_samples: 0,
+                    focus_distance: lens.focus_distance.max(f32::EPSILON),
+                    aperture_radius: lens.aperture_radius.max(0.0),
+                    aperture_blades: lens.blades,
+                    blade_rotation: lens.blade_rotation,
+                    pixel_filter: match camera.pixel_filter {
+                        PixelFilter::Box => 0,
+                        PixelFilter::Tent => 1,
+                        PixelFilter::Gaussian => 2,
+                        PixelFilter::BlackmanHarris => 3,
+                    },
+                    fog_falloff,
+                    fog_color,
+                    fog_parameters,
+                    fog_inscattering,
+                    checkerboard: matches!(camera.sampling, RaytraceSampling::Checkerboard).into(),
+                    checkerboard_parity: 0,
+                    exposure: exposure / default_exposure,
+                    overlay,
+                    overlay_scale,
+                    // Filled in by `prepare_camera_matrices` once the view is jittered
+                    world_from_clip: Mat4::IDENTITY,
+                    previous_view_from_world: Mat4::IDENTITY,
+                    previous_clip_from_world: Mat4::IDENTITY,
+                    clip_planes,
+                    clip_plane_count: clip_plane_count as u32,
                 }
             }
             // Currently unsupported
@@ -153,39 +477,619 @@ impl ExtractComponent for CameraExtract {
             _padding: Vec3::default(),
         };
 
-        Some((level, camera_extract))
+        Some((
+            level,
+            camera_extract,
+            EnvironmentExtract { image: environment },
+            PipelineExtract {
+                linear_hdr: camera.output == RaytraceOutput::LinearHdr,
+                bounces: camera.bounces,
+                spectral: camera.spectral,
+            },
+        ))
+    }
+}
+
+// The view entities are cleared every frame, so the matrices of the last frame are kept here by camera.
+// Features looking back at the last frame use the previous matrices in `CameraExtract` instead of keeping their own history.
+// The camera ends up in render space along with its matrices, see `RenderOrigin`
+fn prepare_camera_matrices(
+    mut views: Query<(
+        Entity,
+        &mut CameraExtract,
+        &ExtractedView,
+        Option<&TemporalJitter>,
+        Option<&RenderTile>,
+    )>,
+    origin: Res<RenderOrigin>,
+    // The view_from_world and clip_from_world of every camera's last frame, in world space as the origin may have moved since
+    mut history: Local<EntityHashMap<(Mat4, Mat4)>>,
+) {
+    let mut seen = EntityHashSet::default();
+    for (entity, mut camera, view, jitter, tile) in &mut views {
+        let mut clip_from_view = view.clip_from_view;
+        if let Some(jitter) = jitter {
+            jitter.jitter_projection(&mut clip_from_view, view.viewport.zw().as_vec2());
+        }
+        if let Some(tile) = tile {
+            clip_from_view = tile.tile_from_image() * clip_from_view;
+        }
+        let world_from_view = view.world_from_view.compute_matrix();
+        let view_from_world = world_from_view.inverse();
+        let clip_from_world = clip_from_view * view_from_world;
+
+        camera.position = origin.point(camera.position);
+        let clip_plane_count = camera.clip_plane_count as usize;
+        for plane in &mut camera.clip_planes[..clip_plane_count] {
+            let normal = plane.truncate();
+            plane.w = -normal.dot(origin.point(-plane.w * normal));
+        }
+        camera.world_from_clip =
+            origin.render_from_local(world_from_view) * clip_from_view.inverse();
+        let (previous_view_from_world, previous_clip_from_world) = history
+            .insert(entity, (view_from_world, clip_from_world))
+            .unwrap_or((view_from_world, clip_from_world));
+        camera.previous_view_from_world = origin.rebase_from_world(previous_view_from_world);
+        camera.previous_clip_from_world = origin.rebase_from_world(previous_clip_from_world);
+        seen.insert(entity);
+    }
+
+    history.retain(|entity, _| seen.contains(entity));
+}
+
+// The bevy shadow markers of an object, they only affect shadow rays, and whether it is a `RaytraceShadowCatcher`
+pub(super) fn shadow_flags(not_caster: bool, not_receiver: bool, catcher: bool) -> u32 {
+    const SHADOW_CASTER_OFF: u32 = 1;
+    const SHADOW_RECEIVER_OFF: u32 = 2;
+    const SHADOW_CATCHER: u32 = 4;
+
+    let mut flags = 0;
+    if not_caster {
+        flags |= SHADOW_CASTER_OFF;
+    }
+    if not_receiver {
+        flags |= SHADOW_RECEIVER_OFF;
+    }
+    if catcher {
+        flags |= SHADOW_CATCHER;
+    }
+    flags
+}
+
+// The cubemap a camera traces against with its brightness.
+// The skybox is what the raster view shows, so it wins over the environment map light.
+pub(super) fn camera_environment(
+    skybox: Option<&Skybox>,
+    light: Option<&EnvironmentMapLight>,
+) -> Option<(AssetId<Image>, f32)> {
+    match (skybox, light) {
+        (Some(skybox), _) => Some((skybox.image.id(), skybox.brightness)),
+        (None, Some(light)) => Some((light.specular_map.id(), light.intensity)),
+        (None, None) => None,
+    }
+}
+
+// A sphere, cuboid or plane, they share their slots in the buffers
+#[derive(Clone, Component)]
+pub struct PrimitiveExtract {
+    primitive: u32,
+    // The bounding sphere, for culling
+    position: Vec3,
+    radius: f32,
+    // Scales the unit sphere, cube or square to the traced one
+    world_from_local: Mat4,
+    dispersion: f32,
+    shadow_flags: u32,
+    is_static: bool,
+    mask: u32,
+    light_link: u32,
+    precise: bool,
+}
+
+impl ExtractComponent for PrimitiveExtract {
+    type QueryData = (
+        AnyOf<(
+            &'static RaytracedSphere,
+            &'static RaytracedCuboid,
+            &'static RaytracedPlane,
+        )>,
+        &'static GlobalTransform,
+        Option<&'static RaytraceDispersion>,
+        Has<NotShadowCaster>,
+        Has<NotShadowReceiver>,
+        Has<RaytraceStatic>,
+        Option<&'static RaytraceInstanceMask>,
+        Option<&'static RaytraceLightLink>,
+        Has<RaytraceShadowCatcher>,
+        Has<RaytracePrecisePosition>,
+        Has<RaytraceReflectionOnly>,
+        Option<&'static InheritedVisibility>,
+    );
+
+    type QueryFilter = ();
+
+    type Out = Self;
+
+    fn extract_component(item: QueryItem<'_, Self::QueryData>) -> Option<Self::Out> {
+        // Hidden like the rasterized meshes are, primitives spawned without a visibility are always traced
+        if item.11.is_some_and(|visibility| !visibility.get()) {
+            return None;
+        }
+
+        let (primitive, position, radius, world_from_local) = match item.0 {
+            (Some(sphere), ..) => {
+                let (scale, rotation, translation) = item.1.to_scale_rotation_translation();
+                // The radius goes along the longest axis of the scale, the others are shorter in proportion
+                let longest = scale.abs().max_element();
+                let shape = if longest > 0.0 {
+                    scale.abs() / longest * sphere.radius
+                } else {
+                    Vec3::splat(sphere.radius)
+                };
+                // Kept invertible, spheres without a radius are never hit either way
+                let world_from_local = Mat4::from_scale_rotation_translation(
+                    shape.max(Vec3::splat(f32::EPSILON)),
+                    rotation,
+                    translation,
+                );
+                (
+                    PRIMITIVE_SPHERE,
+                    translation,
+                    sphere.radius,
+                    world_from_local,
+                )
+            }
+            // Cuboids and planes take the whole transform, shear included
+            (None, Some(cuboid), _) => {
+                let size = (cuboid.half_size * 2.0).max(Vec3::splat(f32::EPSILON));
+                let world_from_local = item.1.compute_matrix() * Mat4::from_scale(size);
+                let (position, radius) = bounding_sphere(world_from_local);
+                (PRIMITIVE_CUBOID, position, radius, world_from_local)
+            }
+            (None, None, Some(plane)) => {
+                // The height stays 1, it only keeps the matrix invertible
+                let size = (plane.half_size * 2.0).max(Vec2::splat(f32::EPSILON));
+                let world_from_local = item.1.compute_matrix()
+                    * Mat4::from_quat(Quat::from_rotation_arc(Vec3::Y, *plane.normal))
+                    * Mat4::from_scale(Vec3::new(size.x, 1.0, size.y));
+                let (position, radius) =
+                    bounding_sphere(world_from_local * Mat4::from_scale(Vec3::new(1.0, 0.0, 1.0)));
+                (PRIMITIVE_PLANE, position, radius, world_from_local)
+            }
+            (None, None, None) => return None,
+        };
+
+        Some(PrimitiveExtract {
+            primitive,
+            position,
+            radius,
+            world_from_local,
+            dispersion: item.2.map_or(0.0, |dispersion| dispersion.cauchy_b),
+            shadow_flags: shadow_flags(item.3, item.4, item.8),
+            is_static: item.5,
+            mask: RaytraceInstanceMask::packed(item.6, item.10),
+            light_link: item.7.copied().unwrap_or_default().0,
+            // Only spheres are intersected precisely
+            precise: item.9 && primitive == PRIMITIVE_SPHERE,
+        })
+    }
+}
+
+// Around the unit cube after the transform, as far out as its corners can reach
+fn bounding_sphere(world_from_local: Mat4) -> (Vec3, f32) {
+    let matrix = Mat3::from_mat4(world_from_local);
+    let radius = (matrix.x_axis.length() + matrix.y_axis.length() + matrix.z_axis.length()) / 2.0;
+    (world_from_local.w_axis.truncate(), radius)
+}
+
+#[derive(Clone, PartialEq, Component, ShaderType)]
+pub struct FogVolumeExtract {
+    local_from_world: Mat4,
+    scattering_color: Vec3,
+    density: f32,
+    // 0 -> box; 1 -> sphere
+    shape: u32,
+}
+
+impl ExtractComponent for FogVolumeExtract {
+    type QueryData = (&'static RaytraceFogVolume, &'static GlobalTransform);
+
+    type QueryFilter = ();
+
+    type Out = Self;
+
+    fn extract_component(item: QueryItem<'_, Self::QueryData>) -> Option<Self::Out> {
+        let (fog, transform) = item;
+        Some(FogVolumeExtract {
+            local_from_world: transform.compute_matrix().inverse(),
+            scattering_color: fog.scattering_color.to_linear().to_vec3(),
+            density: fog.density,
+            shape: match fog.shape {
+                FogVolumeShape::Box => 0,
+                FogVolumeShape::Sphere => 1,
+            },
+        })
+    }
+}
+
+// The culling of a traced camera, see `RaytraceCulling`
+#[derive(Clone, Component)]
+pub struct ViewCulling {
+    frustum: Frustum,
+    position: Vec3,
+    guard_band: f32,
+    max_distance: f32,
+}
+
+impl ExtractComponent for ViewCulling {
+    type QueryData = (
+        &'static RaytraceCulling,
+        &'static RaytracedCamera,
+        &'static Frustum,
+        &'static GlobalTransform,
+    );
+
+    type QueryFilter = ();
+
+    type Out = Self;
+
+    fn extract_component(item: QueryItem<'_, Self::QueryData>) -> Option<Self::Out> {
+        let (culling, camera, frustum, transform) = item;
+        // The frustum only covers what the bevy projection sees
+        if !matches!(camera.projection, RaytraceProjection::Camera) {
+            return None;
+        }
+
+        Some(ViewCulling {
+            frustum: *frustum,
+            position: transform.translation(),
+            guard_band: culling.guard_band.max(0.0),
+            max_distance: culling.max_distance,
+        })
+    }
+}
+
+impl ViewCulling {
+    fn sees(&self, center: Vec3, radius: f32) -> bool {
+        let sphere = Sphere {
+            center: center.into(),
+            radius: radius + self.guard_band,
+        };
+        // The far plane is left to `max_distance`
+        center.distance(self.position) - radius <= self.max_distance
+            && self.frustum.intersects_sphere(&sphere, false)
     }
 }
 
 #[derive(Clone, Component)]
-pub struct RaytracedSphereExtract {
+pub struct BillboardExtract {
+    image: AssetId<Image>,
+    // Scales the unit quad to the size of the billboard
+    world_from_local: Mat4,
+    alpha_cutoff: f32,
+    facing: BillboardFacing,
+    // The bounding sphere, for culling
     position: Vec3,
     radius: f32,
+    shadow_flags: u32,
+    is_static: bool,
+    mask: u32,
+    light_link: u32,
+}
+
+impl ExtractComponent for BillboardExtract {
+    type QueryData = (
+        &'static RaytracedBillboard,
+        &'static GlobalTransform,
+        Has<NotShadowCaster>,
+        Has<NotShadowReceiver>,
+        Has<RaytraceStatic>,
+        Option<&'static RaytraceInstanceMask>,
+        Option<&'static RaytraceLightLink>,
+        Has<RaytraceReflectionOnly>,
+    );
+
+    type QueryFilter = ();
+
+    type Out = Self;
+
+    fn extract_component(item: QueryItem<'_, Self::QueryData>) -> Option<Self::Out> {
+        let (
+            billboard,
+            transform,
+            not_caster,
+            not_receiver,
+            is_static,
+            mask,
+            light_link,
+            reflection_only,
+        ) = item;
+        // Kept invertible, the depth is the width so upright billboards look the same from every side
+        let size = billboard.size.max(Vec2::splat(f32::EPSILON));
+        let world_from_local =
+            transform.compute_matrix() * Mat4::from_scale(Vec3::new(size.x, size.y, size.x));
+        let bounds = ModelBounds::of_billboard(world_from_local);
+
+        Some(BillboardExtract {
+            image: billboard.image.id(),
+            world_from_local,
+            alpha_cutoff: billboard.alpha_cutoff,
+            facing: billboard.facing,
+            position: (bounds.min + bounds.max) / 2.0,
+            radius: (bounds.max - bounds.min).length() / 2.0,
+            shadow_flags: shadow_flags(not_caster, not_receiver, false),
+            is_static,
+            mask: RaytraceInstanceMask::packed(mask, reflection_only),
+            light_link: light_link.copied().unwrap_or_default().0,
+        })
+    }
+}
+
+#[derive(Clone, Component)]
+pub struct HeightfieldExtract {
+    image: AssetId<Image>,
+    world_from_local: Mat4,
+    shadow_flags: u32,
+    // World space bounds, the level of detail depends on the distance to them
+    bounds_min: Vec3,
+    bounds_max: Vec3,
+    lod: Option<RaytraceLod>,
+    is_static: bool,
+    mask: u32,
+    light_link: u32,
 }
 
-impl ExtractComponent for RaytracedSphereExtract {
-    type QueryData = (&'static RaytracedSphere, &'static GlobalTransform);
+impl ExtractComponent for HeightfieldExtract {
+    type QueryData = (
+        &'static RaytracedHeightfield,
+        &'static GlobalTransform,
+        Has<NotShadowCaster>,
+        Has<NotShadowReceiver>,
+        Option<&'static RaytraceLod>,
+        Has<RaytraceStatic>,
+        Option<&'static RaytraceInstanceMask>,
+        Option<&'static RaytraceLightLink>,
+        Has<RaytraceShadowCatcher>,
+        Has<RaytraceReflectionOnly>,
+    );
 
     type QueryFilter = ();
 
     type Out = Self;
 
     fn extract_component(item: QueryItem<'_, Self::QueryData>) -> Option<Self::Out> {
-        Some(RaytracedSphereExtract {
-            position: item.1.translation(),
-            radius: item.0.radius,
+        let (
+            heightfield,
+            transform,
+            not_caster,
+            not_receiver,
+            lod,
+            is_static,
+            mask,
+            light_link,
+            catcher,
+            reflection_only,
+        ) = item;
+        // The shader works with a unit square and heights from 0 to 1
+        let world_from_local = transform.compute_matrix() * Mat4::from_scale(heightfield.scale);
+
+        let corners = (0..8).map(|corner| {
+            let local = Vec3::new(
+                if corner & 1 == 0 { -0.5 } else { 0.5 },
+                if corner & 2 == 0 { 0.0 } else { 1.0 },
+                if corner & 4 == 0 { -0.5 } else { 0.5 },
+            );
+            world_from_local.transform_point3(local)
+        });
+        let (bounds_min, bounds_max) = corners.fold(
+            (Vec3::splat(f32::INFINITY), Vec3::splat(f32::NEG_INFINITY)),
+            |(min, max), corner| (min.min(corner), max.max(corner)),
+        );
+
+        Some(HeightfieldExtract {
+            image: heightfield.heightmap.id(),
+            world_from_local,
+            shadow_flags: shadow_flags(not_caster, not_receiver, catcher),
+            bounds_min,
+            bounds_max,
+            lod: lod.cloned(),
+            is_static,
+            mask: RaytraceInstanceMask::packed(mask, reflection_only),
+            light_link: light_link.copied().unwrap_or_default().0,
         })
     }
 }
 
-#[derive(Clone, Component, ShaderType)]
+impl HeightfieldExtract {
+    fn distance_to(&self, point: Vec3) -> f32 {
+        point
+            .clamp(self.bounds_min, self.bounds_max)
+            .distance(point)
+    }
+}
+
+// A heightmap with every level of detail, each one with half the cells of the one before
+pub struct Heightmap {
+    levels: Vec<HeightmapLevel>,
+}
+
+pub struct HeightmapLevel {
+    resolution: UVec2,
+    heights: Vec<f32>,
+}
+
+impl Heightmap {
+    // Past the last level the coarsest one is used
+    fn level(&self, level: usize) -> &HeightmapLevel {
+        &self.levels[level.min(self.levels.len() - 1)]
+    }
+}
+
+impl HeightmapLevel {
+    // Keeps every other vertex, the corners stay where they are so the terrain keeps its extent
+    fn halved(&self) -> Option<Self> {
+        let cells = self.resolution - 1;
+        let resolution = cells / 2 + 1;
+        if resolution.x < 2 || resolution.y < 2 {
+            return None;
+        }
+
+        let source = |vertex: u32, axis: usize| {
+            let position = vertex as f32 * cells[axis] as f32 / (resolution[axis] - 1) as f32;
+            (position.round() as u32).min(cells[axis])
+        };
+        let heights = (0..resolution.y)
+            .flat_map(|y| (0..resolution.x).map(move |x| (x, y)))
+            .map(|(x, y)| self.heights[(source(y, 1) * self.resolution.x + source(x, 0)) as usize])
+            .collect();
+
+        Some(HeightmapLevel {
+            resolution,
+            heights,
+        })
+    }
+}
+
+// The heights of every heightmap in use, they only get read back from the images when those change
+#[derive(Resource, Default, Deref, DerefMut)]
+pub struct HeightmapCache(HashMap<AssetId<Image>, Heightmap>);
+
+fn extract_heightmaps(
+    mut cache: ResMut<HeightmapCache>,
+    heightfields: Extract<Query<&RaytracedHeightfield>>,
+    images: Extract<Res<Assets<Image>>>,
+    image_events: Extract<Res<Events<AssetEvent<Image>>>>,
+) {
+    for event in image_events.iter_current_update_events() {
+        match *event {
+            AssetEvent::Modified { id }
+            | AssetEvent::Removed { id }
+            | AssetEvent::Unused { id } => {
+                cache.remove(&id);
+            }
+            _ => {}
+        }
+    }
+
+    for heightfield in &heightfields {
+        let id = heightfield.heightmap.id();
+        if cache.contains_key(&id) {
+            continue;
+        }
+
+        if let Some(image) = images.get(id) {
+            cache.insert(id, heightmap_from_image(image));
+        }
+    }
+}
+
+fn heightmap_from_image(image: &Image) -> Heightmap {
+    let resolution = image.size();
+    let data = &image.data;
+
+    let mut heights: Vec<f32> = match image.texture_descriptor.format {
+        TextureFormat::R8Unorm => data
+            .iter()
+            .map(|&height| f32::from(height) / 255.0)
+            .collect(),
+        TextureFormat::Rgba8Unorm | TextureFormat::Rgba8UnormSrgb => data
+            .chunks_exact(4)
+            .map(|pixel| f32::from(pixel[0]) / 255.0)
+            .collect(),
+        TextureFormat::R16Unorm => data
+            .chunks_exact(2)
+            .map(|bytes| f32::from(u16::from_le_bytes([bytes[0], bytes[1]])) / 65535.0)
+            .collect(),
+        TextureFormat::R32Float => data
+            .chunks_exact(4)
+            .map(|bytes| f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+            .collect(),
+        format => {
+            warn!("Heightmaps with the format {format:?} are not supported, the heightfield will be flat");
+            Vec::new()
+        }
+    };
+
+    // Only the first layer is used
+    heights.resize((resolution.x * resolution.y) as usize, 0.0);
+
+    let mut levels = vec![HeightmapLevel {
+        resolution,
+        heights,
+    }];
+    while let Some(level) = levels.last().and_then(HeightmapLevel::halved) {
+        levels.push(level);
+    }
+
+    Heightmap { levels }
+}
+
+// Bevy clamps the roughness to this before squaring it, so even smooth materials are a little blurry in the rasterizer.
+// The traced materials do the same, or they would converge to a sharper image than the rasterized one
+const MIN_PERCEPTUAL_ROUGHNESS: f32 = 0.089;
+
+// Bevy's remapping of the reflectance, 0.5 is the 4% of most dielectrics
+fn dielectric_f0(reflectance: f32) -> f32 {
+    0.16 * reflectance * reflectance
+}
+
+#[derive(Clone, Default, PartialEq, Component, ShaderType)]
 pub struct RaytraceMaterial {
     base_color: Vec3,
     metallic: f32,
+    // Clamped like bevy does, see `MIN_PERCEPTUAL_ROUGHNESS`
     roughness: f32,
-    reflectance: f32,
+    // F0 of the glossy coat of dielectrics
+    normal_reflectance: f32,
     ior: f32,
     specular_transmission: f32,
+    dispersion: f32,
+    thin_walled: u32,
+    // 0 -> none; 1 -> checker; 2 -> value noise; 3 -> perlin noise; 4 -> turbulence
+    texture: u32,
+    texture_scale: f32,
+    texture_octaves: u32,
+    texture_color: Vec3,
+    // 0 -> built in; otherwise the tag of the custom BSDF + 1
+    custom_bsdf: u32,
+    // Set per object, see `shadow_flags`
+    shadow_flags: u32,
+    // The `RaytraceLightLink` of the object
+    light_link: u32,
+    // Radiance added wherever a path hits the material, can go well above 1.0 for bloom to pick up
+    emissive: Vec3,
+    // 0 -> none; otherwise the first texel of the image in the `DecalTexelBuffer` + 1, set per billboard
+    image: u32,
+    // Texels of the image with a lower alpha are cut out
+    alpha_cutoff: f32,
+}
+
+impl RaytraceMaterial {
+    fn with_override(&self, material_override: Option<&RaytraceMaterialOverride>) -> Self {
+        let mut material = self.clone();
+        let Some(material_override) = material_override else {
+            return material;
+        };
+
+        (
+            material.texture,
+            material.texture_scale,
+            material.texture_octaves,
+        ) = match material_override.texture {
+            RaytraceTexture::None => (0, 1.0, 0),
+            RaytraceTexture::Checker { scale, odd_color } => {
+                material.texture_color = odd_color.to_linear().to_vec3();
+                (1, scale, 0)
+            }
+            RaytraceTexture::ValueNoise { scale } => (2, scale, 0),
+            RaytraceTexture::Perlin { scale } => (3, scale, 0),
+            RaytraceTexture::Turbulence { scale, octaves } => (4, scale, octaves),
+        };
+
+        material.custom_bsdf = material_override.custom_bsdf.map_or(0, |tag| tag + 1);
+
+        material
+    }
 }
 
 impl RenderAsset for RaytraceMaterial {
@@ -200,37 +1104,131 @@ impl RenderAsset for RaytraceMaterial {
         Ok(RaytraceMaterial {
             base_color: source_asset.base_color.to_linear().to_vec3(),
             metallic: source_asset.metallic,
-            roughness: source_asset.perceptual_roughness,
-            reflectance: source_asset.reflectance,
+            roughness: source_asset
+                .perceptual_roughness
+                .clamp(MIN_PERCEPTUAL_ROUGHNESS, 1.0),
+            normal_reflectance: dielectric_f0(source_asset.reflectance),
             ior: source_asset.ior,
             specular_transmission: source_asset.specular_transmission,
+            dispersion: 0.0,
+            // Like in bevy, transmissive materials without thickness are thin walled
+            thin_walled: (source_asset.thickness == 0.0).into(),
+            texture: 0,
+            texture_scale: 1.0,
+            texture_octaves: 0,
+            texture_color: Vec3::ZERO,
+            custom_bsdf: 0,
+            shadow_flags: 0,
+            light_link: u32::MAX,
+            // The traced lights are brought into range with the default exposure, emissive materials get it in the same
+            // proportion as in bevy's `pbr_functions.wgsl`, where a weight of 0.0 leaves the emissive color as it is.
+            // Unlike in bevy the camera's own exposure scales them anyway, it applies to the whole traced image
+            emissive: source_asset.emissive.to_vec3()
+                * (1.0 - source_asset.emissive_exposure_weight
+                    + source_asset.emissive_exposure_weight * Exposure::default().exposure()),
+            image: 0,
+            alpha_cutoff: 0.0,
         })
     }
 }
 
-// TODO: This becomes transform matrix, triangle_start, triangle_count and material
+// TODO: Meshes still need a triangle_start and triangle_count next to the transform,
 // triangle actually points at the index buffer
-#[derive(ShaderType, Clone)]
+// Free slots keep a default model, no leaf points at them
+#[derive(ShaderType, Clone, Default, PartialEq)]
 pub struct Model {
-    position: Vec3,
-    radius: f32,
+    // Into the space the primitive is defined in, like the unit sphere for spheres
+    local_from_world: Mat4,
     material_id: u32,
+    // Which intersection the shader runs for the model
+    primitive: u32,
+    // Into the buffer of the primitive type, unused for spheres. The `BillboardFacing` of billboards
+    index: u32,
+    // The `RaytraceInstanceMask`, rays whose mask shares no bit with it pass through
+    mask: u32,
+    // What is left of the translation of `local_from_world` after rounding it to a float, see `RaytracePrecisePosition`
+    translation_low: Vec3,
+    // Set for models with a `RaytracePrecisePosition`
+    precise_position: u32,
+}
+
+// Have to match the constants in scene.wgsl
+const PRIMITIVE_SPHERE: u32 = 0;
+const PRIMITIVE_HEIGHTFIELD: u32 = 1;
+const PRIMITIVE_BILLBOARD: u32 = 2;
+const PRIMITIVE_CUBOID: u32 = 3;
+const PRIMITIVE_PLANE: u32 = 4;
+
+// The render space bounds of a model for the BVH, the shader only needs its transform
+#[derive(Clone, Copy, Default)]
+pub struct ModelBounds {
+    min: Vec3,
+    max: Vec3,
+}
+
+impl ModelBounds {
+    // Of the unit sphere after the transform, every axis of the world reaches as far as the row of the matrix is long
+    fn of_sphere(world_from_local: Mat4) -> Self {
+        let matrix = Mat3::from_mat4(world_from_local);
+        let half_size = Vec3::new(
+            matrix.row(0).length(),
+            matrix.row(1).length(),
+            matrix.row(2).length(),
+        );
+        let center = world_from_local.w_axis.truncate();
+        Self {
+            min: center - half_size,
+            max: center + half_size,
+        }
+    }
+
+    // Of the unit cube after the transform, every axis of the world reaches as far as half the sum along the row of the matrix.
+    // Planes are flat, they get padded so the slab test of the BVH doesn't miss them
+    fn of_cuboid(world_from_local: Mat4) -> Self {
+        let matrix = Mat3::from_mat4(world_from_local);
+        let half_size = Vec3::new(
+            matrix.row(0).abs().element_sum(),
+            matrix.row(1).abs().element_sum(),
+            matrix.row(2).abs().element_sum(),
+        ) / 2.0
+            + 1e-4;
+        let center = world_from_local.w_axis.truncate();
+        Self {
+            min: center - half_size,
+            max: center + half_size,
+        }
+    }
+
+    // The unit quad can turn every way, so it stays within the sphere through its corners
+    fn of_billboard(world_from_local: Mat4) -> Self {
+        Self::of_sphere(world_from_local * Mat4::from_scale(Vec3::splat(FRAC_1_SQRT_2)))
+    }
 }
 
-impl Boundable for Model {
+impl Boundable for ModelBounds {
     fn aabb(&self) -> obvhs::aabb::Aabb {
         obvhs::aabb::Aabb::new(
-            Vec3A::from(self.position) - Vec3A::splat(self.radius + 0.1),
-            Vec3A::from(self.position) + Vec3A::splat(self.radius + 0.1),
+            Vec3A::from(self.min) - Vec3A::splat(0.1),
+            Vec3A::from(self.max) + Vec3A::splat(0.1),
         )
     }
 }
 
-#[derive(ShaderType, Debug)]
+// The transform and material are in its model
+#[derive(ShaderType, Clone, PartialEq)]
+pub struct Heightfield {
+    resolution: UVec2,
+    // Index of the first height in the height buffer
+    offset: u32,
+    // Which of the height buffers the samples are in
+    chunk: u32,
+}
+
+#[derive(ShaderType, Clone, PartialEq, Debug)]
 pub struct BVHNode {
     pub bounds_min: Vec3,
     pub bounds_max: Vec3,
-    // is the model_index if it is a leaf node (model_count > 0)
+    // is the first index into the `BvhPrimitiveBuffer` if it is a leaf node (model_count > 0)
     // otherwise the first child index (second child directly after that)
     pub index: u32,
     pub model_count: u32,
@@ -248,18 +1246,83 @@ pub struct ModelBVHNode {
 }
 */
 
-// There is probably a better way to send all these buffers to the gpu
-#[derive(Resource, Default, Deref)]
-pub struct ModelBuffer(std::sync::Mutex<StorageBuffer<Vec<Model>>>);
+// Filled and uploaded in `prepare_buffers`, the nodes only bind them
+#[derive(Resource, Default, Deref, DerefMut)]
+pub struct ModelBuffer(SceneBuffer<Model>);
 
-#[derive(Resource, Default, Deref)]
-pub struct MaterialBuffer(std::sync::Mutex<StorageBuffer<Vec<RaytraceMaterial>>>);
+#[derive(Resource, Default, Deref, DerefMut)]
+pub struct MaterialBuffer(SceneBuffer<RaytraceMaterial>);
 
 // The BVH and ModelBVH are different buffers because the idea behind them is,
 // that the ModelBVHBuffer is in model local space and pretty much constant in its data
 // while the BVH is for the world and rebuilt every time stuff moves
-#[derive(Resource, Default, Deref)]
-pub struct BVHBuffer(std::sync::Mutex<StorageBuffer<Vec<BVHNode>>>);
+#[derive(Resource, Default, Deref, DerefMut)]
+pub struct BVHBuffer(SceneBuffer<BVHNode>);
+
+// The slots of the models in the leaves of the BVH, in the order the leaves point into
+#[derive(Resource, Default, Deref, DerefMut)]
+pub struct BvhPrimitiveBuffer(SceneBuffer<u32>);
+
+// Every sphere keeps its slot in the model buffer for as long as it exists, so moving one only changes its own model
+// and anything kept per model stays with it. Freed slots are handed out again before the buffer grows
+#[derive(Resource, Default)]
+pub struct ModelSlots {
+    slots: EntityHashMap<u32>,
+    // Sorted from the highest to the lowest, so the lowest ones get reused first
+    free: Vec<u32>,
+    len: u32,
+}
+
+impl ModelSlots {
+    // Frees the slots of the spheres that are gone and hands out slots to the new ones
+    fn assign(&mut self, entities: &[Entity]) {
+        let alive = entities.iter().copied().collect::<EntityHashSet>();
+        let free = &mut self.free;
+        self.slots.retain(|entity, &mut slot| {
+            let keep = alive.contains(entity);
+            if !keep {
+                free.push(slot);
+            }
+            keep
+        });
+
+        // Free slots at the end are given back, so the buffer shrinks again once spheres are removed
+        self.free.sort_unstable();
+        while self.len > 0 && self.free.last() == Some(&(self.len - 1)) {
+            self.free.pop();
+            self.len -= 1;
+        }
+        self.free.reverse();
+
+        for &entity in entities {
+            if self.slots.contains_key(&entity) {
+                continue;
+            }
+            let slot = self.free.pop().unwrap_or_else(|| {
+                self.len += 1;
+                self.len - 1
+            });
+            self.slots.insert(entity, slot);
+        }
+    }
+}
+
+// The sphere or heightfield behind every material in the buffer, for looking up what a ray hit
+#[derive(Resource, Default, Deref, DerefMut)]
+pub struct MaterialOwners(Vec<Option<Entity>>);
+
+#[derive(Resource, Default, Deref, DerefMut)]
+pub struct FogBuffer(SceneBuffer<FogVolumeExtract>);
+
+#[derive(Resource, Default, Deref, DerefMut)]
+pub struct HeightfieldBuffer(SceneBuffer<Heightfield>);
+
+// The heights of all heightfields back to back, spread over several bindings so they aren't bound by the size limit of one.
+// Has to match the amount of height buffers in scene.wgsl
+pub const HEIGHT_CHUNKS: usize = 4;
+
+#[derive(Resource, Default, Deref, DerefMut)]
+pub struct HeightBuffer([SceneBuffer<f32>; HEIGHT_CHUNKS]);
 
 // Note: Bevy Builds Aabb's automatically | This probably needs to be inserted seperatly for my special meshes?
 // Todo: look into stuff like this for dynamic bvh:
@@ -267,71 +1330,585 @@ pub struct BVHBuffer(std::sync::Mutex<StorageBuffer<Vec<BVHNode>>>);
 // https://dl.acm.org/doi/pdf/10.1145/3543867
 
 /*
-#[derive(Resource, Default, Deref)]
-pub struct ModelBVHBuffer(std::sync::Mutex<StorageBuffer<Vec<ModelBVHNode>>>);
+#[derive(Resource, Default, Deref, DerefMut)]
+pub struct ModelBVHBuffer(SceneBuffer<ModelBVHNode>);
 
-#[derive(Resource, Default, Deref)]
-pub struct VertexBuffer(std::sync::Mutex<StorageBuffer<Vec<Vertex>>>);
+#[derive(Resource, Default, Deref, DerefMut)]
+pub struct VertexBuffer(SceneBuffer<Vertex>);
 
-#[derive(Resource, Default, Deref)]
-pub struct IndexBuffer(std::sync::Mutex<StorageBuffer<Vec<u32>>>);
+#[derive(Resource, Default, Deref, DerefMut)]
+pub struct IndexBuffer(SceneBuffer<u32>);
 */
 
 pub fn prepare_buffers(
-    model_buffer: Res<ModelBuffer>,
-    material_buffer: Res<MaterialBuffer>,
-    bvh_buffer: Res<BVHBuffer>,
-    data: Query<(&RaytracedSphereExtract, &Handle<StandardMaterial>)>,
+    mut model_buffer: ResMut<ModelBuffer>,
+    mut material_buffer: ResMut<MaterialBuffer>,
+    (mut bvh_buffer, mut bvh_primitive_buffer): (ResMut<BVHBuffer>, ResMut<BvhPrimitiveBuffer>),
+    mut fog_buffer: ResMut<FogBuffer>,
+    mut heightfield_buffer: ResMut<HeightfieldBuffer>,
+    mut height_buffer: ResMut<HeightBuffer>,
+    data: Query<(
+        Entity,
+        &PrimitiveExtract,
+        &Handle<StandardMaterial>,
+        Option<&RaytraceMaterialOverride>,
+    )>,
+    fog_volumes: Query<&FogVolumeExtract>,
+    heightfields: Query<(
+        Entity,
+        &HeightfieldExtract,
+        &Handle<StandardMaterial>,
+        Option<&RaytraceMaterialOverride>,
+    )>,
+    heightmaps: Res<HeightmapCache>,
+    // Prepared again on every `AssetEvent::Modified`, before this runs, so edited materials end up in the buffer the same frame
     materials: Res<RenderAssets<RaytraceMaterial>>,
+    cameras: Query<(&ExtractedView, Option<&ViewCulling>), With<CameraExtract>>,
+    render_device: Res<RenderDevice>,
+    render_queue: Res<RenderQueue>,
+    memory_report: Res<MemoryReport>,
+    (
+        mut bvh_cache,
+        mut model_slots,
+        mut material_owners,
+        origin,
+        particle_systems,
+        billboards,
+        image_offsets,
+    ): (
+        ResMut<BvhCache>,
+        ResMut<ModelSlots>,
+        ResMut<MaterialOwners>,
+        Res<RenderOrigin>,
+        Query<(
+            Entity,
+            &RaytraceParticlesExtract,
+            &Handle<StandardMaterial>,
+            Option<&RaytraceMaterialOverride>,
+        )>,
+        Query<(
+            Entity,
+            &BillboardExtract,
+            &Handle<StandardMaterial>,
+            Option<&RaytraceMaterialOverride>,
+        )>,
+        Res<ImageTexelOffsets>,
+    ),
 ) {
-    let Ok(mut model_buffer) = model_buffer.lock() else {
-        return;
-    };
+    // Every sphere, cuboid and plane takes up a model, a material and about two BVH nodes, the ones beyond what fits into one binding are left out.
+    // Heightfields take up a model as well, but there are far fewer of them
+    let max_binding_size = u64::from(render_device.limits().max_storage_buffer_binding_size);
+    let sphere_size = u64::from(Model::min_size())
+        .max(u64::from(RaytraceMaterial::min_size()))
+        .max(2 * u64::from(BVHNode::min_size()));
+    let max_spheres = (max_binding_size / sphere_size) as usize;
+    let mut dropped_objects = data.iter().len().saturating_sub(max_spheres) as u32;
+    // Particles take up what the spheres leave
+    let particle_count = particle_systems
+        .iter()
+        .map(|(_, particles, ..)| particles.particles.len())
+        .sum::<usize>();
+    let max_particles = max_spheres.saturating_sub(data.iter().len());
+    dropped_objects += particle_count.saturating_sub(max_particles) as u32;
 
-    let Ok(mut material_buffer) = material_buffer.lock() else {
-        return;
+    // None while one of the cameras doesn't cull, then everything has to stay
+    let culling = cameras
+        .iter()
+        .map(|(_, culling)| culling)
+        .collect::<Option<Vec<_>>>();
+    let visible = |center: Vec3, radius: f32| {
+        culling
+            .as_ref()
+            .is_none_or(|views| views.iter().any(|view| view.sees(center, radius)))
     };
 
-    let Ok(mut bvh_buffer) = bvh_buffer.lock() else {
-        return;
-    };
+    let spheres = data.iter().take(max_spheres).collect::<Vec<_>>();
+    model_slots.assign(
+        &spheres
+            .iter()
+            .map(|(entity, ..)| *entity)
+            .chain(heightfields.iter().map(|(entity, ..)| entity))
+            .chain(billboards.iter().map(|(entity, ..)| entity))
+            .collect::<Vec<_>>(),
+    );
 
-    let mut all_spheres = Vec::new();
-    let mut all_materials = Vec::new();
-    for (index, (sphere, material_handle)) in data.iter().enumerate() {
-        let material = materials.get(material_handle).expect("This should exist");
-        // TODO: Intergrate this with change detection so these buffers don't get replaced every frame
-        all_materials.push(material.clone());
-
-        all_spheres.push(Model {
-            position: sphere.position,
-            radius: sphere.radius,
-            material_id: index as u32,
+    // The materials of the models share their slots.
+    // Particles don't keep their slots, they come and go too often and take the ones after all the spheres and heightfields
+    let particle_slots = model_slots.len as usize;
+    let slot_count = particle_slots + particle_count.min(max_particles);
+    let mut slots = SlotWriter::new(
+        &mut model_buffer,
+        &mut material_buffer,
+        &mut material_owners,
+        slot_count,
+    );
+    let mut bounds = vec![ModelBounds::default(); slot_count];
+
+    // The BVH is split into the static and the dynamic models, so the one over the static models stays while others come and go
+    let mut static_models = (Vec::new(), Vec::new());
+    let mut dynamic_models = (Vec::new(), Vec::new());
+    for (entity, object, material_handle, material_override) in spheres {
+        let slot = model_slots.slots[&entity];
+        // Left out until its material is prepared, like the heightfields below, its slot stays empty
+        let Some(material) = materials.get(material_handle) else {
+            continue;
+        };
+        let material = RaytraceMaterial {
+            dispersion: object.dispersion,
+            shadow_flags: object.shadow_flags,
+            light_link: object.light_link,
+            ..material.with_override(material_override)
+        };
+        let model = if object.precise {
+            let (local_from_world, translation_low) =
+                origin.precise_local_from_render(object.world_from_local);
+            Model {
+                local_from_world,
+                material_id: slot,
+                primitive: PRIMITIVE_SPHERE,
+                index: 0,
+                mask: object.mask,
+                translation_low,
+                precise_position: 1,
+            }
+        } else {
+            Model {
+                local_from_world: origin.local_from_render(object.world_from_local),
+                material_id: slot,
+                primitive: object.primitive,
+                index: 0,
+                mask: object.mask,
+                ..default()
+            }
+        };
+        slots.fill(slot, entity, model, material);
+        let render_from_local = origin.render_from_local(object.world_from_local);
+        bounds[slot as usize] = match object.primitive {
+            PRIMITIVE_CUBOID => ModelBounds::of_cuboid(render_from_local),
+            // Flat along its height
+            PRIMITIVE_PLANE => ModelBounds::of_cuboid(
+                render_from_local * Mat4::from_scale(Vec3::new(1.0, 0.0, 1.0)),
+            ),
+            _ => ModelBounds::of_sphere(render_from_local),
+        };
+
+        // Culled objects keep their slot, they are only left out of the BVH
+        if !visible(object.position, object.radius) {
+            continue;
+        }
+
+        let (entities, slots) = if object.is_static {
+            &mut static_models
+        } else {
+            &mut dynamic_models
+        };
+        entities.push(entity);
+        slots.push(slot);
+    }
+
+    // The buffers are shared by all cameras, so the closest one decides the level of detail
+    let camera_positions = cameras
+        .iter()
+        .map(|(view, _)| view.world_from_view.translation())
+        .collect::<Vec<_>>();
+
+    // A heightfield always stays within one chunk of heights, the first one with enough room left
+    let chunk_capacity = (max_binding_size / 4) as usize;
+    heightfield_buffer.clear();
+    for heights in height_buffer.iter_mut() {
+        heights.clear();
+    }
+    for (entity, heightfield, material_handle, material_override) in &heightfields {
+        let slot = model_slots.slots[&entity];
+        let center = (heightfield.bounds_min + heightfield.bounds_max) / 2.0;
+        let radius = heightfield.bounds_min.distance(center);
+        if !visible(center, radius) {
+            continue;
+        }
+
+        let (Some(heightmap), Some(material)) = (
+            heightmaps.get(&heightfield.image),
+            materials.get(material_handle),
+        ) else {
+            continue;
+        };
+
+        let level = heightfield.lod.as_ref().map_or(0, |lod| {
+            let distance = camera_positions
+                .iter()
+                .map(|&position| heightfield.distance_to(position))
+                .fold(f32::INFINITY, f32::min);
+            lod.level(distance)
+        });
+        let heightmap = heightmap.level(level);
+
+        let Some(chunk) = height_buffer
+            .iter()
+            .position(|heights| heights.len() + heightmap.heights.len() <= chunk_capacity)
+        else {
+            dropped_objects += 1;
+            continue;
+        };
+
+        slots.fill(
+            slot,
+            entity,
+            Model {
+                local_from_world: origin.local_from_render(heightfield.world_from_local),
+                material_id: slot,
+                primitive: PRIMITIVE_HEIGHTFIELD,
+                index: heightfield_buffer.len() as u32,
+                mask: heightfield.mask,
+                ..default()
+            },
+            RaytraceMaterial {
+                shadow_flags: heightfield.shadow_flags,
+                light_link: heightfield.light_link,
+                ..material.with_override(material_override)
+            },
+        );
+        bounds[slot as usize] = ModelBounds {
+            min: origin.point(heightfield.bounds_min),
+            max: origin.point(heightfield.bounds_max),
+        };
+        heightfield_buffer.push(Heightfield {
+            resolution: heightmap.resolution,
+            offset: height_buffer[chunk].len() as u32,
+            chunk: chunk as u32,
         });
+        height_buffer[chunk].extend(heightmap.heights.iter().copied());
+
+        let (entities, slots) = if heightfield.is_static {
+            &mut static_models
+        } else {
+            &mut dynamic_models
+        };
+        entities.push(entity);
+        slots.push(slot);
     }
 
-    // TODO: Look into optimizer/presorting/switching algorithm and what these limits are
+    for (entity, billboard, material_handle, material_override) in &billboards {
+        let slot = model_slots.slots[&entity];
+        if !visible(billboard.position, billboard.radius) {
+            continue;
+        }
 
-    let aabbs = all_spheres.iter().map(Boundable::aabb).collect::<Vec<_>>();
-    let bvh = build_ploc::<24>(
-        &aabbs,
-        (0u32..(all_spheres.len() as u32)).collect::<Vec<_>>(),
-        obvhs::ploc::SortPrecision::U64,
-        0,
-    );
+        // Left out until the image is ready, they would show up as solid quads otherwise
+        let (Some(&texel_offset), Some(material)) = (
+            image_offsets.get(&billboard.image),
+            materials.get(material_handle),
+        ) else {
+            continue;
+        };
 
-    let bvh_nodes = bvh
-        .nodes
-        .into_iter()
-        .map(|node| BVHNode {
-            bounds_min: node.aabb.min.into(),
-            bounds_max: node.aabb.max.into(),
-            index: node.first_index,
-            model_count: node.prim_count,
+        slots.fill(
+            slot,
+            entity,
+            Model {
+                local_from_world: origin.local_from_render(billboard.world_from_local),
+                material_id: slot,
+                primitive: PRIMITIVE_BILLBOARD,
+                index: billboard.facing as u32,
+                mask: billboard.mask,
+                ..default()
+            },
+            RaytraceMaterial {
+                shadow_flags: billboard.shadow_flags,
+                light_link: billboard.light_link,
+                image: texel_offset + 1,
+                alpha_cutoff: billboard.alpha_cutoff,
+                ..material.with_override(material_override)
+            },
+        );
+        bounds[slot as usize] =
+            ModelBounds::of_billboard(origin.render_from_local(billboard.world_from_local));
+
+        let (entities, slots) = if billboard.is_static {
+            &mut static_models
+        } else {
+            &mut dynamic_models
+        };
+        entities.push(entity);
+        slots.push(slot);
+    }
+
+    let particles = particle_systems
+        .iter()
+        .flat_map(|(entity, system, material_handle, material_override)| {
+            system
+                .particles
+                .iter()
+                .map(move |particle| (entity, system, material_handle, material_override, particle))
         })
-        .collect::<Vec<_>>();
+        .take(max_particles);
+    for (slot, (entity, system, material_handle, material_override, particle)) in
+        (particle_slots..).zip(particles)
+    {
+        let Some(material) = materials.get(material_handle) else {
+            continue;
+        };
+        let world_from_local = Mat4::from_scale_rotation_translation(
+            Vec3::splat(particle.radius),
+            Quat::IDENTITY,
+            particle.position,
+        );
+        slots.fill(
+            slot as u32,
+            entity,
+            Model {
+                local_from_world: origin.local_from_render(world_from_local),
+                material_id: slot as u32,
+                primitive: PRIMITIVE_SPHERE,
+                index: 0,
+                mask: system.mask,
+                ..default()
+            },
+            RaytraceMaterial {
+                base_color: particle.color,
+                emissive: particle.emissive,
+                shadow_flags: system.shadow_flags,
+                light_link: system.light_link,
+                ..material.with_override(material_override)
+            },
+        );
+        bounds[slot] = ModelBounds::of_sphere(origin.render_from_local(world_from_local));
+
+        if visible(particle.position, particle.radius) {
+            dynamic_models.0.push(entity);
+            dynamic_models.1.push(slot as u32);
+        }
+    }
 
-    model_buffer.set(all_spheres);
-    material_buffer.set(all_materials);
+    slots.empty_the_rest();
+
+    bvh_cache.rebase(*origin);
+    let (bvh_nodes, bvh_primitives) = bvh_cache.nodes(static_models, dynamic_models, &bounds);
     bvh_buffer.set(bvh_nodes);
+    bvh_primitive_buffer.set(bvh_primitives);
+    fog_buffer.set(fog_volumes.iter().map(|fog| FogVolumeExtract {
+        local_from_world: origin.rebase_from_world(fog.local_from_world),
+        ..fog.clone()
+    }));
+
+    let used_chunks = height_buffer
+        .iter()
+        .filter(|heights| !heights.is_empty())
+        .count();
+    let height_bytes = height_buffer
+        .iter()
+        .map(|heights| heights.len() as u64 * 4)
+        .sum();
+    {
+        let mut memory = memory_report.lock();
+        memory.record("models", model_buffer.size(), 1);
+        memory.record("materials", material_buffer.size(), 1);
+        memory.record("bvh nodes", bvh_buffer.size(), 1);
+        memory.record("bvh primitives", bvh_primitive_buffer.size(), 1);
+        memory.record("fog volumes", fog_buffer.size(), 1);
+        memory.record("heightfields", heightfield_buffer.size(), 1);
+        memory.record("heights", height_bytes, used_chunks.max(1) as u32);
+        memory.dropped_objects = dropped_objects;
+    }
+
+    model_buffer.write_buffer(&render_device, &render_queue);
+    material_buffer.write_buffer(&render_device, &render_queue);
+    bvh_buffer.write_buffer(&render_device, &render_queue);
+    bvh_primitive_buffer.write_buffer(&render_device, &render_queue);
+    fog_buffer.write_buffer(&render_device, &render_queue);
+    heightfield_buffer.write_buffer(&render_device, &render_queue);
+    for buffer in height_buffer.iter_mut() {
+        buffer.write_buffer(&render_device, &render_queue);
+    }
+}
+
+// Writes the model and material of every object into its slot. The buffers keep them between frames
+// and only upload the slots whose object was added, changed or removed
+struct SlotWriter<'a> {
+    models: &'a mut ModelBuffer,
+    materials: &'a mut MaterialBuffer,
+    owners: &'a mut MaterialOwners,
+    filled: Vec<bool>,
+}
+
+impl<'a> SlotWriter<'a> {
+    fn new(
+        models: &'a mut ModelBuffer,
+        materials: &'a mut MaterialBuffer,
+        owners: &'a mut MaterialOwners,
+        slot_count: usize,
+    ) -> Self {
+        models.resize(slot_count);
+        materials.resize(slot_count);
+        owners.resize(slot_count, None);
+        Self {
+            models,
+            materials,
+            owners,
+            filled: vec![false; slot_count],
+        }
+    }
+
+    fn fill(&mut self, slot: u32, owner: Entity, model: Model, material: RaytraceMaterial) {
+        let slot = slot as usize;
+        self.models.update(slot, model);
+        self.materials.update(slot, material);
+        self.owners[slot] = Some(owner);
+        self.filled[slot] = true;
+    }
+
+    // The slots of removed objects and of the ones left out this frame get a model without a mask,
+    // so no ray hits them, and no material owned by anything
+    fn empty_the_rest(self) {
+        for (slot, filled) in self.filled.into_iter().enumerate() {
+            if !filled {
+                self.models.update(slot, Model::default());
+                self.materials.update(slot, RaytraceMaterial::default());
+                self.owners[slot] = None;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::{
+        prelude::*,
+        render::{camera::Exposure, render_asset::RenderAsset},
+    };
+
+    use super::{palette_materials, ModelBounds, RaytraceMaterial};
+    use crate::raytracing::{RaytraceMaterialId, RaytraceMaterialPalette};
+
+    fn prepare(material: StandardMaterial) -> RaytraceMaterial {
+        let Ok(prepared) = RaytraceMaterial::prepare_asset(material, &mut ()) else {
+            panic!("Could not prepare the material");
+        };
+        prepared
+    }
+
+    // perceptualRoughnessToRoughness in bevy's pbr_lighting.wgsl, the shader squares the clamped value
+    fn bevy_alpha(perceptual_roughness: f32) -> f32 {
+        let clamped = perceptual_roughness.clamp(0.089, 1.0);
+        clamped * clamped
+    }
+
+    // calculate_F0 in bevy's pbr_functions.wgsl
+    fn bevy_f0(base_color: Vec3, metallic: f32, reflectance: f32) -> Vec3 {
+        0.16 * reflectance * reflectance * (1.0 - metallic) + base_color * metallic
+    }
+
+    #[test]
+    fn roughness_matches_bevy() {
+        for perceptual_roughness in [0.0, 0.05, 0.089, 0.3, 0.5, 1.0, 1.5] {
+            let material = prepare(StandardMaterial {
+                perceptual_roughness,
+                ..default()
+            });
+            let alpha = material.roughness * material.roughness;
+            assert!(
+                (alpha - bevy_alpha(perceptual_roughness)).abs() < 1e-6,
+                "alpha {alpha} for a perceptual roughness of {perceptual_roughness}"
+            );
+        }
+    }
+
+    #[test]
+    fn f0_matches_bevy() {
+        let base_color = Vec3::new(0.9, 0.6, 0.2);
+        for reflectance in [0.0, 0.35, 0.5, 1.0] {
+            for metallic in [0.0, 0.25, 0.5, 1.0] {
+                let material = prepare(StandardMaterial {
+                    base_color: Color::linear_rgb(base_color.x, base_color.y, base_color.z),
+                    metallic,
+                    reflectance,
+                    ..default()
+                });
+                // The shader picks the metallic lobe with a chance of `metallic`, so its F0 averages out to the blend
+                let f0 = Vec3::splat(material.normal_reflectance)
+                    .lerp(material.base_color, material.metallic);
+                let expected = bevy_f0(base_color, metallic, reflectance);
+                assert!(
+                    f0.abs_diff_eq(expected, 1e-6),
+                    "F0 {f0} instead of {expected} for a reflectance of {reflectance} and metallic of {metallic}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn default_material_reflects_four_percent() {
+        let material = prepare(StandardMaterial::default());
+        assert!((material.normal_reflectance - 0.04).abs() < 1e-6);
+    }
+
+    #[test]
+    fn emissive_keeps_its_hdr_range() {
+        let emissive = LinearRgba::rgb(8.0, 4.0, 1.0);
+        let material = prepare(StandardMaterial {
+            emissive,
+            ..default()
+        });
+        // Nothing clamps it to 1.0, or bright emitters couldn't bloom
+        assert_eq!(material.emissive, emissive.to_vec3());
+
+        // With the full exposure weight it is scaled like the lights are
+        let material = prepare(StandardMaterial {
+            emissive,
+            emissive_exposure_weight: 1.0,
+            ..default()
+        });
+        let expected = emissive.to_vec3() * Exposure::default().exposure();
+        assert!(material.emissive.abs_diff_eq(expected, 1e-6));
+    }
+
+    #[test]
+    fn sphere_bounds_follow_scale_and_rotation() {
+        // The long axis of the sphere turned upwards
+        let world_from_local = Mat4::from_scale_rotation_translation(
+            Vec3::new(2.0, 1.0, 1.0),
+            Quat::from_rotation_z(std::f32::consts::FRAC_PI_2),
+            Vec3::new(0.0, 5.0, 0.0),
+        );
+        let bounds = ModelBounds::of_sphere(world_from_local);
+        assert!(bounds.min.abs_diff_eq(Vec3::new(-1.0, 3.0, -1.0), 1e-5));
+        assert!(bounds.max.abs_diff_eq(Vec3::new(1.0, 7.0, 1.0), 1e-5));
+    }
+
+    #[test]
+    fn billboard_bounds_hold_every_turn() {
+        let world_from_local = Mat4::from_scale(Vec3::new(1.0, 2.0, 1.0));
+        let bounds = ModelBounds::of_billboard(world_from_local);
+        for angle in 0..16 {
+            let rotation = Quat::from_euler(EulerRot::XYZ, angle as f32, angle as f32 * 0.7, 0.0);
+            for corner in [Vec3::new(0.5, 0.5, 0.0), Vec3::new(-0.5, 0.5, 0.0)] {
+                let point = world_from_local.transform_point3(rotation * corner);
+                assert!(
+                    point.cmpge(bounds.min - 1e-5).all() && point.cmple(bounds.max + 1e-5).all()
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn palette_skips_indices_past_its_end() {
+        let first = Handle::weak_from_u128(1);
+        let second = Handle::weak_from_u128(2);
+        let palette = RaytraceMaterialPalette(vec![first.clone(), second.clone()]);
+        let ids = [
+            RaytraceMaterialId(1),
+            RaytraceMaterialId(2),
+            RaytraceMaterialId(u32::MAX),
+            RaytraceMaterialId(0),
+        ];
+        let objects = ids
+            .iter()
+            .enumerate()
+            .map(|(index, id)| (Entity::from_raw(index as u32), id));
+
+        let resolved = palette_materials(&palette, objects).collect::<Vec<_>>();
+        assert_eq!(
+            resolved,
+            vec![(Entity::from_raw(0), second), (Entity::from_raw(3), first)]
+        );
+    }
 }
@@ -0,0 +1,511 @@
+use std::io::{Error, ErrorKind};
+
+use bevy::{
+    asset::{io::Reader, AssetLoader, AsyncReadExt, LoadContext},
+    ecs::query::QueryItem,
+    prelude::*,
+    render::{
+        camera::Exposure,
+        extract_component::{ExtractComponent, ExtractComponentPlugin},
+        render_asset::{PrepareAssetError, RenderAsset, RenderAssetPlugin, RenderAssets},
+        render_resource::{ShaderType, TextureFormat},
+        renderer::{RenderDevice, RenderQueue},
+        Extract, ExtractSchedule, Render, RenderApp, RenderSet,
+    },
+    utils::HashMap,
+};
+
+use super::{buffer::SceneBuffer, memory::MemoryReport, origin::RenderOrigin};
+
+// Have to match the constants in scene.wgsl, profiles get resampled to this many angles and cookies to this many texels
+const IES_VERTICAL_SAMPLES: usize = 64;
+const IES_HORIZONTAL_SAMPLES: usize = 32;
+const COOKIE_RESOLUTION: usize = 128;
+// Marks lights without a profile or cookie
+const NO_LIGHT_DATA: u32 = u32::MAX;
+
+pub struct RaytraceLightPlugin;
+
+impl Plugin for RaytraceLightPlugin {
+    fn build(&self, app: &mut App) {
+        app.register_type::<RaytraceIesProfile>()
+            .register_type::<RaytraceLightCookie>()
+            .register_type::<RaytraceDiskLight>()
+            .register_type::<RaytraceLightLink>()
+            .init_asset::<IesProfile>()
+            .register_asset_loader(IesLoader)
+            .add_plugins((
+                ExtractComponentPlugin::<LightExtract>::default(),
+                RenderAssetPlugin::<IesSamples>::default(),
+            ));
+
+        let Some(render_app) = app.get_sub_app_mut(RenderApp) else {
+            return;
+        };
+
+        render_app
+            .init_resource::<LightBuffer>()
+            .init_resource::<LightDataBuffer>()
+            .init_resource::<CookieCache>()
+            .add_systems(ExtractSchedule, extract_cookies)
+            .add_systems(Render, prepare_lights.in_set(RenderSet::PrepareResources));
+    }
+}
+
+// Shapes the light of a `PointLight` or `SpotLight` in the traced scene with a measured IES profile.
+// The 0° vertical angle of the profile points along the forward direction of the light, 0° horizontal towards its right.
+// The profile only redistributes the light, its total output stays the intensity of the bevy light.
+#[derive(Component, Reflect, Clone)]
+pub struct RaytraceIesProfile(pub Handle<IesProfile>);
+
+// Projects an image from a `SpotLight` into the traced scene, like light falling through window blinds.
+// The image covers the square around the outer cone, seen looking along the light with its top towards the light's up direction.
+// It gets resampled to a fixed resolution, colors multiply the light and black blocks it.
+#[derive(Component, Reflect, Clone)]
+pub struct RaytraceLightCookie(pub Handle<Image>);
+
+// Turns the `radius` of a `PointLight` or `SpotLight` into a one-sided disk facing along the light's forward direction,
+// like a ceiling panel, instead of a sphere. Either way a radius above 0 gives the light soft shadows.
+#[derive(Component, Reflect, Clone, Copy, Default)]
+pub struct RaytraceDiskLight;
+
+// Light linking through 32 groups. On a light it sets the groups the light is in, on a traced sphere or heightfield
+// the groups of the lights that light it, the two only meet if they share one. Without it either is in all of them.
+// Only the direct light is linked, light bouncing off other surfaces and the light shafts in fog reach everything
+#[derive(Component, Reflect, Clone, Copy, PartialEq, Eq, Debug)]
+pub struct RaytraceLightLink(pub u32);
+
+impl Default for RaytraceLightLink {
+    fn default() -> Self {
+        Self(u32::MAX)
+    }
+}
+
+// The candela distribution of a luminaire, loaded from IESNA LM-63 photometric files (.ies) with type C photometry
+#[derive(Asset, TypePath, Clone)]
+pub struct IesProfile {
+    // Degrees from the nadir, ascending
+    vertical_angles: Vec<f32>,
+    // Degrees around the nadir, ascending. A last angle of 0, 90 or 180 means the profile is symmetric
+    horizontal_angles: Vec<f32>,
+    // One row of vertical samples per horizontal angle
+    candela: Vec<f32>,
+}
+
+impl IesProfile {
+    fn parse(text: &str) -> Result<Self, Error> {
+        let invalid = |message: &str| Error::new(ErrorKind::InvalidData, message.to_string());
+
+        // Everything up to the TILT line is keywords describing the luminaire
+        let mut lines = text.lines();
+        let tilt = lines
+            .by_ref()
+            .find_map(|line| line.trim().strip_prefix("TILT="))
+            .ok_or_else(|| invalid("missing TILT line"))?
+            .trim()
+            .to_string();
+
+        let mut values = lines
+            .flat_map(|line| line.split(|c: char| c.is_whitespace() || c == ','))
+            .filter(|value| !value.is_empty())
+            .map(|value| {
+                value
+                    .parse::<f32>()
+                    .map_err(|_| invalid("expected a number"))
+            });
+        let mut next = move || {
+            values
+                .next()
+                .unwrap_or_else(|| Err(invalid("file ends early")))
+        };
+
+        // Lamp tilt data only matters for luminaires mounted at an angle
+        if tilt == "INCLUDE" {
+            let _geometry = next()?;
+            let pairs = next()? as usize;
+            for _ in 0..pairs * 2 {
+                next()?;
+            }
+        }
+
+        let _lamps = next()?;
+        let _lumens_per_lamp = next()?;
+        let multiplier = next()?;
+        let vertical_count = next()? as usize;
+        let horizontal_count = next()? as usize;
+        let photometric_type = next()?;
+        // Units and the size of the luminaire, followed by ballast factor, a reserved value and the input watts
+        for _ in 0..7 {
+            next()?;
+        }
+
+        if photometric_type != 1.0 {
+            return Err(invalid("only type C photometry is supported"));
+        }
+        if vertical_count == 0 || horizontal_count == 0 {
+            return Err(invalid("the profile has no angles"));
+        }
+
+        let vertical_angles = (0..vertical_count)
+            .map(|_| next())
+            .collect::<Result<Vec<_>, _>>()?;
+        let horizontal_angles = (0..horizontal_count)
+            .map(|_| next())
+            .collect::<Result<Vec<_>, _>>()?;
+        let candela = (0..vertical_count * horizontal_count)
+            .map(|_| next().map(|value| value * multiplier))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(Self {
+            vertical_angles,
+            horizontal_angles,
+            candela,
+        })
+    }
+
+    // The candela at the given angles in degrees, using the symmetry of the profile
+    fn sample(&self, vertical: f32, horizontal: f32) -> f32 {
+        let last = *self.horizontal_angles.last().unwrap_or(&0.0);
+        let horizontal = if last == 0.0 {
+            0.0
+        } else if last == 90.0 {
+            let mirrored = horizontal % 180.0;
+            mirrored.min(180.0 - mirrored)
+        } else if last == 180.0 {
+            horizontal.min(360.0 - horizontal)
+        } else {
+            horizontal
+        };
+
+        let (h0, h1, h_blend) = interpolation(&self.horizontal_angles, horizontal);
+        let vertical_count = self.vertical_angles.len();
+        let row = |h: usize| {
+            let first = *self.vertical_angles.first().unwrap_or(&0.0);
+            let last = *self.vertical_angles.last().unwrap_or(&0.0);
+            if vertical < first || vertical > last {
+                return 0.0;
+            }
+            let (v0, v1, v_blend) = interpolation(&self.vertical_angles, vertical);
+            let values = &self.candela[h * vertical_count..(h + 1) * vertical_count];
+            values[v0] + (values[v1] - values[v0]) * v_blend
+        };
+        row(h0) + (row(h1) - row(h0)) * h_blend
+    }
+}
+
+// The two neighbouring entries of a sorted list of angles and how far the angle is between them
+fn interpolation(angles: &[f32], angle: f32) -> (usize, usize, f32) {
+    let upper = angles.partition_point(|&a| a < angle);
+    if upper == 0 {
+        return (0, 0, 0.0);
+    }
+    if upper == angles.len() {
+        return (upper - 1, upper - 1, 0.0);
+    }
+    let (a0, a1) = (angles[upper - 1], angles[upper]);
+    (upper - 1, upper, (angle - a0) / (a1 - a0).max(f32::EPSILON))
+}
+
+#[derive(Default)]
+pub struct IesLoader;
+
+impl AssetLoader for IesLoader {
+    type Asset = IesProfile;
+    type Settings = ();
+    type Error = Error;
+
+    async fn load<'a>(
+        &'a self,
+        reader: &'a mut Reader<'_>,
+        _settings: &'a (),
+        _load_context: &'a mut LoadContext<'_>,
+    ) -> Result<IesProfile, Error> {
+        let mut text = String::new();
+        reader.read_to_string(&mut text).await?;
+        IesProfile::parse(&text)
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["ies"]
+    }
+}
+
+// A profile resampled to a fixed grid of angles, normalized so it averages to 1.0 over the sphere
+pub struct IesSamples(Vec<f32>);
+
+impl RenderAsset for IesSamples {
+    type SourceAsset = IesProfile;
+
+    type Param = ();
+
+    fn prepare_asset(
+        source_asset: Self::SourceAsset,
+        _param: &mut bevy::ecs::system::SystemParamItem<Self::Param>,
+    ) -> Result<Self, PrepareAssetError<Self::SourceAsset>> {
+        let mut samples = Vec::with_capacity(IES_VERTICAL_SAMPLES * IES_HORIZONTAL_SAMPLES);
+        let mut weighted_sum = 0.0;
+        let mut weight_sum = 0.0;
+        for h in 0..IES_HORIZONTAL_SAMPLES {
+            let horizontal = h as f32 / IES_HORIZONTAL_SAMPLES as f32 * 360.0;
+            for v in 0..IES_VERTICAL_SAMPLES {
+                let vertical = v as f32 / (IES_VERTICAL_SAMPLES - 1) as f32 * 180.0;
+                let candela = source_asset.sample(vertical, horizontal).max(0.0);
+
+                // Samples near the poles stand for less of the sphere
+                let weight = vertical.to_radians().sin().max(1e-3);
+                weighted_sum += candela * weight;
+                weight_sum += weight;
+                samples.push(candela);
+            }
+        }
+
+        let average = weighted_sum / weight_sum;
+        if average > 0.0 {
+            samples.iter_mut().for_each(|sample| *sample /= average);
+        }
+
+        Ok(Self(samples))
+    }
+}
+
+#[derive(Clone, Default, PartialEq, ShaderType)]
+pub struct RaytraceLight {
+    position: Vec3,
+    range: f32,
+    // Luminous intensity times the color, already exposed
+    intensity: Vec3,
+    // 0 -> point; 1 -> spot
+    kind: u32,
+    forward: Vec3,
+    // The spot cone falloff is saturate(cos_angle * spot_scale + spot_offset)², like in bevy
+    spot_scale: f32,
+    right: Vec3,
+    spot_offset: f32,
+    // Index of the first sample in the light data, NO_LIGHT_DATA without a profile
+    ies_offset: u32,
+    // Index of the first texel in the light data, NO_LIGHT_DATA without a cookie
+    cookie_offset: u32,
+    // 1 / tan(outer angle), maps the spot cone onto the cookie
+    cookie_scale: f32,
+    // 0 for a point, otherwise the radius of the sphere or disk
+    radius: f32,
+    // 0 -> sphere; 1 -> disk
+    shape: u32,
+    // The `RaytraceLightLink` of the light
+    link: u32,
+}
+
+#[derive(Clone, Component)]
+pub struct LightExtract {
+    light: RaytraceLight,
+    ies_profile: Option<AssetId<IesProfile>>,
+    cookie: Option<AssetId<Image>>,
+}
+
+impl ExtractComponent for LightExtract {
+    type QueryData = (
+        Option<&'static PointLight>,
+        Option<&'static SpotLight>,
+        &'static GlobalTransform,
+        Option<&'static RaytraceIesProfile>,
+        Option<&'static RaytraceLightCookie>,
+        Has<RaytraceDiskLight>,
+        Option<&'static RaytraceLightLink>,
+    );
+
+    type QueryFilter = Or<(With<PointLight>, With<SpotLight>)>;
+
+    type Out = Self;
+
+    fn extract_component(item: QueryItem<'_, Self::QueryData>) -> Option<Self::Out> {
+        let (point, spot, transform, ies_profile, cookie, disk, link) = item;
+
+        // Bevy treats the intensity as lumens spread over the whole sphere, also for spot lights.
+        // The lights are shared by all views, so they use the default exposure of bevy cameras.
+        // Every camera scales its traced image by its own exposure relative to that
+        let candela_per_lumen = Exposure::default().exposure() / (4.0 * std::f32::consts::PI);
+        let (color, intensity, range, radius, kind, spot_scale, spot_offset, cookie_scale) =
+            match (point, spot) {
+                (_, Some(spot)) => {
+                    let cos_outer = spot.outer_angle.cos();
+                    let spot_scale = 1.0 / (spot.inner_angle.cos() - cos_outer).max(1e-4);
+                    (
+                        spot.color,
+                        spot.intensity,
+                        spot.range,
+                        spot.radius,
+                        1,
+                        spot_scale,
+                        -cos_outer * spot_scale,
+                        1.0 / spot.outer_angle.tan().max(1e-4),
+                    )
+                }
+                (Some(point), None) => (
+                    point.color,
+                    point.intensity,
+                    point.range,
+                    point.radius,
+                    0,
+                    0.0,
+                    0.0,
+                    0.0,
+                ),
+                (None, None) => return None,
+            };
+
+        Some(LightExtract {
+            light: RaytraceLight {
+                position: transform.translation(),
+                range,
+                intensity: color.to_linear().to_vec3() * intensity * candela_per_lumen,
+                kind,
+                forward: transform.forward().as_vec3(),
+                spot_scale,
+                right: transform.right().as_vec3(),
+                spot_offset,
+                ies_offset: NO_LIGHT_DATA,
+                cookie_offset: NO_LIGHT_DATA,
+                cookie_scale,
+                radius: radius.max(0.0),
+                shape: disk.into(),
+                link: link.copied().unwrap_or_default().0,
+            },
+            ies_profile: ies_profile.map(|profile| profile.0.id()),
+            // Only the cone of spot lights can be mapped onto an image
+            cookie: cookie
+                .filter(|_| spot.is_some())
+                .map(|cookie| cookie.0.id()),
+        })
+    }
+}
+
+#[derive(Resource, Default, Deref, DerefMut)]
+pub struct LightBuffer(SceneBuffer<RaytraceLight>);
+
+// The samples of every IES profile and the texels of every cookie in use back to back
+#[derive(Resource, Default, Deref, DerefMut)]
+pub struct LightDataBuffer(SceneBuffer<f32>);
+
+// The resampled texels of every cookie in use, they only get read back from the images when those change
+#[derive(Resource, Default, Deref, DerefMut)]
+pub struct CookieCache(HashMap<AssetId<Image>, Vec<f32>>);
+
+fn extract_cookies(
+    mut cache: ResMut<CookieCache>,
+    cookies: Extract<Query<&RaytraceLightCookie, With<SpotLight>>>,
+    images: Extract<Res<Assets<Image>>>,
+    image_events: Extract<Res<Events<AssetEvent<Image>>>>,
+) {
+    for event in image_events.iter_current_update_events() {
+        match *event {
+            AssetEvent::Modified { id }
+            | AssetEvent::Removed { id }
+            | AssetEvent::Unused { id } => {
+                cache.remove(&id);
+            }
+            _ => {}
+        }
+    }
+
+    for cookie in &cookies {
+        let id = cookie.0.id();
+        if cache.contains_key(&id) {
+            continue;
+        }
+
+        if let Some(image) = images.get(id) {
+            cache.insert(id, cookie_from_image(image));
+        }
+    }
+}
+
+// Linear rgb, cookies in formats that can't be read let the light shine unshaped
+fn cookie_from_image(image: &Image) -> Vec<f32> {
+    let Some(texels) = resample_image(image, COOKIE_RESOLUTION) else {
+        warn!(
+            "Light cookies with the format {:?} are not supported, the light will shine unshaped",
+            image.texture_descriptor.format
+        );
+        return vec![1.0; COOKIE_RESOLUTION * COOKIE_RESOLUTION * 3];
+    };
+
+    texels
+        .iter()
+        .flat_map(|texel| texel.to_vec3().to_array())
+        .collect()
+}
+
+// Nearest neighbour resampling of the first layer into a square of linear colors, row by row from the top.
+// None for formats that aren't supported
+pub(super) fn resample_image(image: &Image, resolution: usize) -> Option<Vec<LinearRgba>> {
+    let size = image.size();
+    let (texel_size, color): (usize, fn(&[u8]) -> LinearRgba) =
+        match image.texture_descriptor.format {
+            TextureFormat::R8Unorm => (1, |texel| {
+                let value = f32::from(texel[0]) / 255.0;
+                LinearRgba::rgb(value, value, value)
+            }),
+            TextureFormat::Rgba8Unorm => (4, |texel| {
+                LinearRgba::new(
+                    f32::from(texel[0]) / 255.0,
+                    f32::from(texel[1]) / 255.0,
+                    f32::from(texel[2]) / 255.0,
+                    f32::from(texel[3]) / 255.0,
+                )
+            }),
+            TextureFormat::Rgba8UnormSrgb => (4, |texel| {
+                Srgba::rgba_u8(texel[0], texel[1], texel[2], texel[3]).into()
+            }),
+            _ => return None,
+        };
+
+    let mut texels = Vec::with_capacity(resolution * resolution);
+    for y in 0..resolution {
+        for x in 0..resolution {
+            let source_x = x * size.x as usize / resolution;
+            let source_y = y * size.y as usize / resolution;
+            let offset = (source_y * size.x as usize + source_x) * texel_size;
+            let texel = image
+                .data
+                .get(offset..offset + texel_size)
+                .map_or(LinearRgba::WHITE, color);
+            texels.push(texel);
+        }
+    }
+    Some(texels)
+}
+
+fn prepare_lights(
+    mut light_buffer: ResMut<LightBuffer>,
+    mut light_data_buffer: ResMut<LightDataBuffer>,
+    lights: Query<&LightExtract>,
+    profiles: Res<RenderAssets<IesSamples>>,
+    cookies: Res<CookieCache>,
+    origin: Res<RenderOrigin>,
+    memory_report: Res<MemoryReport>,
+    render_device: Res<RenderDevice>,
+    render_queue: Res<RenderQueue>,
+) {
+    light_buffer.clear();
+    light_data_buffer.clear();
+    for extract in &lights {
+        let mut light = extract.light.clone();
+        light.position = origin.point(light.position);
+        // Lights keep shining evenly until their profile or cookie is loaded
+        if let Some(samples) = extract.ies_profile.and_then(|id| profiles.get(id)) {
+            light.ies_offset = light_data_buffer.len() as u32;
+            light_data_buffer.extend(samples.0.iter().copied());
+        }
+        if let Some(texels) = extract.cookie.and_then(|id| cookies.get(&id)) {
+            light.cookie_offset = light_data_buffer.len() as u32;
+            light_data_buffer.extend(texels.iter().copied());
+        }
+        light_buffer.push(light);
+    }
+
+    memory_report.record("lights", light_buffer.size(), 1);
+    memory_report.record("light profiles and cookies", light_data_buffer.size(), 1);
+
+    light_buffer.write_buffer(&render_device, &render_queue);
+    light_data_buffer.write_buffer(&render_device, &render_queue);
+}
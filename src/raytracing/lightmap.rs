@@ -0,0 +1,535 @@
+use bevy::{
+    ecs::entity::EntityHashMap,
+    pbr::Lightmap,
+    prelude::*,
+    render::{
+        graph::CameraDriverLabel,
+        mesh::{GpuBufferInfo, GpuMesh, MeshVertexBufferLayoutRef},
+        render_asset::{RenderAssetUsages, RenderAssets},
+        render_graph::{Node, NodeRunError, RenderGraph, RenderGraphContext, RenderLabel},
+        render_resource::{
+            binding_types::{texture_2d, uniform_buffer},
+            BindGroup, BindGroupEntries, BindGroupLayout, BindGroupLayoutEntries, BlendComponent,
+            BlendFactor, BlendOperation, BlendState, CachedRenderPipelineId, ColorTargetState,
+            ColorWrites, DynamicUniformBuffer, Extent3d, FragmentState, LoadOp, MultisampleState,
+            Operations, PipelineCache, PrimitiveState, PrimitiveTopology,
+            RenderPassColorAttachment, RenderPassDescriptor, RenderPipelineDescriptor,
+            ShaderStages, ShaderType, SpecializedMeshPipeline, SpecializedMeshPipelineError,
+            SpecializedMeshPipelines, StoreOp, TextureDescriptor, TextureDimension, TextureFormat,
+            TextureSampleType, TextureUsages, VertexState,
+        },
+        renderer::{RenderContext, RenderDevice, RenderQueue},
+        texture::{CachedTexture, GpuImage, TextureCache},
+        Extract, ExtractSchedule, Render, RenderApp, RenderSet,
+    },
+};
+use rand::{thread_rng, Rng};
+
+use super::{
+    dirty::RaytraceSceneDirty,
+    origin::RenderOrigin,
+    pipeline::{fullscreen_pipeline, geometry_bind_group, RaytracingPipeline},
+};
+
+const LIGHTMAP_FORMAT: TextureFormat = TextureFormat::Rgba16Float;
+
+#[derive(Debug, Hash, PartialEq, Eq, Clone, RenderLabel)]
+pub struct LightmapBakeLabel;
+
+pub struct RaytraceLightmapPlugin;
+
+impl Plugin for RaytraceLightmapPlugin {
+    fn build(&self, app: &mut App) {
+        app.register_type::<RaytraceLightmapBake>()
+            .add_systems(Update, insert_lightmaps);
+
+        let Some(render_app) = app.get_sub_app_mut(RenderApp) else {
+            return;
+        };
+
+        render_app
+            .init_resource::<ExtractedLightmapBakes>()
+            .init_resource::<LightmapBakeProgress>()
+            .init_resource::<PreparedLightmapBakes>()
+            .init_resource::<SpecializedMeshPipelines<LightmapBakePipeline>>()
+            .add_systems(ExtractSchedule, extract_lightmap_bakes)
+            .add_systems(
+                Render,
+                (
+                    queue_lightmap_bakes.in_set(RenderSet::Queue),
+                    prepare_lightmap_bakes.in_set(RenderSet::PrepareBindGroups),
+                ),
+            );
+
+        // Baking doesn't belong to any view, so it runs once per frame in the main graph
+        let mut render_graph = render_app.world_mut().resource_mut::<RenderGraph>();
+        render_graph.add_node(LightmapBakeLabel, LightmapBakeNode);
+        render_graph.add_node_edge(CameraDriverLabel, LightmapBakeLabel);
+    }
+
+    fn finish(&self, app: &mut App) {
+        let Some(render_app) = app.get_sub_app_mut(RenderApp) else {
+            return;
+        };
+
+        // This reuses the geometry layout of the main pipeline, so it has to be initialized after it
+        render_app.init_resource::<LightmapBakePipeline>();
+    }
+}
+
+// Path traces the light arriving at the surface of this mesh into `image` over several frames, turning the tracer into a GI baker.
+// The mesh needs lightmap UVs (`Mesh::ATTRIBUTE_UV_1`) and the image is inserted as bevy `Lightmap` right away, so the bake can be watched converging.
+// After every frame the UV charts are grown by a few texels into the empty space around them, so they don't bleed black at their seams.
+// Only the raytraced scene is visible to the bake, modifying this component restarts it.
+#[derive(Component, Reflect, Clone)]
+pub struct RaytraceLightmapBake {
+    pub image: Handle<Image>,
+    pub samples_per_frame: u32,
+    pub bounces: u32,
+    // After this many frames the lightmap is left alone
+    pub frame_count: u32,
+}
+
+impl RaytraceLightmapBake {
+    pub fn new(size: u32, images: &mut Assets<Image>) -> Self {
+        let mut image = Image::new_fill(
+            Extent3d {
+                width: size,
+                height: size,
+                depth_or_array_layers: 1,
+            },
+            TextureDimension::D2,
+            &[0; 8],
+            LIGHTMAP_FORMAT,
+            RenderAssetUsages::default(),
+        );
+        image.texture_descriptor.usage = TextureUsages::TEXTURE_BINDING
+            | TextureUsages::COPY_SRC
+            | TextureUsages::COPY_DST
+            | TextureUsages::RENDER_ATTACHMENT;
+
+        Self {
+            image: images.add(image),
+            samples_per_frame: 16,
+            bounces: 4,
+            frame_count: 64,
+        }
+    }
+}
+
+fn insert_lightmaps(
+    bakes: Query<(Entity, &RaytraceLightmapBake), Without<Lightmap>>,
+    mut cmd: Commands,
+) {
+    for (entity, bake) in &bakes {
+        cmd.entity(entity).insert(Lightmap {
+            image: bake.image.clone(),
+            uv_rect: Rect::new(0.0, 0.0, 1.0, 1.0),
+        });
+    }
+}
+
+pub struct ExtractedLightmapBake {
+    entity: Entity,
+    image: AssetId<Image>,
+    mesh: AssetId<Mesh>,
+    world_from_local: Mat4,
+    samples_per_frame: u32,
+    bounces: u32,
+    frame_count: u32,
+    restart: bool,
+}
+
+#[derive(Resource, Default, Deref, DerefMut)]
+pub struct ExtractedLightmapBakes(Vec<ExtractedLightmapBake>);
+
+// The amount of frames every bake has accumulated so far
+#[derive(Resource, Default, Deref, DerefMut)]
+pub struct LightmapBakeProgress(EntityHashMap<u32>);
+
+fn extract_lightmap_bakes(
+    mut extracted: ResMut<ExtractedLightmapBakes>,
+    bakes: Extract<
+        Query<(
+            Entity,
+            Ref<RaytraceLightmapBake>,
+            &Handle<Mesh>,
+            &GlobalTransform,
+        )>,
+    >,
+    scene_dirty: Extract<Res<Events<RaytraceSceneDirty>>>,
+    origin: Extract<Res<RenderOrigin>>,
+) {
+    // Light that was baked into the old scene would never average out
+    let scene_changed = scene_dirty.iter_current_update_events().next().is_some();
+
+    extracted.clear();
+    extracted.extend(
+        bakes
+            .iter()
+            .map(|(entity, bake, mesh, transform)| ExtractedLightmapBake {
+                entity,
+                image: bake.image.id(),
+                mesh: mesh.id(),
+                world_from_local: origin.render_from_local(transform.compute_matrix()),
+                samples_per_frame: bake.samples_per_frame,
+                bounces: bake.bounces,
+                frame_count: bake.frame_count,
+                restart: scene_changed || bake.is_changed(),
+            }),
+    );
+}
+
+#[derive(Clone, ShaderType)]
+pub struct LightmapBakeUniform {
+    world_from_local: Mat4,
+    // inverse transpose of the upper 3x3 of world_from_local
+    normal_from_local: Mat4,
+    random_seed: f32,
+    sample_count: u32,
+    bounce_count: u32,
+}
+
+pub struct PreparedLightmapBake {
+    image: AssetId<Image>,
+    mesh: AssetId<Mesh>,
+    pipeline_id: CachedRenderPipelineId,
+    uniform: LightmapBakeUniform,
+    uniform_offset: u32,
+    // Used for a running average through the blend constant
+    frame_index: u32,
+    // The lightmap gets copied into this for the dilation to read from
+    dilation_source: CachedTexture,
+}
+
+#[derive(Resource, Default)]
+pub struct PreparedLightmapBakes {
+    bakes: Vec<PreparedLightmapBake>,
+    uniforms: DynamicUniformBuffer<LightmapBakeUniform>,
+    bind_group: Option<BindGroup>,
+}
+
+fn queue_lightmap_bakes(
+    extracted: Res<ExtractedLightmapBakes>,
+    mut progress: ResMut<LightmapBakeProgress>,
+    mut prepared: ResMut<PreparedLightmapBakes>,
+    bake_pipeline: Res<LightmapBakePipeline>,
+    mut pipelines: ResMut<SpecializedMeshPipelines<LightmapBakePipeline>>,
+    pipeline_cache: Res<PipelineCache>,
+    meshes: Res<RenderAssets<GpuMesh>>,
+    images: Res<RenderAssets<GpuImage>>,
+    mut texture_cache: ResMut<TextureCache>,
+    render_device: Res<RenderDevice>,
+) {
+    prepared.bakes.clear();
+    progress.retain(|entity, _| extracted.iter().any(|bake| bake.entity == *entity));
+
+    let mut rng = thread_rng();
+    for bake in extracted.iter() {
+        let frame_index = progress.entry(bake.entity).or_default();
+        if bake.restart {
+            *frame_index = 0;
+        }
+
+        if *frame_index >= bake.frame_count {
+            continue;
+        }
+
+        let (Some(mesh), Some(image)) = (meshes.get(bake.mesh), images.get(bake.image)) else {
+            continue;
+        };
+
+        let pipeline_id = match pipelines.specialize(
+            &pipeline_cache,
+            &bake_pipeline,
+            mesh.primitive_topology(),
+            &mesh.layout,
+        ) {
+            Ok(id) => id,
+            Err(err) => {
+                error!("Can't bake a lightmap for {:?}: {err}", bake.entity);
+                continue;
+            }
+        };
+
+        // Frames that can't be rendered yet shouldn't count towards the bake
+        if pipeline_cache.get_render_pipeline(pipeline_id).is_none() {
+            continue;
+        }
+
+        prepared.bakes.push(PreparedLightmapBake {
+            image: bake.image,
+            mesh: bake.mesh,
+            pipeline_id,
+            uniform: LightmapBakeUniform {
+                world_from_local: bake.world_from_local,
+                normal_from_local: bake.world_from_local.inverse().transpose(),
+                random_seed: rng.gen_range(0.0..1.0),
+                sample_count: bake.samples_per_frame,
+                bounce_count: bake.bounces,
+            },
+            uniform_offset: 0,
+            frame_index: *frame_index,
+            dilation_source: texture_cache.get(
+                &render_device,
+                TextureDescriptor {
+                    label: Some("lightmap_dilation_source"),
+                    size: image.texture.size(),
+                    mip_level_count: 1,
+                    sample_count: 1,
+                    dimension: TextureDimension::D2,
+                    format: LIGHTMAP_FORMAT,
+                    usage: TextureUsages::TEXTURE_BINDING | TextureUsages::COPY_DST,
+                    view_formats: &[],
+                },
+            ),
+        });
+        *frame_index += 1;
+    }
+}
+
+fn prepare_lightmap_bakes(
+    mut prepared: ResMut<PreparedLightmapBakes>,
+    bake_pipeline: Res<LightmapBakePipeline>,
+    render_device: Res<RenderDevice>,
+    render_queue: Res<RenderQueue>,
+) {
+    let prepared = &mut *prepared;
+    prepared.uniforms.clear();
+    for bake in &mut prepared.bakes {
+        bake.uniform_offset = prepared.uniforms.push(&bake.uniform);
+    }
+    prepared
+        .uniforms
+        .write_buffer(&render_device, &render_queue);
+
+    prepared.bind_group = prepared.uniforms.binding().map(|binding| {
+        render_device.create_bind_group(
+            "lightmap_bake_bind_group",
+            &bake_pipeline.layout,
+            &BindGroupEntries::single(binding),
+        )
+    });
+}
+
+// Rasterizes the baked meshes in lightmap UV space, every fragment traces the light arriving at its surface point
+pub struct LightmapBakeNode;
+
+impl Node for LightmapBakeNode {
+    fn run(
+        &self,
+        _graph: &mut RenderGraphContext,
+        render_context: &mut RenderContext,
+        world: &World,
+    ) -> Result<(), NodeRunError> {
+        let prepared = world.resource::<PreparedLightmapBakes>();
+        let Some(bind_group) = &prepared.bind_group else {
+            return Ok(());
+        };
+
+        if prepared.bakes.is_empty() {
+            return Ok(());
+        }
+
+        let Some(buffer_bind_group) = geometry_bind_group(
+            world,
+            render_context.render_device(),
+            &world.resource::<LightmapBakePipeline>().buffer_layout,
+            "lightmap_bake_geometry_bind_group",
+        ) else {
+            return Ok(());
+        };
+
+        let bake_pipeline = world.resource::<LightmapBakePipeline>();
+        let pipeline_cache = world.resource::<PipelineCache>();
+        let meshes = world.resource::<RenderAssets<GpuMesh>>();
+        let images = world.resource::<RenderAssets<GpuImage>>();
+        let dilate_pipeline = pipeline_cache.get_render_pipeline(bake_pipeline.dilate_pipeline_id);
+
+        for bake in &prepared.bakes {
+            let (Some(pipeline), Some(mesh), Some(image)) = (
+                pipeline_cache.get_render_pipeline(bake.pipeline_id),
+                meshes.get(bake.mesh),
+                images.get(bake.image),
+            ) else {
+                continue;
+            };
+
+            let mut render_pass = render_context.begin_tracked_render_pass(RenderPassDescriptor {
+                label: Some("lightmap_bake_pass"),
+                color_attachments: &[Some(RenderPassColorAttachment {
+                    view: &image.texture_view,
+                    resolve_target: None,
+                    ops: Operations {
+                        load: LoadOp::Load,
+                        store: StoreOp::Store,
+                    },
+                })],
+                depth_stencil_attachment: None,
+                timestamp_writes: None,
+                occlusion_query_set: None,
+            });
+
+            // The new frame gets weighted so the lightmap always holds the average of all frames so far
+            let weight = 1.0 / (bake.frame_index + 1) as f32;
+            render_pass.set_blend_constant(LinearRgba::new(weight, weight, weight, weight));
+
+            render_pass.set_render_pipeline(pipeline);
+            render_pass.set_bind_group(0, bind_group, &[bake.uniform_offset]);
+            render_pass.set_bind_group(1, &buffer_bind_group, &[]);
+            render_pass.set_vertex_buffer(0, mesh.vertex_buffer.slice(..));
+
+            match &mesh.buffer_info {
+                GpuBufferInfo::Indexed {
+                    buffer,
+                    count,
+                    index_format,
+                } => {
+                    render_pass.set_index_buffer(buffer.slice(..), 0, *index_format);
+                    render_pass.draw_indexed(0..*count, 0, 0..1);
+                }
+                GpuBufferInfo::NonIndexed => {
+                    render_pass.draw(0..mesh.vertex_count, 0..1);
+                }
+            }
+            drop(render_pass);
+
+            let Some(dilate_pipeline) = dilate_pipeline else {
+                continue;
+            };
+
+            // The pass can't read the lightmap it writes to
+            render_context.command_encoder().copy_texture_to_texture(
+                image.texture.as_image_copy(),
+                bake.dilation_source.texture.as_image_copy(),
+                image.texture.size(),
+            );
+            let dilate_bind_group = render_context.render_device().create_bind_group(
+                "lightmap_dilate_bind_group",
+                &bake_pipeline.dilate_layout,
+                &BindGroupEntries::single(&bake.dilation_source.default_view),
+            );
+
+            let mut render_pass = render_context.begin_tracked_render_pass(RenderPassDescriptor {
+                label: Some("lightmap_dilate_pass"),
+                color_attachments: &[Some(RenderPassColorAttachment {
+                    view: &image.texture_view,
+                    resolve_target: None,
+                    ops: Operations::default(),
+                })],
+                depth_stencil_attachment: None,
+                timestamp_writes: None,
+                occlusion_query_set: None,
+            });
+            render_pass.set_render_pipeline(dilate_pipeline);
+            render_pass.set_bind_group(0, &dilate_bind_group, &[]);
+            render_pass.draw(0..3, 0..1);
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Resource)]
+pub struct LightmapBakePipeline {
+    layout: BindGroupLayout,
+    buffer_layout: BindGroupLayout,
+    shader: Handle<Shader>,
+    dilate_layout: BindGroupLayout,
+    dilate_pipeline_id: CachedRenderPipelineId,
+}
+
+impl FromWorld for LightmapBakePipeline {
+    fn from_world(world: &mut World) -> Self {
+        let render_device = world.resource::<RenderDevice>();
+
+        let layout = render_device.create_bind_group_layout(
+            "lightmap_bake_bind_group_layout",
+            &BindGroupLayoutEntries::single(
+                ShaderStages::VERTEX_FRAGMENT,
+                uniform_buffer::<LightmapBakeUniform>(true),
+            ),
+        );
+
+        let dilate_layout = render_device.create_bind_group_layout(
+            "lightmap_dilate_bind_group_layout",
+            &BindGroupLayoutEntries::single(
+                ShaderStages::FRAGMENT,
+                texture_2d(TextureSampleType::Float { filterable: false }),
+            ),
+        );
+        let dilate_shader = world.load_asset("shaders/lightmap_dilate.wgsl");
+        let dilate_pipeline_id =
+            world
+                .resource::<PipelineCache>()
+                .queue_render_pipeline(fullscreen_pipeline(
+                    "lightmap_dilate_pipeline",
+                    vec![dilate_layout.clone()],
+                    &dilate_shader,
+                    LIGHTMAP_FORMAT,
+                ));
+
+        Self {
+            layout,
+            buffer_layout: world.resource::<RaytracingPipeline>().buffer_layout.clone(),
+            shader: world.load_asset("shaders/lightmap.wgsl"),
+            dilate_layout,
+            dilate_pipeline_id,
+        }
+    }
+}
+
+impl SpecializedMeshPipeline for LightmapBakePipeline {
+    type Key = PrimitiveTopology;
+
+    fn specialize(
+        &self,
+        key: Self::Key,
+        layout: &MeshVertexBufferLayoutRef,
+    ) -> Result<RenderPipelineDescriptor, SpecializedMeshPipelineError> {
+        let vertex_layout = layout.0.get_layout(&[
+            Mesh::ATTRIBUTE_POSITION.at_shader_location(0),
+            Mesh::ATTRIBUTE_NORMAL.at_shader_location(1),
+            Mesh::ATTRIBUTE_UV_1.at_shader_location(2),
+        ])?;
+
+        let running_average = BlendComponent {
+            src_factor: BlendFactor::Constant,
+            dst_factor: BlendFactor::OneMinusConstant,
+            operation: BlendOperation::Add,
+        };
+
+        Ok(RenderPipelineDescriptor {
+            label: Some("lightmap_bake_pipeline".into()),
+            layout: vec![self.layout.clone(), self.buffer_layout.clone()],
+            vertex: VertexState {
+                shader: self.shader.clone(),
+                shader_defs: vec![],
+                entry_point: "vertex".into(),
+                buffers: vec![vertex_layout],
+            },
+            fragment: Some(FragmentState {
+                shader: self.shader.clone(),
+                shader_defs: vec![],
+                entry_point: "fragment".into(),
+                targets: vec![Some(ColorTargetState {
+                    format: LIGHTMAP_FORMAT,
+                    blend: Some(BlendState {
+                        color: running_average,
+                        alpha: running_average,
+                    }),
+                    write_mask: ColorWrites::ALL,
+                })],
+            }),
+            // UV islands can be mirrored, so nothing gets culled
+            primitive: PrimitiveState {
+                topology: key,
+                cull_mode: None,
+                ..default()
+            },
+            depth_stencil: None,
+            multisample: MultisampleState::default(),
+            push_constant_ranges: vec![],
+        })
+    }
+}
@@ -0,0 +1,164 @@
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+};
+
+use bevy::{
+    prelude::*,
+    render::{renderer::RenderDevice, RenderApp},
+};
+
+// Buffers above this share of the binding limit get reported, before objects start getting left out
+const BINDING_WARNING_THRESHOLD: f64 = 0.8;
+
+pub struct RaytraceMemoryPlugin;
+
+impl Plugin for RaytraceMemoryPlugin {
+    fn build(&self, app: &mut App) {
+        // Both worlds share the report, the render world fills it in while preparing its buffers and textures
+        let report = MemoryReport::default();
+        app.register_type::<RaytraceMemoryBudget>()
+            .init_resource::<RaytraceMemoryBudget>()
+            .insert_resource(report.clone())
+            .add_systems(First, (sync_memory_budget, warn_about_memory).chain());
+
+        let Some(render_app) = app.get_sub_app_mut(RenderApp) else {
+            return;
+        };
+
+        render_app.insert_resource(report);
+    }
+
+    fn finish(&self, app: &mut App) {
+        let Some(render_app) = app.get_sub_app_mut(RenderApp) else {
+            return;
+        };
+
+        let limit = render_app
+            .world()
+            .resource::<RenderDevice>()
+            .limits()
+            .max_storage_buffer_binding_size;
+        render_app
+            .world()
+            .resource::<MemoryReport>()
+            .lock()
+            .max_binding_size = u64::from(limit);
+    }
+}
+
+// How much GPU memory the buffers and textures of the tracer take up, updated every frame.
+// Shows up in the inspector, and a warning is logged once a buffer gets close to `max_binding_size`
+#[derive(Resource, Reflect, Clone, Default, PartialEq, Debug)]
+#[reflect(Resource)]
+pub struct RaytraceMemoryBudget {
+    // The largest storage buffer binding the device allows in bytes, no single chunk gets bigger than this
+    pub max_binding_size: u64,
+    // Sorted by name
+    pub usages: Vec<MemoryUsage>,
+    // Objects that fit in no chunk anymore, they are left out of the traced scene
+    pub dropped_objects: u32,
+}
+
+#[derive(Reflect, Clone, Default, PartialEq, Debug)]
+pub struct MemoryUsage {
+    pub name: String,
+    pub bytes: u64,
+    // The bindings a buffer is split into, textures count as a single one
+    pub chunks: u32,
+}
+
+impl MemoryUsage {
+    // The size of the largest binding, assuming the chunks are filled evenly
+    pub fn bytes_per_chunk(&self) -> u64 {
+        self.bytes / u64::from(self.chunks.max(1))
+    }
+}
+
+impl RaytraceMemoryBudget {
+    pub fn total_bytes(&self) -> u64 {
+        self.usages.iter().map(|usage| usage.bytes).sum()
+    }
+}
+
+#[derive(Default)]
+pub(super) struct MemoryEntries {
+    pub max_binding_size: u64,
+    pub dropped_objects: u32,
+    usages: BTreeMap<&'static str, (u64, u32)>,
+}
+
+impl MemoryEntries {
+    // Replaces what was recorded under this name before
+    pub fn record(&mut self, name: &'static str, bytes: u64, chunks: u32) {
+        self.usages.insert(name, (bytes, chunks));
+    }
+}
+
+#[derive(Resource, Clone, Default)]
+pub(super) struct MemoryReport(Arc<Mutex<MemoryEntries>>);
+
+impl MemoryReport {
+    pub(super) fn lock(&self) -> std::sync::MutexGuard<'_, MemoryEntries> {
+        self.0
+            .lock()
+            .expect("Could not get memory report out of mutex")
+    }
+
+    pub(super) fn record(&self, name: &'static str, bytes: u64, chunks: u32) {
+        self.lock().record(name, bytes, chunks);
+    }
+}
+
+fn sync_memory_budget(report: Res<MemoryReport>, mut budget: ResMut<RaytraceMemoryBudget>) {
+    let entries = report.lock();
+    budget.set_if_neq(RaytraceMemoryBudget {
+        max_binding_size: entries.max_binding_size,
+        usages: entries
+            .usages
+            .iter()
+            .map(|(name, &(bytes, chunks))| MemoryUsage {
+                name: name.to_string(),
+                bytes,
+                chunks,
+            })
+            .collect(),
+        dropped_objects: entries.dropped_objects,
+    });
+}
+
+// Warns once when a buffer crosses the threshold or objects get dropped, and again after it went back below
+fn warn_about_memory(
+    budget: Res<RaytraceMemoryBudget>,
+    mut warned: Local<Vec<String>>,
+    mut dropped: Local<u32>,
+) {
+    if !budget.is_changed() || budget.max_binding_size == 0 {
+        return;
+    }
+
+    let threshold = (budget.max_binding_size as f64 * BINDING_WARNING_THRESHOLD) as u64;
+    for usage in &budget.usages {
+        let close = usage.bytes_per_chunk() > threshold;
+        let already_warned = warned.contains(&usage.name);
+        if close && !already_warned {
+            warn!(
+                "The raytraced {} take up {} of {} MiB per binding, the scene will soon be cut off",
+                usage.name,
+                usage.bytes_per_chunk() >> 20,
+                budget.max_binding_size >> 20
+            );
+            warned.push(usage.name.clone());
+        } else if !close && already_warned {
+            warned.retain(|name| *name != usage.name);
+        }
+    }
+
+    if budget.dropped_objects > *dropped {
+        warn!(
+            "{} objects don't fit into the GPU buffers and are left out of the raytraced scene",
+            budget.dropped_objects
+        );
+    }
+    *dropped = budget.dropped_objects;
+}
@@ -2,36 +2,204 @@
 
 use bevy::{
     core_pipeline::{
-        core_3d::graph::{Core3d, Node3d},
+        bloom::BloomSettings,
+        core_3d::{
+            graph::{Core3d, Node3d},
+            Camera3dDepthLoadOp,
+        },
         prepass::DepthPrepass,
     },
     prelude::*,
     render::{
+        camera::ExtractedCamera,
+        extract_component::ExtractComponent,
         render_graph::{RenderGraphApp, RenderLabel, ViewNodeRunner},
-        RenderApp,
+        render_resource::SpecializedRenderPipelines,
+        view::prepare_view_targets,
+        Render, RenderApp, RenderSet,
     },
 };
 
+mod accumulation;
+mod bsdf;
+mod buffer;
+mod bvh;
+mod capabilities;
+mod caustics;
+mod cubemap;
+mod decal;
+mod dirty;
+mod environment;
+mod exposure;
 mod extract;
+mod light;
+mod lightmap;
+mod memory;
+mod nan_debug;
+mod origin;
+mod particles;
+mod path_debug;
+mod pbrt;
+mod picking;
 mod pipeline;
+mod portal;
+mod primitives;
+mod probe_grid;
+mod readback;
+mod render_to_file;
+mod sky;
+mod stats;
+#[cfg(feature = "ui")]
+mod ui;
+mod volume;
+mod watchdog;
 
-use extract::RaytraceExtractPlugin;
-use pipeline::{RayTracingNode, RaytracingPipeline};
+use accumulation::RaytraceAccumulationPlugin;
+use bsdf::RaytraceBsdfPlugin;
+use bvh::RaytraceBvhPlugin;
+use capabilities::RaytraceCapabilitiesPlugin;
+use caustics::RaytraceCausticsPlugin;
+use cubemap::RaytraceCubemapPlugin;
+use decal::RaytraceDecalPlugin;
+use dirty::RaytraceDirtyPlugin;
+use environment::RaytraceEnvironmentPlugin;
+use exposure::RaytraceExposurePlugin;
+use extract::{CameraExtract, RaytraceExtractPlugin};
+use light::RaytraceLightPlugin;
+use lightmap::RaytraceLightmapPlugin;
+use memory::RaytraceMemoryPlugin;
+use nan_debug::RaytraceNanDebugPlugin;
+use origin::RaytraceOriginPlugin;
+use particles::RaytraceParticlesPlugin;
+use path_debug::RaytracePathDebugPlugin;
+use pbrt::RaytracePbrtPlugin;
+use pipeline::{
+    queue_raytrace_pipelines, report_shader_errors, RayTracingNode, RaytracingPipeline,
+};
+use portal::RaytracePortalPlugin;
+use primitives::RaytracePrimitivesPlugin;
+use probe_grid::RaytraceProbeGridPlugin;
+use render_to_file::RaytraceRenderToFilePlugin;
+use sky::RaytraceSkyPlugin;
+use stats::RaytraceStatsPlugin;
+use volume::RaytraceVolumePlugin;
+use watchdog::RaytraceWatchdogPlugin;
 
+pub use accumulation::{
+    CameraCut, RaytraceConvergenceOverlay, RaytracePaused, RaytraceProgress, RenderFinished,
+    SetRaytraceMode,
+};
+pub use bsdf::RaytraceBsdfAppExt;
+pub use bvh::{BvhRebuildPolicy, BvhStats, RaytraceStatic, RebuildBvh};
+pub use capabilities::RaytraceCapabilities;
+pub use caustics::RaytraceCaustics;
+pub use cubemap::{CaptureCubemap, RaytraceCubemapCapture};
+pub use decal::RaytraceDecal;
+pub use dirty::{RaytraceSceneDirty, RaytraceSceneState};
+pub use exposure::RaytraceAutoExposure;
+pub use light::{
+    IesProfile, RaytraceDiskLight, RaytraceIesProfile, RaytraceLightCookie, RaytraceLightLink,
+};
+pub use lightmap::RaytraceLightmapBake;
+pub use memory::RaytraceMemoryBudget;
+pub use nan_debug::{RaytraceNanDebug, RaytraceNanReport};
+pub use particles::RaytraceParticles;
+pub use path_debug::{PathVertexKind, RaytracePathDebugger, RaytraceRecordedPath};
+pub use pbrt::{PbrtScene, RaytracePbrtScene};
+pub use picking::{HoveredRaytracedEntity, RaytracePickingPlugin};
+pub use portal::{RaytraceMirror, RaytracePortal};
+pub use primitives::{RaytraceHideMesh, RaytracePrimitiveMesh};
+pub use probe_grid::RaytraceProbeGrid;
+pub use render_to_file::{RenderSaved, RenderToFile};
+pub use sky::{RaytraceSky, RaytraceWhiteFurnace};
+pub use stats::RaytraceFrameStats;
+#[cfg(feature = "ui")]
+pub use ui::RaytraceUiPlugin;
+pub use volume::RaytraceDensityVolume;
+pub use watchdog::RaytraceRayBudget;
+
+// Runs after tonemapping, for hdr cameras with `RaytraceOutput::Tonemapped`
 #[derive(Debug, Hash, PartialEq, Eq, Clone, RenderLabel)]
 pub struct RaytraceLabel;
 
+// Runs in the main pass before bloom and tonemapping, for cameras with `RaytraceOutput::LinearHdr` and cameras without hdr
+#[derive(Debug, Hash, PartialEq, Eq, Clone, RenderLabel)]
+pub struct RaytraceLinearLabel;
+
 pub struct RaytracePlugin;
 
 impl Plugin for RaytracePlugin {
     fn build(&self, app: &mut App) {
-        app.add_plugins(RaytraceExtractPlugin)
-            // TODO: Investigate how to make this Msaa compatible
-            .insert_resource(Msaa::Off)
-            .register_type::<RaytracedCamera>()
-            .register_type::<Raytracing>()
-            .register_type::<RaytracedSphere>()
-            .add_systems(Update, auto_add_camera_components);
+        app.add_plugins((
+            RaytraceExtractPlugin,
+            RaytraceCubemapPlugin,
+            RaytraceLightmapPlugin,
+            RaytraceProbeGridPlugin,
+            RaytraceCausticsPlugin,
+            RaytraceVolumePlugin,
+            RaytraceBsdfPlugin,
+            RaytraceSkyPlugin,
+            RaytraceEnvironmentPlugin,
+            RaytraceLightPlugin,
+            RaytraceAccumulationPlugin,
+            RaytracePbrtPlugin,
+            RaytraceMemoryPlugin,
+            RaytraceBvhPlugin,
+            RaytracePortalPlugin,
+        ))
+        .add_plugins((
+            RaytraceDirtyPlugin,
+            RaytracePathDebugPlugin,
+            RaytraceNanDebugPlugin,
+            RaytraceExposurePlugin,
+            RaytraceStatsPlugin,
+            RaytraceRenderToFilePlugin,
+            RaytraceDecalPlugin,
+            RaytraceOriginPlugin,
+            RaytraceParticlesPlugin,
+            RaytraceWatchdogPlugin,
+            RaytraceCapabilitiesPlugin,
+            RaytracePrimitivesPlugin,
+        ))
+        // TODO: Investigate how to make this Msaa compatible
+        .insert_resource(Msaa::Off)
+        .register_type::<RaytracedCamera>()
+        .register_type::<Quality>()
+        .register_type::<RaytraceSampling>()
+        .register_type::<RaytraceOutput>()
+        .register_type::<PathTermination>()
+        .register_type::<RaytraceRayMasks>()
+        .register_type::<RaytraceInstanceMask>()
+        .register_type::<RaytraceShadowCatcher>()
+        .register_type::<RaytraceReflectionOnly>()
+        .register_type::<RaytracePrecisePosition>()
+        .register_type::<PixelFilter>()
+        .register_type::<RaytraceMode>()
+        .register_type::<Raytracing>()
+        .register_type::<RaytraceProjection>()
+        .register_type::<FisheyeMapping>()
+        .register_type::<RaytraceDepthOfField>()
+        .register_type::<RaytraceClipPlanes>()
+        .register_type::<RaytraceCulling>()
+        .register_type::<RaytraceTiling>()
+        .register_type::<RaytracedSphere>()
+        .register_type::<RaytracedCuboid>()
+        .register_type::<RaytracedPlane>()
+        .register_type::<RaytracedHeightfield>()
+        .register_type::<RaytracedBillboard>()
+        .register_type::<RaytraceLod>()
+        .register_type::<RaytraceDispersion>()
+        .register_type::<RaytraceMaterialOverride>()
+        .register_type::<RaytraceMaterialId>()
+        .register_type::<RaytraceMaterialPalette>()
+        .init_resource::<RaytraceMaterialPalette>()
+        .register_type::<RaytraceTexture>()
+        .register_type::<RaytraceFogVolume>()
+        .register_type::<FogVolumeShape>()
+        .add_systems(
+            Update,
+            (auto_add_camera_components, validate_raytraced_cameras),
+        );
 
         // We need to get the render app from the main app
         let Some(render_app) = app.get_sub_app_mut(RenderApp) else {
@@ -53,7 +221,7 @@ impl Plugin for RaytracePlugin {
             // The [`ViewNodeRunner`] is a special [`Node`] that will automatically run the node for each view
             // matching the [`ViewQuery`]
             // Buffers used to send data to the GPU
-            .add_render_graph_node::<ViewNodeRunner<RayTracingNode>>(
+            .add_render_graph_node::<ViewNodeRunner<RayTracingNode<false>>>(
                 // Specify the label of the graph, in this case we want the graph for 3d
                 Core3d, // It also needs the label of the node
                 RaytraceLabel,
@@ -62,12 +230,35 @@ impl Plugin for RaytracePlugin {
                 Core3d,
                 // Specify the node ordering.
                 // This will automatically create all required node edges to enforce the given ordering.
-                // NOTE: Should this be done before tonemapping?
                 (
                     Node3d::Tonemapping,
                     RaytraceLabel,
                     Node3d::EndMainPassPostProcessing,
                 ),
+            )
+            // Every camera only runs one of the two, depending on its `RaytraceOutput` and `hdr`.
+            // The one in the main pass goes after the opaque meshes, so the traced image gets blurred, bloomed and
+            // tonemapped like the rasterized one, and transmissive and transparent meshes and gizmos are drawn over it by depth.
+            // Both write the traced depth. Bevy's UI is drawn after both, and cameras rendering later into the same target
+            // load the traced image and its depth, see `composite_overlay_cameras`
+            .add_render_graph_node::<ViewNodeRunner<RayTracingNode<true>>>(
+                Core3d,
+                RaytraceLinearLabel,
+            )
+            .add_render_graph_edges(
+                Core3d,
+                (
+                    Node3d::MainOpaquePass,
+                    RaytraceLinearLabel,
+                    Node3d::MainTransmissivePass,
+                ),
+            )
+            // Before the cameras get their textures, which takes the clear color and depth load op from them
+            .add_systems(
+                Render,
+                composite_overlay_cameras
+                    .in_set(RenderSet::ManageViews)
+                    .before(prepare_view_targets),
             );
     }
 
@@ -79,7 +270,16 @@ impl Plugin for RaytracePlugin {
 
         render_app
             // Initialize the pipeline
-            .init_resource::<RaytracingPipeline>();
+            .init_resource::<RaytracingPipeline>()
+            .init_resource::<SpecializedRenderPipelines<RaytracingPipeline>>()
+            .add_systems(
+                Render,
+                (
+                    queue_raytrace_pipelines,
+                    report_shader_errors.after(queue_raytrace_pipelines),
+                )
+                    .in_set(RenderSet::Queue),
+            );
     }
 }
 
@@ -88,11 +288,170 @@ pub struct RaytracedCamera {
     pub level: Raytracing,
     pub sample_count: u32,
     pub bounces: u32,
+    pub projection: RaytraceProjection,
+    // Opt-in spectral rendering, every path carries a single wavelength so glass can disperse light.
+    // This needs more samples to converge, as the color of a pixel is built up over many paths
+    pub spectral: bool,
+    // Path regularization, the roughness added to every interaction after the first diffuse one, 0.0 turns it off.
+    // Around 0.1 removes most fireflies from caustics seen through glass, at the cost of blurring them
+    pub regularization: f32,
+    pub termination: PathTermination,
+    pub sampling: RaytraceSampling,
+    pub pixel_filter: PixelFilter,
+    pub output: RaytraceOutput,
+}
+
+// Ends paths before they run out of bounces once they can't add much to the image, which saves most in scenes full of glass.
+// The default never ends them early
+#[derive(Reflect, Clone, Copy, PartialEq, Debug)]
+pub struct PathTermination {
+    // Paths whose brightest channel of throughput falls below this continue with a chance proportional to it,
+    // the ones that do are brightened to make up for the rest. This adds a little noise but keeps the image unbiased, 0.0 turns it off
+    pub min_throughput: f32,
+    // Paths end after this many specular interactions in a row, like bouncing between glass spheres.
+    // Unlike the throughput this darkens what can only be seen through long chains
+    pub max_specular_bounces: u32,
+    // Paths that run out of bounces pick up the light of the bevy `IrradianceVolume` around their last hit instead of ending dark,
+    // like the one of a `RaytraceProbeGrid`. This brings back most of the light of the missing bounces at the cost of some bias,
+    // outside of the volume they still end dark
+    pub irradiance_fallback: bool,
+}
+
+impl Default for PathTermination {
+    fn default() -> Self {
+        Self {
+            min_throughput: 0.0,
+            max_specular_bounces: RaytracedCamera::MAX_BOUNCES,
+            irradiance_fallback: false,
+        }
+    }
+}
+
+// Where the traced image goes in bevy's post processing
+#[derive(Reflect, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RaytraceOutput {
+    // Drawn over the tonemapped image, with the square root the traced colors always had in place of tonemapping.
+    // The `ColorGrading` of the camera is applied before it, like bevy's tonemapping pass does for the rasterized image.
+    // Blends with the rasterized image are off in brightness, as one side is tonemapped and the other isn't.
+    // Without `hdr` bevy tonemaps meshes as it draws them, so it goes into the main pass like `LinearHdr` and
+    // transparent meshes and gizmos end up in front of it by depth. With `hdr` it has to wait for bevy's tonemapping,
+    // after transparent meshes and gizmos, which then only show where the rasterized image wins
+    #[default]
+    Tonemapped,
+    // Linear radiance, drawn before bloom and tonemapping and blended with the rasterized image while both are linear.
+    // Needs a camera with `hdr`, without it the camera falls back to `Tonemapped`.
+    // Bright emissive surfaces and specular highlights stay above 1.0, so `BloomSettings` on the camera make them glow.
+    // Bevy's tonemapping pass then applies the `ColorGrading` of the camera to both images alike.
+    // It is drawn before transmissive and transparent meshes and gizmos, which then show up in front of traced objects they are closer than
+    LinearHdr,
+}
+
+// The reconstruction filter the jitter of the primary rays follows, the rays are spread like the filter so every sample counts the same.
+// Wider filters trade a little sharpness for less aliasing shimmer while samples accumulate
+#[derive(Reflect, Clone, Copy, Default)]
+pub enum PixelFilter {
+    // Uniform over the pixel, the sharpest but it aliases the most
+    #[default]
+    Box,
+    // Linear falloff over a radius of 1 pixel
+    Tent,
+    // Standard deviation of 0.5 pixels, cut off at a radius of 1.5
+    Gaussian,
+    // Radius of 2 pixels, a bit softer than the gaussian with even less aliasing
+    BlackmanHarris,
+}
+
+// Whether a camera traces all of its samples every frame, or builds the image up over several frames
+#[derive(Reflect, Clone, Copy, Default)]
+pub enum RaytraceSampling {
+    // `sample_count` samples per pixel every frame
+    #[default]
+    EveryFrame,
+    // `sample_count` is the total to reach, with `samples_per_frame` added every frame while nothing changes.
+    // Moving the camera or anything in the scene starts over, `RaytraceProgress` reports how far along it is
+    Progressive {
+        samples_per_frame: u32,
+    },
+    // `sample_count` samples for half of the pixels every frame, in a checkerboard that flips every frame.
+    // The other half is reprojected from the last frame, where it didn't see the same surface a resolve pass
+    // fills the pixel in from its traced neighbours. About halves the cost, the image gets blurrier while things move.
+    // Only the perspective projection gets reprojected, the others always resolve
+    Checkerboard,
+}
+
+// Rough starting points trading speed for noise, the fields can still be tweaked afterwards
+#[derive(Reflect, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Quality {
+    Low,
+    Medium,
+    High,
+    Ultra,
+}
+
+// Switches a camera between quick previews and a finished image, without touching its other settings.
+// Set it with `RaytracedCamera::set_mode` or by sending a `SetRaytraceMode` event
+#[derive(Reflect, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum RaytraceMode {
+    // A single fresh sample every frame, so the image keeps up while things move
+    Interactive,
+    // Accumulates `samples` per pixel over several frames and then keeps showing them, sending a `RenderFinished` event
+    Final {
+        samples: u32,
+        samples_per_frame: u32,
+    },
+}
+
+impl RaytracedCamera {
+    // The largest bounce count that is still accepted, longer paths barely change the image but cost a lot
+    pub const MAX_BOUNCES: u32 = 64;
+
+    pub fn preset(quality: Quality) -> Self {
+        let (sample_count, bounces, regularization, min_throughput) = match quality {
+            Quality::Low => (1, 2, 0.2, 0.1),
+            Quality::Medium => (4, 4, 0.1, 0.05),
+            Quality::High => (16, 8, 0.05, 0.02),
+            Quality::Ultra => (64, 16, 0.0, 0.0),
+        };
+
+        Self {
+            level: Raytracing::FallbackRaytraced,
+            sample_count,
+            bounces,
+            projection: RaytraceProjection::Camera,
+            spectral: false,
+            regularization,
+            termination: PathTermination {
+                min_throughput,
+                ..default()
+            },
+            sampling: RaytraceSampling::EveryFrame,
+            pixel_filter: PixelFilter::Box,
+            output: RaytraceOutput::Tonemapped,
+        }
+    }
+}
+
+impl RaytracedCamera {
+    pub fn set_mode(&mut self, mode: RaytraceMode) {
+        (self.sample_count, self.sampling) = match mode {
+            RaytraceMode::Interactive => (1, RaytraceSampling::EveryFrame),
+            RaytraceMode::Final {
+                samples,
+                samples_per_frame,
+            } => (samples, RaytraceSampling::Progressive { samples_per_frame }),
+        };
+    }
+}
+
+impl Default for RaytracedCamera {
+    fn default() -> Self {
+        Self::preset(Quality::Medium)
+    }
 }
 
 // This is a marker component that specifies the raytracing level for a camera
 #[repr(u32)]
-#[derive(Reflect, Clone, Copy)]
+#[derive(Reflect, Clone, Copy, PartialEq, Eq)]
 pub enum Raytracing {
     Skip,
     FallbackRaster,
@@ -100,11 +459,439 @@ pub enum Raytracing {
     Pure,
 }
 
+// Controls how the primary rays of a camera are generated
+#[derive(Reflect, Clone, Copy, Default)]
+pub enum RaytraceProjection {
+    // Follow the bevy Projection of the camera
+    #[default]
+    Camera,
+    // Equirectangular projection over the full sphere around the camera, useful for skyboxes and lightprobes
+    // The depth based blending is skipped as the rasterized image can't match this projection
+    Panoramic360,
+    // Fisheye lens with the given vertical field of view in radians, which may go beyond 180°
+    // Like the panorama, this skips the depth based blending
+    Fisheye {
+        fov: f32,
+        mapping: FisheyeMapping,
+    },
+}
+
+// Cuts the traced scene open for section views. Hits on the side of a plane its normal points to are ignored, so rays
+// pass through whatever is cut away and find the insides of the objects behind it. Every ray of the camera is clipped,
+// so light reaches into the cut as well. Only the first `MAX_CLIP_PLANES` are used, the kept part is where all of them agree
+#[derive(Component, Reflect, Clone, Default)]
+pub struct RaytraceClipPlanes(pub Vec<ClipPlane>);
+
+// Has to match the constant in scene.wgsl
+pub const MAX_CLIP_PLANES: usize = 4;
+
+#[derive(Reflect, Clone, Copy, Debug)]
+pub struct ClipPlane {
+    pub point: Vec3,
+    // Points towards the side that is cut away, doesn't need to be normalized
+    pub normal: Vec3,
+}
+
+// A thin lens for traced perspective cameras, everything away from `focus_distance` gets blurred.
+// With 3 or more `blades` the aperture is a regular polygon like the diaphragm of a real lens,
+// which gives out of focus highlights their pentagonal or hexagonal shape instead of a perfect circle
+#[derive(Component, Reflect, Clone, Copy)]
+pub struct RaytraceDepthOfField {
+    // Distance along the view direction that stays sharp
+    pub focus_distance: f32,
+    // Radius of the lens in world units, 0.0 turns the blur off
+    pub aperture_radius: f32,
+    pub blades: u32,
+    // Rotation of the polygon in radians
+    pub blade_rotation: f32,
+}
+
+impl Default for RaytraceDepthOfField {
+    fn default() -> Self {
+        Self {
+            focus_distance: 5.0,
+            aperture_radius: 0.05,
+            blades: 0,
+            blade_rotation: 0.0,
+        }
+    }
+}
+
+// Leaves spheres and heightfields this camera can't see out of the traced scene, which keeps the BVH small in large worlds.
+// The scene is shared by all cameras, so objects stay as long as one of them sees them,
+// and nothing is left out while a traced camera without culling, or with a panoramic or fisheye projection, is active.
+// Lightmap bakes and probe grids trace the same scene, so they miss what got culled
+#[derive(Component, Reflect, Clone, Copy)]
+pub struct RaytraceCulling {
+    // How far in world units objects may be outside of the view and still be kept, reflections and shadows need some of them
+    pub guard_band: f32,
+    // Objects further away than this are left out, rays that would have hit them see the sky instead
+    pub max_distance: f32,
+}
+
+impl Default for RaytraceCulling {
+    fn default() -> Self {
+        Self {
+            guard_band: 10.0,
+            max_distance: f32::INFINITY,
+        }
+    }
+}
+
+// Traces the frames of this camera in square tiles, each drawn in a render pass and command buffer of its own.
+// A single draw over a big target with many samples per frame can take long enough for the driver to reset the GPU,
+// split up no piece of work covers more than a tile. Costs a little per tile, so it is meant for offline renders,
+// `RenderToFile` with a resolution adds it to its cameras unless they already have one
+#[derive(Component, ExtractComponent, Reflect, Clone, Copy)]
+pub struct RaytraceTiling {
+    // In pixels of the render target
+    pub tile_size: u32,
+}
+
+impl Default for RaytraceTiling {
+    fn default() -> Self {
+        Self { tile_size: 512 }
+    }
+}
+
+impl RaytraceTiling {
+    // The tiles of a target with `size` pixels row by row, the last ones end at its edge
+    pub fn tiles(&self, size: UVec2) -> impl Iterator<Item = URect> {
+        let tile_size = self.tile_size.max(1);
+        let tiles = UVec2::new(size.x.div_ceil(tile_size), size.y.div_ceil(tile_size));
+        (0..tiles.y).flat_map(move |y| {
+            (0..tiles.x).map(move |x| {
+                let min = UVec2::new(x, y) * tile_size;
+                URect::from_corners(min, (min + tile_size).min(size))
+            })
+        })
+    }
+}
+
+// How the angle to the optical axis maps to the distance from the image center for a fisheye lens
+#[derive(Reflect, Clone, Copy, Default)]
+pub enum FisheyeMapping {
+    // The angle grows linearly with the distance
+    #[default]
+    Equidistant,
+    // Every pixel covers the same solid angle
+    Equisolid,
+}
+
+// Follows the rotation of the transform. A non uniform scale stretches it into an ellipsoid, with the radius along the
+// longest axis of the scale and the others shorter in proportion, so a uniform scale leaves the radius as it is
 #[derive(Component, Reflect)]
 pub struct RaytracedSphere {
     pub radius: f32,
 }
 
+// A box traced without triangles, like bevy's `Cuboid`. It turns, scales and shears with the transform
+#[derive(Component, Reflect)]
+pub struct RaytracedCuboid {
+    pub half_size: Vec3,
+}
+
+// A rectangle traced without triangles and hit from both sides, like bevy's `Plane3d`, so one with its default
+// normal lies flat on the local XZ plane
+#[derive(Component, Reflect)]
+pub struct RaytracedPlane {
+    pub normal: Dir3,
+    pub half_size: Vec2,
+}
+
+// The materials traced objects can pick from with a `RaytraceMaterialId` instead of a `Handle<StandardMaterial>` of their own
+#[derive(Resource, Reflect, Clone, Default, Debug, Deref, DerefMut)]
+#[reflect(Resource)]
+pub struct RaytraceMaterialPalette(pub Vec<Handle<StandardMaterial>>);
+
+// The index of the material of a traced sphere, cuboid, plane, heightfield, billboard or particle system in the
+// `RaytraceMaterialPalette`, looked up when it gets extracted. For procedurally generated scenes with thousands of
+// objects sharing a few materials, which then need neither a material asset nor a handle each.
+// A `Handle<StandardMaterial>` on the same entity wins, objects with an index past the end of the palette are left out
+#[derive(Component, Reflect, Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub struct RaytraceMaterialId(pub u32);
+
+// Raytracing specific material settings that have no place on the StandardMaterial of a traced object
+#[derive(Component, ExtractComponent, Reflect, Clone, Copy, Default)]
+pub struct RaytraceMaterialOverride {
+    pub texture: RaytraceTexture,
+    // The tag of a custom BSDF registered with `register_raytrace_bsdf`, which replaces the built in one
+    pub custom_bsdf: Option<u32>,
+}
+
+// Procedural textures multiplied into the base color, evaluated in world space so they need no image assets
+#[derive(Reflect, Clone, Copy, Default)]
+pub enum RaytraceTexture {
+    // Just the base color
+    #[default]
+    None,
+    // 3d checker pattern of the base color and `odd_color` with cells of size 1 / scale
+    Checker {
+        scale: f32,
+        odd_color: Color,
+    },
+    // Smooth random values between 0 and 1
+    ValueNoise {
+        scale: f32,
+    },
+    Perlin {
+        scale: f32,
+    },
+    // Multiple octaves of perlin noise summed up
+    Turbulence {
+        scale: f32,
+        octaves: u32,
+    },
+}
+
+// A terrain traced straight from a heightmap, so it doesn't need to be split into triangles for the BVH.
+// The red channel of `heightmap` is the height, the terrain is centered on the entity and spans `scale`,
+// with heights going from 0 up to `scale.y`. Like the spheres it needs a `Handle<StandardMaterial>`.
+// The heights are read on the CPU, so the image has to stay in the main world (the default `RenderAssetUsages`).
+#[derive(Component, Reflect, Clone)]
+pub struct RaytracedHeightfield {
+    pub heightmap: Handle<Image>,
+    pub scale: Vec3,
+}
+
+// A quad that turns to face every ray hitting it, for impostor trees and grass that are far cheaper to trace than meshes.
+// It is centered on the entity and spans `size`, scaled by the transform. Texels of `image` with an alpha below
+// `alpha_cutoff` are cut out, the others multiply the base color of its `Handle<StandardMaterial>`.
+// Like the heightmaps the image is read on the CPU and resampled to the resolution of decals
+#[derive(Component, Reflect, Clone)]
+pub struct RaytracedBillboard {
+    pub image: Handle<Image>,
+    pub size: Vec2,
+    pub alpha_cutoff: f32,
+    pub facing: BillboardFacing,
+}
+
+#[derive(Reflect, Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub enum BillboardFacing {
+    // Turns fully towards every ray, like particles
+    #[default]
+    Ray,
+    // Only turns around its local up axis, so vegetation stays upright when seen from above
+    Upright,
+}
+
+// Puts a traced sphere, heightfield or billboard into up to 8 groups like the instance masks of DXR,
+// rays only hit it if their mask in the `RaytraceRayMasks` of the camera shares a bit with it. Without one it is in every group
+#[derive(Component, Reflect, Clone, Copy, PartialEq, Eq, Debug)]
+pub struct RaytraceInstanceMask(pub u8);
+
+impl Default for RaytraceInstanceMask {
+    fn default() -> Self {
+        Self(u8::MAX)
+    }
+}
+
+impl RaytraceInstanceMask {
+    // Reflection only models keep their groups in the second byte, which camera rays never test against
+    fn packed(mask: Option<&Self>, reflection_only: bool) -> u32 {
+        let groups = u32::from(mask.copied().unwrap_or_default().0);
+        if reflection_only {
+            groups << 8
+        } else {
+            groups
+        }
+    }
+}
+
+// The instance masks the rays of a camera hit by what they are for, like leaving a character's own head out of
+// the reflections or keeping an object out of shadow rays so it casts none. Without it every ray hits everything
+#[derive(Component, Reflect, Clone, Copy, PartialEq, Eq, Debug)]
+pub struct RaytraceRayMasks {
+    // From the camera to the first surface or fog the path scatters at
+    pub camera: u8,
+    // Towards lights and the environment, to find out whether something is in the way
+    pub shadow: u8,
+    // Every ray after the first scattering, reflected, refracted or diffuse alike
+    pub reflection: u8,
+}
+
+impl Default for RaytraceRayMasks {
+    fn default() -> Self {
+        Self {
+            camera: u8::MAX,
+            shadow: u8::MAX,
+            reflection: u8::MAX,
+        }
+    }
+}
+
+impl RaytraceRayMasks {
+    // One byte per type of ray, in the order the shader unpacks them
+    fn packed(&self) -> u32 {
+        u32::from(self.camera) | u32::from(self.shadow) << 8 | u32::from(self.reflection) << 16
+    }
+}
+
+// Turns a traced sphere or heightfield into a stand in for the ground of a photographic backplate, like the `Skybox`
+// of the camera. Camera rays pass through it to the backplate, darkened by the shadows the traced objects cast onto it,
+// and with the reflections of those objects on top. The reflections are as strong as the fresnel term of its material,
+// reflections of the backplate itself are left out since the photo already has them. Other rays see it as a regular surface
+#[derive(Component, Reflect, Clone, Copy, Default, Debug)]
+pub struct RaytraceShadowCatcher;
+
+// Leaves a traced sphere, heightfield, billboard or particle system out of what cameras see directly, while shadow
+// and reflected rays still hit it. For set dressing that only has to show up in reflections and light the scene,
+// like a detailed backdrop behind the camera or a set extension off screen. Its `RaytraceInstanceMask` still applies
+#[derive(Component, Reflect, Clone, Copy, Default, Debug)]
+pub struct RaytraceReflectionOnly;
+
+// Intersects a traced sphere through a compensated float-float transform, two floats adding up to a more precise one.
+// Meant for planet sized spheres, where a float at the scale of the radius can't tell the ground right below the camera
+// from a meter above it, which shows up as banding and shadow acne or rays slipping through the surface.
+// Costs a few more operations per intersection, so only the spheres that need it should have it, heightfields ignore it.
+// Rays don't need it, they already start out close to the `RenderOrigin` near the camera
+#[derive(Component, Reflect, Clone, Copy, Default, Debug)]
+pub struct RaytracePrecisePosition;
+
+// Distance based detail levels for a traced object, picked every frame while the scene buffers get built.
+// Level n is used from `distances[n - 1]` away from the closest raytraced camera, so the distances should be ascending.
+// Every level halves the resolution of a heightfield, spheres have no detail to drop and ignore this.
+#[derive(Component, Reflect, Clone, Default)]
+pub struct RaytraceLod {
+    pub distances: Vec<f32>,
+}
+
+impl RaytraceLod {
+    pub fn level(&self, distance: f32) -> usize {
+        self.distances
+            .iter()
+            .take_while(|&&threshold| distance >= threshold)
+            .count()
+    }
+}
+
+// A box or sphere of homogeneous fog, rays passing through it scatter in random directions.
+// Like bevy light probes the volume is a unit cube or a sphere with a diameter of 1 around the entity,
+// scaled and positioned by its transform.
+#[derive(Component, Reflect, Clone, Copy)]
+pub struct RaytraceFogVolume {
+    pub shape: FogVolumeShape,
+    // Chance to scatter per unit of distance
+    pub density: f32,
+    // The color the light gets tinted with on every scattering event, white fog doesn't absorb anything
+    pub scattering_color: Color,
+}
+
+impl Default for RaytraceFogVolume {
+    fn default() -> Self {
+        Self {
+            shape: FogVolumeShape::Box,
+            density: 1.0,
+            scattering_color: Color::WHITE,
+        }
+    }
+}
+
+#[derive(Reflect, Clone, Copy, Default)]
+pub enum FogVolumeShape {
+    // The unit cube
+    #[default]
+    Box,
+    // The sphere inside the unit cube, an ellipsoid for non-uniform scales
+    Sphere,
+}
+
+// Makes the index of refraction of a traced object depend on the wavelength, following Cauchy's equation.
+// The ior of the material is used at 589nm, `cauchy_b` is in µm² (around 0.004 for crown glass, 0.01 for flint glass).
+// This only has an effect on cameras with spectral rendering enabled
+#[derive(Component, Reflect, Clone, Copy)]
+pub struct RaytraceDispersion {
+    pub cauchy_b: f32,
+}
+
+// Catches settings that would break the image instead of just being slow, warning once when they are clamped
+fn validate_raytraced_cameras(
+    mut cameras: Query<
+        (
+            Entity,
+            &mut RaytracedCamera,
+            &Camera,
+            Has<BloomSettings>,
+            Has<RaytraceAutoExposure>,
+        ),
+        Or<(
+            Changed<RaytracedCamera>,
+            Added<BloomSettings>,
+            Added<RaytraceAutoExposure>,
+        )>,
+    >,
+) {
+    for (entity, mut camera, bevy_camera, bloom, auto_exposure) in &mut cameras {
+        if camera.sample_count == 0 {
+            warn!("RaytracedCamera on {entity} has a sample_count of 0, using 1 instead");
+            camera.sample_count = 1;
+        }
+        if let RaytraceSampling::Progressive {
+            samples_per_frame: 0,
+        } = camera.sampling
+        {
+            warn!("RaytracedCamera on {entity} has 0 samples_per_frame, using 1 instead");
+            camera.sampling = RaytraceSampling::Progressive {
+                samples_per_frame: 1,
+            };
+        }
+        if camera.bounces > RaytracedCamera::MAX_BOUNCES {
+            warn!(
+                "RaytracedCamera on {entity} has {} bounces, clamping to {}",
+                camera.bounces,
+                RaytracedCamera::MAX_BOUNCES
+            );
+            camera.bounces = RaytracedCamera::MAX_BOUNCES;
+        }
+        if camera.regularization.is_nan() || camera.regularization < 0.0 {
+            warn!(
+                "RaytracedCamera on {entity} has a regularization of {}, using 0.0 instead",
+                camera.regularization
+            );
+            camera.regularization = 0.0;
+        }
+        if camera.termination.min_throughput.is_nan() || camera.termination.min_throughput < 0.0 {
+            warn!(
+                "RaytracedCamera on {entity} has a min_throughput of {}, using 0.0 instead",
+                camera.termination.min_throughput
+            );
+            camera.termination.min_throughput = 0.0;
+        }
+        if camera.output == RaytraceOutput::LinearHdr && !bevy_camera.hdr {
+            warn!("RaytracedCamera on {entity} outputs linear HDR without an hdr Camera, it is drawn after tonemapping instead");
+        }
+        if bloom && (camera.output == RaytraceOutput::Tonemapped || !bevy_camera.hdr) {
+            warn!("RaytracedCamera on {entity} has BloomSettings, but its traced image is drawn after bloom, use RaytraceOutput::LinearHdr on an hdr Camera for it to glow");
+        }
+        if auto_exposure && (camera.output == RaytraceOutput::Tonemapped || !bevy_camera.hdr) {
+            warn!("RaytracedCamera on {entity} has RaytraceAutoExposure, but it only meters RaytraceOutput::LinearHdr on an hdr Camera, the exposure stays as it is");
+        }
+    }
+}
+
+// Cameras rendering after a raytraced camera into the same target, like the one of the transform gizmo or an editor overlay,
+// draw on top of the traced image. Clearing would throw it away, so they load it instead, together with the depth
+// buffer the traced camera wrote into, which bevy shares between the cameras of a target. Only the copies extracted
+// for this frame are changed, the cameras in the main world keep their settings.
+// Cameras with a different `hdr` draw into other textures and are left as they are
+fn composite_overlay_cameras(
+    traced: Query<&ExtractedCamera, With<CameraExtract>>,
+    mut overlays: Query<(&mut ExtractedCamera, &mut Camera3d), Without<CameraExtract>>,
+) {
+    for (mut overlay, mut camera_3d) in &mut overlays {
+        let covers_traced = overlay.target.is_some()
+            && traced.iter().any(|camera| {
+                camera.order < overlay.order
+                    && camera.target == overlay.target
+                    && camera.hdr == overlay.hdr
+            });
+        if covers_traced {
+            overlay.clear_color = ClearColorConfig::None;
+            camera_3d.depth_load_op = Camera3dDepthLoadOp::Load;
+        }
+    }
+}
+
 fn auto_add_camera_components(
     added: Query<Entity, (With<Camera>, With<Projection>, Without<DepthPrepass>)>,
     mut cmd: Commands,
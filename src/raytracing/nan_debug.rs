@@ -0,0 +1,164 @@
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use bevy::{
+    prelude::*,
+    render::{
+        render_resource::{BindingResource, Buffer, BufferDescriptor, BufferUsages},
+        renderer::{RenderDevice, RenderQueue},
+        Extract, ExtractSchedule, Render, RenderApp, RenderSet,
+    },
+};
+
+use super::readback::Readback;
+
+pub struct RaytraceNanDebugPlugin;
+
+impl Plugin for RaytraceNanDebugPlugin {
+    fn build(&self, app: &mut App) {
+        // Both worlds share the count, the render world fills it in and the main world copies it into the report
+        let count = NanCount::default();
+        app.insert_resource(count.clone())
+            .register_type::<RaytraceNanDebug>()
+            .register_type::<RaytraceNanReport>()
+            .init_resource::<RaytraceNanDebug>()
+            .init_resource::<RaytraceNanReport>()
+            .add_systems(Update, update_nan_report);
+
+        let Some(render_app) = app.get_sub_app_mut(RenderApp) else {
+            return;
+        };
+
+        render_app
+            .insert_resource(count)
+            .add_systems(ExtractSchedule, extract_nan_debug)
+            .add_systems(
+                Render,
+                (
+                    prepare_nan_counter.in_set(RenderSet::PrepareResources),
+                    read_back_nan_counter.in_set(RenderSet::Cleanup),
+                ),
+            );
+    }
+
+    fn finish(&self, app: &mut App) {
+        let Some(render_app) = app.get_sub_app_mut(RenderApp) else {
+            return;
+        };
+
+        render_app.init_resource::<NanDebugBuffers>();
+    }
+}
+
+// Switches the cameras over to a variant of the tracing shader that checks every path for NaN or infinite radiance.
+// Those paths are left out of the pixel, which gets drawn magenta instead, and are counted in `RaytraceNanReport`
+#[derive(Resource, Reflect, Clone, Default)]
+#[reflect(Resource)]
+pub struct RaytraceNanDebug {
+    pub enabled: bool,
+}
+
+// How many paths of one recent frame had NaN or infinite radiance, over all cameras.
+// The count is read back from the GPU, so it lags a few frames behind and stays at 0 while the debugging is off
+#[derive(Resource, Reflect, Clone, Copy, Default, PartialEq, Debug)]
+#[reflect(Resource)]
+pub struct RaytraceNanReport {
+    pub non_finite_paths: u32,
+}
+
+// Holds a new count until the main world takes it
+#[derive(Resource, Clone, Default)]
+struct NanCount(Arc<Mutex<Option<u32>>>);
+
+impl NanCount {
+    fn lock(&self) -> MutexGuard<'_, Option<u32>> {
+        self.0.lock().expect("Could not get NaN count out of mutex")
+    }
+}
+
+fn update_nan_report(
+    debug: Res<RaytraceNanDebug>,
+    count: Res<NanCount>,
+    mut report: ResMut<RaytraceNanReport>,
+) {
+    let count = count.lock().take();
+    if !debug.enabled {
+        report.set_if_neq(RaytraceNanReport::default());
+        return;
+    }
+
+    if let Some(non_finite_paths) = count {
+        report.set_if_neq(RaytraceNanReport { non_finite_paths });
+    }
+}
+
+#[derive(Resource)]
+pub struct NanDebugBuffers {
+    // Picks the pipeline variant with the checks
+    pub(super) enabled: bool,
+    counter: Buffer,
+    readback: Readback,
+    // Set while the paths of this frame get counted
+    counting: bool,
+}
+
+impl FromWorld for NanDebugBuffers {
+    fn from_world(world: &mut World) -> Self {
+        let render_device = world.resource::<RenderDevice>();
+        let size = std::mem::size_of::<u32>() as u64;
+
+        Self {
+            enabled: false,
+            counter: render_device.create_buffer(&BufferDescriptor {
+                label: Some("nan_debug_counter"),
+                size,
+                usage: BufferUsages::STORAGE | BufferUsages::COPY_DST | BufferUsages::COPY_SRC,
+                mapped_at_creation: false,
+            }),
+            readback: Readback::new(render_device, "nan_debug_readback", size),
+            counting: false,
+        }
+    }
+}
+
+impl NanDebugBuffers {
+    pub(super) fn binding(&self) -> BindingResource<'_> {
+        self.counter.as_entire_binding()
+    }
+}
+
+fn extract_nan_debug(mut buffers: ResMut<NanDebugBuffers>, debug: Extract<Res<RaytraceNanDebug>>) {
+    buffers.enabled = debug.enabled;
+}
+
+// Only the frames that start while no count is on its way get counted, the ones in between add up in the counter until it is reset
+fn prepare_nan_counter(mut buffers: ResMut<NanDebugBuffers>, render_queue: Res<RenderQueue>) {
+    if !buffers.enabled || buffers.counting || buffers.readback.is_pending() {
+        return;
+    }
+
+    render_queue.write_buffer(&buffers.counter, 0, &0u32.to_le_bytes());
+    buffers.counting = true;
+}
+
+fn read_back_nan_counter(
+    mut buffers: ResMut<NanDebugBuffers>,
+    count: Res<NanCount>,
+    render_device: Res<RenderDevice>,
+    render_queue: Res<RenderQueue>,
+) {
+    let buffers = &mut *buffers;
+    if std::mem::take(&mut buffers.counting) {
+        buffers
+            .readback
+            .start(&render_device, &render_queue, &buffers.counter);
+        return;
+    }
+
+    let Some(outcome) = buffers.readback.poll(&render_device) else {
+        return;
+    };
+    match outcome {
+        Ok(bytes) => *count.lock() = Some(u32::from_le_bytes(bytes[0..4].try_into().unwrap())),
+        Err(error) => warn!("Could not read back the NaN count: {error}"),
+    }
+}
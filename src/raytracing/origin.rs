@@ -0,0 +1,130 @@
+use bevy::{
+    prelude::*,
+    render::extract_resource::{ExtractResource, ExtractResourcePlugin},
+    transform::TransformSystem,
+};
+
+use super::RaytracedCamera;
+
+// The render origin snaps to a grid of cells this big, so it only moves once the camera gets this far
+const ORIGIN_CELL_SIZE: f32 = 64.0;
+
+pub struct RaytraceOriginPlugin;
+
+impl Plugin for RaytraceOriginPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<RenderOrigin>()
+            .add_plugins(ExtractResourcePlugin::<RenderOrigin>::default())
+            .add_systems(
+                PostUpdate,
+                update_render_origin.after(TransformSystem::TransformPropagate),
+            );
+    }
+}
+
+// Everything the tracer uploads is relative to this point instead of the world origin.
+// Far from the world origin floats get too coarse for the hit positions, which shows up as acne and gaps in shadows,
+// relative to a point close to the camera they stay small where it matters.
+// All cameras share the scene buffers, so the first active raytraced camera decides where it is.
+// It snaps to a grid, so the uploaded scene and the BVH only change when the camera moves into another cell
+#[derive(Resource, ExtractResource, Clone, Copy, Default, PartialEq, Debug)]
+pub struct RenderOrigin(Vec3);
+
+impl RenderOrigin {
+    pub fn point(&self, world: Vec3) -> Vec3 {
+        world - self.0
+    }
+
+    // Moving the translation keeps it exact, multiplying by a translation would round it at the size of the world position
+    pub fn render_from_local(&self, world_from_local: Mat4) -> Mat4 {
+        let mut render_from_local = world_from_local;
+        render_from_local.w_axis -= self.0.extend(0.0);
+        render_from_local
+    }
+
+    pub fn local_from_render(&self, world_from_local: Mat4) -> Mat4 {
+        self.render_from_local(world_from_local).inverse()
+    }
+
+    // Like `local_from_render`, but inverted in double precision with the part of the translation
+    // that didn't fit into a float returned on its own, see `RaytracePrecisePosition`
+    pub fn precise_local_from_render(&self, world_from_local: Mat4) -> (Mat4, Vec3) {
+        let mut render_from_local = world_from_local.as_dmat4();
+        render_from_local.w_axis -= self.0.as_dvec3().extend(0.0);
+        let local_from_render = render_from_local.inverse();
+        let translation = local_from_render.w_axis.truncate();
+        let low = translation - translation.as_vec3().as_dvec3();
+        (local_from_render.as_mat4(), low.as_vec3())
+    }
+
+    // For matrices of which only the inverse is known, like the ones of past frames
+    pub fn rebase_from_world(&self, from_world: Mat4) -> Mat4 {
+        from_world * Mat4::from_translation(self.0)
+    }
+
+    pub fn world_point(&self, render: Vec3) -> Vec3 {
+        render + self.0
+    }
+}
+
+fn update_render_origin(
+    cameras: Query<(&Camera, &GlobalTransform), With<RaytracedCamera>>,
+    mut origin: ResMut<RenderOrigin>,
+) {
+    let Some((_, transform)) = cameras
+        .iter()
+        .filter(|(camera, _)| camera.is_active)
+        .min_by_key(|(camera, _)| camera.order)
+    else {
+        return;
+    };
+
+    let cell = (transform.translation() / ORIGIN_CELL_SIZE).round() * ORIGIN_CELL_SIZE;
+    origin.set_if_neq(RenderOrigin(cell));
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::prelude::*;
+
+    use super::RenderOrigin;
+
+    #[test]
+    fn render_space_is_close_to_the_origin() {
+        let origin = RenderOrigin(Vec3::new(100_000.0, 0.0, -100_000.0));
+        let world_from_local = Mat4::from_scale_rotation_translation(
+            Vec3::splat(0.5),
+            Quat::from_rotation_y(1.0),
+            Vec3::new(100_003.25, 1.5, -99_998.75),
+        );
+
+        let local_from_render = origin.local_from_render(world_from_local);
+        let local = Vec3::new(0.25, -0.5, 1.0);
+        let render = origin
+            .render_from_local(world_from_local)
+            .transform_point3(local);
+        assert!(render.abs_diff_eq(Vec3::new(3.25, 1.5, 1.25), 1.0));
+        assert!(local_from_render
+            .transform_point3(render)
+            .abs_diff_eq(local, 1e-5));
+        assert_eq!(origin.world_point(origin.point(Vec3::X)), Vec3::X);
+    }
+
+    #[test]
+    fn precise_translation_keeps_the_rounded_part() {
+        // A planet below a camera close to the world origin
+        let origin = RenderOrigin(Vec3::ZERO);
+        let radius = 6_371_000.0;
+        let world_from_local = Mat4::from_scale_rotation_translation(
+            Vec3::splat(radius),
+            Quat::IDENTITY,
+            Vec3::new(0.0, -radius - 1.0, 0.0),
+        );
+
+        let (local_from_render, low) = origin.precise_local_from_render(world_from_local);
+        let high = local_from_render.w_axis.y as f64;
+        let exact = (radius as f64 + 1.0) / radius as f64;
+        assert!((high - exact).abs() > 1e-9);
+        assert!((high + low.y as f64 - exact).abs() < 1e-12);
+    }
+}
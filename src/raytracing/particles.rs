@@ -0,0 +1,152 @@
+use bevy::{
+    ecs::query::QueryItem,
+    pbr::{NotShadowCaster, NotShadowReceiver},
+    prelude::*,
+    render::extract_component::{ExtractComponent, ExtractComponentPlugin},
+};
+
+use super::{
+    extract::shadow_flags, light::RaytraceLightLink, RaytraceInstanceMask, RaytraceMaterialId,
+    RaytraceReflectionOnly,
+};
+
+pub struct RaytraceParticlesPlugin;
+
+impl Plugin for RaytraceParticlesPlugin {
+    fn build(&self, app: &mut App) {
+        app.register_type::<RaytraceParticles>()
+            .add_plugins(ExtractComponentPlugin::<RaytraceParticlesExtract>::default());
+    }
+}
+
+// The particles of a particle system, traced as spheres so sparks and fireflies show up in reflections and light up
+// what is around them. The buffers are read side by side, a particle is left out when one of them is too short,
+// and the positions are relative to the transform of the entity.
+// Each particle gets the `StandardMaterial` of the entity with its own color, emissive particles add their radiance
+// wherever a path hits them like emissive materials do, so their light gets less noisy the bigger they are.
+// Particle systems that simulate on the GPU have to read their particles back into this every frame
+#[derive(Component, Reflect, Clone, Default)]
+pub struct RaytraceParticles {
+    pub positions: Vec<Vec3>,
+    pub radii: Vec<f32>,
+    pub colors: Vec<LinearRgba>,
+    // Optional, particles without an entry here don't glow
+    pub emissive: Vec<LinearRgba>,
+}
+
+impl RaytraceParticles {
+    pub fn len(&self) -> usize {
+        self.positions
+            .len()
+            .min(self.radii.len())
+            .min(self.colors.len())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn clear(&mut self) {
+        self.positions.clear();
+        self.radii.clear();
+        self.colors.clear();
+        self.emissive.clear();
+    }
+
+    pub fn push(&mut self, position: Vec3, radius: f32, color: LinearRgba, emissive: LinearRgba) {
+        // Keeps the emissive buffer lined up with the others once the first particle glows
+        if emissive != LinearRgba::BLACK || !self.emissive.is_empty() {
+            self.emissive.resize(self.len(), LinearRgba::BLACK);
+            self.emissive.push(emissive);
+        }
+        self.positions.push(position);
+        self.radii.push(radius);
+        self.colors.push(color);
+    }
+}
+
+// A particle in world space, ready to be added to the models
+#[derive(Clone, Copy)]
+pub struct TracedParticle {
+    pub position: Vec3,
+    pub radius: f32,
+    pub color: Vec3,
+    pub emissive: Vec3,
+}
+
+#[derive(Clone, Component)]
+pub struct RaytraceParticlesExtract {
+    pub particles: Vec<TracedParticle>,
+    pub shadow_flags: u32,
+    pub mask: u32,
+    pub light_link: u32,
+}
+
+impl ExtractComponent for RaytraceParticlesExtract {
+    type QueryData = (
+        &'static RaytraceParticles,
+        &'static GlobalTransform,
+        Has<NotShadowCaster>,
+        Has<NotShadowReceiver>,
+        Option<&'static RaytraceInstanceMask>,
+        Option<&'static RaytraceLightLink>,
+        Has<RaytraceReflectionOnly>,
+    );
+
+    type QueryFilter = Or<(With<Handle<StandardMaterial>>, With<RaytraceMaterialId>)>;
+
+    type Out = Self;
+
+    fn extract_component(item: QueryItem<'_, Self::QueryData>) -> Option<Self::Out> {
+        let (particles, transform, not_caster, not_receiver, mask, light_link, reflection_only) =
+            item;
+        if particles.is_empty() {
+            return None;
+        }
+
+        // The radii scale along the longest axis, particles stay round
+        let scale = transform.compute_transform().scale.abs().max_element();
+        let particles = (0..particles.len())
+            .map(|index| TracedParticle {
+                position: transform.transform_point(particles.positions[index]),
+                radius: particles.radii[index] * scale,
+                color: particles.colors[index].to_vec3(),
+                emissive: particles
+                    .emissive
+                    .get(index)
+                    .map_or(Vec3::ZERO, |emissive| emissive.to_vec3()),
+            })
+            .filter(|particle| particle.radius > 0.0)
+            .collect();
+
+        Some(RaytraceParticlesExtract {
+            particles,
+            shadow_flags: shadow_flags(not_caster, not_receiver, false),
+            mask: RaytraceInstanceMask::packed(mask, reflection_only),
+            light_link: light_link.copied().unwrap_or_default().0,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::prelude::*;
+
+    use super::RaytraceParticles;
+
+    #[test]
+    fn emissive_stays_lined_up() {
+        let mut particles = RaytraceParticles::default();
+        particles.push(Vec3::ZERO, 0.1, LinearRgba::WHITE, LinearRgba::BLACK);
+        particles.push(Vec3::X, 0.1, LinearRgba::WHITE, LinearRgba::BLACK);
+        assert!(particles.emissive.is_empty());
+
+        let glow = LinearRgba::rgb(4.0, 2.0, 0.5);
+        particles.push(Vec3::Y, 0.1, LinearRgba::WHITE, glow);
+        assert_eq!(particles.len(), 3);
+        assert_eq!(
+            particles.emissive,
+            vec![LinearRgba::BLACK, LinearRgba::BLACK, glow]
+        );
+    }
+}
@@ -0,0 +1,382 @@
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use bevy::{
+    prelude::*,
+    render::{
+        render_resource::{
+            encase, BindingResource, Buffer, BufferDescriptor, BufferInitDescriptor, BufferUsages,
+            ShaderType,
+        },
+        renderer::{RenderDevice, RenderQueue},
+        Render, RenderApp, RenderSet,
+    },
+    window::PrimaryWindow,
+};
+
+use super::{
+    origin::RenderOrigin, picking::topmost_window_camera, readback::Readback, RaytracedCamera,
+};
+
+// Has to match scene.wgsl
+const MAX_PATH_VERTICES: usize = 32;
+// Checkerboard cameras don't trace every pixel every frame, so an empty path is tried again a few times
+const MAX_ATTEMPTS: u32 = 8;
+
+pub struct RaytracePathDebugPlugin;
+
+impl Plugin for RaytracePathDebugPlugin {
+    fn build(&self, app: &mut App) {
+        // Both worlds share the requests and the recorded paths
+        let shared = PathDebugShared::default();
+        app.insert_resource(shared.clone())
+            .register_type::<RaytracePathDebugger>()
+            .init_resource::<RaytracePathDebugger>()
+            .init_resource::<RaytraceRecordedPath>()
+            .add_systems(
+                Update,
+                (request_path, receive_recorded_path, draw_recorded_path).chain(),
+            );
+
+        let Some(render_app) = app.get_sub_app_mut(RenderApp) else {
+            return;
+        };
+
+        render_app.insert_resource(shared).add_systems(
+            Render,
+            (
+                prepare_path_debug.in_set(RenderSet::PrepareResources),
+                read_back_path_debug.in_set(RenderSet::Cleanup),
+            ),
+        );
+    }
+
+    fn finish(&self, app: &mut App) {
+        let Some(render_app) = app.get_sub_app_mut(RenderApp) else {
+            return;
+        };
+
+        render_app.init_resource::<PathDebugBuffers>();
+    }
+}
+
+// While enabled, clicking a pixel of a raytraced camera in the primary window records the path of its first sample.
+// The path is drawn with gizmos, with vertices that carry a NaN or infinity marked in red,
+// and can be inspected in `RaytraceRecordedPath`
+#[derive(Resource, Reflect, Clone)]
+#[reflect(Resource)]
+pub struct RaytracePathDebugger {
+    pub enabled: bool,
+    pub button: MouseButton,
+}
+
+impl Default for RaytracePathDebugger {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            button: MouseButton::Right,
+        }
+    }
+}
+
+// The last path recorded by the path debugger
+#[derive(Resource, Clone, Default, Debug)]
+pub struct RaytraceRecordedPath {
+    pub camera: Option<Entity>,
+    // In physical pixels of the window
+    pub pixel: UVec2,
+    pub vertices: Vec<RecordedPathVertex>,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct RecordedPathVertex {
+    pub position: Vec3,
+    pub kind: PathVertexKind,
+    // What the path carries when it arrives here
+    pub throughput: Vec3,
+}
+
+// Portals show up twice, once where the path enters and once where it comes out of the linked one.
+// Misses are put a fixed distance along the ray that left the scene
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PathVertexKind {
+    Camera,
+    Surface,
+    Fog,
+    PortalEntry,
+    PortalExit,
+    Miss,
+}
+
+impl PathVertexKind {
+    // The kinds in scene.wgsl
+    fn from_shader(kind: u32) -> Self {
+        match kind {
+            0 => Self::Camera,
+            1 => Self::Surface,
+            2 => Self::Fog,
+            3 => Self::PortalEntry,
+            4 => Self::PortalExit,
+            _ => Self::Miss,
+        }
+    }
+}
+
+#[derive(Default)]
+struct PathDebugState {
+    // The camera and pixel to record next, taken by the render world
+    request: Option<(Entity, UVec2)>,
+    // Taken by the main world
+    recorded: Option<RaytraceRecordedPath>,
+}
+
+#[derive(Resource, Clone, Default)]
+struct PathDebugShared(Arc<Mutex<PathDebugState>>);
+
+impl PathDebugShared {
+    fn lock(&self) -> MutexGuard<'_, PathDebugState> {
+        self.0
+            .lock()
+            .expect("Could not get path debug state out of mutex")
+    }
+}
+
+fn request_path(
+    debugger: Res<RaytracePathDebugger>,
+    buttons: Res<ButtonInput<MouseButton>>,
+    windows: Query<&Window, With<PrimaryWindow>>,
+    cameras: Query<(&Camera, Entity), With<RaytracedCamera>>,
+    shared: Res<PathDebugShared>,
+) {
+    if !debugger.enabled || !buttons.just_pressed(debugger.button) {
+        return;
+    }
+
+    let Some(cursor) = windows
+        .get_single()
+        .ok()
+        .and_then(Window::physical_cursor_position)
+    else {
+        return;
+    };
+    let Some((_, camera)) = topmost_window_camera(cameras.iter()) else {
+        return;
+    };
+
+    shared.lock().request = Some((camera, cursor.as_uvec2()));
+}
+
+fn receive_recorded_path(shared: Res<PathDebugShared>, mut recorded: ResMut<RaytraceRecordedPath>) {
+    if let Some(path) = shared.lock().recorded.take() {
+        *recorded = path;
+    }
+}
+
+fn draw_recorded_path(
+    debugger: Res<RaytracePathDebugger>,
+    recorded: Res<RaytraceRecordedPath>,
+    mut gizmos: Gizmos,
+) {
+    if !debugger.enabled {
+        return;
+    }
+
+    for (from, to) in recorded
+        .vertices
+        .iter()
+        .zip(recorded.vertices.iter().skip(1))
+    {
+        // The path doesn't travel between linked portals
+        if from.kind == PathVertexKind::PortalEntry && to.kind == PathVertexKind::PortalExit {
+            continue;
+        }
+        gizmos.line(
+            from.position,
+            to.position,
+            throughput_color(from.throughput),
+        );
+    }
+
+    for vertex in &recorded.vertices {
+        let radius = if vertex.throughput.is_finite() {
+            0.03
+        } else {
+            0.1
+        };
+        gizmos.sphere(
+            vertex.position,
+            Quat::IDENTITY,
+            radius,
+            throughput_color(vertex.throughput),
+        );
+    }
+}
+
+// The tint the path carries, NaNs and infinities are red and paths that lost all their energy grey
+fn throughput_color(throughput: Vec3) -> Color {
+    if !throughput.is_finite() {
+        return Color::srgb(1.0, 0.0, 0.0);
+    }
+
+    let brightest = throughput.max_element();
+    if brightest <= 0.0 {
+        return Color::srgb(0.3, 0.3, 0.3);
+    }
+    let tint = throughput / brightest;
+    Color::linear_rgb(tint.x, tint.y, tint.z)
+}
+
+// Has to match the PathDebug in scene.wgsl
+#[derive(ShaderType, Default)]
+pub(super) struct PathDebugData {
+    pixel: IVec2,
+    vertex_count: u32,
+    vertices: [PathVertexData; MAX_PATH_VERTICES],
+}
+
+#[derive(ShaderType, Clone, Copy, Default)]
+struct PathVertexData {
+    position: Vec3,
+    kind: u32,
+    throughput: Vec3,
+}
+
+impl PathDebugData {
+    fn bytes(&self) -> Vec<u8> {
+        let mut bytes = encase::StorageBuffer::new(Vec::new());
+        bytes.write(self).expect("Could not write path debug data");
+        bytes.into_inner()
+    }
+}
+
+#[derive(Resource)]
+pub struct PathDebugBuffers {
+    buffer: Buffer,
+    // Bound for every view that doesn't record, its pixel is never hit
+    fallback: Buffer,
+    readback: Readback,
+    target: Option<(Entity, UVec2)>,
+    attempts: u32,
+    // Set while the target view records into the buffer this frame
+    recording: bool,
+    // Where render space was when the path was recorded, the positions get moved back into world space with it
+    origin: RenderOrigin,
+}
+
+impl FromWorld for PathDebugBuffers {
+    fn from_world(world: &mut World) -> Self {
+        let render_device = world.resource::<RenderDevice>();
+        let size = PathDebugData::min_size().get();
+
+        Self {
+            buffer: render_device.create_buffer(&BufferDescriptor {
+                label: Some("path_debug_buffer"),
+                size,
+                usage: BufferUsages::STORAGE | BufferUsages::COPY_DST | BufferUsages::COPY_SRC,
+                mapped_at_creation: false,
+            }),
+            fallback: render_device.create_buffer_with_data(&BufferInitDescriptor {
+                label: Some("path_debug_fallback"),
+                contents: &PathDebugData {
+                    pixel: IVec2::splat(-1),
+                    ..default()
+                }
+                .bytes(),
+                usage: BufferUsages::STORAGE,
+            }),
+            readback: Readback::new(render_device, "path_debug_readback", size),
+            target: None,
+            attempts: 0,
+            recording: false,
+            origin: RenderOrigin::default(),
+        }
+    }
+}
+
+impl PathDebugBuffers {
+    // Only the view the path is recorded for gets the real buffer
+    pub(super) fn binding(&self, view: Entity) -> BindingResource<'_> {
+        let recording = self.recording && self.target.is_some_and(|(camera, _)| camera == view);
+        if recording {
+            self.buffer.as_entire_binding()
+        } else {
+            self.fallback.as_entire_binding()
+        }
+    }
+}
+
+fn prepare_path_debug(
+    mut buffers: ResMut<PathDebugBuffers>,
+    shared: Res<PathDebugShared>,
+    render_queue: Res<RenderQueue>,
+) {
+    // One path at a time, clicks in between wait until the last one is read back
+    if buffers.recording || buffers.readback.is_pending() {
+        return;
+    }
+    let Some((camera, pixel)) = shared.lock().request.take() else {
+        return;
+    };
+
+    let header = PathDebugData {
+        pixel: pixel.as_ivec2(),
+        ..default()
+    };
+    render_queue.write_buffer(&buffers.buffer, 0, &header.bytes());
+    buffers.target = Some((camera, pixel));
+    buffers.attempts = 0;
+    buffers.recording = true;
+}
+
+fn read_back_path_debug(
+    mut buffers: ResMut<PathDebugBuffers>,
+    shared: Res<PathDebugShared>,
+    origin: Res<RenderOrigin>,
+    render_device: Res<RenderDevice>,
+    render_queue: Res<RenderQueue>,
+) {
+    let buffers = &mut *buffers;
+    if std::mem::take(&mut buffers.recording) {
+        buffers.origin = *origin;
+        buffers
+            .readback
+            .start(&render_device, &render_queue, &buffers.buffer);
+        return;
+    }
+
+    let Some(outcome) = buffers.readback.poll(&render_device) else {
+        return;
+    };
+    let bytes = match outcome {
+        Ok(bytes) => bytes,
+        Err(error) => {
+            warn!("Could not read back the debugged path: {error}");
+            return;
+        }
+    };
+    let data = encase::StorageBuffer::new(&bytes)
+        .create::<PathDebugData>()
+        .expect("Could not read path debug data");
+
+    let Some((camera, pixel)) = buffers.target else {
+        return;
+    };
+    if data.vertex_count == 0 && buffers.attempts < MAX_ATTEMPTS {
+        buffers.attempts += 1;
+        buffers.recording = true;
+        return;
+    }
+
+    let vertices = data.vertices[..(data.vertex_count as usize).min(MAX_PATH_VERTICES)]
+        .iter()
+        .map(|vertex| RecordedPathVertex {
+            position: buffers.origin.world_point(vertex.position),
+            kind: PathVertexKind::from_shader(vertex.kind),
+            throughput: vertex.throughput,
+        })
+        .collect();
+    shared.lock().recorded = Some(RaytraceRecordedPath {
+        camera: Some(camera),
+        pixel,
+        vertices,
+    });
+}
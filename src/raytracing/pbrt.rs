@@ -0,0 +1,896 @@
+use std::{
+    f32::consts::PI,
+    io::{Error, ErrorKind},
+};
+
+use bevy::{
+    asset::{io::Reader, AssetLoader, AsyncReadExt, LoadContext},
+    prelude::*,
+    render::{
+        camera::Exposure, mesh::Indices, render_asset::RenderAssetUsages,
+        render_resource::PrimitiveTopology,
+    },
+    utils::HashMap,
+};
+
+use super::{
+    PixelFilter, RaytraceDiskLight, RaytraceHideMesh, RaytraceMode, RaytraceStatic,
+    RaytracedCamera, RaytracedSphere,
+};
+
+pub struct RaytracePbrtPlugin;
+
+impl Plugin for RaytracePbrtPlugin {
+    fn build(&self, app: &mut App) {
+        app.register_type::<RaytracePbrtScene>()
+            .init_asset::<PbrtScene>()
+            .register_asset_loader(PbrtLoader)
+            .add_systems(Update, spawn_pbrt_scenes);
+    }
+}
+
+// Spawns the content of a PBRT v4 scene file as children of this entity, for comparing against established renderers.
+// Spheres become static `RaytracedSphere`s and point and spot lights bevy lights. Triangle and bilinear meshes become bevy meshes,
+// which are only rasterized for now. Area lights are turned into sphere or disk lights of the same power.
+// The camera is spawned with its samples as a final render and without path regularization.
+// Textures, instancing, includes and most materials aren't supported, they get replaced and a warning is logged.
+#[derive(Component, Reflect, Clone)]
+pub struct RaytracePbrtScene(pub Handle<PbrtScene>);
+
+// Marks scenes that have been spawned
+#[derive(Component)]
+struct PbrtSceneSpawned;
+
+// The supported subset of a PBRT scene, already converted from PBRT's left handed coordinates
+#[derive(Asset, TypePath, Default)]
+pub struct PbrtScene {
+    camera: Option<PbrtCamera>,
+    materials: Vec<StandardMaterial>,
+    spheres: Vec<PbrtSphere>,
+    meshes: Vec<PbrtMesh>,
+    lights: Vec<PbrtLight>,
+}
+
+struct PbrtCamera {
+    transform: Transform,
+    // Vertical, in radians
+    fov: f32,
+    sample_count: u32,
+    bounces: u32,
+    pixel_filter: PixelFilter,
+}
+
+struct PbrtSphere {
+    center: Vec3,
+    radius: f32,
+    material: usize,
+}
+
+struct PbrtMesh {
+    mesh: Mesh,
+    material: usize,
+}
+
+enum PbrtLight {
+    Point {
+        transform: Transform,
+        color: Color,
+        // In lumens, like bevy lights
+        intensity: f32,
+        radius: f32,
+        disk: bool,
+    },
+    Spot {
+        transform: Transform,
+        color: Color,
+        intensity: f32,
+        outer_angle: f32,
+        inner_angle: f32,
+    },
+}
+
+#[derive(Default)]
+pub struct PbrtLoader;
+
+impl AssetLoader for PbrtLoader {
+    type Asset = PbrtScene;
+    type Settings = ();
+    type Error = Error;
+
+    async fn load<'a>(
+        &'a self,
+        reader: &'a mut Reader<'_>,
+        _settings: &'a (),
+        _load_context: &'a mut LoadContext<'_>,
+    ) -> Result<PbrtScene, Error> {
+        let mut text = String::new();
+        reader.read_to_string(&mut text).await?;
+        PbrtScene::parse(&text)
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["pbrt"]
+    }
+}
+
+fn spawn_pbrt_scenes(
+    roots: Query<(Entity, &RaytracePbrtScene), Without<PbrtSceneSpawned>>,
+    scenes: Res<Assets<PbrtScene>>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+    mut commands: Commands,
+) {
+    for (root, scene) in &roots {
+        let Some(scene) = scenes.get(&scene.0) else {
+            continue;
+        };
+
+        let scene_materials = scene
+            .materials
+            .iter()
+            // PBRT has no notion of back faces
+            .map(|material| {
+                materials.add(StandardMaterial {
+                    cull_mode: None,
+                    ..material.clone()
+                })
+            })
+            .collect::<Vec<_>>();
+
+        commands
+            .entity(root)
+            .insert(PbrtSceneSpawned)
+            .with_children(|parent| {
+                if let Some(camera) = &scene.camera {
+                    let mut settings = RaytracedCamera {
+                        bounces: camera.bounces,
+                        regularization: 0.0,
+                        pixel_filter: camera.pixel_filter,
+                        ..default()
+                    };
+                    settings.set_mode(RaytraceMode::Final {
+                        samples: camera.sample_count,
+                        samples_per_frame: 4,
+                    });
+
+                    parent.spawn((
+                        Camera3dBundle {
+                            transform: camera.transform,
+                            projection: Projection::Perspective(PerspectiveProjection {
+                                fov: camera.fov,
+                                ..default()
+                            }),
+                            ..default()
+                        },
+                        settings,
+                        Name::new("PBRT Camera"),
+                    ));
+                }
+
+                for sphere in &scene.spheres {
+                    parent.spawn((
+                        PbrBundle {
+                            mesh: meshes.add(Sphere::new(1.0)),
+                            material: scene_materials[sphere.material].clone(),
+                            transform: Transform::from_translation(sphere.center)
+                                .with_scale(Vec3::splat(sphere.radius)),
+                            ..default()
+                        },
+                        RaytracedSphere {
+                            radius: sphere.radius,
+                        },
+                        RaytraceHideMesh,
+                        RaytraceStatic,
+                    ));
+                }
+
+                for mesh in &scene.meshes {
+                    parent.spawn(PbrBundle {
+                        mesh: meshes.add(mesh.mesh.clone()),
+                        material: scene_materials[mesh.material].clone(),
+                        ..default()
+                    });
+                }
+
+                for light in &scene.lights {
+                    match *light {
+                        PbrtLight::Point {
+                            transform,
+                            color,
+                            intensity,
+                            radius,
+                            disk,
+                        } => {
+                            let mut light = parent.spawn(PointLightBundle {
+                                point_light: PointLight {
+                                    color,
+                                    intensity,
+                                    radius,
+                                    range: 1000.0,
+                                    ..default()
+                                },
+                                transform,
+                                ..default()
+                            });
+                            if disk {
+                                light.insert(RaytraceDiskLight);
+                            }
+                        }
+                        PbrtLight::Spot {
+                            transform,
+                            color,
+                            intensity,
+                            outer_angle,
+                            inner_angle,
+                        } => {
+                            parent.spawn(SpotLightBundle {
+                                spot_light: SpotLight {
+                                    color,
+                                    intensity,
+                                    range: 1000.0,
+                                    outer_angle,
+                                    inner_angle,
+                                    ..default()
+                                },
+                                transform,
+                                ..default()
+                            });
+                        }
+                    }
+                }
+            });
+    }
+}
+
+enum Token {
+    Directive(String),
+    String(String),
+    Number(f32),
+    Bool(bool),
+    Open,
+    Close,
+}
+
+fn tokenize(text: &str) -> Result<Vec<Token>, Error> {
+    let mut tokens = Vec::new();
+    let mut chars = text.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        match c {
+            _ if c.is_whitespace() => {
+                chars.next();
+            }
+            '#' => {
+                chars.by_ref().find(|&c| c == '\n');
+            }
+            '[' => {
+                chars.next();
+                tokens.push(Token::Open);
+            }
+            ']' => {
+                chars.next();
+                tokens.push(Token::Close);
+            }
+            '"' => {
+                chars.next();
+                let string = chars.by_ref().take_while(|&c| c != '"').collect();
+                tokens.push(Token::String(string));
+            }
+            _ => {
+                let mut word = String::new();
+                while let Some(&c) = chars.peek() {
+                    if c.is_whitespace() || matches!(c, '[' | ']' | '"' | '#') {
+                        break;
+                    }
+                    word.push(c);
+                    chars.next();
+                }
+
+                tokens.push(match word.as_str() {
+                    "true" => Token::Bool(true),
+                    "false" => Token::Bool(false),
+                    _ if c.is_ascii_alphabetic() => Token::Directive(word),
+                    _ => Token::Number(word.parse().map_err(|_| {
+                        Error::new(ErrorKind::InvalidData, format!("invalid number {word}"))
+                    })?),
+                });
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+enum Value {
+    Numbers(Vec<f32>),
+    Strings(Vec<String>),
+    Bools(Vec<bool>),
+}
+
+// A directive with its positional arguments and the named "type name" parameters
+struct Statement {
+    directive: String,
+    arguments: Vec<Value>,
+    parameters: HashMap<String, (String, Value)>,
+}
+
+impl Statement {
+    fn string(&self, index: usize) -> Option<&str> {
+        match self.arguments.get(index) {
+            Some(Value::Strings(strings)) => strings.first().map(String::as_str),
+            _ => None,
+        }
+    }
+
+    fn numbers(&self) -> Vec<f32> {
+        self.arguments
+            .iter()
+            .flat_map(|argument| match argument {
+                Value::Numbers(numbers) => numbers.clone(),
+                _ => Vec::new(),
+            })
+            .collect()
+    }
+
+    fn parameter_numbers(&self, name: &str) -> Option<&[f32]> {
+        match self.parameters.get(name) {
+            Some((_, Value::Numbers(numbers))) => Some(numbers),
+            _ => None,
+        }
+    }
+
+    fn float(&self, name: &str, default: f32) -> f32 {
+        self.parameter_numbers(name)
+            .and_then(|numbers| numbers.first().copied())
+            .unwrap_or(default)
+    }
+
+    fn vec3(&self, name: &str) -> Option<Vec3> {
+        self.parameter_numbers(name)
+            .filter(|numbers| numbers.len() >= 3)
+            .map(|numbers| Vec3::new(numbers[0], numbers[1], numbers[2]))
+    }
+
+    // Only rgb spectra are supported, everything else falls back to the default
+    fn rgb(&self, name: &str, default: Vec3) -> Vec3 {
+        match self.parameters.get(name) {
+            Some((kind, _)) if kind == "rgb" || kind == "color" => {
+                self.vec3(name).unwrap_or(default)
+            }
+            Some((kind, _)) => {
+                warn!("PBRT: {kind} \"{name}\" isn't supported, only rgb");
+                default
+            }
+            None => default,
+        }
+    }
+
+    fn parameter_string(&self, name: &str) -> Option<&str> {
+        match self.parameters.get(name) {
+            Some((_, Value::Strings(strings))) => strings.first().map(String::as_str),
+            _ => None,
+        }
+    }
+
+    fn bool(&self, name: &str, default: bool) -> bool {
+        match self.parameters.get(name) {
+            Some((_, Value::Bools(bools))) => bools.first().copied().unwrap_or(default),
+            // Older files quote their bools
+            Some((_, Value::Strings(strings))) => strings.first().map_or(default, |s| s == "true"),
+            _ => default,
+        }
+    }
+}
+
+const PARAMETER_TYPES: [&str; 17] = [
+    "integer",
+    "float",
+    "point2",
+    "vector2",
+    "point3",
+    "vector3",
+    "normal3",
+    "normal",
+    "point",
+    "vector",
+    "rgb",
+    "color",
+    "spectrum",
+    "blackbody",
+    "bool",
+    "string",
+    "texture",
+];
+
+fn statements(tokens: Vec<Token>) -> Result<Vec<Statement>, Error> {
+    let invalid = |message: &str| Error::new(ErrorKind::InvalidData, message.to_string());
+
+    let mut statements = Vec::new();
+    let mut tokens = tokens.into_iter().peekable();
+    while let Some(token) = tokens.next() {
+        let Token::Directive(directive) = token else {
+            return Err(invalid("expected a directive"));
+        };
+
+        let mut values = Vec::new();
+        while let Some(token) = tokens.next_if(|token| !matches!(token, Token::Directive(_))) {
+            values.push(match token {
+                Token::Number(number) => Value::Numbers(vec![number]),
+                Token::String(string) => Value::Strings(vec![string]),
+                Token::Bool(bool) => Value::Bools(vec![bool]),
+                Token::Open => {
+                    let mut numbers = Vec::new();
+                    let mut strings = Vec::new();
+                    let mut bools = Vec::new();
+                    loop {
+                        match tokens.next() {
+                            Some(Token::Number(number)) => numbers.push(number),
+                            Some(Token::String(string)) => strings.push(string),
+                            Some(Token::Bool(bool)) => bools.push(bool),
+                            Some(Token::Close) => break,
+                            _ => return Err(invalid("unterminated [")),
+                        }
+                    }
+                    if !strings.is_empty() {
+                        Value::Strings(strings)
+                    } else if !bools.is_empty() {
+                        Value::Bools(bools)
+                    } else {
+                        Value::Numbers(numbers)
+                    }
+                }
+                Token::Close => return Err(invalid("unexpected ]")),
+                Token::Directive(_) => unreachable!(),
+            });
+        }
+
+        // Parameters are a "type name" string followed by their value, everything before them is positional
+        let mut arguments = Vec::new();
+        let mut parameters = HashMap::new();
+        let mut values = values.into_iter();
+        while let Some(value) = values.next() {
+            let declaration = match &value {
+                Value::Strings(strings) if strings.len() == 1 => {
+                    let mut words = strings[0].split_whitespace();
+                    match (words.next(), words.next(), words.next()) {
+                        (Some(kind), Some(name), None) if PARAMETER_TYPES.contains(&kind) => {
+                            Some((kind.to_string(), name.to_string()))
+                        }
+                        _ => None,
+                    }
+                }
+                _ => None,
+            };
+
+            match declaration {
+                Some((kind, name)) => {
+                    let value = values
+                        .next()
+                        .ok_or_else(|| invalid("missing parameter value"))?;
+                    parameters.insert(name, (kind, value));
+                }
+                _ => arguments.push(value),
+            }
+        }
+
+        statements.push(Statement {
+            directive,
+            arguments,
+            parameters,
+        });
+    }
+    Ok(statements)
+}
+
+#[derive(Clone)]
+struct GraphicsState {
+    transform: Mat4,
+    material: usize,
+    // Radiance of the shapes that follow, if they are emitters
+    area_light: Option<Vec3>,
+}
+
+// PBRT is left handed, mirroring the z axis brings the scene into bevy's coordinates.
+// Scenes converted from right handed renderers mirror their camera instead (`Scale -1 1 1`), those are kept as they are
+const MIRROR: Vec3 = Vec3::new(1.0, 1.0, -1.0);
+
+impl PbrtScene {
+    pub fn parse(text: &str) -> Result<Self, Error> {
+        let mut scene = PbrtScene::default();
+        scene.materials.push(diffuse_material(Vec3::splat(0.5)));
+
+        let mut state = GraphicsState {
+            transform: Mat4::IDENTITY,
+            material: 0,
+            area_light: None,
+        };
+        let mut stack = Vec::new();
+        let mut named_materials = HashMap::new();
+
+        let mut camera_from_world = None;
+        let mut mirror = MIRROR;
+        let mut fov = 90.0;
+        let mut resolution = Vec2::new(1280.0, 720.0);
+        let mut sample_count = 16;
+        let mut bounces = 5;
+        // The default of PBRT
+        let mut pixel_filter = PixelFilter::Gaussian;
+
+        for statement in statements(tokenize(text)?)? {
+            let numbers = statement.numbers();
+            match statement.directive.as_str() {
+                "Identity" => state.transform = Mat4::IDENTITY,
+                "Translate" if numbers.len() == 3 => {
+                    state.transform *= Mat4::from_translation(Vec3::from_slice(&numbers));
+                }
+                "Scale" if numbers.len() == 3 => {
+                    state.transform *= Mat4::from_scale(Vec3::from_slice(&numbers));
+                }
+                "Rotate" if numbers.len() == 4 => {
+                    state.transform *= Mat4::from_axis_angle(
+                        Vec3::from_slice(&numbers[1..]).normalize(),
+                        numbers[0].to_radians(),
+                    );
+                }
+                "LookAt" if numbers.len() == 9 => {
+                    state.transform *= Mat4::look_at_lh(
+                        Vec3::from_slice(&numbers[0..3]),
+                        Vec3::from_slice(&numbers[3..6]),
+                        Vec3::from_slice(&numbers[6..9]),
+                    );
+                }
+                "Transform" if numbers.len() == 16 => {
+                    state.transform = Mat4::from_cols_slice(&numbers);
+                }
+                "ConcatTransform" if numbers.len() == 16 => {
+                    state.transform *= Mat4::from_cols_slice(&numbers);
+                }
+                "Camera" => {
+                    camera_from_world = Some(state.transform);
+                    if state.transform.determinant() < 0.0 {
+                        mirror = Vec3::ONE;
+                    }
+                    fov = statement.float("fov", fov);
+                }
+                "Film" => {
+                    resolution.x = statement.float("xresolution", resolution.x);
+                    resolution.y = statement.float("yresolution", resolution.y);
+                }
+                "Sampler" => sample_count = statement.float("pixelsamples", 16.0) as u32,
+                "PixelFilter" => {
+                    pixel_filter = match statement.string(0) {
+                        Some("box") => PixelFilter::Box,
+                        Some("triangle") => PixelFilter::Tent,
+                        Some("gaussian") => PixelFilter::Gaussian,
+                        kind => {
+                            let kind = kind.unwrap_or("unnamed");
+                            warn!("PBRT: the {kind} filter isn't supported, using blackman-harris");
+                            PixelFilter::BlackmanHarris
+                        }
+                    }
+                }
+                "Integrator" => bounces = statement.float("maxdepth", 5.0) as u32,
+                "WorldBegin" => state.transform = Mat4::IDENTITY,
+                "AttributeBegin" | "TransformBegin" => stack.push(state.clone()),
+                "AttributeEnd" => state = stack.pop().unwrap_or(state),
+                "TransformEnd" => {
+                    if let Some(previous) = stack.pop() {
+                        state.transform = previous.transform;
+                    }
+                }
+                "Material" => {
+                    scene
+                        .materials
+                        .push(material(statement.string(0).unwrap_or(""), &statement));
+                    state.material = scene.materials.len() - 1;
+                }
+                "MakeNamedMaterial" => {
+                    let kind = statement.parameter_string("type").unwrap_or("");
+                    scene.materials.push(material(kind, &statement));
+                    if let Some(name) = statement.string(0) {
+                        named_materials.insert(name.to_string(), scene.materials.len() - 1);
+                    }
+                }
+                "NamedMaterial" => {
+                    match statement
+                        .string(0)
+                        .and_then(|name| named_materials.get(name))
+                    {
+                        Some(&material) => state.material = material,
+                        None => warn!("PBRT: unknown named material, keeping the current one"),
+                    }
+                }
+                "AreaLightSource" => {
+                    state.area_light =
+                        Some(statement.rgb("L", Vec3::ONE) * statement.float("scale", 1.0));
+                }
+                "LightSource" => {
+                    if let Some(light) = light(&statement, state.transform, mirror) {
+                        scene.lights.push(light);
+                    }
+                }
+                "Shape" => shape(&mut scene, &statement, &state, mirror),
+                directive => warn!("PBRT: {directive} isn't supported and gets skipped"),
+            }
+        }
+
+        if let Some(camera_from_world) = camera_from_world {
+            // PBRT cameras look along +z, the field of view covers the shorter side of the image
+            let world_from_camera = camera_from_world.inverse();
+            let position = world_from_camera.transform_point3(Vec3::ZERO) * mirror;
+            let forward = world_from_camera.transform_vector3(Vec3::Z) * mirror;
+            let up = world_from_camera.transform_vector3(Vec3::Y) * mirror;
+
+            let half_fov = (fov.to_radians() * 0.5).tan();
+            let vertical = if resolution.y > resolution.x {
+                half_fov * resolution.y / resolution.x
+            } else {
+                half_fov
+            };
+
+            scene.camera = Some(PbrtCamera {
+                transform: Transform::from_translation(position).looking_to(forward, up),
+                fov: 2.0 * vertical.atan(),
+                sample_count: sample_count.max(1),
+                bounces,
+                pixel_filter,
+            });
+        }
+
+        Ok(scene)
+    }
+}
+
+fn diffuse_material(reflectance: Vec3) -> StandardMaterial {
+    StandardMaterial {
+        base_color: LinearRgba::from_vec3(reflectance).into(),
+        metallic: 0.0,
+        perceptual_roughness: 1.0,
+        ..default()
+    }
+}
+
+fn material(kind: &str, statement: &Statement) -> StandardMaterial {
+    // PBRT remaps its roughness to the GGX alpha by default, bevy squares the perceptual roughness into it
+    let roughness = {
+        let roughness = statement.float(
+            "roughness",
+            (statement.float("uroughness", 0.0) + statement.float("vroughness", 0.0)) * 0.5,
+        );
+        let alpha = if statement.bool("remaproughness", true) {
+            roughness.sqrt()
+        } else {
+            roughness
+        };
+        alpha.sqrt()
+    };
+
+    match kind {
+        "diffuse" => diffuse_material(statement.rgb("reflectance", Vec3::splat(0.5))),
+        "coateddiffuse" => StandardMaterial {
+            perceptual_roughness: roughness,
+            ..diffuse_material(statement.rgb("reflectance", Vec3::splat(0.5)))
+        },
+        "conductor" | "coatedconductor" => StandardMaterial {
+            // Copper, like the PBRT default
+            base_color: LinearRgba::from_vec3(
+                statement.rgb("reflectance", Vec3::new(0.95, 0.64, 0.54)),
+            )
+            .into(),
+            metallic: 1.0,
+            perceptual_roughness: roughness,
+            ..default()
+        },
+        "dielectric" | "thindielectric" => StandardMaterial {
+            base_color: Color::WHITE,
+            metallic: 0.0,
+            perceptual_roughness: roughness,
+            ior: statement.float("eta", 1.5),
+            specular_transmission: 1.0,
+            // Transmissive materials without thickness are thin walled
+            thickness: if kind == "thindielectric" { 0.0 } else { 1.0 },
+            ..default()
+        },
+        _ => {
+            warn!("PBRT: the {kind} material isn't supported, using a diffuse one");
+            diffuse_material(statement.rgb("reflectance", Vec3::splat(0.5)))
+        }
+    }
+}
+
+// Photometric intensity of a PBRT light with the given radiant intensity, brought into bevy's lumens
+// with the same exposure the traced lights use
+fn lumens(intensity: Vec3) -> (Color, f32) {
+    let peak = intensity.max_element().max(f32::EPSILON);
+    let lumens = peak * 4.0 * PI / Exposure::default().exposure();
+    (LinearRgba::from_vec3(intensity / peak).into(), lumens)
+}
+
+fn light(statement: &Statement, transform: Mat4, mirror: Vec3) -> Option<PbrtLight> {
+    let intensity = statement.rgb("I", Vec3::ONE) * statement.float("scale", 1.0);
+    let (color, intensity) = lumens(intensity);
+    let from = transform.transform_point3(statement.vec3("from").unwrap_or(Vec3::ZERO)) * mirror;
+
+    match statement.string(0) {
+        Some("point") => Some(PbrtLight::Point {
+            transform: Transform::from_translation(from),
+            color,
+            intensity,
+            radius: 0.0,
+            disk: false,
+        }),
+        Some("spot") => {
+            let to = transform.transform_point3(statement.vec3("to").unwrap_or(Vec3::Z)) * mirror;
+            let outer_angle = statement.float("coneangle", 30.0).to_radians();
+            let inner_angle = outer_angle - statement.float("conedelta", 5.0).to_radians();
+            Some(PbrtLight::Spot {
+                transform: Transform::from_translation(from)
+                    .looking_at(to, any_orthogonal(to - from)),
+                color,
+                intensity,
+                outer_angle,
+                inner_angle: inner_angle.max(0.0),
+            })
+        }
+        kind => {
+            warn!(
+                "PBRT: {} lights aren't supported",
+                kind.unwrap_or("unnamed")
+            );
+            None
+        }
+    }
+}
+
+fn any_orthogonal(direction: Vec3) -> Vec3 {
+    if direction.normalize_or_zero().y.abs() > 0.99 {
+        Vec3::Z
+    } else {
+        Vec3::Y
+    }
+}
+
+fn shape(scene: &mut PbrtScene, statement: &Statement, state: &GraphicsState, mirror: Vec3) {
+    let material = match state.area_light {
+        // Emitters are drawn in their color, the light itself comes from the sphere or disk light
+        Some(radiance) => {
+            scene.materials.push(StandardMaterial {
+                base_color: Color::BLACK,
+                emissive: LinearRgba::from_vec3(radiance),
+                ..default()
+            });
+            scene.materials.len() - 1
+        }
+        None => state.material,
+    };
+
+    match statement.string(0) {
+        Some("sphere") => {
+            let center = state.transform.transform_point3(Vec3::ZERO) * mirror;
+            let radius = statement.float("radius", 1.0)
+                * state.transform.transform_vector3(Vec3::X).length();
+
+            // A sphere light is the same as a bevy light with a radius, the tracer doesn't need the emitter itself
+            match state.area_light {
+                Some(radiance) => {
+                    let (color, intensity) = lumens(radiance * PI * radius * radius);
+                    scene.lights.push(PbrtLight::Point {
+                        transform: Transform::from_translation(center),
+                        color,
+                        intensity,
+                        radius,
+                        disk: false,
+                    });
+                }
+                None => scene.spheres.push(PbrtSphere {
+                    center,
+                    radius,
+                    material,
+                }),
+            }
+        }
+        Some(kind @ ("trianglemesh" | "bilinearmesh")) => {
+            let Some(points) = statement.parameter_numbers("P") else {
+                warn!("PBRT: {kind} without P");
+                return;
+            };
+            let positions = points
+                .chunks_exact(3)
+                .map(|p| state.transform.transform_point3(Vec3::from_slice(p)))
+                .collect::<Vec<_>>();
+
+            let corners = if kind == "trianglemesh" { 3 } else { 4 };
+            let indices = statement.parameter_numbers("indices").map_or_else(
+                || (0..positions.len() as u32).collect::<Vec<_>>(),
+                |indices| indices.iter().map(|&index| index as u32).collect(),
+            );
+            let mut triangles = Vec::new();
+            for corner in indices.chunks_exact(corners) {
+                if corners == 3 {
+                    triangles.push([corner[0], corner[1], corner[2]]);
+                } else {
+                    // The corners of bilinear patches are p00, p10, p01, p11
+                    triangles.push([corner[0], corner[1], corner[3]]);
+                    triangles.push([corner[0], corner[3], corner[2]]);
+                }
+            }
+            if triangles
+                .iter()
+                .flatten()
+                .any(|&index| index as usize >= positions.len())
+            {
+                warn!("PBRT: {kind} with indices out of range");
+                return;
+            }
+
+            if let Some(radiance) = state.area_light {
+                scene
+                    .lights
+                    .push(area_light(&positions, &triangles, radiance, mirror));
+            }
+
+            // Mirroring flips the winding, so the triangles get reversed to keep facing the same way
+            if mirror != Vec3::ONE {
+                triangles
+                    .iter_mut()
+                    .for_each(|triangle| triangle.swap(1, 2));
+            }
+            let mut mesh = Mesh::new(
+                PrimitiveTopology::TriangleList,
+                RenderAssetUsages::default(),
+            );
+            mesh.insert_attribute(
+                Mesh::ATTRIBUTE_POSITION,
+                positions
+                    .iter()
+                    .map(|&position| (position * mirror).to_array())
+                    .collect::<Vec<_>>(),
+            );
+            mesh.insert_indices(Indices::U32(triangles.into_iter().flatten().collect()));
+            mesh.duplicate_vertices();
+            mesh.compute_flat_normals();
+
+            scene.meshes.push(PbrtMesh { mesh, material });
+        }
+        kind => warn!(
+            "PBRT: {} shapes aren't supported",
+            kind.unwrap_or("unnamed")
+        ),
+    }
+}
+
+// A disk light with the area, orientation and power of a (roughly planar) emitting mesh
+fn area_light(
+    positions: &[Vec3],
+    triangles: &[[u32; 3]],
+    radiance: Vec3,
+    mirror: Vec3,
+) -> PbrtLight {
+    let mut vector_area = Vec3::ZERO;
+    let mut weighted_center = Vec3::ZERO;
+    let mut area_sum = 0.0;
+    for &[a, b, c] in triangles {
+        let (a, b, c) = (
+            positions[a as usize],
+            positions[b as usize],
+            positions[c as usize],
+        );
+        let triangle = (b - a).cross(c - a) * 0.5;
+        vector_area += triangle;
+        weighted_center += (a + b + c) / 3.0 * triangle.length();
+        area_sum += triangle.length();
+    }
+
+    // Emitters face along the normal of their winding
+    let area = vector_area.length().max(f32::EPSILON);
+    let normal = (vector_area / area) * mirror;
+    let center = weighted_center / area_sum.max(f32::EPSILON) * mirror;
+
+    // A Lambertian disk sends out π·L·A, a bevy light with the same output has a quarter of L·A as its intensity
+    let (color, intensity) = lumens(radiance * area / 4.0);
+    PbrtLight::Point {
+        transform: Transform::from_translation(center).looking_to(normal, any_orthogonal(normal)),
+        color,
+        intensity,
+        radius: (area / PI).sqrt(),
+        disk: true,
+    }
+}
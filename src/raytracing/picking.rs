@@ -0,0 +1,386 @@
+use std::sync::{Arc, Mutex};
+
+use bevy::{
+    prelude::*,
+    render::{
+        camera::RenderTarget,
+        graph::CameraDriverLabel,
+        render_graph::{Node, NodeRunError, RenderGraph, RenderGraphContext, RenderLabel},
+        render_resource::{
+            binding_types::{storage_buffer, uniform_buffer},
+            BindGroup, BindGroupEntries, BindGroupLayout, BindGroupLayoutEntries, Buffer,
+            BufferDescriptor, BufferUsages, CachedComputePipelineId, ComputePassDescriptor,
+            ComputePipelineDescriptor, PipelineCache, ShaderSize, ShaderStages, ShaderType,
+            UniformBuffer,
+        },
+        renderer::{RenderContext, RenderDevice, RenderQueue},
+        Extract, ExtractSchedule, Render, RenderApp, RenderSet,
+    },
+    window::{PrimaryWindow, WindowRef},
+};
+
+use super::{
+    extract::MaterialOwners,
+    origin::RenderOrigin,
+    pipeline::{geometry_bind_group, RaytracingPipeline},
+    readback::Readback,
+    RaytraceRayMasks, RaytracedCamera,
+};
+
+// Has to match cursor_pick.wgsl
+const NO_HIT: u32 = u32::MAX;
+
+#[derive(Debug, Hash, PartialEq, Eq, Clone, RenderLabel)]
+pub struct CursorPickLabel;
+
+// Traces the ray under the cursor on the GPU and reports what it hits in `HoveredRaytracedEntity`.
+// Unlike picking against the rasterized meshes this sees the traced scene, so it goes through portals
+// and only finds spheres and heightfields the way they are drawn.
+// The result arrives a few frames late, since it has to be read back from the GPU
+pub struct RaytracePickingPlugin;
+
+impl Plugin for RaytracePickingPlugin {
+    fn build(&self, app: &mut App) {
+        // Both worlds share the result, the render world fills it in and the main world copies it into the resource
+        let result = PickResult::default();
+        app.insert_resource(result.clone())
+            .init_resource::<HoveredRaytracedEntity>()
+            .add_systems(First, update_hovered_entity);
+
+        let Some(render_app) = app.get_sub_app_mut(RenderApp) else {
+            return;
+        };
+
+        render_app
+            .insert_resource(result)
+            .init_resource::<CursorRay>()
+            .add_systems(ExtractSchedule, extract_cursor_ray)
+            .add_systems(
+                Render,
+                (
+                    prepare_cursor_pick.in_set(RenderSet::PrepareBindGroups),
+                    read_back_cursor_pick.in_set(RenderSet::Cleanup),
+                ),
+            );
+
+        // Traced before the cameras, against the same buffers they see this frame
+        let mut render_graph = render_app.world_mut().resource_mut::<RenderGraph>();
+        render_graph.add_node(CursorPickLabel, CursorPickNode);
+        render_graph.add_node_edge(CursorPickLabel, CameraDriverLabel);
+    }
+
+    fn finish(&self, app: &mut App) {
+        let Some(render_app) = app.get_sub_app_mut(RenderApp) else {
+            return;
+        };
+
+        // This reuses the geometry layout of the main pipeline, so it has to be initialized after it
+        render_app
+            .init_resource::<CursorPickPipeline>()
+            .init_resource::<CursorPickBuffers>();
+    }
+}
+
+// The sphere or heightfield the cursor is over in the traced image of the primary window, with how far along the ray it is.
+// Rays through portals count the distance from the camera, not from the portal
+#[derive(Resource, Clone, Copy, Debug, PartialEq)]
+pub struct HoveredRaytracedEntity {
+    pub entity: Option<Entity>,
+    pub distance: f32,
+}
+
+impl Default for HoveredRaytracedEntity {
+    fn default() -> Self {
+        Self {
+            entity: None,
+            distance: f32::INFINITY,
+        }
+    }
+}
+
+#[derive(Resource, Clone, Default)]
+struct PickResult(Arc<Mutex<HoveredRaytracedEntity>>);
+
+impl PickResult {
+    fn get(&self) -> HoveredRaytracedEntity {
+        *self
+            .0
+            .lock()
+            .expect("Could not get pick result out of mutex")
+    }
+
+    fn set(&self, hovered: HoveredRaytracedEntity) {
+        *self
+            .0
+            .lock()
+            .expect("Could not get pick result out of mutex") = hovered;
+    }
+}
+
+fn update_hovered_entity(result: Res<PickResult>, mut hovered: ResMut<HoveredRaytracedEntity>) {
+    hovered.set_if_neq(result.get());
+}
+
+// None while the cursor isn't over a raytraced camera in the primary window, with the camera ray mask of that camera
+#[derive(Resource, Default)]
+struct CursorRay(Option<(Ray3d, u8)>);
+
+#[derive(Default, ShaderType)]
+struct CursorRayUniform {
+    origin: Vec3,
+    direction: Vec3,
+    mask: u32,
+}
+
+// Has to match the result in cursor_pick.wgsl
+#[derive(ShaderType)]
+struct PickResultUniform {
+    material: u32,
+    distance: f32,
+}
+
+fn extract_cursor_ray(
+    mut cursor_ray: ResMut<CursorRay>,
+    windows: Extract<Query<&Window, With<PrimaryWindow>>>,
+    cameras: Extract<
+        Query<(&Camera, (&GlobalTransform, Option<&RaytraceRayMasks>)), With<RaytracedCamera>>,
+    >,
+) {
+    cursor_ray.0 = windows
+        .get_single()
+        .ok()
+        .and_then(Window::cursor_position)
+        .and_then(|cursor| {
+            let (camera, (transform, ray_masks)) = topmost_window_camera(cameras.iter())?;
+            let mask = ray_masks.copied().unwrap_or_default().camera;
+            Some((camera.viewport_to_world(transform, cursor)?, mask))
+        });
+}
+
+// The active camera drawing into the primary window, with several the one drawn on top is the one the cursor is over
+pub(super) fn topmost_window_camera<'a, T>(
+    cameras: impl Iterator<Item = (&'a Camera, T)>,
+) -> Option<(&'a Camera, T)> {
+    cameras
+        .filter(|(camera, _)| {
+            camera.is_active && matches!(camera.target, RenderTarget::Window(WindowRef::Primary))
+        })
+        .max_by_key(|(camera, _)| camera.order)
+}
+
+#[derive(Resource)]
+struct CursorPickBuffers {
+    cursor: UniformBuffer<CursorRayUniform>,
+    result: Buffer,
+    readback: Readback,
+    bind_group: Option<BindGroup>,
+    // Set while the ray gets traced this frame
+    traced: bool,
+    // The entities behind the materials at the time the ray was traced, the result points into these
+    owners: Vec<Option<Entity>>,
+}
+
+impl FromWorld for CursorPickBuffers {
+    fn from_world(world: &mut World) -> Self {
+        let render_device = world.resource::<RenderDevice>();
+        let size = u64::from(PickResultUniform::SHADER_SIZE);
+
+        Self {
+            cursor: UniformBuffer::default(),
+            result: render_device.create_buffer(&BufferDescriptor {
+                label: Some("cursor_pick_result"),
+                size,
+                usage: BufferUsages::STORAGE | BufferUsages::COPY_SRC,
+                mapped_at_creation: false,
+            }),
+            readback: Readback::new(render_device, "cursor_pick_readback", size),
+            bind_group: None,
+            traced: false,
+            owners: Vec::new(),
+        }
+    }
+}
+
+fn prepare_cursor_pick(
+    cursor_ray: Res<CursorRay>,
+    mut buffers: ResMut<CursorPickBuffers>,
+    result: Res<PickResult>,
+    pick_pipeline: Res<CursorPickPipeline>,
+    pipeline_cache: Res<PipelineCache>,
+    owners: Res<MaterialOwners>,
+    origin: Res<RenderOrigin>,
+    render_device: Res<RenderDevice>,
+    render_queue: Res<RenderQueue>,
+) {
+    // Only one ray is in flight at a time, newer cursor positions wait until the last one is read back
+    if buffers.readback.is_pending() {
+        return;
+    }
+
+    let Some((ray, mask)) = cursor_ray.0 else {
+        result.set(HoveredRaytracedEntity::default());
+        return;
+    };
+    if pipeline_cache
+        .get_compute_pipeline(pick_pipeline.pipeline_id)
+        .is_none()
+    {
+        return;
+    }
+
+    let buffers = &mut *buffers;
+    buffers.cursor.set(CursorRayUniform {
+        origin: origin.point(ray.origin),
+        direction: *ray.direction,
+        mask: mask.into(),
+    });
+    buffers.cursor.write_buffer(&render_device, &render_queue);
+    let Some(cursor_binding) = buffers.cursor.binding() else {
+        return;
+    };
+
+    buffers.bind_group = Some(render_device.create_bind_group(
+        "cursor_pick_bind_group",
+        &pick_pipeline.layout,
+        &BindGroupEntries::sequential((cursor_binding, buffers.result.as_entire_binding())),
+    ));
+    // Cleared first, so a ray that can't be traced this frame reads back as a miss instead of the last hit
+    let miss = [NO_HIT.to_le_bytes(), f32::INFINITY.to_le_bytes()].concat();
+    render_queue.write_buffer(&buffers.result, 0, &miss);
+    buffers.owners.clone_from(&owners);
+    buffers.traced = true;
+}
+
+fn read_back_cursor_pick(
+    mut buffers: ResMut<CursorPickBuffers>,
+    result: Res<PickResult>,
+    render_device: Res<RenderDevice>,
+    render_queue: Res<RenderQueue>,
+) {
+    let buffers = &mut *buffers;
+    if std::mem::take(&mut buffers.traced) {
+        buffers
+            .readback
+            .start(&render_device, &render_queue, &buffers.result);
+        return;
+    }
+
+    let Some(outcome) = buffers.readback.poll(&render_device) else {
+        return;
+    };
+    let data = match outcome {
+        Ok(data) => data,
+        Err(error) => {
+            warn!("Could not read back the entity under the cursor: {error}");
+            return;
+        }
+    };
+
+    let material = u32::from_le_bytes(data[0..4].try_into().unwrap());
+    let distance = f32::from_le_bytes(data[4..8].try_into().unwrap());
+    let entity = if material == NO_HIT {
+        None
+    } else {
+        buffers.owners.get(material as usize).copied().flatten()
+    };
+    result.set(HoveredRaytracedEntity { entity, distance });
+}
+
+// Traces the ray under the cursor, what it hit gets read back after the frame
+pub struct CursorPickNode;
+
+impl Node for CursorPickNode {
+    fn run(
+        &self,
+        _graph: &mut RenderGraphContext,
+        render_context: &mut RenderContext,
+        world: &World,
+    ) -> Result<(), NodeRunError> {
+        let Some(buffers) = world.get_resource::<CursorPickBuffers>() else {
+            return Ok(());
+        };
+        if !buffers.traced {
+            return Ok(());
+        }
+        let Some(bind_group) = &buffers.bind_group else {
+            return Ok(());
+        };
+
+        let pick_pipeline = world.resource::<CursorPickPipeline>();
+        let pipeline_cache = world.resource::<PipelineCache>();
+        let buffer_bind_group = geometry_bind_group(
+            world,
+            render_context.render_device(),
+            &pick_pipeline.buffer_layout,
+            "cursor_pick_geometry_bind_group",
+        );
+
+        // Without a scene to trace the cleared result is read back as it is
+        if let (Some(pipeline), Some(buffer_bind_group)) = (
+            pipeline_cache.get_compute_pipeline(pick_pipeline.pipeline_id),
+            buffer_bind_group,
+        ) {
+            let mut compute_pass =
+                render_context
+                    .command_encoder()
+                    .begin_compute_pass(&ComputePassDescriptor {
+                        label: Some("cursor_pick_pass"),
+                        timestamp_writes: None,
+                    });
+
+            compute_pass.set_pipeline(pipeline);
+            compute_pass.set_bind_group(0, bind_group, &[]);
+            compute_pass.set_bind_group(1, &buffer_bind_group, &[]);
+            compute_pass.dispatch_workgroups(1, 1, 1);
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Resource)]
+pub struct CursorPickPipeline {
+    layout: BindGroupLayout,
+    buffer_layout: BindGroupLayout,
+    pipeline_id: CachedComputePipelineId,
+}
+
+impl FromWorld for CursorPickPipeline {
+    fn from_world(world: &mut World) -> Self {
+        let render_device = world.resource::<RenderDevice>();
+
+        let layout = render_device.create_bind_group_layout(
+            "cursor_pick_bind_group_layout",
+            &BindGroupLayoutEntries::sequential(
+                ShaderStages::COMPUTE,
+                (
+                    // The ray under the cursor
+                    uniform_buffer::<CursorRayUniform>(false),
+                    // What it hit
+                    storage_buffer::<PickResultUniform>(false),
+                ),
+            ),
+        );
+
+        let buffer_layout = world.resource::<RaytracingPipeline>().buffer_layout.clone();
+
+        let shader = world.load_asset("shaders/cursor_pick.wgsl");
+
+        let pipeline_id = world
+            .resource_mut::<PipelineCache>()
+            .queue_compute_pipeline(ComputePipelineDescriptor {
+                label: Some("cursor_pick_pipeline".into()),
+                layout: vec![layout.clone(), buffer_layout.clone()],
+                push_constant_ranges: vec![],
+                shader,
+                shader_defs: vec![],
+                entry_point: "pick".into(),
+            });
+
+        Self {
+            layout,
+            buffer_layout,
+            pipeline_id,
+        }
+    }
+}
@@ -1,35 +1,127 @@
+use std::sync::Mutex;
+
 use bevy::{
     core_pipeline::{
-        fullscreen_vertex_shader::fullscreen_shader_vertex_state, prepass::ViewPrepassTextures,
+        core_3d::CORE_3D_DEPTH_FORMAT, fullscreen_vertex_shader::fullscreen_shader_vertex_state,
+        prepass::ViewPrepassTextures,
     },
     ecs::query::QueryItem,
     prelude::*,
     render::{
         extract_component::{ComponentUniforms, DynamicUniformIndex},
+        render_asset::RenderAssets,
         render_graph::{NodeRunError, RenderGraphContext, ViewNode},
+        render_phase::TrackedRenderPass,
         render_resource::{
-            binding_types::{sampler, texture_2d, uniform_buffer},
-            BindGroupEntries, BindGroupLayout, BindGroupLayoutEntries, BindingType,
-            BufferBindingType, CachedRenderPipelineId, ColorTargetState, ColorWrites,
-            FragmentState, MultisampleState, Operations, PipelineCache, PrimitiveState,
-            RenderPassColorAttachment, RenderPassDescriptor, RenderPipelineDescriptor, Sampler,
-            SamplerBindingType, SamplerDescriptor, ShaderStages, TextureFormat, TextureSampleType,
+            binding_types::{
+                sampler, storage_buffer, storage_buffer_read_only_sized, texture_2d, texture_3d,
+                texture_cube, texture_storage_2d, uniform_buffer,
+            },
+            BindGroup, BindGroupEntries, BindGroupLayout, BindGroupLayoutEntries, BindingType,
+            BufferBindingType, CachedPipelineState, CachedRenderPipelineId, ColorTargetState,
+            ColorWrites, CommandEncoderDescriptor, CompareFunction, DepthStencilState, Extent3d,
+            FilterMode, FragmentState, LoadOp, MultisampleState, Operations, PipelineCache,
+            PrimitiveState, RenderPassColorAttachment, RenderPassDescriptor,
+            RenderPipelineDescriptor, Sampler, SamplerBindingType, SamplerDescriptor, ShaderDefVal,
+            ShaderStages, SpecializedRenderPipeline, SpecializedRenderPipelines,
+            StorageTextureAccess, StoreOp, TextureDescriptor, TextureDimension, TextureFormat,
+            TextureSampleType, TextureUsages, TextureView, TextureViewDescriptor,
+            TextureViewDimension,
+        },
+        renderer::{RenderContext, RenderDevice},
+        texture::{BevyDefault, GpuImage},
+        view::{
+            ExtractedView, ViewDepthTexture, ViewTarget, ViewUniform, ViewUniformOffset,
+            ViewUniforms,
         },
-        renderer::{RenderContext, RenderDevice, RenderQueue},
-        texture::BevyDefault,
-        view::ViewTarget,
     },
+    utils::futures::now_or_never,
 };
+use wgpu::ErrorFilter;
 
+use super::accumulation::{AccumulationTextures, ACCUMULATION_FORMAT, MOMENTS_FORMAT};
+use super::caustics::{CausticsBuffers, CausticsUniform};
+use super::decal::{DecalBuffer, DecalTexelBuffer};
+use super::environment::EnvironmentCdfBuffers;
 use super::extract::{
-    BVHBuffer, CameraExtract, MaterialBuffer, ModelBuffer, RaytraceLevelExtract, WindowExtract,
+    BVHBuffer, BvhPrimitiveBuffer, CameraExtract, EnvironmentExtract, FogBuffer, HeightBuffer,
+    HeightfieldBuffer, MaterialBuffer, ModelBuffer, PipelineExtract, RaytraceLevelExtract,
+    WindowExtract,
 };
-// The post process node used for the render graph
+use super::light::{LightBuffer, LightDataBuffer};
+use super::nan_debug::NanDebugBuffers;
+use super::path_debug::{PathDebugBuffers, PathDebugData};
+use super::portal::PortalBuffer;
+use super::sky::{SkyBuffer, SkyUniform};
+use super::volume::{DensityVolumeUniform, IrradianceVolumeUniform, VolumeBuffers};
+use super::{RaytraceCapabilities, RaytraceTiling};
+// The post process node used for the render graph.
+// There is one for each position in the graph, `MAIN_PASS` is the one before transmissive and transparent meshes
 #[derive(Default)]
-pub struct RayTracingNode;
+pub struct RayTracingNode<const MAIN_PASS: bool> {
+    // The validation error of the last failed frame, a failing view only logs it when it changes
+    last_error: Mutex<Option<String>>,
+}
+
+impl<const MAIN_PASS: bool> RayTracingNode<MAIN_PASS> {
+    fn report_error(&self, error: String) {
+        let mut last_error = self
+            .last_error
+            .lock()
+            .expect("Could not get last raytrace error out of mutex");
+        if last_error.as_ref() != Some(&error) {
+            error!(
+                "Tracing a view failed, it shows magenta until the next frame that works: {error}"
+            );
+            *last_error = Some(error);
+        }
+    }
+
+    fn report_error_fixed(&self) {
+        let mut last_error = self
+            .last_error
+            .lock()
+            .expect("Could not get last raytrace error out of mutex");
+        if last_error.take().is_some() {
+            info!("Tracing works again");
+        }
+    }
+}
+
+// Fills the view with magenta while its pipeline is broken, the traced image would otherwise be a copy of the rasterized one
+fn draw_error_fallback(
+    render_context: &mut RenderContext,
+    raytrace_pipeline: &RaytracingPipeline,
+    pipeline_cache: &PipelineCache,
+    destination: &TextureView,
+    hdr: bool,
+) {
+    let pipeline_id = if hdr {
+        raytrace_pipeline.hdr_error_pipeline_id
+    } else {
+        raytrace_pipeline.error_pipeline_id
+    };
+    let Some(pipeline) = pipeline_cache.get_render_pipeline(pipeline_id) else {
+        return;
+    };
+
+    let mut render_pass = render_context.begin_tracked_render_pass(RenderPassDescriptor {
+        label: Some("raytrace_error_pass"),
+        color_attachments: &[Some(RenderPassColorAttachment {
+            view: destination,
+            resolve_target: None,
+            ops: Operations::default(),
+        })],
+        depth_stencil_attachment: None,
+        timestamp_writes: None,
+        occlusion_query_set: None,
+    });
+    render_pass.set_render_pipeline(pipeline);
+    render_pass.draw(0..3, 0..1);
+}
 
 // The ViewNode trait is required by the ViewNodeRunner
-impl ViewNode for RayTracingNode {
+impl<const MAIN_PASS: bool> ViewNode for RayTracingNode<MAIN_PASS> {
     // The node needs a query to gather data from the ECS in order to do its rendering,
     // but it's not a normal system so we need to define it manually.
     //
@@ -46,6 +138,19 @@ impl ViewNode for RayTracingNode {
         // The camera data
         &'static CameraExtract,
         &'static DynamicUniformIndex<CameraExtract>,
+        // The size and random seed of the view
+        &'static DynamicUniformIndex<WindowExtract>,
+        // The cubemap rays that miss the scene sample
+        &'static EnvironmentExtract,
+        // Only progressive cameras have these
+        Option<&'static AccumulationTextures>,
+        // The variants of the pipeline for the target and output of the view
+        &'static ViewRaytracePipelines,
+        // Bevy's view uniform, for the color grading
+        &'static ViewUniformOffset,
+        // The linear output writes the depth of what it drew
+        &'static ViewDepthTexture,
+        Option<&'static RaytraceTiling>,
     );
 
     // Runs the node logic
@@ -57,18 +162,30 @@ impl ViewNode for RayTracingNode {
     // to identify which camera(s) should run the effect.
     fn run(
         &self,
-        _graph: &mut RenderGraphContext,
+        graph: &mut RenderGraphContext,
         render_context: &mut RenderContext,
         (
             view_target,
             prepass_textures,
             _raytrace_level,
             settings_index,
-            _camera,
+            camera,
             camera_index,
+            window_index,
+            environment,
+            accumulation,
+            pipelines,
+            view_offset,
+            view_depth,
+            tiling,
         ): QueryItem<Self::ViewQuery>,
         world: &World,
     ) -> Result<(), NodeRunError> {
+        // The other node draws this view
+        if pipelines.main_pass != MAIN_PASS {
+            return Ok(());
+        }
+
         // Get the pipeline resource that contains the global data we need
         // to create the render pipeline
         let raytrace_pipeline = world.resource::<RaytracingPipeline>();
@@ -78,9 +195,27 @@ impl ViewNode for RayTracingNode {
         // which is expensive due to shader compilation.
         let pipeline_cache = world.resource::<PipelineCache>();
 
-        // Get the pipeline from the cache
-        let Some(pipeline) = pipeline_cache.get_render_pipeline(raytrace_pipeline.pipeline_id)
+        // Get the pipeline from the cache, the one checking for NaNs stands in once it is compiled
+        let nan_debug = world.resource::<NanDebugBuffers>();
+        let nan_debug_pipeline = pipelines
+            .nan_debug
+            .and_then(|id| pipeline_cache.get_render_pipeline(id));
+        let Some(pipeline) =
+            nan_debug_pipeline.or_else(|| pipeline_cache.get_render_pipeline(pipelines.pipeline))
         else {
+            // A compiling pipeline leaves the view as it is for a few frames, a broken one is shown until it compiles again
+            if let CachedPipelineState::Err(_) =
+                pipeline_cache.get_render_pipeline_state(pipelines.pipeline)
+            {
+                let post_process = view_target.post_process_write();
+                draw_error_fallback(
+                    render_context,
+                    raytrace_pipeline,
+                    pipeline_cache,
+                    post_process.destination,
+                    view_target.is_hdr(),
+                );
+            }
             return Ok(());
         };
 
@@ -101,6 +236,19 @@ impl ViewNode for RayTracingNode {
             return Ok(());
         };
 
+        let Some(view_binding) = world.resource::<ViewUniforms>().uniforms.binding() else {
+            return Ok(());
+        };
+
+        let Some(caustics_bind_group) = &world.resource::<CausticsBuffers>().gather_bind_group
+        else {
+            return Ok(());
+        };
+
+        let Some(volume_bind_group) = &world.resource::<VolumeBuffers>().bind_group else {
+            return Ok(());
+        };
+
         // This will start a new "post process write", obtaining two texture
         // views from the view target - a `source` and a `destination`.
         // `source` is the "current" main texture and you _must_ write into
@@ -114,41 +262,62 @@ impl ViewNode for RayTracingNode {
             return Ok(());
         };
 
-        let model = world.resource::<ModelBuffer>();
-        let mut model_buffer = model
-            .lock()
-            .expect("Could not get geometry buffer out of mutex");
-
-        let material = world.resource::<MaterialBuffer>();
-        let mut material_buffer = material
-            .lock()
-            .expect("Could not get material buffer out of mutex");
-
-        let bvh = world.resource::<BVHBuffer>();
-        let mut bvh_buffer = bvh
-            .lock()
-            .expect("Could not get material buffer out of mutex");
+        // Until the cubemap is loaded the environment stays black
+        let images = world.resource::<RenderAssets<GpuImage>>();
+        let environment_view = environment
+            .image
+            .and_then(|image| images.get(image))
+            .map_or(&raytrace_pipeline.fallback_environment, |image| {
+                &image.texture_view
+            });
+        let Some(environment_cdf) = world
+            .resource::<EnvironmentCdfBuffers>()
+            .get(environment.image)
+            .binding()
+        else {
+            return Ok(());
+        };
 
-        let render_device = render_context.render_device();
-        {
-            let render_queue = world.resource::<RenderQueue>();
+        let (history_view, accumulation_view, moments_history_view, moments_view) = accumulation
+            .map_or(
+                (
+                    &raytrace_pipeline.fallback_history,
+                    &raytrace_pipeline.fallback_accumulation,
+                    &raytrace_pipeline.fallback_moments_history,
+                    &raytrace_pipeline.fallback_moments,
+                ),
+                |textures| {
+                    (
+                        &textures.history,
+                        &textures.output,
+                        &textures.moments_history,
+                        &textures.moments_output,
+                    )
+                },
+            );
 
-            model_buffer.write_buffer(render_device, render_queue);
-            material_buffer.write_buffer(render_device, render_queue);
-            bvh_buffer.write_buffer(render_device, render_queue);
-        }
+        let path_debug = world
+            .resource::<PathDebugBuffers>()
+            .binding(graph.view_entity());
 
-        let Some(model_buffer_binding) = model_buffer.binding() else {
-            return Ok(());
-        };
+        // Cloned, the tiles go into command buffers of their own while this is still in use
+        let render_device = render_context.render_device().clone();
+        let render_device = &render_device;
 
-        let Some(material_buffer_binding) = material_buffer.binding() else {
+        let Some(buffer_bind_group) = geometry_bind_group(
+            world,
+            render_device,
+            &raytrace_pipeline.buffer_layout,
+            "raytrace_geometry_bind_group",
+        ) else {
             return Ok(());
         };
 
-        let Some(bvh_buffer_binding) = bvh_buffer.binding() else {
-            return Ok(());
-        };
+        // Validation errors from here on are caught instead of taking down the render app,
+        // the view shows the magenta fallback instead. Nothing may return before the scope is popped
+        render_device
+            .wgpu_device()
+            .push_error_scope(ErrorFilter::Validation);
 
         // The bind_group gets created each frame.
         //
@@ -174,25 +343,127 @@ impl ViewNode for RayTracingNode {
                 camera_binding.clone(),
                 // Window data
                 window_binding.clone(),
+                environment_view,
+                &raytrace_pipeline.environment_sampler,
+                environment_cdf,
+                history_view,
+                accumulation_view,
+                path_debug,
+                nan_debug.binding(),
+                view_binding,
+                moments_history_view,
+                moments_view,
             )),
         );
 
-        let buffer_bind_group = render_device.create_bind_group(
-            "raytrace_geometry_bind_group",
-            &raytrace_pipeline.buffer_layout,
-            &BindGroupEntries::sequential((
-                model_buffer_binding,
-                material_buffer_binding,
-                bvh_buffer_binding,
-            )),
+        // Without `RaytraceTiling` the whole target is a single tile
+        let target_size = view_target.main_texture().size();
+        let target_size = UVec2::new(target_size.width, target_size.height);
+        let tiles = tiling.map_or_else(
+            || vec![URect::from_corners(UVec2::ZERO, target_size)],
+            |tiling| tiling.tiles(target_size).collect(),
+        );
+
+        let mut command_buffers = Vec::with_capacity(tiles.len());
+        for (index, tile) in tiles.into_iter().enumerate() {
+            let mut encoder = render_device.create_command_encoder(&CommandEncoderDescriptor {
+                label: Some("raytrace_tile"),
+            });
+
+            // Begin the render pass
+            let mut render_pass = TrackedRenderPass::new(
+                render_device,
+                encoder.begin_render_pass(&RenderPassDescriptor {
+                    label: Some("raytrace_pass"),
+                    color_attachments: &[Some(RenderPassColorAttachment {
+                        // We need to specify the post process destination view here
+                        // to make sure we write to the appropriate texture.
+                        view: post_process.destination,
+                        resolve_target: None,
+                        // Later tiles keep what the ones before them drew
+                        ops: if index == 0 {
+                            Operations::default()
+                        } else {
+                            Operations {
+                                load: LoadOp::Load,
+                                store: StoreOp::Store,
+                            }
+                        },
+                    })],
+                    depth_stencil_attachment: Some(view_depth.get_attachment(StoreOp::Store)),
+                    timestamp_writes: None,
+                    occlusion_query_set: None,
+                }),
+            );
+
+            // This is mostly just wgpu boilerplate for drawing a fullscreen triangle,
+            // using the pipeline/bind_group created above
+            render_pass.set_render_pipeline(pipeline);
+            // By passing in the index of the settings on this view, we ensure
+            // that in the event that multiple settings were sent to the GPU (as would be the
+            // case with multiple cameras), we use the correct one.
+            render_pass.set_bind_group(
+                0,
+                &bind_group,
+                &[
+                    settings_index.index(),
+                    camera_index.index(),
+                    window_index.index(),
+                    view_offset.offset,
+                ],
+            );
+            render_pass.set_bind_group(1, &buffer_bind_group, &[]);
+            render_pass.set_bind_group(2, caustics_bind_group, &[]);
+            render_pass.set_bind_group(3, volume_bind_group, &[]);
+            let size = tile.size();
+            render_pass.set_scissor_rect(tile.min.x, tile.min.y, size.x, size.y);
+            render_pass.draw(0..3, 0..1);
+            drop(render_pass);
+
+            command_buffers.push(encoder.finish());
+        }
+
+        // Available right away on native, on the web errors still end up with wgpu
+        let error = now_or_never(render_device.wgpu_device().pop_error_scope()).flatten();
+        if let Some(error) = error {
+            self.report_error(error.to_string());
+            draw_error_fallback(
+                render_context,
+                raytrace_pipeline,
+                pipeline_cache,
+                post_process.destination,
+                view_target.is_hdr(),
+            );
+            return Ok(());
+        }
+        self.report_error_fixed();
+        for command_buffer in command_buffers {
+            render_context.add_command_buffer(command_buffer);
+        }
+
+        // Checkerboard cameras leave out the pixels they couldn't reproject, the resolve pass fills them in
+        if !camera.checkerboard() {
+            return Ok(());
+        }
+        let resolve_pipeline_id = if view_target.is_hdr() {
+            raytrace_pipeline.hdr_resolve_pipeline_id
+        } else {
+            raytrace_pipeline.resolve_pipeline_id
+        };
+        let Some(resolve_pipeline) = pipeline_cache.get_render_pipeline(resolve_pipeline_id) else {
+            return Ok(());
+        };
+
+        let post_process = view_target.post_process_write();
+        let resolve_bind_group = render_context.render_device().create_bind_group(
+            "checkerboard_resolve_bind_group",
+            &raytrace_pipeline.resolve_layout,
+            &BindGroupEntries::single(post_process.source),
         );
 
-        // Begin the render pass
         let mut render_pass = render_context.begin_tracked_render_pass(RenderPassDescriptor {
-            label: Some("raytrace_pass"),
+            label: Some("checkerboard_resolve_pass"),
             color_attachments: &[Some(RenderPassColorAttachment {
-                // We need to specify the post process destination view here
-                // to make sure we write to the appropriate texture.
                 view: post_process.destination,
                 resolve_target: None,
                 ops: Operations::default(),
@@ -201,33 +472,70 @@ impl ViewNode for RayTracingNode {
             timestamp_writes: None,
             occlusion_query_set: None,
         });
-
-        // This is mostly just wgpu boilerplate for drawing a fullscreen triangle,
-        // using the pipeline/bind_group created above
-        render_pass.set_render_pipeline(pipeline);
-        // By passing in the index of the settings on this view, we ensure
-        // that in the event that multiple settings were sent to the GPU (as would be the
-        // case with multiple cameras), we use the correct one.
-        render_pass.set_bind_group(
-            0,
-            &bind_group,
-            &[settings_index.index(), camera_index.index()],
-        );
-        render_pass.set_bind_group(1, &buffer_bind_group, &[]);
+        render_pass.set_render_pipeline(resolve_pipeline);
+        render_pass.set_bind_group(0, &resolve_bind_group, &[]);
         render_pass.draw(0..3, 0..1);
 
         Ok(())
     }
 }
 
+// Binds the geometry buffers, which are already uploaded in `RenderSet::PrepareResources`.
+// Every pass that traces the scene needs this
+pub(super) fn geometry_bind_group(
+    world: &World,
+    render_device: &RenderDevice,
+    layout: &BindGroupLayout,
+    label: &'static str,
+) -> Option<BindGroup> {
+    let height_buffers = world.resource::<HeightBuffer>();
+
+    Some(render_device.create_bind_group(
+        label,
+        layout,
+        &BindGroupEntries::sequential((
+            world.resource::<ModelBuffer>().binding()?,
+            world.resource::<MaterialBuffer>().binding()?,
+            world.resource::<BVHBuffer>().binding()?,
+            world.resource::<FogBuffer>().binding()?,
+            world.resource::<HeightfieldBuffer>().binding()?,
+            height_buffers[0].binding()?,
+            world.resource::<SkyBuffer>().binding()?,
+            world.resource::<LightBuffer>().binding()?,
+            world.resource::<LightDataBuffer>().binding()?,
+            height_buffers[1].binding()?,
+            height_buffers[2].binding()?,
+            height_buffers[3].binding()?,
+            world.resource::<PortalBuffer>().binding()?,
+            world.resource::<BvhPrimitiveBuffer>().binding()?,
+            world.resource::<DecalBuffer>().binding()?,
+            world.resource::<DecalTexelBuffer>().binding()?,
+        )),
+    ))
+}
+
 // This contains global data used by the render pipeline. This will be created once on startup.
 #[derive(Resource)]
 pub struct RaytracingPipeline {
     layout: BindGroupLayout,
-    buffer_layout: BindGroupLayout,
+    pub(super) buffer_layout: BindGroupLayout,
+    pub(super) caustics_layout: BindGroupLayout,
+    pub(super) volume_layout: BindGroupLayout,
     sampler: Sampler,
     depth_sampler: Sampler,
-    pipeline_id: CachedRenderPipelineId,
+    environment_sampler: Sampler,
+    fallback_environment: TextureView,
+    fallback_history: TextureView,
+    fallback_accumulation: TextureView,
+    fallback_moments_history: TextureView,
+    fallback_moments: TextureView,
+    shader: Handle<Shader>,
+    resolve_layout: BindGroupLayout,
+    resolve_pipeline_id: CachedRenderPipelineId,
+    hdr_resolve_pipeline_id: CachedRenderPipelineId,
+    // Magenta, for views whose pipeline failed
+    error_pipeline_id: CachedRenderPipelineId,
+    hdr_error_pipeline_id: CachedRenderPipelineId,
 }
 
 impl FromWorld for RaytracingPipeline {
@@ -253,8 +561,28 @@ impl FromWorld for RaytracingPipeline {
                     uniform_buffer::<RaytraceLevelExtract>(true),
                     // The camera uniform
                     uniform_buffer::<CameraExtract>(true),
-                    // The window uniform
-                    uniform_buffer::<WindowExtract>(false),
+                    // The size and random seed of the view
+                    uniform_buffer::<WindowExtract>(true),
+                    // The environment cubemap of the camera
+                    texture_cube(TextureSampleType::Float { filterable: true }),
+                    // The sampler for the environment cubemap
+                    sampler(SamplerBindingType::Filtering),
+                    // The cdf for importance sampling the environment cubemap
+                    storage_buffer_read_only_sized(false, None),
+                    // The accumulation of the previous frames
+                    texture_2d(TextureSampleType::Float { filterable: false }),
+                    // The accumulation including this frame
+                    texture_storage_2d(ACCUMULATION_FORMAT, StorageTextureAccess::WriteOnly),
+                    // The path the path debugger records
+                    storage_buffer::<PathDebugData>(false),
+                    // The count of paths with NaN radiance
+                    storage_buffer::<u32>(false),
+                    // Bevy's view uniform
+                    uniform_buffer::<ViewUniform>(true),
+                    // The average squared luminance of the previous frames
+                    texture_2d(TextureSampleType::Float { filterable: false }),
+                    // The average squared luminance including this frame
+                    texture_storage_2d(MOMENTS_FORMAT, StorageTextureAccess::WriteOnly),
                 ),
             ),
         );
@@ -262,8 +590,8 @@ impl FromWorld for RaytracingPipeline {
         let buffer_layout = render_device.create_bind_group_layout(
             "raytrace_geometry_bind_group_layout",
             &BindGroupLayoutEntries::sequential(
-                // The layout entries will only be visible in the fragment stage
-                ShaderStages::FRAGMENT,
+                // The geometry is also traced from compute shaders, like the probe grid update
+                ShaderStages::FRAGMENT | ShaderStages::COMPUTE,
                 (
                     // the geometry buffer
                     BindingType::Buffer {
@@ -283,49 +611,587 @@ impl FromWorld for RaytracingPipeline {
                         has_dynamic_offset: false,
                         min_binding_size: None,
                     },
+                    // The fog volume buffer
+                    BindingType::Buffer {
+                        ty: BufferBindingType::Storage { read_only: true },
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    // The heightfield buffer
+                    BindingType::Buffer {
+                        ty: BufferBindingType::Storage { read_only: true },
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    // The heights of the heightfields
+                    BindingType::Buffer {
+                        ty: BufferBindingType::Storage { read_only: true },
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    // The sky settings
+                    uniform_buffer::<SkyUniform>(false),
+                    // The light buffer
+                    BindingType::Buffer {
+                        ty: BufferBindingType::Storage { read_only: true },
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    // The IES profiles and cookies of the lights
+                    BindingType::Buffer {
+                        ty: BufferBindingType::Storage { read_only: true },
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    // The heights that didn't fit into the first height buffer
+                    storage_buffer_read_only_sized(false, None),
+                    storage_buffer_read_only_sized(false, None),
+                    storage_buffer_read_only_sized(false, None),
+                    // The portals
+                    storage_buffer_read_only_sized(false, None),
+                    // The models of the BVH leaves
+                    storage_buffer_read_only_sized(false, None),
+                    // The decals and their images
+                    storage_buffer_read_only_sized(false, None),
+                    storage_buffer_read_only_sized(false, None),
                 ),
             ),
         );
 
+        let caustics_layout = render_device.create_bind_group_layout(
+            "raytrace_caustics_bind_group_layout",
+            &BindGroupLayoutEntries::sequential(
+                ShaderStages::FRAGMENT,
+                (
+                    // The caustics settings
+                    uniform_buffer::<CausticsUniform>(false),
+                    // The amount of photons in every cell of the photon map
+                    storage_buffer_read_only_sized(false, None),
+                    // The photons
+                    storage_buffer_read_only_sized(false, None),
+                ),
+            ),
+        );
+
+        let volume_layout = render_device.create_bind_group_layout(
+            "raytrace_volume_bind_group_layout",
+            &BindGroupLayoutEntries::sequential(
+                ShaderStages::FRAGMENT,
+                (
+                    // The density volume settings
+                    uniform_buffer::<DensityVolumeUniform>(false),
+                    // The density texture
+                    texture_3d(TextureSampleType::Float { filterable: true }),
+                    // The sampler for the density texture
+                    sampler(SamplerBindingType::Filtering),
+                    // The irradiance volume settings
+                    uniform_buffer::<IrradianceVolumeUniform>(false),
+                    // The probes of the irradiance volume, in the layout of bevy's irradiance volumes
+                    texture_3d(TextureSampleType::Float { filterable: true }),
+                    // The sampler for the probes
+                    sampler(SamplerBindingType::Filtering),
+                ),
+            ),
+        );
+
+        // Fills in the pixels checkerboard cameras left out, it only reads the traced image
+        let resolve_layout = render_device.create_bind_group_layout(
+            "checkerboard_resolve_bind_group_layout",
+            &BindGroupLayoutEntries::single(
+                ShaderStages::FRAGMENT,
+                texture_2d(TextureSampleType::Float { filterable: false }),
+            ),
+        );
+
         // We can create the sampler here since it won't change at runtime and doesn't depend on the view
         let sampler = render_device.create_sampler(&SamplerDescriptor::default());
         let depth_sampler = render_device.create_sampler(&SamplerDescriptor::default());
+        let environment_sampler = render_device.create_sampler(&SamplerDescriptor {
+            mag_filter: FilterMode::Linear,
+            min_filter: FilterMode::Linear,
+            // Blends between the mips picked by the ray cones
+            mipmap_filter: FilterMode::Linear,
+            ..default()
+        });
 
-        // Get the shader handle
+        // Bound for cameras without an environment, the shader doesn't sample it then
+        let fallback_environment = render_device
+            .create_texture(&TextureDescriptor {
+                label: Some("raytrace_fallback_environment"),
+                size: Extent3d {
+                    width: 1,
+                    height: 1,
+                    depth_or_array_layers: 6,
+                },
+                mip_level_count: 1,
+                sample_count: 1,
+                dimension: TextureDimension::D2,
+                format: TextureFormat::Rgba8Unorm,
+                usage: TextureUsages::TEXTURE_BINDING,
+                view_formats: &[],
+            })
+            .create_view(&TextureViewDescriptor {
+                dimension: Some(TextureViewDimension::Cube),
+                ..default()
+            });
+
+        // Bound for cameras that don't accumulate, a texture can't be read and written in the same pass
+        let accumulation_fallback = |label, format, usage| {
+            render_device
+                .create_texture(&TextureDescriptor {
+                    label: Some(label),
+                    size: Extent3d::default(),
+                    mip_level_count: 1,
+                    sample_count: 1,
+                    dimension: TextureDimension::D2,
+                    format,
+                    usage,
+                    view_formats: &[],
+                })
+                .create_view(&TextureViewDescriptor::default())
+        };
+        let fallback_history = accumulation_fallback(
+            "raytrace_fallback_history",
+            ACCUMULATION_FORMAT,
+            TextureUsages::TEXTURE_BINDING,
+        );
+        let fallback_accumulation = accumulation_fallback(
+            "raytrace_fallback_accumulation",
+            ACCUMULATION_FORMAT,
+            TextureUsages::STORAGE_BINDING,
+        );
+        let fallback_moments_history = accumulation_fallback(
+            "raytrace_fallback_moments_history",
+            MOMENTS_FORMAT,
+            TextureUsages::TEXTURE_BINDING,
+        );
+        let fallback_moments = accumulation_fallback(
+            "raytrace_fallback_moments",
+            MOMENTS_FORMAT,
+            TextureUsages::STORAGE_BINDING,
+        );
+
+        // Get the shader handle, the pipelines are specialized for every view in `queue_raytrace_pipelines`
         let shader = world.load_asset("shaders/raytrace.wgsl");
 
-        let pipeline_id = world
-            .resource_mut::<PipelineCache>()
-            // This will add the pipeline to the cache and queue it's creation
-            .queue_render_pipeline(RenderPipelineDescriptor {
-                label: Some("raytrace_pipeline".into()),
-                layout: vec![layout.clone(), buffer_layout.clone()],
-                // This will setup a fullscreen triangle for the vertex state
-                vertex: fullscreen_shader_vertex_state(),
-                fragment: Some(FragmentState {
-                    shader,
-                    shader_defs: vec![],
-                    // Make sure this matches the entry point of your shader.
-                    // It can be anything as long as it matches here and in the shader.
-                    entry_point: "fragment".into(),
-                    targets: vec![Some(ColorTargetState {
-                        format: TextureFormat::bevy_default(),
-                        blend: None,
-                        write_mask: ColorWrites::ALL,
-                    })],
-                }),
-                primitive: PrimitiveState::default(),
-                depth_stencil: None,
-                multisample: MultisampleState::default(),
-                push_constant_ranges: vec![],
-            });
+        let resolve_shader = world.load_asset("shaders/checkerboard_resolve.wgsl");
+        let error_shader = world.load_asset("shaders/error_fallback.wgsl");
+
+        // One for each format the main texture of a view can have
+        let resolve_pipeline = |label: &'static str, format| {
+            fullscreen_pipeline(label, vec![resolve_layout.clone()], &resolve_shader, format)
+        };
+        let error_pipeline =
+            |label: &'static str, format| fullscreen_pipeline(label, vec![], &error_shader, format);
+        let pipeline_cache = world.resource::<PipelineCache>();
+        let resolve_pipeline_id = pipeline_cache.queue_render_pipeline(resolve_pipeline(
+            "checkerboard_resolve_pipeline",
+            TextureFormat::bevy_default(),
+        ));
+        let hdr_resolve_pipeline_id = pipeline_cache.queue_render_pipeline(resolve_pipeline(
+            "checkerboard_resolve_hdr_pipeline",
+            ViewTarget::TEXTURE_FORMAT_HDR,
+        ));
+        let error_pipeline_id = pipeline_cache.queue_render_pipeline(error_pipeline(
+            "raytrace_error_pipeline",
+            TextureFormat::bevy_default(),
+        ));
+        let hdr_error_pipeline_id = pipeline_cache.queue_render_pipeline(error_pipeline(
+            "raytrace_error_hdr_pipeline",
+            ViewTarget::TEXTURE_FORMAT_HDR,
+        ));
 
         Self {
             layout,
             buffer_layout,
+            caustics_layout,
+            volume_layout,
             sampler,
             depth_sampler,
-            pipeline_id,
+            environment_sampler,
+            fallback_environment,
+            fallback_history,
+            fallback_accumulation,
+            fallback_moments_history,
+            fallback_moments,
+            shader,
+            resolve_layout,
+            resolve_pipeline_id,
+            hdr_resolve_pipeline_id,
+            error_pipeline_id,
+            hdr_error_pipeline_id,
+        }
+    }
+}
+
+// A fullscreen triangle with a fragment shader of its own, for the passes that only work on the main texture
+pub(super) fn fullscreen_pipeline(
+    label: &'static str,
+    layout: Vec<BindGroupLayout>,
+    shader: &Handle<Shader>,
+    format: TextureFormat,
+) -> RenderPipelineDescriptor {
+    RenderPipelineDescriptor {
+        label: Some(label.into()),
+        layout,
+        vertex: fullscreen_shader_vertex_state(),
+        fragment: Some(FragmentState {
+            shader: shader.clone(),
+            shader_defs: vec![],
+            entry_point: "fragment".into(),
+            targets: vec![Some(ColorTargetState {
+                format,
+                blend: None,
+                write_mask: ColorWrites::ALL,
+            })],
+        }),
+        primitive: PrimitiveState::default(),
+        depth_stencil: None,
+        multisample: MultisampleState::default(),
+        push_constant_ranges: vec![],
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct RaytracePipelineKey {
+    hdr: bool,
+    // Skips the color grading and square root applied to the traced colors, only for hdr views
+    linear_output: bool,
+    // Drawn in the main pass with its depth, for the linear output and views without hdr
+    main_pass: bool,
+    // The variant checking every path for NaNs, only used while `RaytraceNanDebug` is enabled
+    nan_debug: bool,
+    // Compiled in so the inner loop of the path tracer doesn't branch on uniforms. The BVH traversal needs no variant,
+    // its short stack has the same size for every BVH
+    bounces: u32,
+    spectral: bool,
+}
+
+impl RaytracePipelineKey {
+    fn shader_defs(&self) -> Vec<ShaderDefVal> {
+        // Only the cameras gather from the photon map, trace the density volume, fall back to the irradiance volume,
+        // have an environment map and can record their paths for debugging
+        let mut shader_defs: Vec<ShaderDefVal> = vec![
+            "CAUSTICS".into(),
+            "DENSITY_VOLUME".into(),
+            "IRRADIANCE_VOLUME".into(),
+            "ENVIRONMENT_MAP".into(),
+            "PATH_DEBUG".into(),
+        ];
+        if self.linear_output {
+            shader_defs.push("LINEAR_OUTPUT".into());
+        }
+        if self.nan_debug {
+            shader_defs.push("NAN_DEBUG".into());
+        }
+        shader_defs.extend([
+            ShaderDefVal::UInt("MAX_BOUNCES".into(), self.bounces),
+            ShaderDefVal::Bool("SPECTRAL".into(), self.spectral),
+        ]);
+        shader_defs
+    }
+}
+
+impl SpecializedRenderPipeline for RaytracingPipeline {
+    type Key = RaytracePipelineKey;
+
+    fn specialize(&self, key: Self::Key) -> RenderPipelineDescriptor {
+        let format = if key.hdr {
+            ViewTarget::TEXTURE_FORMAT_HDR
+        } else {
+            TextureFormat::bevy_default()
+        };
+
+        RenderPipelineDescriptor {
+            label: Some("raytrace_pipeline".into()),
+            layout: vec![
+                self.layout.clone(),
+                self.buffer_layout.clone(),
+                self.caustics_layout.clone(),
+                self.volume_layout.clone(),
+            ],
+            // This will setup a fullscreen triangle for the vertex state
+            vertex: fullscreen_shader_vertex_state(),
+            fragment: Some(FragmentState {
+                shader: self.shader.clone(),
+                shader_defs: key.shader_defs(),
+                // Make sure this matches the entry point of your shader.
+                // It can be anything as long as it matches here and in the shader.
+                entry_point: "fragment".into(),
+                targets: vec![Some(ColorTargetState {
+                    format,
+                    blend: None,
+                    write_mask: ColorWrites::ALL,
+                })],
+            }),
+            primitive: PrimitiveState::default(),
+            // Hands its depth on to the meshes and gizmos drawn after it, and to the cameras drawing over it
+            depth_stencil: Some(DepthStencilState {
+                format: CORE_3D_DEPTH_FORMAT,
+                depth_write_enabled: true,
+                depth_compare: CompareFunction::Always,
+                stencil: default(),
+                bias: default(),
+            }),
+            multisample: MultisampleState::default(),
+            push_constant_ranges: vec![],
+        }
+    }
+}
+
+// Bevy logs why a shader doesn't compile, this adds what that means for the traced cameras and when they're back.
+// Edited shaders get compiled again through asset hot reloading
+pub(super) fn report_shader_errors(
+    views: Query<&ViewRaytracePipelines>,
+    pipeline_cache: Res<PipelineCache>,
+    mut failing: Local<bool>,
+) {
+    let states = views
+        .iter()
+        .map(|pipelines| pipeline_cache.get_render_pipeline_state(pipelines.pipeline))
+        .collect::<Vec<_>>();
+    if !*failing
+        && states
+            .iter()
+            .any(|state| matches!(state, CachedPipelineState::Err(_)))
+    {
+        error!("The raytracing shader failed to compile, traced cameras show magenta until it is fixed");
+        *failing = true;
+    } else if *failing
+        && states
+            .iter()
+            .all(|state| matches!(state, CachedPipelineState::Ok(_)))
+    {
+        info!("The raytracing shader compiled again");
+        *failing = false;
+    }
+}
+
+// The pipelines a view is drawn with, and which of the two graph nodes draws it
+#[derive(Component)]
+pub struct ViewRaytracePipelines {
+    pub(super) pipeline: CachedRenderPipelineId,
+    // Only while `RaytraceNanDebug` is enabled
+    nan_debug: Option<CachedRenderPipelineId>,
+    pub(super) linear_output: bool,
+    pub(super) main_pass: bool,
+}
+
+pub(super) fn queue_raytrace_pipelines(
+    views: Query<(Entity, &ExtractedView, &PipelineExtract)>,
+    raytrace_pipeline: Res<RaytracingPipeline>,
+    // Only built later in the frame, so this is still the BVH of the last one
+    nan_debug: Res<NanDebugBuffers>,
+    pipeline_cache: Res<PipelineCache>,
+    mut pipelines: ResMut<SpecializedRenderPipelines<RaytracingPipeline>>,
+    capabilities: Res<RaytraceCapabilities>,
+    mut commands: Commands,
+) {
+    // Without pipelines the nodes leave the views alone, so they show the rasterized scene
+    if !capabilities.supported() {
+        return;
+    }
+
+    for (entity, view, settings) in &views {
+        // Without hdr bevy tonemaps the meshes as they are drawn, so the image in the main pass already is tonemapped.
+        // Those views draw the tonemapped output there as well, before transparent meshes and gizmos.
+        // Only hdr views with the tonemapped output have to wait for bevy's tonemapping pass
+        let linear_output = settings.linear_hdr && view.hdr;
+        let key = RaytracePipelineKey {
+            hdr: view.hdr,
+            linear_output,
+            main_pass: linear_output || !view.hdr,
+            nan_debug: false,
+            bounces: settings.bounces,
+            spectral: settings.spectral,
+        };
+        let pipeline = pipelines.specialize(&pipeline_cache, &raytrace_pipeline, key);
+        let nan_debug = nan_debug.enabled.then(|| {
+            pipelines.specialize(
+                &pipeline_cache,
+                &raytrace_pipeline,
+                RaytracePipelineKey {
+                    nan_debug: true,
+                    ..key
+                },
+            )
+        });
+
+        commands.entity(entity).insert(ViewRaytracePipelines {
+            pipeline,
+            nan_debug,
+            linear_output: key.linear_output,
+            main_pass: key.main_pass,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, fs, path::Path};
+
+    use bevy::{
+        core_pipeline::CorePipelinePlugin,
+        prelude::*,
+        render::{
+            render_resource::{ShaderDefVal, ShaderImport},
+            settings::{WgpuLimits, WgpuSettings},
+            RenderPlugin,
+        },
+    };
+    use naga_oil::compose::{Composer, NagaModuleDescriptor, ShaderDefValue};
+
+    use super::RaytracePipelineKey;
+    use crate::raytracing::{bsdf::RaytraceBsdfPlugin, RaytraceBsdfAppExt};
+
+    // Every shader of the assets by its asset path, the way `AssetServer` would load them
+    fn load_asset_shaders(directory: &Path, shaders: &mut HashMap<ShaderImport, Shader>) {
+        for entry in fs::read_dir(directory).expect("Could not read the shader directory") {
+            let path = entry.expect("Could not read the shader directory").path();
+            if path.is_dir() {
+                load_asset_shaders(&path, shaders);
+                continue;
+            }
+
+            let asset_path = path
+                .strip_prefix("assets")
+                .expect("Shaders are in the assets")
+                .to_string_lossy()
+                .replace('\\', "/");
+            let source = fs::read_to_string(&path).expect("Could not read shader");
+            let shader = Shader::from_wgsl(source, asset_path);
+            shaders.insert(shader.import_path().clone(), shader);
+        }
+    }
+
+    // Like `ShaderCache::add_import_to_composer`, imports come before the modules importing them
+    fn add_import(
+        composer: &mut Composer,
+        shaders: &HashMap<ShaderImport, Shader>,
+        import: &ShaderImport,
+    ) {
+        if composer.contains_module(&import.module_name()) {
+            return;
+        }
+        let shader = shaders
+            .get(import)
+            .unwrap_or_else(|| panic!("Missing shader module {import:?}"));
+        for import in shader.imports() {
+            add_import(composer, shaders, import);
+        }
+        if let Err(error) = composer.add_composable_module(shader.into()) {
+            panic!("{}", error.emit_to_string(composer));
+        }
+    }
+
+    // Composes and validates a shader the way the pipeline cache does on a device with the default features and limits
+    fn validate(shaders: &HashMap<ShaderImport, Shader>, shader: &Shader, defs: &[ShaderDefVal]) {
+        let mut composer = Composer::default();
+        for import in shader.imports() {
+            add_import(&mut composer, shaders, import);
+        }
+
+        let limits = WgpuLimits::default();
+        let shader_defs = defs
+            .iter()
+            .cloned()
+            .chain([ShaderDefVal::UInt(
+                "AVAILABLE_STORAGE_BUFFER_BINDINGS".into(),
+                limits.max_storage_buffers_per_shader_stage,
+            )])
+            .map(|def| match def {
+                ShaderDefVal::Bool(key, value) => (key, ShaderDefValue::Bool(value)),
+                ShaderDefVal::Int(key, value) => (key, ShaderDefValue::Int(value)),
+                ShaderDefVal::UInt(key, value) => (key, ShaderDefValue::UInt(value)),
+            })
+            .collect();
+        if let Err(error) = composer.make_naga_module(NagaModuleDescriptor {
+            shader_defs,
+            ..shader.into()
+        }) {
+            panic!(
+                "{} with {defs:?}:\n{}",
+                shader.path,
+                error.emit_to_string(&composer)
+            );
+        }
+    }
+
+    #[test]
+    fn shaders_validate() {
+        // Bevy's own shader modules only exist once its plugins are built, no device is needed for that
+        let mut app = App::new();
+        app.add_plugins((
+            MinimalPlugins,
+            AssetPlugin::default(),
+            RenderPlugin {
+                render_creation: WgpuSettings {
+                    backends: None,
+                    ..default()
+                }
+                .into(),
+                ..default()
+            },
+            ImagePlugin::default(),
+            CorePipelinePlugin,
+            RaytraceBsdfPlugin,
+        ));
+
+        let mut shaders = HashMap::new();
+        load_asset_shaders(Path::new("assets/shaders"), &mut shaders);
+        // The custom BSDF dispatch is generated from what got registered, so every BSDF module ends up in it
+        let bsdfs = shaders
+            .values()
+            .filter(|shader| shader.path.starts_with("shaders/bsdf/"))
+            .map(|shader| shader.path.clone())
+            .collect::<Vec<_>>();
+        for (tag, path) in bsdfs.into_iter().enumerate() {
+            app.register_raytrace_bsdf(tag as u32, path);
+        }
+        app.finish();
+        app.cleanup();
+
+        for (_, shader) in app.world().resource::<Assets<Shader>>().iter() {
+            shaders
+                .entry(shader.import_path().clone())
+                .or_insert_with(|| shader.clone());
+        }
+
+        let entry_points = shaders
+            .values()
+            .filter(|shader| {
+                let source = shader.source.as_str();
+                shader.path.starts_with("shaders/")
+                    && ["@fragment", "@compute", "@vertex"]
+                        .iter()
+                        .any(|stage| source.contains(stage))
+            })
+            .collect::<Vec<_>>();
+        assert!(!entry_points.is_empty());
+
+        for shader in entry_points {
+            if shader.path != "shaders/raytrace.wgsl" {
+                validate(&shaders, shader, &[]);
+                continue;
+            }
+
+            for (linear_output, main_pass, nan_debug, spectral) in [
+                (false, false, false, false),
+                (true, true, false, false),
+                (false, true, false, false),
+                (false, false, true, false),
+                (false, false, false, true),
+            ] {
+                let key = RaytracePipelineKey {
+                    hdr: true,
+                    linear_output,
+                    main_pass,
+                    nan_debug,
+                    bounces: 8,
+                    spectral,
+                };
+                validate(&shaders, shader, &key.shader_defs());
+            }
         }
     }
 }
@@ -0,0 +1,103 @@
+use bevy::{
+    prelude::*,
+    render::{
+        render_resource::ShaderType,
+        renderer::{RenderDevice, RenderQueue},
+        Extract, ExtractSchedule, Render, RenderApp, RenderSet,
+    },
+};
+
+use super::{buffer::SceneBuffer, memory::MemoryReport, origin::RenderOrigin};
+
+pub struct RaytracePortalPlugin;
+
+impl Plugin for RaytracePortalPlugin {
+    fn build(&self, app: &mut App) {
+        app.register_type::<RaytracePortal>()
+            .register_type::<RaytraceMirror>();
+
+        let Some(render_app) = app.get_sub_app_mut(RenderApp) else {
+            return;
+        };
+
+        render_app
+            .init_resource::<PortalBuffer>()
+            .add_systems(ExtractSchedule, extract_portals)
+            .add_systems(Render, prepare_portals.in_set(RenderSet::PrepareResources));
+    }
+}
+
+// A rectangle that rays pass through to come out at `target`, for non-euclidean scenes and mirrored rooms.
+// Like the fog volumes it is the unit square on the local xy plane, scaled and positioned by the transform.
+// Rays hitting it continue from the same spot relative to the transform of the target entity,
+// a target with a scale of -1 on z in front of the portal turns it into a perfect mirror.
+// Portals block shadow rays, light only makes it through them along the paths themselves
+#[derive(Component, Reflect, Clone, Copy)]
+pub struct RaytracePortal {
+    pub target: Entity,
+}
+
+// A perfectly smooth planar mirror on the unit square of the local xy plane, reflecting from both sides.
+// Rays get reflected exactly, without shading the mirror, sampling lights or drawing random numbers,
+// so reflections are noise free and cost a single extra ray. The tint multiplies the reflected light, like the color of a metal.
+// Traced like a portal with its own reflection as the target, so it also takes up a bounce and blocks shadow rays
+#[derive(Component, Reflect, Clone, Copy, Default)]
+pub struct RaytraceMirror {
+    pub tint: Color,
+}
+
+#[derive(Clone, PartialEq, ShaderType)]
+pub struct Portal {
+    local_from_world: Mat4,
+    // Carries points and directions at the portal over to the target
+    target_from_world: Mat4,
+    // Multiplies the light coming through, white for portals
+    tint: Vec3,
+}
+
+// Bound with the geometry, portals are traced in every pass
+#[derive(Resource, Default, Deref, DerefMut)]
+pub struct PortalBuffer(SceneBuffer<Portal>);
+
+fn extract_portals(
+    mut portal_buffer: ResMut<PortalBuffer>,
+    portals: Extract<Query<(&RaytracePortal, &GlobalTransform)>>,
+    mirrors: Extract<Query<(&RaytraceMirror, &GlobalTransform)>>,
+    transforms: Extract<Query<&GlobalTransform>>,
+    origin: Extract<Res<RenderOrigin>>,
+    memory_report: Res<MemoryReport>,
+) {
+    // Portals with a despawned target are left out
+    let portals = portals.iter().filter_map(|(portal, transform)| {
+        let target = transforms.get(portal.target).ok()?;
+        let local_from_world = origin.local_from_render(transform.compute_matrix());
+        Some(Portal {
+            local_from_world,
+            target_from_world: origin.render_from_local(target.compute_matrix()) * local_from_world,
+            tint: Vec3::ONE,
+        })
+    });
+    // Flipping z in local space reflects through the plane of the mirror
+    let mirrors = mirrors.iter().map(|(mirror, transform)| {
+        let world_from_local = origin.render_from_local(transform.compute_matrix());
+        let local_from_world = world_from_local.inverse();
+        Portal {
+            local_from_world,
+            target_from_world: world_from_local
+                * Mat4::from_scale(Vec3::new(1.0, 1.0, -1.0))
+                * local_from_world,
+            tint: mirror.tint.to_linear().to_vec3(),
+        }
+    });
+    portal_buffer.set(portals.chain(mirrors));
+
+    memory_report.record("portals", portal_buffer.size(), 1);
+}
+
+fn prepare_portals(
+    mut portal_buffer: ResMut<PortalBuffer>,
+    render_device: Res<RenderDevice>,
+    render_queue: Res<RenderQueue>,
+) {
+    portal_buffer.write_buffer(&render_device, &render_queue);
+}
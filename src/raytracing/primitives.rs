@@ -0,0 +1,390 @@
+use bevy::{
+    pbr::{CascadesVisibleEntities, CubemapVisibleEntities, SimulationLightSystems},
+    prelude::*,
+    render::{
+        mesh::{MeshVertexAttributeId, VertexAttributeValues},
+        view::{VisibilitySystems, VisibleEntities, WithMesh},
+    },
+};
+
+use super::{
+    RaytraceCapabilities, RaytraceLightmapBake, RaytraceMaterialId, RaytracedBillboard,
+    RaytracedCamera, RaytracedCuboid, RaytracedHeightfield, RaytracedPlane, RaytracedSphere,
+    Raytracing,
+};
+
+pub struct RaytracePrimitivesPlugin;
+
+impl Plugin for RaytracePrimitivesPlugin {
+    fn build(&self, app: &mut App) {
+        app.register_type::<RaytracePrimitiveMesh>()
+            .register_type::<RaytraceHideMesh>()
+            .add_systems(
+                Update,
+                (detect_primitive_meshes, scale_detected_spheres).chain(),
+            )
+            .add_systems(
+                PostUpdate,
+                (
+                    skip_traced_meshes.after(VisibilitySystems::CheckVisibility),
+                    hide_traced_meshes
+                        .after(VisibilitySystems::CheckVisibility)
+                        .after(SimulationLightSystems::CheckLightVisibility),
+                ),
+            );
+    }
+}
+
+// Traces an entity whose mesh is a bevy `Sphere`, `Cuboid` or `Plane3d` as that primitive, it gets a `RaytracedSphere`,
+// `RaytracedCuboid` or `RaytracedPlane` found from the vertices of the mesh. Only entities with this marker and a
+// `StandardMaterial` or `RaytraceMaterialId` are looked at, every other mesh stays rasterized only.
+// Cameras that trace the scene skip its rasterized mesh, since the traced one takes its place, every other camera keeps
+// drawing it. Removing the marker takes the primitive away again. Heightfields, billboards and meshes that get a
+// lightmap baked onto them are left as they are
+#[derive(Component, Reflect, Clone, Copy, Default, Debug)]
+pub struct RaytracePrimitiveMesh;
+
+// Keeps the rasterized mesh of a traced primitive out of every camera and shadow map, also the ones that don't trace,
+// while the primitive itself is still traced. The mesh is only there to be picked and moved with the gizmo then.
+// Hiding the entity instead would leave the primitive out of the trace as well
+#[derive(Component, Reflect, Clone, Copy, Default, Debug)]
+pub struct RaytraceHideMesh;
+
+// The mesh an entity was checked with, it only gets checked again once its handle changes
+#[derive(Component)]
+struct DetectedPrimitive {
+    mesh: AssetId<Mesh>,
+    // What the mesh is traced as, None if it isn't a primitive
+    traced: Option<MeshPrimitive>,
+}
+
+// What a mesh was built from, found from its vertices since meshes don't remember their shape
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum MeshPrimitive {
+    Sphere { radius: f32 },
+    Cuboid { half_size: Vec3 },
+    Plane { normal: Dir3, half_size: Vec2 },
+}
+
+impl MeshPrimitive {
+    fn detect(mesh: &Mesh) -> Option<Self> {
+        let positions = float3_attribute(mesh, Mesh::ATTRIBUTE_POSITION)?;
+        let normals = float3_attribute(mesh, Mesh::ATTRIBUTE_NORMAL).unwrap_or_default();
+        if positions.len() < 4 {
+            return None;
+        }
+
+        // Every primitive is centered on the origin of the mesh
+        let (min, max) = positions.iter().fold(
+            (Vec3::splat(f32::INFINITY), Vec3::splat(f32::NEG_INFINITY)),
+            |(min, max), position| (min.min(*position), max.max(*position)),
+        );
+        let extent = max.max(-min);
+        let tolerance = extent.max_element() * 1e-4;
+        if tolerance <= 0.0 || !min.abs_diff_eq(-max, tolerance) {
+            return None;
+        }
+
+        Self::cuboid(&positions, extent, tolerance)
+            .or_else(|| Self::sphere(&positions, &normals, tolerance))
+            .or_else(|| Self::plane(&positions, &normals, tolerance))
+    }
+
+    // Every vertex is a corner, with all eight of them there
+    fn cuboid(positions: &[Vec3], extent: Vec3, tolerance: f32) -> Option<Self> {
+        if extent.min_element() <= tolerance {
+            return None;
+        }
+
+        let mut corners = 0u8;
+        for position in positions {
+            if !position.abs().abs_diff_eq(extent, tolerance) {
+                return None;
+            }
+            let corner = u8::from(position.x > 0.0)
+                | u8::from(position.y > 0.0) << 1
+                | u8::from(position.z > 0.0) << 2;
+            corners |= 1 << corner;
+        }
+        (corners == u8::MAX).then_some(Self::Cuboid { half_size: extent })
+    }
+
+    // Every vertex is as far from the center, with its normal pointing straight out.
+    // The corners of a cuboid are too, but their normals point along the faces
+    fn sphere(positions: &[Vec3], normals: &[Vec3], tolerance: f32) -> Option<Self> {
+        let radius = positions[0].length();
+        let round = positions
+            .iter()
+            .all(|position| (position.length() - radius).abs() <= tolerance);
+        let smooth = normals
+            .iter()
+            .zip(positions)
+            .all(|(normal, position)| normal.abs_diff_eq(*position / radius, 1e-3));
+        (positions.len() >= 12 && round && smooth).then_some(Self::Sphere { radius })
+    }
+
+    // Every vertex lies on a plane through the center with the same normal, and the corners of the rectangle are there
+    fn plane(positions: &[Vec3], normals: &[Vec3], tolerance: f32) -> Option<Self> {
+        let normal = Dir3::new(*normals.first()?).ok()?;
+        if normals.len() != positions.len()
+            || normals
+                .iter()
+                .any(|other| !other.abs_diff_eq(*normal, 1e-3))
+            || positions
+                .iter()
+                .any(|position| position.dot(*normal).abs() > tolerance)
+        {
+            return None;
+        }
+
+        // Into the space of a plane facing up, like the `Plane3d` mesh builder rotates it out of
+        let local_from_mesh = Quat::from_rotation_arc(*normal, Vec3::Y);
+        let local = positions
+            .iter()
+            .map(|position| (local_from_mesh * *position).xz())
+            .collect::<Vec<_>>();
+        let half_size = local.iter().fold(Vec2::ZERO, |half_size, position| {
+            half_size.max(position.abs())
+        });
+        if half_size.min_element() <= tolerance {
+            return None;
+        }
+
+        let has_corner = |corner: Vec2| {
+            local
+                .iter()
+                .any(|position| position.abs_diff_eq(corner, tolerance))
+        };
+        let corners = [
+            half_size,
+            -half_size,
+            Vec2::new(half_size.x, -half_size.y),
+            Vec2::new(-half_size.x, half_size.y),
+        ];
+        corners
+            .into_iter()
+            .all(has_corner)
+            .then_some(Self::Plane { normal, half_size })
+    }
+}
+
+fn float3_attribute(mesh: &Mesh, attribute: impl Into<MeshVertexAttributeId>) -> Option<Vec<Vec3>> {
+    match mesh.attribute(attribute)? {
+        VertexAttributeValues::Float32x3(values) => {
+            Some(values.iter().map(|value| Vec3::from(*value)).collect())
+        }
+        _ => None,
+    }
+}
+
+// Brings back the rasterized mesh of an entity that was traced as a primitive
+fn restore_mesh(commands: &mut Commands, entity: Entity, detected: &DetectedPrimitive) {
+    if detected.traced.is_some() {
+        commands
+            .entity(entity)
+            .remove::<(RaytracedSphere, RaytracedCuboid, RaytracedPlane)>();
+    }
+}
+
+#[allow(clippy::type_complexity)]
+fn detect_primitive_meshes(
+    meshes: Res<Assets<Mesh>>,
+    capabilities: Option<Res<RaytraceCapabilities>>,
+    candidates: Query<
+        (
+            Entity,
+            &Handle<Mesh>,
+            &GlobalTransform,
+            Option<&DetectedPrimitive>,
+        ),
+        (
+            Or<(With<Handle<StandardMaterial>>, With<RaytraceMaterialId>)>,
+            With<RaytracePrimitiveMesh>,
+            Without<RaytracedHeightfield>,
+            Without<RaytracedBillboard>,
+            // Lightmaps are baked onto the rasterized mesh
+            Without<RaytraceLightmapBake>,
+        ),
+    >,
+    // Entities that got their primitive by hand are left as they are
+    manual: Query<
+        (),
+        Or<(
+            With<RaytracedSphere>,
+            With<RaytracedCuboid>,
+            With<RaytracedPlane>,
+        )>,
+    >,
+    opted_out: Query<(Entity, &DetectedPrimitive), Without<RaytracePrimitiveMesh>>,
+    mut commands: Commands,
+) {
+    // Nothing gets traced on these devices, the rasterized meshes have to stay
+    if capabilities.is_some_and(|capabilities| !capabilities.supported()) {
+        return;
+    }
+
+    for (entity, detected) in &opted_out {
+        restore_mesh(&mut commands, entity, detected);
+        commands.entity(entity).remove::<DetectedPrimitive>();
+    }
+
+    for (entity, mesh_handle, transform, detected) in &candidates {
+        match detected {
+            Some(detected) if detected.mesh == mesh_handle.id() => continue,
+            Some(detected) => restore_mesh(&mut commands, entity, detected),
+            None if manual.contains(entity) => continue,
+            None => {}
+        }
+
+        // Checked once it has loaded
+        let Some(mesh) = meshes.get(mesh_handle) else {
+            continue;
+        };
+
+        let primitive = MeshPrimitive::detect(mesh);
+        let mut entity_commands = commands.entity(entity);
+        match primitive {
+            Some(MeshPrimitive::Sphere { radius }) => {
+                entity_commands.insert(RaytracedSphere {
+                    radius: scaled_radius(radius, transform),
+                });
+            }
+            Some(MeshPrimitive::Cuboid { half_size }) => {
+                entity_commands.insert(RaytracedCuboid { half_size });
+            }
+            Some(MeshPrimitive::Plane { normal, half_size }) => {
+                entity_commands.insert(RaytracedPlane { normal, half_size });
+            }
+            None => {}
+        }
+        entity_commands.insert(DetectedPrimitive {
+            mesh: mesh_handle.id(),
+            traced: primitive,
+        });
+    }
+}
+
+// Leaves the rasterized meshes of traced primitives, detected or added by hand, out of what tracing cameras draw.
+// Visibility itself stays untouched, so children, other cameras and cameras skipping the trace still see the mesh
+#[allow(clippy::type_complexity)]
+fn skip_traced_meshes(
+    capabilities: Option<Res<RaytraceCapabilities>>,
+    mut cameras: Query<(&RaytracedCamera, &mut VisibleEntities)>,
+    traced: Query<
+        (),
+        Or<(
+            With<RaytracedSphere>,
+            With<RaytracedCuboid>,
+            With<RaytracedPlane>,
+        )>,
+    >,
+) {
+    if capabilities.is_some_and(|capabilities| !capabilities.supported()) {
+        return;
+    }
+
+    for (camera, mut visible_entities) in &mut cameras {
+        if camera.level == Raytracing::Skip {
+            continue;
+        }
+        visible_entities
+            .get_mut::<WithMesh>()
+            .retain(|entity| !traced.contains(*entity));
+    }
+}
+
+// Leaves the meshes marked with `RaytraceHideMesh` out of what every camera and light draws
+fn hide_traced_meshes(
+    hidden: Query<(), With<RaytraceHideMesh>>,
+    mut views: Query<&mut VisibleEntities>,
+    mut point_lights: Query<&mut CubemapVisibleEntities>,
+    mut directional_lights: Query<&mut CascadesVisibleEntities>,
+) {
+    if hidden.is_empty() {
+        return;
+    }
+
+    let hide = |visible_entities: &mut VisibleEntities| {
+        visible_entities
+            .get_mut::<WithMesh>()
+            .retain(|entity| !hidden.contains(*entity));
+    };
+    // Cameras and spot lights
+    for mut visible_entities in &mut views {
+        hide(&mut visible_entities);
+    }
+    for mut cubemap in &mut point_lights {
+        cubemap.iter_mut().for_each(hide);
+    }
+    for mut cascades in &mut directional_lights {
+        cascades.entities.values_mut().flatten().for_each(hide);
+    }
+}
+
+// The radius of a `RaytracedSphere` stays the same under a uniform scale, unlike the mesh
+fn scaled_radius(radius: f32, transform: &GlobalTransform) -> f32 {
+    radius * transform.compute_transform().scale.abs().max_element()
+}
+
+// Keeps the radius of detected spheres in line with the scale of their entity
+fn scale_detected_spheres(
+    mut spheres: Query<
+        (&DetectedPrimitive, &GlobalTransform, &mut RaytracedSphere),
+        Changed<GlobalTransform>,
+    >,
+) {
+    for (detected, transform, mut sphere) in &mut spheres {
+        let Some(MeshPrimitive::Sphere { radius }) = detected.traced else {
+            continue;
+        };
+        let radius = scaled_radius(radius, transform);
+        if sphere.radius != radius {
+            sphere.radius = radius;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::prelude::*;
+
+    use super::MeshPrimitive;
+
+    #[test]
+    fn primitive_meshes_are_detected() {
+        let sphere = MeshPrimitive::detect(&Sphere::new(0.5).mesh().build());
+        assert!(
+            matches!(sphere, Some(MeshPrimitive::Sphere { radius }) if (radius - 0.5).abs() < 1e-4)
+        );
+        let uv_sphere = MeshPrimitive::detect(&Sphere::new(2.0).mesh().uv(16, 8));
+        assert!(matches!(uv_sphere, Some(MeshPrimitive::Sphere { .. })));
+
+        assert_eq!(
+            MeshPrimitive::detect(&Cuboid::new(1.0, 2.0, 3.0).mesh().build()),
+            Some(MeshPrimitive::Cuboid {
+                half_size: Vec3::new(0.5, 1.0, 1.5)
+            })
+        );
+
+        let plane = Plane3d::new(Vec3::X, Vec2::new(2.0, 1.0)).mesh().build();
+        let Some(MeshPrimitive::Plane { normal, half_size }) = MeshPrimitive::detect(&plane) else {
+            panic!("The plane wasn't detected");
+        };
+        assert!(normal.abs_diff_eq(Vec3::X, 1e-4));
+        assert!(half_size.abs_diff_eq(Vec2::new(2.0, 1.0), 1e-4));
+
+        // Round, but not a sphere
+        assert_eq!(
+            MeshPrimitive::detect(&Torus::default().mesh().build()),
+            None
+        );
+        assert_eq!(
+            MeshPrimitive::detect(&Circle::new(1.0).mesh().build()),
+            None
+        );
+        assert_eq!(
+            MeshPrimitive::detect(&Cylinder::default().mesh().build()),
+            None
+        );
+    }
+}
@@ -0,0 +1,370 @@
+use bevy::{
+    pbr::{irradiance_volume::IrradianceVolume, LightProbe},
+    prelude::*,
+    render::{
+        graph::CameraDriverLabel,
+        render_asset::{RenderAssetUsages, RenderAssets},
+        render_graph::{Node, NodeRunError, RenderGraph, RenderGraphContext, RenderLabel},
+        render_resource::{
+            binding_types::{texture_3d, uniform_buffer},
+            BindGroup, BindGroupEntries, BindGroupLayout, BindGroupLayoutEntries, BindingType,
+            CachedComputePipelineId, ComputePassDescriptor, ComputePipelineDescriptor,
+            DynamicUniformBuffer, Extent3d, PipelineCache, ShaderStages, ShaderType,
+            StorageTextureAccess, TextureDescriptor, TextureDimension, TextureFormat,
+            TextureSampleType, TextureUsages, TextureViewDimension,
+        },
+        renderer::{RenderContext, RenderDevice, RenderQueue},
+        texture::{CachedTexture, GpuImage, TextureCache},
+        Extract, ExtractSchedule, Render, RenderApp, RenderSet,
+    },
+};
+use rand::{thread_rng, Rng};
+
+use super::{
+    origin::RenderOrigin,
+    pipeline::{geometry_bind_group, RaytracingPipeline},
+};
+
+const PROBE_FORMAT: TextureFormat = TextureFormat::Rgba16Float;
+const WORKGROUP_SIZE: u32 = 4;
+
+#[derive(Debug, Hash, PartialEq, Eq, Clone, RenderLabel)]
+pub struct ProbeGridLabel;
+
+pub struct RaytraceProbeGridPlugin;
+
+impl Plugin for RaytraceProbeGridPlugin {
+    fn build(&self, app: &mut App) {
+        app.register_type::<RaytraceProbeGrid>()
+            .add_systems(Update, sync_irradiance_volumes);
+
+        let Some(render_app) = app.get_sub_app_mut(RenderApp) else {
+            return;
+        };
+
+        render_app
+            .init_resource::<ExtractedProbeGrids>()
+            .init_resource::<PreparedProbeGrids>()
+            .add_systems(ExtractSchedule, extract_probe_grids)
+            .add_systems(
+                Render,
+                prepare_probe_grids.in_set(RenderSet::PrepareBindGroups),
+            );
+
+        // The probes are updated before any camera renders, so the raster pipeline already sees this frame's lighting
+        let mut render_graph = render_app.world_mut().resource_mut::<RenderGraph>();
+        render_graph.add_node(ProbeGridLabel, ProbeGridNode);
+        render_graph.add_node_edge(ProbeGridLabel, CameraDriverLabel);
+    }
+
+    fn finish(&self, app: &mut App) {
+        let Some(render_app) = app.get_sub_app_mut(RenderApp) else {
+            return;
+        };
+
+        // This reuses the geometry layout of the main pipeline, so it has to be initialized after it
+        render_app.init_resource::<ProbeGridPipeline>();
+    }
+}
+
+// A DDGI style volume of irradiance probes that gets traced every frame, giving cheap dynamic GI to the raster pipeline.
+// Like bevy light probes the volume is a 1x1x1 cube around the entity, scaled and positioned by its transform.
+// The probes are stored as a bevy `IrradianceVolume`, which gets inserted automatically,
+// the alpha channel holds the mean distance to the surrounding geometry for visibility tests.
+#[derive(Component, Reflect, Clone)]
+pub struct RaytraceProbeGrid {
+    pub image: Handle<Image>,
+    pub rays_per_probe: u32,
+    pub bounces: u32,
+    // How much of the previous frame is kept, higher values are less noisy but react slower to changes
+    pub hysteresis: f32,
+    pub intensity: f32,
+}
+
+impl RaytraceProbeGrid {
+    pub fn new(resolution: UVec3, images: &mut Assets<Image>) -> Self {
+        // Layout of bevy irradiance volumes, every probe is an ambient cube with one color per side
+        let mut image = Image::new_fill(
+            Extent3d {
+                width: resolution.x,
+                height: resolution.y * 2,
+                depth_or_array_layers: resolution.z * 3,
+            },
+            TextureDimension::D3,
+            &[0; 8],
+            PROBE_FORMAT,
+            RenderAssetUsages::default(),
+        );
+        image.texture_descriptor.usage =
+            TextureUsages::TEXTURE_BINDING | TextureUsages::COPY_SRC | TextureUsages::COPY_DST;
+
+        Self {
+            image: images.add(image),
+            rays_per_probe: 32,
+            bounces: 2,
+            hysteresis: 0.95,
+            intensity: 1.0,
+        }
+    }
+}
+
+fn sync_irradiance_volumes(
+    grids: Query<(Entity, &RaytraceProbeGrid), Changed<RaytraceProbeGrid>>,
+    mut cmd: Commands,
+) {
+    for (entity, grid) in &grids {
+        cmd.entity(entity).insert((
+            LightProbe,
+            IrradianceVolume {
+                voxels: grid.image.clone(),
+                intensity: grid.intensity,
+            },
+        ));
+    }
+}
+
+pub struct ExtractedProbeGrid {
+    image: AssetId<Image>,
+    uniform: ProbeGridUniform,
+}
+
+#[derive(Resource, Default, Deref, DerefMut)]
+pub struct ExtractedProbeGrids(Vec<ExtractedProbeGrid>);
+
+#[derive(Clone, ShaderType)]
+pub struct ProbeGridUniform {
+    world_from_local: Mat4,
+    rays_per_probe: u32,
+    bounce_count: u32,
+    hysteresis: f32,
+    random_seed: f32,
+}
+
+fn extract_probe_grids(
+    mut extracted: ResMut<ExtractedProbeGrids>,
+    grids: Extract<Query<(Ref<RaytraceProbeGrid>, &GlobalTransform)>>,
+    origin: Extract<Res<RenderOrigin>>,
+) {
+    extracted.clear();
+
+    let mut rng = thread_rng();
+    for (grid, transform) in &grids {
+        extracted.push(ExtractedProbeGrid {
+            image: grid.image.id(),
+            uniform: ProbeGridUniform {
+                world_from_local: origin.render_from_local(transform.compute_matrix()),
+                rays_per_probe: grid.rays_per_probe,
+                bounce_count: grid.bounces,
+                // Changed settings shouldn't blend with outdated history
+                hysteresis: if grid.is_changed() {
+                    0.0
+                } else {
+                    grid.hysteresis.clamp(0.0, 1.0)
+                },
+                random_seed: rng.gen_range(0.0..1.0),
+            },
+        });
+    }
+}
+
+pub struct PreparedProbeGrid {
+    image: AssetId<Image>,
+    // The compute shader can't read and write the image at the same time, so it writes here and gets copied over
+    output: CachedTexture,
+    bind_group: BindGroup,
+    uniform_offset: u32,
+    size: Extent3d,
+}
+
+#[derive(Resource, Default)]
+pub struct PreparedProbeGrids {
+    grids: Vec<PreparedProbeGrid>,
+    uniforms: DynamicUniformBuffer<ProbeGridUniform>,
+}
+
+fn prepare_probe_grids(
+    extracted: Res<ExtractedProbeGrids>,
+    mut prepared: ResMut<PreparedProbeGrids>,
+    mut texture_cache: ResMut<TextureCache>,
+    probe_pipeline: Res<ProbeGridPipeline>,
+    images: Res<RenderAssets<GpuImage>>,
+    render_device: Res<RenderDevice>,
+    render_queue: Res<RenderQueue>,
+) {
+    let prepared = &mut *prepared;
+    prepared.grids.clear();
+    prepared.uniforms.clear();
+
+    let offsets = extracted
+        .iter()
+        .map(|grid| prepared.uniforms.push(&grid.uniform))
+        .collect::<Vec<_>>();
+    prepared
+        .uniforms
+        .write_buffer(&render_device, &render_queue);
+
+    let Some(uniform_binding) = prepared.uniforms.binding() else {
+        return;
+    };
+
+    for (grid, uniform_offset) in extracted.iter().zip(offsets) {
+        let Some(image) = images.get(grid.image) else {
+            continue;
+        };
+
+        let size = image.texture.size();
+        let output = texture_cache.get(
+            &render_device,
+            TextureDescriptor {
+                label: Some("probe_grid_output"),
+                size,
+                mip_level_count: 1,
+                sample_count: 1,
+                dimension: TextureDimension::D3,
+                format: PROBE_FORMAT,
+                usage: TextureUsages::STORAGE_BINDING | TextureUsages::COPY_SRC,
+                view_formats: &[],
+            },
+        );
+
+        let bind_group = render_device.create_bind_group(
+            "probe_grid_bind_group",
+            &probe_pipeline.layout,
+            &BindGroupEntries::sequential((
+                uniform_binding.clone(),
+                &image.texture_view,
+                &output.default_view,
+            )),
+        );
+
+        prepared.grids.push(PreparedProbeGrid {
+            image: grid.image,
+            output,
+            bind_group,
+            uniform_offset,
+            size,
+        });
+    }
+}
+
+// Traces rays from every probe and blends them into its history
+pub struct ProbeGridNode;
+
+impl Node for ProbeGridNode {
+    fn run(
+        &self,
+        _graph: &mut RenderGraphContext,
+        render_context: &mut RenderContext,
+        world: &World,
+    ) -> Result<(), NodeRunError> {
+        let prepared = world.resource::<PreparedProbeGrids>();
+        if prepared.grids.is_empty() {
+            return Ok(());
+        }
+
+        let probe_pipeline = world.resource::<ProbeGridPipeline>();
+        let pipeline_cache = world.resource::<PipelineCache>();
+        let Some(pipeline) = pipeline_cache.get_compute_pipeline(probe_pipeline.pipeline_id) else {
+            return Ok(());
+        };
+
+        let Some(buffer_bind_group) = geometry_bind_group(
+            world,
+            render_context.render_device(),
+            &probe_pipeline.buffer_layout,
+            "probe_grid_geometry_bind_group",
+        ) else {
+            return Ok(());
+        };
+
+        let images = world.resource::<RenderAssets<GpuImage>>();
+
+        {
+            let mut compute_pass =
+                render_context
+                    .command_encoder()
+                    .begin_compute_pass(&ComputePassDescriptor {
+                        label: Some("probe_grid_pass"),
+                        timestamp_writes: None,
+                    });
+
+            compute_pass.set_pipeline(pipeline);
+            compute_pass.set_bind_group(1, &buffer_bind_group, &[]);
+            for grid in &prepared.grids {
+                compute_pass.set_bind_group(0, &grid.bind_group, &[grid.uniform_offset]);
+                compute_pass.dispatch_workgroups(
+                    grid.size.width.div_ceil(WORKGROUP_SIZE),
+                    grid.size.height.div_ceil(WORKGROUP_SIZE),
+                    grid.size.depth_or_array_layers.div_ceil(WORKGROUP_SIZE),
+                );
+            }
+        }
+
+        for grid in &prepared.grids {
+            let Some(image) = images.get(grid.image) else {
+                continue;
+            };
+
+            render_context.command_encoder().copy_texture_to_texture(
+                grid.output.texture.as_image_copy(),
+                image.texture.as_image_copy(),
+                grid.size,
+            );
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Resource)]
+pub struct ProbeGridPipeline {
+    layout: BindGroupLayout,
+    buffer_layout: BindGroupLayout,
+    pipeline_id: CachedComputePipelineId,
+}
+
+impl FromWorld for ProbeGridPipeline {
+    fn from_world(world: &mut World) -> Self {
+        let render_device = world.resource::<RenderDevice>();
+
+        let layout = render_device.create_bind_group_layout(
+            "probe_grid_bind_group_layout",
+            &BindGroupLayoutEntries::sequential(
+                ShaderStages::COMPUTE,
+                (
+                    // The grid settings
+                    uniform_buffer::<ProbeGridUniform>(true),
+                    // The probes of the previous frame
+                    texture_3d(TextureSampleType::Float { filterable: false }),
+                    // The updated probes
+                    BindingType::StorageTexture {
+                        access: StorageTextureAccess::WriteOnly,
+                        format: PROBE_FORMAT,
+                        view_dimension: TextureViewDimension::D3,
+                    },
+                ),
+            ),
+        );
+
+        let buffer_layout = world.resource::<RaytracingPipeline>().buffer_layout.clone();
+
+        let shader = world.load_asset("shaders/probe_grid.wgsl");
+
+        let pipeline_id = world
+            .resource_mut::<PipelineCache>()
+            .queue_compute_pipeline(ComputePipelineDescriptor {
+                label: Some("probe_grid_pipeline".into()),
+                layout: vec![layout.clone(), buffer_layout.clone()],
+                push_constant_ranges: vec![],
+                shader,
+                shader_defs: vec![],
+                entry_point: "update".into(),
+            });
+
+        Self {
+            layout,
+            buffer_layout,
+            pipeline_id,
+        }
+    }
+}
@@ -0,0 +1,124 @@
+use std::sync::{Arc, Mutex};
+
+use bevy::{
+    math::UVec2,
+    render::{
+        render_resource::{
+            Buffer, BufferAsyncError, BufferDescriptor, BufferUsages, CommandEncoder,
+            CommandEncoderDescriptor, Extent3d, ImageCopyBuffer, ImageDataLayout, Maintain,
+            MapMode, Texture,
+        },
+        renderer::{RenderDevice, RenderQueue},
+    },
+};
+
+// A small buffer the GPU copies results into for the CPU to read, like the entity under the cursor.
+// Only one copy is in flight at a time, it takes a frame or two until the data arrives
+pub struct Readback {
+    buffer: Buffer,
+    // Filled in by the map callback, which can't reach the readback
+    mapped: Arc<Mutex<Option<Result<(), BufferAsyncError>>>>,
+    pending: bool,
+}
+
+impl Readback {
+    pub fn new(render_device: &RenderDevice, label: &'static str, size: u64) -> Self {
+        Self {
+            buffer: render_device.create_buffer(&BufferDescriptor {
+                label: Some(label),
+                size,
+                usage: BufferUsages::MAP_READ | BufferUsages::COPY_DST,
+                mapped_at_creation: false,
+            }),
+            mapped: Arc::default(),
+            pending: false,
+        }
+    }
+
+    pub fn is_pending(&self) -> bool {
+        self.pending
+    }
+
+    // Copies `source` over once everything submitted so far is done and starts mapping it.
+    // Should run after the frame got submitted, like in `RenderSet::Cleanup`
+    pub fn start(
+        &mut self,
+        render_device: &RenderDevice,
+        render_queue: &RenderQueue,
+        source: &Buffer,
+    ) {
+        let mut encoder = render_device.create_command_encoder(&CommandEncoderDescriptor {
+            label: Some("readback_copy"),
+        });
+        encoder.copy_buffer_to_buffer(source, 0, &self.buffer, 0, self.buffer.size());
+        self.map(render_queue, encoder);
+    }
+
+    // Like `start`, for the first `size` texels of a texture. Its rows start every `bytes_per_row` in the copy,
+    // which has to be a multiple of `COPY_BYTES_PER_ROW_ALIGNMENT`
+    pub fn start_texture(
+        &mut self,
+        render_device: &RenderDevice,
+        render_queue: &RenderQueue,
+        source: &Texture,
+        size: UVec2,
+        bytes_per_row: u32,
+    ) {
+        let mut encoder = render_device.create_command_encoder(&CommandEncoderDescriptor {
+            label: Some("readback_copy"),
+        });
+        encoder.copy_texture_to_buffer(
+            source.as_image_copy(),
+            ImageCopyBuffer {
+                buffer: &self.buffer,
+                layout: ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: Some(bytes_per_row),
+                    rows_per_image: None,
+                },
+            },
+            Extent3d {
+                width: size.x,
+                height: size.y,
+                depth_or_array_layers: 1,
+            },
+        );
+        self.map(render_queue, encoder);
+    }
+
+    fn map(&mut self, render_queue: &RenderQueue, encoder: CommandEncoder) {
+        render_queue.submit([encoder.finish()]);
+
+        let mapped = self.mapped.clone();
+        self.buffer
+            .slice(..)
+            .map_async(MapMode::Read, move |outcome| {
+                *mapped.lock().expect("Could not get map state out of mutex") = Some(outcome);
+            });
+        self.pending = true;
+    }
+
+    // The copied bytes once they are mapped, None while they are still on their way
+    pub fn poll(
+        &mut self,
+        render_device: &RenderDevice,
+    ) -> Option<Result<Vec<u8>, BufferAsyncError>> {
+        if !self.pending {
+            return None;
+        }
+
+        render_device.poll(Maintain::Poll);
+        let outcome = self
+            .mapped
+            .lock()
+            .expect("Could not get map state out of mutex")
+            .take()?;
+        self.pending = false;
+
+        Some(outcome.map(|()| {
+            let bytes = self.buffer.slice(..).get_mapped_range().to_vec();
+            self.buffer.unmap();
+            bytes
+        }))
+    }
+}
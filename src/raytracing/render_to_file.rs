@@ -0,0 +1,713 @@
+use std::{
+    path::PathBuf,
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+use bevy::{
+    core_pipeline::{tonemapping::Tonemapping, Skybox},
+    ecs::{entity::EntityHashMap, query::QueryItem},
+    pbr::{environment_map::EnvironmentMapLight, FogSettings},
+    prelude::*,
+    render::{
+        camera::{Exposure, RenderTarget},
+        extract_component::{ExtractComponent, ExtractComponentPlugin},
+        render_asset::{RenderAssetUsages, RenderAssets},
+        render_resource::{Extent3d, TextureDimension, TextureFormat, TextureUsages},
+        renderer::{RenderDevice, RenderQueue},
+        texture::GpuImage,
+        view::{screenshot::ScreenshotManager, ColorGrading},
+        Extract, ExtractSchedule, Render, RenderApp, RenderSet,
+    },
+    transform::TransformSystem,
+    window::{PrimaryWindow, WindowRef},
+};
+
+use super::{
+    readback::Readback, CameraCut, RaytraceClipPlanes, RaytraceDepthOfField, RaytraceMode,
+    RaytraceRayMasks, RaytraceSampling, RaytraceTiling, RaytracedCamera, Raytracing,
+    RenderFinished,
+};
+
+// Renders bigger than this get split into tiles that are traced one after another. Well below the texture size limit
+// of every device, and it keeps the accumulation textures of a tile from taking up too much memory
+const MAX_TILE_SIZE: u32 = 4096;
+
+pub struct RaytraceRenderToFilePlugin;
+
+impl Plugin for RaytraceRenderToFilePlugin {
+    fn build(&self, app: &mut App) {
+        // Both worlds share the finished tiles, the render world reads them back and the main world puts them together
+        let finished_tiles = FinishedTiles::default();
+        app.init_resource::<SavedRenders>()
+            .insert_resource(finished_tiles.clone())
+            .add_event::<RenderSaved>()
+            .add_plugins(ExtractComponentPlugin::<RenderTile>::default())
+            .observe(start_render_to_file)
+            .add_systems(
+                Update,
+                (
+                    save_finished_renders,
+                    finish_saved_renders,
+                    read_back_finished_tiles,
+                    collect_finished_tiles,
+                ),
+            )
+            .add_systems(
+                PostUpdate,
+                hold_render_camera.before(TransformSystem::TransformPropagate),
+            );
+
+        let Some(render_app) = app.get_sub_app_mut(RenderApp) else {
+            return;
+        };
+
+        render_app
+            .insert_resource(finished_tiles)
+            .init_resource::<TileReadbacks>()
+            .add_systems(ExtractSchedule, extract_tile_readbacks)
+            // Once the frame with the finished tile got submitted
+            .add_systems(Render, read_back_tiles.in_set(RenderSet::Cleanup));
+    }
+}
+
+// Accumulates `samples` per pixel for `camera`, saves what it shows into `path` and sends a `RenderSaved` event.
+// The format follows the extension of the path. Until then the camera stays where it was, so nothing moving it restarts the render,
+// and afterwards it goes back to its old sampling. Only cameras that render to a window can be saved.
+// With a `resolution` the window is left alone, a temporary camera with the settings of this one renders the image
+// into a texture of that size instead, in tiles if it is too big for one. It takes the lens, fog, exposure, color grading,
+// tonemapping, environment, ray masks, clip planes and tiling of the camera along, and works for cameras that render to images as well
+#[derive(Event, Clone, Debug)]
+pub struct RenderToFile {
+    pub camera: Entity,
+    pub samples: u32,
+    pub path: PathBuf,
+    pub resolution: Option<UVec2>,
+}
+
+// Sent once the image of a `RenderToFile` was written, or with the reason it couldn't be
+#[derive(Event, Clone, Debug)]
+pub struct RenderSaved {
+    pub camera: Entity,
+    pub path: PathBuf,
+    pub result: Result<(), String>,
+}
+
+// On cameras while their render to a file is in progress
+#[derive(Component)]
+struct PendingRender {
+    path: PathBuf,
+    transform: Transform,
+    // What the camera goes back to once the image is saved
+    sample_count: u32,
+    sampling: RaytraceSampling,
+    finished: bool,
+    screenshot_requested: bool,
+}
+
+// On the temporary camera of a `RenderToFile` with a resolution, which traces one tile after another
+#[derive(Component)]
+struct OffscreenRender {
+    // The camera the render was requested for
+    camera: Entity,
+    path: PathBuf,
+    size: UVec2,
+    tiles: UVec2,
+    tile_size: UVec2,
+    // Row by row from the top left
+    tile: u32,
+    // The tile being traced renders into this, it gets reused for all of them
+    target: Handle<Image>,
+    // Rgba8 of the whole image, the tiles get copied in as they finish
+    pixels: Vec<u8>,
+    reading_back: bool,
+}
+
+impl OffscreenRender {
+    // The pixels the current tile covers, the last ones can reach past the image
+    fn tile_rect(&self) -> URect {
+        let min = UVec2::new(self.tile % self.tiles.x, self.tile / self.tiles.x) * self.tile_size;
+        URect::from_corners(min, min + self.tile_size)
+    }
+
+    fn render_tile(&self) -> RenderTile {
+        let rect = self.tile_rect().as_rect();
+        let size = self.size.as_vec2();
+        // Clip space has y going up, the rows of the image go down
+        let to_clip =
+            |pixel: Vec2| Vec2::new(pixel.x / size.x * 2.0 - 1.0, 1.0 - pixel.y / size.y * 2.0);
+        let (min, max) = (to_clip(rect.min), to_clip(rect.max));
+        RenderTile {
+            center: (min + max) / 2.0,
+            half_size: (max - min).abs() / 2.0,
+        }
+    }
+
+    // Rows of `tile_size` texels, of which only the ones inside the image are kept
+    fn copy_tile(&mut self, pixels: &[u8]) {
+        let rect = self.tile_rect();
+        let width = (rect.max.x.min(self.size.x) - rect.min.x) as usize * 4;
+        for (row, y) in (rect.min.y..rect.max.y.min(self.size.y)).enumerate() {
+            let source = row * self.tile_size.x as usize * 4;
+            let target = (y * self.size.x + rect.min.x) as usize * 4;
+            self.pixels[target..target + width].copy_from_slice(&pixels[source..source + width]);
+        }
+    }
+
+    fn save(&mut self) -> Result<(), String> {
+        let image = Image::new(
+            Extent3d {
+                width: self.size.x,
+                height: self.size.y,
+                depth_or_array_layers: 1,
+            },
+            TextureDimension::D2,
+            std::mem::take(&mut self.pixels),
+            TextureFormat::Rgba8UnormSrgb,
+            RenderAssetUsages::MAIN_WORLD,
+        );
+        image
+            .try_into_dynamic()
+            .map_err(|error| error.to_string())?
+            .to_rgb8()
+            .save(&self.path)
+            .map_err(|error| error.to_string())
+    }
+}
+
+// The part of the clip space of a whole `RenderToFile` image the camera traces, the rays of its tile go through it
+#[derive(Component, ExtractComponent, Clone, Copy)]
+pub(super) struct RenderTile {
+    center: Vec2,
+    half_size: Vec2,
+}
+
+impl RenderTile {
+    // Goes after the projection, so the clip space of the camera only covers its tile
+    pub(super) fn tile_from_image(&self) -> Mat4 {
+        Mat4::from_scale(self.half_size.recip().extend(1.0))
+            * Mat4::from_translation(-self.center.extend(0.0))
+    }
+}
+
+// Filled in by the screenshot callbacks
+#[derive(Resource, Clone, Default)]
+struct SavedRenders(Arc<Mutex<Vec<RenderSaved>>>);
+
+// The rgba8 pixels of every tile the render world read back, or why it couldn't
+struct FinishedTile {
+    camera: Entity,
+    tile: u32,
+    pixels: Result<Vec<u8>, String>,
+}
+
+#[derive(Resource, Clone, Default)]
+struct FinishedTiles(Arc<Mutex<Vec<FinishedTile>>>);
+
+// Render world, the tile every offscreen render copies back last
+#[derive(Resource, Default)]
+struct TileReadbacks(EntityHashMap<TileReadback>);
+
+struct TileReadback {
+    tile: u32,
+    target: AssetId<Image>,
+    size: UVec2,
+    // Until the copy got started
+    readback: Option<Readback>,
+    done: bool,
+}
+
+impl FinishedTiles {
+    fn lock(&self) -> MutexGuard<'_, Vec<FinishedTile>> {
+        self.0
+            .lock()
+            .expect("Could not get finished tiles out of mutex")
+    }
+}
+
+impl SavedRenders {
+    fn lock(&self) -> MutexGuard<'_, Vec<RenderSaved>> {
+        self.0
+            .lock()
+            .expect("Could not get saved renders out of mutex")
+    }
+}
+
+fn start_render_to_file(
+    trigger: Trigger<RenderToFile>,
+    mut cameras: Query<(
+        &mut RaytracedCamera,
+        &Camera,
+        &Transform,
+        Has<PendingRender>,
+    )>,
+    sources: Query<(
+        &GlobalTransform,
+        &Projection,
+        Option<&Camera3d>,
+        CameraSettings,
+    )>,
+    mut images: ResMut<Assets<Image>>,
+    mut saved: EventWriter<RenderSaved>,
+    mut commands: Commands,
+) {
+    let request = trigger.event();
+    let fail = |reason: &str| RenderSaved {
+        camera: request.camera,
+        path: request.path.clone(),
+        result: Err(reason.to_string()),
+    };
+
+    let Ok((mut settings, camera, transform, pending)) = cameras.get_mut(request.camera) else {
+        saved.send(fail("not a raytraced camera"));
+        return;
+    };
+    if pending {
+        saved.send(fail("the camera is already rendering to a file"));
+        return;
+    }
+
+    // Traced with settings of its own, the camera itself keeps going
+    if let Some(size) = request.resolution {
+        let Ok((transform, projection, camera_3d, settings)) = sources.get(request.camera) else {
+            saved.send(fail("not a raytraced camera"));
+            return;
+        };
+        if size.min_element() == 0 {
+            saved.send(fail("the resolution is empty"));
+            return;
+        }
+
+        spawn_offscreen_render(
+            request,
+            size,
+            camera,
+            transform,
+            projection,
+            camera_3d,
+            settings,
+            &mut images,
+            &mut commands,
+        );
+        return;
+    }
+
+    if !matches!(camera.target, RenderTarget::Window(_)) {
+        saved.send(fail("the camera doesn't render to a window"));
+        return;
+    }
+
+    commands.entity(request.camera).insert(PendingRender {
+        path: request.path.clone(),
+        transform: *transform,
+        sample_count: settings.sample_count,
+        sampling: settings.sampling,
+        finished: false,
+        screenshot_requested: false,
+    });
+
+    // Keeps tracing as many samples per frame as before
+    let samples_per_frame = match settings.sampling {
+        RaytraceSampling::Progressive { samples_per_frame } => samples_per_frame,
+        RaytraceSampling::EveryFrame | RaytraceSampling::Checkerboard => settings.sample_count,
+    };
+    settings.set_mode(RaytraceMode::Final {
+        samples: request.samples,
+        samples_per_frame,
+    });
+}
+
+// The components of a camera that change how it traces and how the result is displayed
+type CameraSettings = (
+    &'static RaytracedCamera,
+    Option<&'static Exposure>,
+    Option<&'static Tonemapping>,
+    Option<&'static ColorGrading>,
+    Option<&'static Skybox>,
+    Option<&'static EnvironmentMapLight>,
+    Option<&'static FogSettings>,
+    Option<&'static RaytraceDepthOfField>,
+    Option<&'static RaytraceRayMasks>,
+    Option<&'static RaytraceClipPlanes>,
+    Option<&'static RaytraceTiling>,
+);
+
+#[allow(clippy::too_many_arguments)]
+fn spawn_offscreen_render(
+    request: &RenderToFile,
+    size: UVec2,
+    camera: &Camera,
+    transform: &GlobalTransform,
+    projection: &Projection,
+    camera_3d: Option<&Camera3d>,
+    settings: QueryItem<'_, CameraSettings>,
+    images: &mut Assets<Image>,
+    commands: &mut Commands,
+) {
+    let (
+        settings,
+        exposure,
+        tonemapping,
+        color_grading,
+        skybox,
+        environment,
+        fog,
+        lens,
+        ray_masks,
+        clip_planes,
+        tiling,
+    ) = settings;
+    let tiles = UVec2::new(
+        size.x.div_ceil(MAX_TILE_SIZE),
+        size.y.div_ceil(MAX_TILE_SIZE),
+    );
+    let tile_size = UVec2::new(size.x.div_ceil(tiles.x), size.y.div_ceil(tiles.y));
+
+    let mut target = Image::new_fill(
+        Extent3d {
+            width: tile_size.x,
+            height: tile_size.y,
+            depth_or_array_layers: 1,
+        },
+        TextureDimension::D2,
+        &[0; 4],
+        TextureFormat::Rgba8UnormSrgb,
+        RenderAssetUsages::RENDER_WORLD,
+    );
+    target.texture_descriptor.usage |= TextureUsages::RENDER_ATTACHMENT | TextureUsages::COPY_SRC;
+    let target = images.add(target);
+
+    // The whole image keeps the aspect ratio of the resolution, the tiles only trace their part of it
+    let mut projection = projection.clone();
+    if let Projection::Perspective(perspective) = &mut projection {
+        perspective.aspect_ratio = size.x as f32 / size.y as f32;
+    }
+
+    let mut settings = *settings;
+    let samples_per_frame = match settings.sampling {
+        RaytraceSampling::Progressive { samples_per_frame } => samples_per_frame,
+        RaytraceSampling::EveryFrame | RaytraceSampling::Checkerboard => settings.sample_count,
+    };
+    settings.set_mode(RaytraceMode::Final {
+        samples: request.samples,
+        samples_per_frame,
+    });
+    // The rasterized fallbacks would cover the whole image in every tile
+    if tiles != UVec2::ONE {
+        settings.level = Raytracing::Pure;
+    }
+
+    let render = OffscreenRender {
+        camera: request.camera,
+        path: request.path.clone(),
+        size,
+        tiles,
+        tile_size,
+        tile: 0,
+        target: target.clone(),
+        pixels: vec![0; size.element_product() as usize * 4],
+        reading_back: false,
+    };
+    let mut entity = commands.spawn((
+        Camera3dBundle {
+            camera: Camera {
+                target: RenderTarget::Image(target),
+                hdr: camera.hdr,
+                clear_color: camera.clear_color,
+                // After the camera it was requested for, which keeps deciding the `RenderOrigin`
+                order: camera.order + 1,
+                ..default()
+            },
+            camera_3d: camera_3d.cloned().unwrap_or_default(),
+            projection,
+            transform: transform.compute_transform(),
+            tonemapping: tonemapping.copied().unwrap_or_default(),
+            color_grading: color_grading.cloned().unwrap_or_default(),
+            exposure: exposure.copied().unwrap_or_default(),
+            ..default()
+        },
+        settings,
+        // Offline renders trace many samples per frame into big targets
+        tiling.copied().unwrap_or_default(),
+        render.render_tile(),
+        render,
+        Name::new("Render To File"),
+    ));
+    if let Some(skybox) = skybox {
+        entity.insert(skybox.clone());
+    }
+    if let Some(environment) = environment {
+        entity.insert(environment.clone());
+    }
+    if let Some(fog) = fog {
+        entity.insert(fog.clone());
+    }
+    if let Some(lens) = lens {
+        entity.insert(*lens);
+    }
+    if let Some(ray_masks) = ray_masks {
+        entity.insert(*ray_masks);
+    }
+    if let Some(clip_planes) = clip_planes {
+        entity.insert(clip_planes.clone());
+    }
+}
+
+// Undoes whatever moved the camera before its transform gets propagated
+fn hold_render_camera(mut cameras: Query<(&mut Transform, &PendingRender)>) {
+    for (mut transform, pending) in &mut cameras {
+        transform.set_if_neq(pending.transform);
+    }
+}
+
+// Converged cameras keep showing their image, so the screenshot of the next frame has all samples in it
+fn save_finished_renders(
+    mut finished: EventReader<RenderFinished>,
+    mut cameras: Query<(Entity, &Camera, &mut PendingRender)>,
+    primary_window: Query<Entity, With<PrimaryWindow>>,
+    mut screenshots: ResMut<ScreenshotManager>,
+    saved: Res<SavedRenders>,
+) {
+    for event in finished.read() {
+        if let Ok((_, _, mut pending)) = cameras.get_mut(event.camera) {
+            pending.finished = true;
+        }
+    }
+
+    for (entity, camera, mut pending) in &mut cameras {
+        if !pending.finished || pending.screenshot_requested {
+            continue;
+        }
+
+        let window = match camera.target {
+            RenderTarget::Window(WindowRef::Primary) => primary_window.get_single().ok(),
+            RenderTarget::Window(WindowRef::Entity(window)) => Some(window),
+            _ => None,
+        };
+        let Some(window) = window else {
+            continue;
+        };
+
+        let (camera, path, shared) = (entity, pending.path.clone(), saved.clone());
+        let requested = screenshots.take_screenshot(window, move |image| {
+            // Like `ScreenshotManager::save_screenshot_to_disk`, without the alpha that holds brightness for hdr windows
+            let result = image
+                .try_into_dynamic()
+                .map_err(|error| error.to_string())
+                .and_then(|image| {
+                    image
+                        .to_rgb8()
+                        .save(&path)
+                        .map_err(|error| error.to_string())
+                });
+            shared.lock().push(RenderSaved {
+                camera,
+                path,
+                result,
+            });
+        });
+        // Another screenshot of the window is in the way, the next frame tries again
+        pending.screenshot_requested = requested.is_ok();
+    }
+}
+
+fn finish_saved_renders(
+    saved: Res<SavedRenders>,
+    mut cameras: Query<(&mut RaytracedCamera, &PendingRender)>,
+    mut events: EventWriter<RenderSaved>,
+    mut commands: Commands,
+) {
+    for render in saved.lock().drain(..) {
+        if let Ok((mut settings, pending)) = cameras.get_mut(render.camera) {
+            settings.sample_count = pending.sample_count;
+            settings.sampling = pending.sampling;
+            commands.entity(render.camera).remove::<PendingRender>();
+        }
+        events.send(render);
+    }
+}
+
+fn read_back_finished_tiles(
+    mut finished: EventReader<RenderFinished>,
+    mut renders: Query<&mut OffscreenRender>,
+) {
+    for event in finished.read() {
+        if let Ok(mut render) = renders.get_mut(event.camera) {
+            render.reading_back = true;
+        }
+    }
+}
+
+// Puts every tile that arrived into the image, then traces the next one or saves it and cleans up
+fn collect_finished_tiles(
+    finished: Res<FinishedTiles>,
+    mut renders: Query<(&mut OffscreenRender, &mut RenderTile)>,
+    mut images: ResMut<Assets<Image>>,
+    mut saved: EventWriter<RenderSaved>,
+    mut cuts: EventWriter<CameraCut>,
+    mut commands: Commands,
+) {
+    for tile in finished.lock().drain(..) {
+        let Ok((mut render, mut render_tile)) = renders.get_mut(tile.camera) else {
+            continue;
+        };
+        if tile.tile != render.tile {
+            continue;
+        }
+
+        let result = match tile.pixels {
+            Ok(pixels) => {
+                render.copy_tile(&pixels);
+                if render.tile + 1 < render.tiles.element_product() {
+                    render.tile += 1;
+                    render.reading_back = false;
+                    *render_tile = render.render_tile();
+                    cuts.send(CameraCut {
+                        camera: tile.camera,
+                    });
+                    continue;
+                }
+                render.save()
+            }
+            Err(reason) => Err(reason),
+        };
+
+        saved.send(RenderSaved {
+            camera: render.camera,
+            path: render.path.clone(),
+            result,
+        });
+        images.remove(&render.target);
+        commands.entity(tile.camera).despawn_recursive();
+    }
+}
+
+fn extract_tile_readbacks(
+    renders: Extract<Query<(Entity, &OffscreenRender)>>,
+    mut readbacks: ResMut<TileReadbacks>,
+) {
+    readbacks.0.retain(|entity, _| renders.contains(*entity));
+    for (entity, render) in &renders {
+        if !render.reading_back
+            || readbacks
+                .0
+                .get(&entity)
+                .is_some_and(|readback| readback.tile == render.tile)
+        {
+            continue;
+        }
+
+        readbacks.0.insert(
+            entity,
+            TileReadback {
+                tile: render.tile,
+                target: render.target.id(),
+                size: render.tile_size,
+                readback: None,
+                done: false,
+            },
+        );
+    }
+}
+
+// The rows of the copy are padded to the alignment and get cut back to the tile
+fn read_back_tiles(
+    mut readbacks: ResMut<TileReadbacks>,
+    images: Res<RenderAssets<GpuImage>>,
+    render_device: Res<RenderDevice>,
+    render_queue: Res<RenderQueue>,
+    finished: Res<FinishedTiles>,
+) {
+    for (entity, tile) in &mut readbacks.0 {
+        if tile.done {
+            continue;
+        }
+
+        let row_size = tile.size.x * 4;
+        let bytes_per_row = RenderDevice::align_copy_bytes_per_row(row_size as usize) as u32;
+        let Some(readback) = &mut tile.readback else {
+            let Some(image) = images.get(tile.target) else {
+                continue;
+            };
+            let mut readback = Readback::new(
+                &render_device,
+                "render_to_file_readback",
+                bytes_per_row as u64 * tile.size.y as u64,
+            );
+            readback.start_texture(
+                &render_device,
+                &render_queue,
+                &image.texture,
+                tile.size,
+                bytes_per_row,
+            );
+            tile.readback = Some(readback);
+            continue;
+        };
+        let Some(outcome) = readback.poll(&render_device) else {
+            continue;
+        };
+
+        let pixels = outcome
+            .map(|bytes| {
+                bytes
+                    .chunks(bytes_per_row as usize)
+                    .flat_map(|row| &row[..row_size as usize])
+                    .copied()
+                    .collect()
+            })
+            .map_err(|error| error.to_string());
+        finished.lock().push(FinishedTile {
+            camera: *entity,
+            tile: tile.tile,
+            pixels,
+        });
+        // The buffer of a big tile is worth letting go of early
+        tile.readback = None;
+        tile.done = true;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use bevy::prelude::*;
+
+    use super::OffscreenRender;
+
+    #[test]
+    fn tiles_cover_the_image() {
+        // Two by two tiles of 3x2 pixels, the right ones reach a pixel past the image
+        let mut render = OffscreenRender {
+            camera: Entity::PLACEHOLDER,
+            path: PathBuf::new(),
+            size: UVec2::new(5, 4),
+            tiles: UVec2::new(2, 2),
+            tile_size: UVec2::new(3, 2),
+            tile: 0,
+            target: Handle::default(),
+            pixels: vec![0; 5 * 4 * 4],
+            reading_back: false,
+        };
+
+        // The top left tile ends at 3 of 5 pixels from the left and halfway down
+        let tile = render.render_tile().tile_from_image();
+        let corner = tile.transform_point3(Vec3::new(-1.0, 1.0, 0.5));
+        assert!(corner.abs_diff_eq(Vec3::new(-1.0, 1.0, 0.5), 1e-5));
+        let corner = tile.transform_point3(Vec3::new(0.2, 0.0, 0.5));
+        assert!(corner.abs_diff_eq(Vec3::new(1.0, -1.0, 0.5), 1e-5));
+
+        for tile in 0..4 {
+            render.tile = tile;
+            render.copy_tile(&[tile as u8 + 1; 3 * 2 * 4]);
+        }
+        let row = |y: usize| {
+            render.pixels[y * 20..(y + 1) * 20]
+                .chunks(4)
+                .map(|pixel| pixel[0])
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(row(0), vec![1, 1, 1, 2, 2]);
+        assert_eq!(row(3), vec![3, 3, 3, 4, 4]);
+    }
+}
@@ -0,0 +1,127 @@
+use bevy::{
+    prelude::*,
+    render::{
+        render_resource::{ShaderType, UniformBuffer},
+        renderer::{RenderDevice, RenderQueue},
+        Extract, ExtractSchedule, Render, RenderApp, RenderSet,
+    },
+};
+
+pub struct RaytraceSkyPlugin;
+
+impl Plugin for RaytraceSkyPlugin {
+    fn build(&self, app: &mut App) {
+        app.register_type::<RaytraceSky>()
+            .register_type::<RaytraceWhiteFurnace>()
+            .init_resource::<RaytraceSky>()
+            .init_resource::<RaytraceWhiteFurnace>();
+
+        let Some(render_app) = app.get_sub_app_mut(RenderApp) else {
+            return;
+        };
+
+        render_app
+            .init_resource::<SkyBuffer>()
+            .add_systems(ExtractSchedule, extract_sky)
+            .add_systems(Render, prepare_sky.in_set(RenderSet::PrepareResources));
+    }
+}
+
+// What rays that leave the scene see, this is the only light source of the path tracer
+#[derive(Resource, Reflect, Clone, Default)]
+#[reflect(Resource)]
+pub enum RaytraceSky {
+    // The simple blue to white gradient
+    #[default]
+    Gradient,
+    // Single scattering Rayleigh and Mie atmosphere of an earth like planet, lit by the sun
+    Atmosphere {
+        // Points towards the sun
+        sun_direction: Vec3,
+        sun_intensity: f32,
+        // Meters of air per world unit for the haze in front of distant surfaces, 0.0 turns it off
+        aerial_perspective_scale: f32,
+    },
+}
+
+impl RaytraceSky {
+    pub fn atmosphere(sun_direction: Vec3) -> Self {
+        Self::Atmosphere {
+            sun_direction,
+            sun_intensity: 20.0,
+            aerial_perspective_scale: 100.0,
+        }
+    }
+}
+
+// Validation mode for the BSDFs, every material gets an albedo of 1 and the sky is replaced by uniform light of `radiance`.
+// Lights, caustics, fog colors and the environment map are left out, so a scene that conserves energy renders as flat as the background.
+// Anything darker or brighter than the background is energy the BSDF loses or gains, apart from paths cut off after the last bounce
+#[derive(Resource, Reflect, Clone)]
+#[reflect(Resource)]
+pub struct RaytraceWhiteFurnace {
+    pub enabled: bool,
+    pub radiance: f32,
+}
+
+impl Default for RaytraceWhiteFurnace {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            radiance: 1.0,
+        }
+    }
+}
+
+#[derive(Clone, Default, ShaderType)]
+pub struct SkyUniform {
+    sun_direction: Vec3,
+    sun_intensity: f32,
+    aerial_perspective_scale: f32,
+    // 0 -> gradient; 1 -> atmosphere
+    model: u32,
+    // The radiance of the white furnace, 0.0 while it is off
+    furnace_radiance: f32,
+}
+
+// Bound with the geometry, so every pass that traces the scene sees the same sky
+#[derive(Resource, Default, Deref, DerefMut)]
+pub struct SkyBuffer(UniformBuffer<SkyUniform>);
+
+fn extract_sky(
+    mut sky_buffer: ResMut<SkyBuffer>,
+    sky: Extract<Res<RaytraceSky>>,
+    furnace: Extract<Res<RaytraceWhiteFurnace>>,
+) {
+    let furnace_radiance = if furnace.enabled {
+        // Kept above 0.0, which would turn it off
+        furnace.radiance.max(f32::MIN_POSITIVE)
+    } else {
+        0.0
+    };
+    sky_buffer.set(match **sky {
+        RaytraceSky::Gradient => SkyUniform {
+            furnace_radiance,
+            ..default()
+        },
+        RaytraceSky::Atmosphere {
+            sun_direction,
+            sun_intensity,
+            aerial_perspective_scale,
+        } => SkyUniform {
+            sun_direction: sun_direction.normalize_or(Vec3::Y),
+            sun_intensity,
+            aerial_perspective_scale,
+            model: 1,
+            furnace_radiance,
+        },
+    });
+}
+
+fn prepare_sky(
+    mut sky_buffer: ResMut<SkyBuffer>,
+    render_device: Res<RenderDevice>,
+    render_queue: Res<RenderQueue>,
+) {
+    sky_buffer.write_buffer(&render_device, &render_queue);
+}
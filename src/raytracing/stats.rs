@@ -0,0 +1,126 @@
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use bevy::{
+    prelude::*,
+    render::{camera::ExtractedCamera, Render, RenderApp, RenderSet},
+};
+
+use super::{
+    bvh::BvhCache,
+    extract::{prepare_buffers, CameraExtract, PipelineExtract},
+};
+
+pub struct RaytraceStatsPlugin;
+
+impl Plugin for RaytraceStatsPlugin {
+    fn build(&self, app: &mut App) {
+        // Both worlds share the stats, the render world fills them in and the main world takes them every frame
+        let pending = PendingFrameStats::default();
+        app.insert_resource(pending.clone())
+            .register_type::<RaytraceFrameStats>()
+            .init_resource::<RaytraceFrameStats>()
+            .add_event::<RaytraceFrameStats>()
+            .add_systems(First, update_frame_stats);
+
+        let Some(render_app) = app.get_sub_app_mut(RenderApp) else {
+            return;
+        };
+
+        render_app.insert_resource(pending).add_systems(
+            Render,
+            record_frame_stats
+                .in_set(RenderSet::PrepareResources)
+                .after(prepare_buffers),
+        );
+    }
+}
+
+// What the tracer did in a recent frame, for overlays and profiling. Kept as a resource and also sent as an event
+// for every rendered frame. Filled in by the render world, so it lags a frame behind with pipelined rendering
+#[derive(Event, Resource, Reflect, Clone, Default, PartialEq, Debug)]
+#[reflect(Resource)]
+pub struct RaytraceFrameStats {
+    // The longest path from the root of the BVH to a leaf, 0 for an empty scene
+    pub bvh_depth: u32,
+    pub cameras: Vec<CameraFrameStats>,
+}
+
+#[derive(Reflect, Clone, Copy, PartialEq, Debug)]
+pub struct CameraFrameStats {
+    pub camera: Entity,
+    // An estimate from the pixels, samples and bounces, assuming every path bounces as often as it may with a shadow ray at every hit
+    pub rays: u64,
+    // The samples per pixel in the image, all accumulated ones for progressive cameras
+    pub samples: u32,
+    // The accumulation started over in this frame, or the last frame of a checkerboard camera was thrown away
+    pub reset: bool,
+}
+
+impl RaytraceFrameStats {
+    pub fn get(&self, camera: Entity) -> Option<&CameraFrameStats> {
+        self.cameras.iter().find(|stats| stats.camera == camera)
+    }
+
+    pub fn total_rays(&self) -> u64 {
+        self.cameras.iter().map(|stats| stats.rays).sum()
+    }
+}
+
+// Holds the stats of the last rendered frame until the main world takes them
+#[derive(Resource, Clone, Default)]
+struct PendingFrameStats(Arc<Mutex<Option<RaytraceFrameStats>>>);
+
+impl PendingFrameStats {
+    fn lock(&self) -> MutexGuard<'_, Option<RaytraceFrameStats>> {
+        self.0
+            .lock()
+            .expect("Could not get frame stats out of mutex")
+    }
+}
+
+fn update_frame_stats(
+    pending: Res<PendingFrameStats>,
+    mut stats: ResMut<RaytraceFrameStats>,
+    mut events: EventWriter<RaytraceFrameStats>,
+) {
+    let Some(frame) = pending.lock().take() else {
+        return;
+    };
+
+    events.send(frame.clone());
+    stats.set_if_neq(frame);
+}
+
+// Runs after the accumulation decided what every camera traces this frame and the BVH was built
+fn record_frame_stats(
+    pending: Res<PendingFrameStats>,
+    cameras: Query<(Entity, &CameraExtract, &PipelineExtract, &ExtractedCamera)>,
+    bvh_cache: Res<BvhCache>,
+) {
+    let mut frame = RaytraceFrameStats {
+        bvh_depth: bvh_cache.depth(),
+        cameras: cameras
+            .iter()
+            .map(
+                |(camera, extract, pipeline, extracted_camera)| CameraFrameStats {
+                    camera,
+                    rays: extracted_camera
+                        .physical_target_size
+                        .map_or(0, |size| extract.estimated_rays(size, pipeline.bounces)),
+                    samples: extract.samples(),
+                    reset: extract.starts_over(),
+                },
+            )
+            .collect(),
+    };
+    frame.cameras.sort_by_key(|stats| stats.camera);
+
+    // A reset in a frame the main world didn't take yet shouldn't get lost
+    let mut pending = pending.lock();
+    if let Some(untaken) = pending.as_ref() {
+        for stats in &mut frame.cameras {
+            stats.reset |= untaken.get(stats.camera).is_some_and(|stats| stats.reset);
+        }
+    }
+    *pending = Some(frame);
+}
@@ -0,0 +1,277 @@
+use std::path::PathBuf;
+
+use bevy::prelude::*;
+use bevy_egui::{egui, EguiContexts, EguiPlugin};
+
+use super::{
+    CameraCut, RaytraceConvergenceOverlay, RaytraceNanDebug, RaytraceNanReport,
+    RaytracePathDebugger, RaytracePaused, RaytraceProgress, RaytraceSampling, RaytraceWhiteFurnace,
+    RaytracedCamera, Raytracing, RenderToFile,
+};
+
+// An egui window with the settings of every raytraced camera and the debug views, behind the `ui` feature.
+// Works next to the world inspector, whichever comes first adds the `EguiPlugin`
+pub struct RaytraceUiPlugin;
+
+impl Plugin for RaytraceUiPlugin {
+    fn build(&self, app: &mut App) {
+        if !app.is_plugin_added::<EguiPlugin>() {
+            app.add_plugins(EguiPlugin);
+        }
+
+        app.init_resource::<RaytraceUiState>()
+            .add_systems(Update, draw_raytrace_panel);
+    }
+}
+
+// What the panel remembers between frames
+#[derive(Resource)]
+struct RaytraceUiState {
+    render_path: String,
+    render_samples: u32,
+}
+
+impl Default for RaytraceUiState {
+    fn default() -> Self {
+        Self {
+            render_path: "render.png".to_string(),
+            render_samples: 1024,
+        }
+    }
+}
+
+// Edits go through `bypass_change_detection`, so only actual changes restart the accumulation
+#[allow(clippy::too_many_arguments)]
+fn draw_raytrace_panel(
+    mut contexts: EguiContexts,
+    mut cameras: Query<(
+        Entity,
+        &mut RaytracedCamera,
+        Option<&Name>,
+        Has<RaytracePaused>,
+        Option<&RaytraceConvergenceOverlay>,
+    )>,
+    mut nan_debug: ResMut<RaytraceNanDebug>,
+    mut path_debugger: ResMut<RaytracePathDebugger>,
+    mut furnace: ResMut<RaytraceWhiteFurnace>,
+    nan_report: Res<RaytraceNanReport>,
+    progress: Res<RaytraceProgress>,
+    mut state: ResMut<RaytraceUiState>,
+    mut cuts: EventWriter<CameraCut>,
+    mut commands: Commands,
+) {
+    egui::Window::new("Raytracing").show(contexts.ctx_mut(), |ui| {
+        for (entity, mut camera, name, paused, overlay) in &mut cameras {
+            let title = name.map_or_else(|| entity.to_string(), |name| name.to_string());
+            egui::CollapsingHeader::new(title)
+                .id_source(entity)
+                .default_open(true)
+                .show(ui, |ui| {
+                    let settings = camera.bypass_change_detection();
+                    let mut changed = false;
+
+                    egui::ComboBox::from_label("Level")
+                        .selected_text(level_name(settings.level))
+                        .show_ui(ui, |ui| {
+                            for level in [
+                                Raytracing::Skip,
+                                Raytracing::FallbackRaster,
+                                Raytracing::FallbackRaytraced,
+                                Raytracing::Pure,
+                            ] {
+                                changed |= ui
+                                    .selectable_value(&mut settings.level, level, level_name(level))
+                                    .changed();
+                            }
+                        });
+
+                    changed |= ui
+                        .add(
+                            egui::Slider::new(&mut settings.sample_count, 1..=4096)
+                                .logarithmic(true)
+                                .text("Samples"),
+                        )
+                        .changed();
+                    changed |= ui
+                        .add(
+                            egui::Slider::new(
+                                &mut settings.bounces,
+                                0..=RaytracedCamera::MAX_BOUNCES,
+                            )
+                            .text("Bounces"),
+                        )
+                        .changed();
+
+                    egui::ComboBox::from_label("Sampling")
+                        .selected_text(sampling_name(settings.sampling))
+                        .show_ui(ui, |ui| {
+                            for sampling in [
+                                RaytraceSampling::EveryFrame,
+                                RaytraceSampling::Progressive {
+                                    samples_per_frame: 1,
+                                },
+                                RaytraceSampling::Checkerboard,
+                            ] {
+                                let selected = std::mem::discriminant(&settings.sampling)
+                                    == std::mem::discriminant(&sampling);
+                                if ui
+                                    .selectable_label(selected, sampling_name(sampling))
+                                    .clicked()
+                                    && !selected
+                                {
+                                    settings.sampling = sampling;
+                                    changed = true;
+                                }
+                            }
+                        });
+                    if let RaytraceSampling::Progressive { samples_per_frame } =
+                        &mut settings.sampling
+                    {
+                        changed |= ui
+                            .add(
+                                egui::Slider::new(samples_per_frame, 1..=64)
+                                    .text("Samples per frame"),
+                            )
+                            .changed();
+                    }
+
+                    let sample_count = settings.sample_count;
+                    if changed {
+                        camera.set_changed();
+                    }
+
+                    if let Some(progress) = progress.get(entity) {
+                        ui.add(
+                            egui::ProgressBar::new(
+                                progress.samples as f32 / progress.target.max(1) as f32,
+                            )
+                            .text(format!(
+                                "{} / {} samples",
+                                progress.samples, progress.target
+                            )),
+                        );
+                    }
+
+                    ui.horizontal(|ui| {
+                        let mut pause = paused;
+                        if ui.checkbox(&mut pause, "Paused").changed() {
+                            if pause {
+                                commands.entity(entity).insert(RaytracePaused);
+                            } else {
+                                commands.entity(entity).remove::<RaytracePaused>();
+                            }
+                        }
+                        if ui.button("Reset accumulation").clicked() {
+                            cuts.send(CameraCut { camera: entity });
+                        }
+                    });
+
+                    let mut selected = overlay.copied();
+                    egui::ComboBox::from_label("Overlay")
+                        .selected_text(overlay_name(selected))
+                        .show_ui(ui, |ui| {
+                            for option in [
+                                None,
+                                Some(RaytraceConvergenceOverlay::Samples {
+                                    max_samples: sample_count.max(2),
+                                }),
+                                Some(RaytraceConvergenceOverlay::Error { max_error: 0.1 }),
+                            ] {
+                                let is_selected = selected
+                                    .map(|overlay| std::mem::discriminant(&overlay))
+                                    == option.map(|option| std::mem::discriminant(&option));
+                                if ui
+                                    .selectable_label(is_selected, overlay_name(option))
+                                    .clicked()
+                                {
+                                    selected = option;
+                                }
+                            }
+                        });
+                    if selected != overlay.copied() {
+                        match selected {
+                            Some(overlay) => commands.entity(entity).insert(overlay),
+                            None => commands
+                                .entity(entity)
+                                .remove::<RaytraceConvergenceOverlay>(),
+                        };
+                    }
+
+                    ui.horizontal(|ui| {
+                        ui.text_edit_singleline(&mut state.render_path);
+                        ui.add(
+                            egui::DragValue::new(&mut state.render_samples)
+                                .range(1..=65536)
+                                .suffix(" samples"),
+                        );
+                        if ui.button("Render to file").clicked() {
+                            commands.trigger(RenderToFile {
+                                camera: entity,
+                                samples: state.render_samples,
+                                path: PathBuf::from(&state.render_path),
+                                resolution: None,
+                            });
+                        }
+                    });
+                });
+        }
+
+        ui.separator();
+        ui.label("Debug views");
+        toggle(ui, &mut nan_debug, "NaN debugging", |debug| {
+            &mut debug.enabled
+        });
+        if nan_debug.enabled {
+            ui.label(format!(
+                "{} paths with non-finite radiance",
+                nan_report.non_finite_paths
+            ));
+        }
+        toggle(ui, &mut path_debugger, "Path debugger", |debugger| {
+            &mut debugger.enabled
+        });
+        toggle(ui, &mut furnace, "White furnace", |furnace| {
+            &mut furnace.enabled
+        });
+    });
+}
+
+// A checkbox for a flag of a resource that only marks it changed when clicked
+fn toggle<T: Resource>(
+    ui: &mut egui::Ui,
+    resource: &mut ResMut<T>,
+    label: &str,
+    flag: impl FnOnce(&mut T) -> &mut bool,
+) {
+    if ui
+        .checkbox(flag(resource.bypass_change_detection()), label)
+        .changed()
+    {
+        resource.set_changed();
+    }
+}
+
+fn level_name(level: Raytracing) -> &'static str {
+    match level {
+        Raytracing::Skip => "Skip",
+        Raytracing::FallbackRaster => "Fallback raster",
+        Raytracing::FallbackRaytraced => "Fallback raytraced",
+        Raytracing::Pure => "Pure",
+    }
+}
+
+fn sampling_name(sampling: RaytraceSampling) -> &'static str {
+    match sampling {
+        RaytraceSampling::EveryFrame => "Every frame",
+        RaytraceSampling::Progressive { .. } => "Progressive",
+        RaytraceSampling::Checkerboard => "Checkerboard",
+    }
+}
+
+fn overlay_name(overlay: Option<RaytraceConvergenceOverlay>) -> &'static str {
+    match overlay {
+        None => "None",
+        Some(RaytraceConvergenceOverlay::Samples { .. }) => "Samples",
+        Some(RaytraceConvergenceOverlay::Error { .. }) => "Error",
+    }
+}
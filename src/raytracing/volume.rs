@@ -0,0 +1,234 @@
+use bevy::{
+    pbr::{irradiance_volume::IrradianceVolume, LightProbe},
+    prelude::*,
+    render::{
+        render_asset::RenderAssets,
+        render_resource::{
+            BindGroup, BindGroupEntries, Extent3d, FilterMode, Sampler, SamplerDescriptor,
+            ShaderType, TextureDescriptor, TextureDimension, TextureFormat, TextureUsages,
+            TextureView, TextureViewDescriptor, UniformBuffer,
+        },
+        renderer::{RenderDevice, RenderQueue},
+        texture::GpuImage,
+        Extract, ExtractSchedule, Render, RenderApp, RenderSet,
+    },
+};
+
+use super::{origin::RenderOrigin, pipeline::RaytracingPipeline};
+
+pub struct RaytraceVolumePlugin;
+
+impl Plugin for RaytraceVolumePlugin {
+    fn build(&self, app: &mut App) {
+        app.register_type::<RaytraceDensityVolume>();
+
+        let Some(render_app) = app.get_sub_app_mut(RenderApp) else {
+            return;
+        };
+
+        render_app
+            .init_resource::<ExtractedDensityVolume>()
+            .init_resource::<ExtractedIrradianceVolume>()
+            .add_systems(
+                ExtractSchedule,
+                (extract_density_volume, extract_irradiance_volume),
+            )
+            .add_systems(Render, prepare_volumes.in_set(RenderSet::PrepareBindGroups));
+    }
+
+    fn finish(&self, app: &mut App) {
+        let Some(render_app) = app.get_sub_app_mut(RenderApp) else {
+            return;
+        };
+
+        render_app.init_resource::<VolumeBuffers>();
+    }
+}
+
+// A heterogeneous volume like smoke or clouds, with the density read from a 3d texture, for example a baked simulation.
+// The red channel of `density` scales `max_density`, the texture should be filterable (like R8Unorm or R16Float).
+// Like bevy light probes the texture fills a unit cube around the entity, scaled and positioned by its transform.
+// Currently only a single volume is traced at a time.
+#[derive(Component, Reflect, Clone)]
+pub struct RaytraceDensityVolume {
+    pub density: Handle<Image>,
+    pub max_density: f32,
+    // The color the light gets tinted with on every scattering event
+    pub scattering_color: Color,
+}
+
+#[derive(Clone, Default, ShaderType)]
+pub struct DensityVolumeUniform {
+    local_from_world: Mat4,
+    scattering_color: Vec3,
+    max_density: f32,
+    enabled: u32,
+}
+
+#[derive(Resource, Default)]
+pub struct ExtractedDensityVolume {
+    image: Option<AssetId<Image>>,
+    uniform: DensityVolumeUniform,
+}
+
+fn extract_density_volume(
+    mut extracted: ResMut<ExtractedDensityVolume>,
+    volumes: Extract<Query<(&RaytraceDensityVolume, &GlobalTransform)>>,
+    origin: Extract<Res<RenderOrigin>>,
+) {
+    *extracted = match volumes.iter().next() {
+        Some((volume, transform)) => ExtractedDensityVolume {
+            image: Some(volume.density.id()),
+            uniform: DensityVolumeUniform {
+                local_from_world: origin.local_from_render(transform.compute_matrix()),
+                scattering_color: volume.scattering_color.to_linear().to_vec3(),
+                max_density: volume.max_density,
+                enabled: 1,
+            },
+        },
+        None => ExtractedDensityVolume::default(),
+    };
+}
+
+#[derive(Clone, Default, ShaderType)]
+pub struct IrradianceVolumeUniform {
+    local_from_world: Mat4,
+    intensity: f32,
+    enabled: u32,
+}
+
+// The bevy `IrradianceVolume` paths that run out of bounces fall back to, see `PathTermination::irradiance_fallback`
+#[derive(Resource, Default)]
+pub struct ExtractedIrradianceVolume {
+    image: Option<AssetId<Image>>,
+    uniform: IrradianceVolumeUniform,
+}
+
+// Any irradiance volume works, like the ones of a `RaytraceProbeGrid`. Like the density volume only a single one is used
+fn extract_irradiance_volume(
+    mut extracted: ResMut<ExtractedIrradianceVolume>,
+    volumes: Extract<Query<(&IrradianceVolume, &GlobalTransform), With<LightProbe>>>,
+    origin: Extract<Res<RenderOrigin>>,
+) {
+    *extracted = match volumes.iter().next() {
+        Some((volume, transform)) => ExtractedIrradianceVolume {
+            image: Some(volume.voxels.id()),
+            uniform: IrradianceVolumeUniform {
+                local_from_world: origin.local_from_render(transform.compute_matrix()),
+                intensity: volume.intensity,
+                enabled: 1,
+            },
+        },
+        None => ExtractedIrradianceVolume::default(),
+    };
+}
+
+// The density and irradiance volumes share a bind group, there are only so many of them
+#[derive(Resource)]
+pub struct VolumeBuffers {
+    uniform: UniformBuffer<DensityVolumeUniform>,
+    irradiance_uniform: UniformBuffer<IrradianceVolumeUniform>,
+    // Bound while there is no volume or its image isn't loaded yet
+    fallback: TextureView,
+    irradiance_fallback: TextureView,
+    sampler: Sampler,
+    pub(super) bind_group: Option<BindGroup>,
+}
+
+impl FromWorld for VolumeBuffers {
+    fn from_world(world: &mut World) -> Self {
+        let render_device = world.resource::<RenderDevice>();
+
+        let fallback_texture = |label, format| {
+            render_device
+                .create_texture(&TextureDescriptor {
+                    label: Some(label),
+                    size: Extent3d {
+                        width: 1,
+                        height: 1,
+                        depth_or_array_layers: 1,
+                    },
+                    mip_level_count: 1,
+                    sample_count: 1,
+                    dimension: TextureDimension::D3,
+                    format,
+                    usage: TextureUsages::TEXTURE_BINDING,
+                    view_formats: &[],
+                })
+                .create_view(&TextureViewDescriptor::default())
+        };
+        let fallback = fallback_texture("density_volume_fallback", TextureFormat::R8Unorm);
+        let irradiance_fallback =
+            fallback_texture("irradiance_volume_fallback", TextureFormat::Rgba16Float);
+
+        // Linear filtering smooths out the voxels of low resolution volumes
+        let sampler = render_device.create_sampler(&SamplerDescriptor {
+            mag_filter: FilterMode::Linear,
+            min_filter: FilterMode::Linear,
+            ..default()
+        });
+
+        Self {
+            uniform: UniformBuffer::default(),
+            irradiance_uniform: UniformBuffer::default(),
+            fallback,
+            irradiance_fallback,
+            sampler,
+            bind_group: None,
+        }
+    }
+}
+
+fn prepare_volumes(
+    extracted: Res<ExtractedDensityVolume>,
+    extracted_irradiance: Res<ExtractedIrradianceVolume>,
+    mut buffers: ResMut<VolumeBuffers>,
+    raytrace_pipeline: Res<RaytracingPipeline>,
+    images: Res<RenderAssets<GpuImage>>,
+    render_device: Res<RenderDevice>,
+    render_queue: Res<RenderQueue>,
+) {
+    let buffers = &mut *buffers;
+
+    let image = extracted.image.and_then(|image| images.get(image));
+    let mut uniform = extracted.uniform.clone();
+    if image.is_none() {
+        uniform.enabled = 0;
+    }
+
+    buffers.uniform.set(uniform);
+    buffers.uniform.write_buffer(&render_device, &render_queue);
+
+    let irradiance_image = extracted_irradiance
+        .image
+        .and_then(|image| images.get(image));
+    let mut irradiance_uniform = extracted_irradiance.uniform.clone();
+    if irradiance_image.is_none() {
+        irradiance_uniform.enabled = 0;
+    }
+
+    buffers.irradiance_uniform.set(irradiance_uniform);
+    buffers
+        .irradiance_uniform
+        .write_buffer(&render_device, &render_queue);
+
+    let (Some(uniform_binding), Some(irradiance_binding)) = (
+        buffers.uniform.binding(),
+        buffers.irradiance_uniform.binding(),
+    ) else {
+        return;
+    };
+
+    buffers.bind_group = Some(render_device.create_bind_group(
+        "volume_bind_group",
+        &raytrace_pipeline.volume_layout,
+        &BindGroupEntries::sequential((
+            uniform_binding,
+            image.map_or(&buffers.fallback, |image| &image.texture_view),
+            &buffers.sampler,
+            irradiance_binding,
+            irradiance_image.map_or(&buffers.irradiance_fallback, |image| &image.texture_view),
+            &buffers.sampler,
+        )),
+    ));
+}
@@ -0,0 +1,151 @@
+use bevy::{
+    ecs::entity::EntityHashSet,
+    prelude::*,
+    render::{
+        camera::ExtractedCamera,
+        extract_resource::{ExtractResource, ExtractResourcePlugin},
+        renderer::RenderDevice,
+        Render, RenderApp, RenderSet,
+    },
+};
+
+use super::{
+    accumulation::prepare_accumulation,
+    extract::{CameraExtract, PipelineExtract},
+    RaytraceTiling,
+};
+
+// Tiles don't get smaller than this, below it the cost of a pass outweighs what it traces
+const MIN_TILE_SIZE: u32 = 64;
+
+pub struct RaytraceWatchdogPlugin;
+
+impl Plugin for RaytraceWatchdogPlugin {
+    fn build(&self, app: &mut App) {
+        app.register_type::<RaytraceRayBudget>()
+            .init_resource::<RaytraceRayBudget>()
+            .add_plugins(ExtractResourcePlugin::<RaytraceRayBudget>::default());
+
+        let Some(render_app) = app.get_sub_app_mut(RenderApp) else {
+            return;
+        };
+
+        render_app.add_systems(
+            Render,
+            // Once the progressive cameras know how many samples they trace this frame
+            split_raytrace_passes
+                .in_set(RenderSet::Queue)
+                .after(prepare_accumulation),
+        );
+    }
+
+    fn finish(&self, app: &mut App) {
+        let Some(render_app) = app.get_sub_app_mut(RenderApp) else {
+            return;
+        };
+
+        // Nothing can be done about it at that point, but the log should say what likely happened
+        render_app
+            .world()
+            .resource::<RenderDevice>()
+            .wgpu_device()
+            .set_device_lost_callback(|reason, message| {
+                error!("The GPU device was lost ({reason:?}): {message}. Frames that trace for too long can trip the watchdog of the driver, lower RaytraceRayBudget::max_rays_per_pass");
+            });
+    }
+}
+
+// How many rays a single pass of the tracer may trace, estimated like `CameraFrameStats::rays`.
+// A pass that takes too long gets the GPU reset by the driver, which loses the device. Frames above this get traced
+// in tiles, as if the camera had a smaller `RaytraceTiling`, and progressive cameras trace fewer samples per frame
+// when even the smallest tile would be too much. Cameras that trace all their samples every frame can't be split further,
+// they get a warning instead. The default leaves room for slow GPUs, fast ones can go a lot higher
+#[derive(Resource, ExtractResource, Reflect, Clone, Copy, Debug)]
+#[reflect(Resource)]
+pub struct RaytraceRayBudget {
+    pub max_rays_per_pass: u64,
+}
+
+impl Default for RaytraceRayBudget {
+    fn default() -> Self {
+        Self {
+            max_rays_per_pass: 1 << 28,
+        }
+    }
+}
+
+impl RaytraceRayBudget {
+    // The most samples a progressive camera traces in a frame, so a tile of the smallest size stays within the budget
+    pub(super) fn max_frame_samples(&self, bounces: u32) -> u32 {
+        let rays_per_sample = u64::from(MIN_TILE_SIZE * MIN_TILE_SIZE) * u64::from(bounces + 1) * 2;
+        (self.max_rays_per_pass / rays_per_sample).clamp(1, u64::from(u32::MAX)) as u32
+    }
+
+    // The side of the biggest square tile whose pixels stay within the budget
+    fn tile_size(&self, rays_per_pixel: u64) -> u32 {
+        let pixels = self.max_rays_per_pass / rays_per_pixel.max(1);
+        (pixels as f64).sqrt().min(f64::from(u32::MAX)) as u32
+    }
+}
+
+// Gives every view that would trace too much in one pass tiles small enough, a `RaytraceTiling` only gets made smaller
+fn split_raytrace_passes(
+    views: Query<(
+        Entity,
+        &CameraExtract,
+        &PipelineExtract,
+        &ExtractedCamera,
+        Option<&RaytraceTiling>,
+    )>,
+    budget: Res<RaytraceRayBudget>,
+    mut warned: Local<EntityHashSet>,
+    mut commands: Commands,
+) {
+    for (entity, camera, pipeline, extracted_camera, tiling) in &views {
+        let Some(size) = extracted_camera.physical_target_size else {
+            continue;
+        };
+        let pixels = size.as_u64vec2().element_product().max(1);
+        let rays_per_pixel = camera
+            .estimated_rays(size, pipeline.bounces)
+            .div_ceil(pixels);
+        if rays_per_pixel == 0 {
+            continue;
+        }
+
+        let tile_size = budget.tile_size(rays_per_pixel);
+        let current = tiling.map_or(size.max_element(), |tiling| tiling.tile_size);
+        if current <= tile_size {
+            continue;
+        }
+
+        if tile_size < MIN_TILE_SIZE && warned.insert(entity) {
+            warn!("RaytracedCamera on {entity} traces about {rays_per_pixel} rays per pixel in a frame, more than RaytraceRayBudget allows in a tile, which risks losing the GPU device. Lower its sample_count or bounces, or accumulate with RaytraceSampling::Progressive");
+        }
+        commands.entity(entity).insert(RaytraceTiling {
+            tile_size: tile_size.max(MIN_TILE_SIZE),
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{RaytraceRayBudget, MIN_TILE_SIZE};
+
+    #[test]
+    fn tiles_stay_within_the_budget() {
+        let budget = RaytraceRayBudget {
+            max_rays_per_pass: 1 << 24,
+        };
+
+        // 64 samples with 3 bounces and their shadow rays
+        let rays_per_pixel = 64 * 4 * 2;
+        let tile_size = u64::from(budget.tile_size(rays_per_pixel));
+        assert!(tile_size * tile_size * rays_per_pixel <= budget.max_rays_per_pass);
+        assert!((tile_size + 1) * (tile_size + 1) * rays_per_pixel > budget.max_rays_per_pass);
+
+        let samples = u64::from(budget.max_frame_samples(3));
+        let min_tile = u64::from(MIN_TILE_SIZE * MIN_TILE_SIZE);
+        assert!(min_tile * samples * 4 * 2 <= budget.max_rays_per_pass);
+    }
+}
@@ -0,0 +1,282 @@
+use std::{collections::HashMap, fmt::Display};
+
+use bevy::prelude::*;
+use bevy_flycam::FlyCam;
+use serde::Deserialize;
+
+use crate::raytracing::{
+    PixelFilter, Quality, RaytraceHideMesh, RaytraceSampling, RaytraceSky, RaytracedCamera,
+    RaytracedSphere,
+};
+
+// A scene with its render settings, loaded from a RON file given on the command line:
+// `cargo run -- assets/scenes/showcase.ron`
+// It replaces the built in scene, so benchmark and showcase scenes can be swapped without recompiling.
+// Everything but the camera is optional, see assets/scenes for an example
+#[derive(Resource, Deserialize)]
+pub struct SceneFile {
+    camera: CameraSettings,
+    #[serde(default)]
+    sky: SkySettings,
+    // Referenced by name from the objects, objects with an unknown material get the default one
+    #[serde(default)]
+    materials: HashMap<String, MaterialSettings>,
+    #[serde(default)]
+    spheres: Vec<SphereSettings>,
+    #[serde(default)]
+    cubes: Vec<CubeSettings>,
+    #[serde(default)]
+    lights: Vec<LightSettings>,
+}
+
+impl SceneFile {
+    pub fn parse(text: &str) -> Result<Self, ron::error::SpannedError> {
+        ron::from_str(text)
+    }
+}
+
+// A scene that can't be loaded ends the program, an empty window would only hide the typo
+pub fn load_or_exit<T, E: Display>(path: &str, parse: impl FnOnce(&str) -> Result<T, E>) -> T {
+    let result = std::fs::read_to_string(path)
+        .map_err(|error| error.to_string())
+        .and_then(|text| parse(&text).map_err(|error| error.to_string()));
+    result.unwrap_or_else(|error| {
+        error!("Could not load the scene file {path}: {error}");
+        std::process::exit(1);
+    })
+}
+
+#[derive(Deserialize)]
+struct CameraSettings {
+    position: [f32; 3],
+    look_at: [f32; 3],
+    // The preset the other settings start from
+    #[serde(default)]
+    quality: QualitySettings,
+    sample_count: Option<u32>,
+    bounces: Option<u32>,
+    regularization: Option<f32>,
+    #[serde(default)]
+    spectral: bool,
+    // Accumulates `sample_count` samples over several frames, with this many per frame
+    progressive: Option<u32>,
+    // Traces half of the pixels per frame and reprojects the rest, ignored for progressive cameras
+    #[serde(default)]
+    checkerboard: bool,
+    #[serde(default)]
+    pixel_filter: PixelFilterSettings,
+}
+
+// Mirrors `Quality`, which isn't deserializable itself
+#[derive(Deserialize, Default)]
+enum QualitySettings {
+    Low,
+    #[default]
+    Medium,
+    High,
+    Ultra,
+}
+
+// Mirrors `PixelFilter`
+#[derive(Deserialize, Default)]
+enum PixelFilterSettings {
+    #[default]
+    Box,
+    Tent,
+    Gaussian,
+    BlackmanHarris,
+}
+
+#[derive(Deserialize, Default)]
+enum SkySettings {
+    #[default]
+    Gradient,
+    Atmosphere {
+        sun_direction: [f32; 3],
+    },
+}
+
+#[derive(Deserialize)]
+#[serde(default)]
+struct MaterialSettings {
+    // Linear rgb
+    base_color: [f32; 3],
+    metallic: f32,
+    roughness: f32,
+    transmission: f32,
+    ior: f32,
+    thickness: f32,
+    emissive: [f32; 3],
+}
+
+impl Default for MaterialSettings {
+    fn default() -> Self {
+        Self {
+            base_color: [0.8, 0.8, 0.8],
+            metallic: 0.0,
+            roughness: 0.5,
+            transmission: 0.0,
+            ior: 1.5,
+            thickness: 0.0,
+            emissive: [0.0, 0.0, 0.0],
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct SphereSettings {
+    position: [f32; 3],
+    radius: f32,
+    #[serde(default)]
+    material: String,
+}
+
+#[derive(Deserialize)]
+struct CubeSettings {
+    position: [f32; 3],
+    size: [f32; 3],
+    #[serde(default)]
+    material: String,
+}
+
+#[derive(Deserialize)]
+struct LightSettings {
+    position: [f32; 3],
+    // In lumens, like bevy point lights
+    intensity: f32,
+    #[serde(default = "white")]
+    color: [f32; 3],
+    // Above 0.0 this is a sphere light with soft shadows
+    #[serde(default)]
+    radius: f32,
+}
+
+fn white() -> [f32; 3] {
+    [1.0, 1.0, 1.0]
+}
+
+fn linear([red, green, blue]: [f32; 3]) -> LinearRgba {
+    LinearRgba::rgb(red, green, blue)
+}
+
+pub fn spawn_scene_file(
+    scene: Res<SceneFile>,
+    mut commands: Commands,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+    mut sky: ResMut<RaytraceSky>,
+) {
+    let camera = &scene.camera;
+    let mut settings = RaytracedCamera::preset(match camera.quality {
+        QualitySettings::Low => Quality::Low,
+        QualitySettings::Medium => Quality::Medium,
+        QualitySettings::High => Quality::High,
+        QualitySettings::Ultra => Quality::Ultra,
+    });
+    settings.sample_count = camera.sample_count.unwrap_or(settings.sample_count);
+    settings.bounces = camera.bounces.unwrap_or(settings.bounces);
+    settings.regularization = camera.regularization.unwrap_or(settings.regularization);
+    settings.spectral = camera.spectral;
+    settings.pixel_filter = match camera.pixel_filter {
+        PixelFilterSettings::Box => PixelFilter::Box,
+        PixelFilterSettings::Tent => PixelFilter::Tent,
+        PixelFilterSettings::Gaussian => PixelFilter::Gaussian,
+        PixelFilterSettings::BlackmanHarris => PixelFilter::BlackmanHarris,
+    };
+    if let Some(samples_per_frame) = camera.progressive {
+        settings.sampling = RaytraceSampling::Progressive { samples_per_frame };
+    } else if camera.checkerboard {
+        settings.sampling = RaytraceSampling::Checkerboard;
+    }
+
+    commands.spawn((
+        Camera3dBundle {
+            transform: Transform::from_translation(Vec3::from_array(camera.position))
+                .looking_at(Vec3::from_array(camera.look_at), Vec3::Y),
+            camera: Camera {
+                clear_color: Color::WHITE.into(),
+                ..default()
+            },
+            ..default()
+        },
+        Name::new("Raytraced Camera"),
+        settings,
+        bevy_transform_gizmo::GizmoPickSource::default(),
+        FlyCam,
+    ));
+
+    *sky = match scene.sky {
+        SkySettings::Gradient => RaytraceSky::Gradient,
+        SkySettings::Atmosphere { sun_direction } => {
+            RaytraceSky::atmosphere(Vec3::from_array(sun_direction))
+        }
+    };
+
+    let scene_materials = scene
+        .materials
+        .iter()
+        .map(|(name, material)| {
+            let handle = materials.add(StandardMaterial {
+                base_color: linear(material.base_color).into(),
+                metallic: material.metallic,
+                perceptual_roughness: material.roughness,
+                specular_transmission: material.transmission,
+                ior: material.ior,
+                thickness: material.thickness,
+                emissive: linear(material.emissive),
+                ..default()
+            });
+            (name.as_str(), handle)
+        })
+        .collect::<HashMap<_, _>>();
+    let default_material = materials.add(StandardMaterial::default());
+    let material = |name: &str| {
+        scene_materials.get(name).cloned().unwrap_or_else(|| {
+            warn!("Unknown material \"{name}\" in the scene file, using the default one");
+            default_material.clone()
+        })
+    };
+
+    for sphere in &scene.spheres {
+        commands.spawn((
+            PbrBundle {
+                mesh: meshes.add(Sphere::new(1.0)),
+                material: material(&sphere.material),
+                transform: Transform::from_translation(Vec3::from_array(sphere.position)),
+                ..default()
+            },
+            RaytracedSphere {
+                radius: sphere.radius,
+            },
+            RaytraceHideMesh,
+            bevy_mod_picking::PickableBundle::default(),
+            bevy_transform_gizmo::GizmoTransformable,
+        ));
+    }
+
+    for cube in &scene.cubes {
+        commands.spawn((
+            PbrBundle {
+                mesh: meshes.add(Cuboid::from_size(Vec3::from_array(cube.size))),
+                material: material(&cube.material),
+                transform: Transform::from_translation(Vec3::from_array(cube.position)),
+                ..default()
+            },
+            bevy_mod_picking::PickableBundle::default(),
+            bevy_transform_gizmo::GizmoTransformable,
+        ));
+    }
+
+    for light in &scene.lights {
+        commands.spawn(PointLightBundle {
+            point_light: PointLight {
+                intensity: light.intensity,
+                color: linear(light.color).into(),
+                radius: light.radius,
+                ..default()
+            },
+            transform: Transform::from_translation(Vec3::from_array(light.position)),
+            ..default()
+        });
+    }
+}